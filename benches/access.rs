@@ -0,0 +1,97 @@
+//! Compares the cost of accessing an association with a raw `thread_local!`.
+//!
+//! Run with `cargo bench`, reports the cost of every access relative to the raw thread
+//! local access and fails when it exceeds a bound.  Associations with a `const` INIT
+//! compile to the same single TLS load as a raw const thread local, which the macro checks
+//! at compile time.  Timings of a few nanoseconds vary between runs, the accesses are timed
+//! in alternating rounds against the raw access and the bounds on the median ratio only
+//! catch gross regressions like a lazy initialization check or an out of line call on the
+//! fast path.
+
+// the raw thread local is lazily initialized like the associations
+#![allow(clippy::missing_const_for_thread_local)]
+
+use assoc_threadlocal::*;
+use std::cell::Cell;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 2_000_000;
+const ROUNDS: u32 = 9;
+
+std::thread_local!(static RAW: Cell<u64> = Cell::new(0));
+std::thread_local!(static RAW_CONST: Cell<u64> = const { Cell::new(0) });
+
+struct Bench;
+assoc_threadlocal!(Bench, u64 = 0);
+
+struct ConstBench;
+assoc_threadlocal!(ConstBench, u64 = const 0);
+
+fn time(f: &mut impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed()
+}
+
+// times 'raw' and 'assoc' in alternating rounds, so both see the same machine load, prints
+// the median ratio and returns whether it exceeds 'bound'
+fn compare(name: &str, mut raw: impl FnMut(), mut assoc: impl FnMut(), bound: f64) -> bool {
+    // warm up, this also initializes the thread locals
+    for _ in 0..ITERATIONS / 10 {
+        raw();
+        assoc();
+    }
+    let mut ratios: Vec<f64> = (0..ROUNDS)
+        .map(|_| {
+            let raw = time(&mut raw);
+            time(&mut assoc).as_secs_f64() / raw.as_secs_f64()
+        })
+        .collect();
+    ratios.sort_by(f64::total_cmp);
+    let ratio = ratios[ratios.len() / 2];
+    let exceeded = ratio > bound;
+    let note = if exceeded {
+        format!(", more than {bound}x")
+    } else {
+        String::new()
+    };
+    println!("{name:<24} {ratio:>8.2}x raw{note}");
+    exceeded
+}
+
+fn main() {
+    let failed = [
+        compare(
+            "get_threadlocal",
+            || {
+                black_box(RAW.with(|c| c.get()));
+            },
+            || {
+                black_box(Bench::get_threadlocal());
+            },
+            2.0,
+        ),
+        compare(
+            "const get_threadlocal",
+            || {
+                black_box(RAW_CONST.with(|c| c.get()));
+            },
+            || {
+                black_box(ConstBench::get_threadlocal());
+            },
+            // an extra branch or call would double the cost
+            1.5,
+        ),
+        // sets additionally bump the generation and register on the first set
+        compare(
+            "set_threadlocal",
+            || RAW.with(|c| c.set(black_box(1))),
+            || Bench::set_threadlocal(black_box(1)),
+            4.0,
+        ),
+    ];
+    assert!(!failed.contains(&true), "access cost regressed");
+}
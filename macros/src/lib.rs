@@ -0,0 +1,64 @@
+#![warn(missing_docs)]
+//! Attribute macros for the `assoc_threadlocal` crate, use them through its `test-utils`
+//! feature.
+
+use proc_macro::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Turns a function into a test which runs with all registered associations reset to
+/// their INIT, the previous values are restored afterwards.
+///
+/// The generated test calls `assoc_threadlocal::registry::isolate_threadlocals()`, the
+/// crate has to be available under this name.
+#[proc_macro_attribute]
+pub fn assoc_test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if let Some(token) = attr.into_iter().next() {
+        return compile_error("#[assoc_test] takes no arguments", token.span());
+    }
+
+    let mut tokens: Vec<TokenTree> = item.into_iter().collect();
+    let body = match tokens.pop() {
+        Some(TokenTree::Group(body)) if body.delimiter() == Delimiter::Brace => body,
+        other => {
+            let span = other.map_or_else(Span::call_site, |t| t.span());
+            return compile_error("#[assoc_test] expects a function", span);
+        }
+    };
+
+    let isolation: TokenStream =
+        "let _assoc_isolation = ::assoc_threadlocal::registry::isolate_threadlocals();"
+            .parse()
+            .expect("valid tokens");
+    let span = body.span();
+    let mut wrapped = isolation;
+    wrapped.extend([TokenTree::Group(body)]);
+    let mut wrapped_body = Group::new(Delimiter::Brace, wrapped);
+    wrapped_body.set_span(span);
+
+    let mut output: TokenStream = "#[test]".parse().expect("valid tokens");
+    output.extend(tokens);
+    output.extend([TokenTree::Group(wrapped_body)]);
+    output
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let tokens = [
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("core", span)),
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenStream::from(TokenTree::Literal(proc_macro::Literal::string(message))),
+        )),
+    ];
+    tokens
+        .into_iter()
+        .map(|mut token| {
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
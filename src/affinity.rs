@@ -0,0 +1,59 @@
+//! Ownership of associations by a single thread.
+//!
+//! Used by `assoc_threadlocal!(T, TARGET = INIT, main_thread)`, the first thread that
+//! accesses such an association becomes its owner.
+
+use crate::AccessError;
+use std::sync::OnceLock;
+use std::thread::{self, ThreadId};
+
+/// The thread owning an association, claimed by the first access.
+#[derive(Debug)]
+pub struct OwnerThread(OnceLock<ThreadId>);
+
+impl OwnerThread {
+    /// Creates an unclaimed owner.
+    pub const fn new() -> Self {
+        OwnerThread(OnceLock::new())
+    }
+
+    /// Claims ownership for the current thread when unclaimed, fails when another
+    /// thread owns it.
+    #[inline]
+    pub fn claim(&self) -> Result<(), AccessError> {
+        let current = thread::current().id();
+        if *self.0.get_or_init(|| current) == current {
+            Ok(())
+        } else {
+            Err(AccessError::WrongThread)
+        }
+    }
+
+    /// Returns the owning thread, `None` when not claimed yet.
+    pub fn owner(&self) -> Option<ThreadId> {
+        self.0.get().copied()
+    }
+}
+
+impl Default for OwnerThread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OwnerThread;
+
+    #[test]
+    fn first_claims() {
+        static OWNER: OwnerThread = OwnerThread::new();
+        assert_eq!(OWNER.owner(), None);
+        assert!(OWNER.claim().is_ok());
+        assert!(OWNER.claim().is_ok());
+        assert!(std::thread::spawn(|| OWNER.claim().is_err())
+            .join()
+            .unwrap());
+        assert_eq!(OWNER.owner(), Some(std::thread::current().id()));
+    }
+}
@@ -0,0 +1,120 @@
+//! Per-thread allocation counting (requires the `alloc-counter` feature).
+//!
+//! `CountingAlloc` wraps a global allocator and counts the allocations done by each thread
+//! in an association of a marker type, tests can then assert that a code path does not
+//! allocate.
+
+use crate::AssocThreadLocal;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::marker::PhantomData;
+
+/// Tag for the thread local allocation counter.
+pub struct AllocCount;
+
+/// Global allocator wrapper counting allocations per thread in `M`.
+///
+/// ```
+/// use assoc_threadlocal::*;
+///
+/// struct Allocations;
+/// assoc_alloc_counter!(Allocations);
+///
+/// #[global_allocator]
+/// static ALLOC: CountingAlloc<Allocations> = CountingAlloc::new(std::alloc::System);
+///
+/// let mark = Allocations::allocation_count();
+/// let _nothing = [0u8; 16];
+/// assert_eq!(Allocations::allocations_since(mark), 0);
+/// let _boxed = Box::new(1u64);
+/// assert_eq!(Allocations::allocations_since(mark), 1);
+/// ```
+pub struct CountingAlloc<M, A = System> {
+    inner: A,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<M, A> CountingAlloc<M, A> {
+    /// Wraps 'inner'.
+    pub const fn new(inner: A) -> Self {
+        CountingAlloc {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+unsafe impl<M: AssocAllocCounter, A: GlobalAlloc> GlobalAlloc for CountingAlloc<M, A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        M::set_threadlocal(M::get_threadlocal().wrapping_add(1));
+        unsafe { self.inner.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        M::set_threadlocal(M::get_threadlocal().wrapping_add(1));
+        unsafe { self.inner.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        M::set_threadlocal(M::get_threadlocal().wrapping_add(1));
+        unsafe { self.inner.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+}
+
+/// A per-thread allocation counter fed by `CountingAlloc`.
+/// Use the `assoc_alloc_counter!()` macro for implementing this trait on types.
+pub trait AssocAllocCounter: AssocThreadLocal<u64, AllocCount> {
+    /// Returns the number of allocations (including reallocations) done by this thread.
+    fn allocation_count() -> u64 {
+        Self::get_threadlocal()
+    }
+
+    /// Returns the number of allocations since 'mark' was taken by `allocation_count()`.
+    fn allocations_since(mark: u64) -> u64 {
+        Self::get_threadlocal().wrapping_sub(mark)
+    }
+}
+
+/// Associates a per-thread allocation counter to a marker type.
+///
+///  * 'T' is the marker type passed to `CountingAlloc`
+#[macro_export]
+macro_rules! assoc_alloc_counter {
+    ($T:ty) => {
+        $crate::assoc_threadlocal!($crate::AllocCount:$T, u64 = 0);
+
+        impl $crate::AssocAllocCounter for $T {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocAllocCounter, CountingAlloc};
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    struct TestAllocs;
+    assoc_alloc_counter!(TestAllocs);
+
+    static ALLOC: CountingAlloc<TestAllocs> = CountingAlloc::new(System);
+
+    #[test]
+    fn counts_per_thread() {
+        let mark = TestAllocs::allocation_count();
+        let layout = Layout::new::<u64>();
+        unsafe {
+            let p = ALLOC.alloc(layout);
+            let p = ALLOC.realloc(p, layout, 16);
+            ALLOC.dealloc(p, Layout::from_size_align(16, layout.align()).unwrap());
+        }
+        assert_eq!(TestAllocs::allocations_since(mark), 2);
+        assert_eq!(
+            std::thread::spawn(TestAllocs::allocation_count)
+                .join()
+                .unwrap(),
+            0
+        );
+    }
+}
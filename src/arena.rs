@@ -0,0 +1,193 @@
+//! Per-thread bump arenas associated to types.
+
+use std::alloc::Layout;
+use std::cell::{Cell, RefCell};
+use std::mem::MaybeUninit;
+use std::ptr::NonNull;
+
+/// Size of the first chunk a `Bump` allocates.
+const FIRST_CHUNK_SIZE: usize = 1024;
+
+/// A simple bump allocator.
+///
+/// Allocation only moves a pointer forward, memory is released all at once by `reset()`.
+/// Destructors of allocated values are never run.
+#[derive(Default)]
+pub struct Bump {
+    // chunks are kept as raw pointers, handing out references into them must not be
+    // invalidated by borrowing the chunk list again
+    chunks: RefCell<Vec<NonNull<[MaybeUninit<u8>]>>>,
+    used: Cell<usize>,
+}
+
+impl Bump {
+    /// Creates an empty arena, no memory is allocated until the first `alloc()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves 'value' into the arena and returns a reference to it.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<V>(&self, value: V) -> &mut V {
+        let ptr = self.alloc_layout(Layout::new::<V>()).cast::<V>();
+        unsafe {
+            ptr.as_ptr().write(value);
+            &mut *ptr.as_ptr()
+        }
+    }
+
+    /// Releases all allocations, keeping only the largest chunk for reuse.
+    pub fn reset(&mut self) {
+        let chunks = self.chunks.get_mut();
+        if let Some(last) = chunks.pop() {
+            for chunk in chunks.drain(..) {
+                drop(unsafe { Box::from_raw(chunk.as_ptr()) });
+            }
+            chunks.push(last);
+        }
+        self.used.set(0);
+    }
+
+    /// Returns the number of bytes reserved from the system.
+    pub fn capacity(&self) -> usize {
+        self.chunks.borrow().iter().map(|c| c.len()).sum()
+    }
+
+    fn alloc_layout(&self, layout: Layout) -> NonNull<u8> {
+        if layout.size() == 0 {
+            // SAFETY: align is a nonzero power of two
+            return unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+        }
+
+        let mut chunks = self.chunks.borrow_mut();
+        if let Some(chunk) = chunks.last() {
+            let base = chunk.as_ptr() as *mut u8 as usize;
+            let start = (base + self.used.get()).next_multiple_of(layout.align()) - base;
+            if start + layout.size() <= chunk.len() {
+                self.used.set(start + layout.size());
+                return unsafe { chunk.cast::<u8>().add(start) };
+            }
+        }
+
+        // the current chunk is exhausted, allocate one that at least doubles the capacity
+        let last_len = chunks.last().map_or(FIRST_CHUNK_SIZE / 2, |c| c.len());
+        let len = (last_len * 2).max(layout.size() + layout.align());
+        let chunk = NonNull::from(Box::leak(Box::<[u8]>::new_uninit_slice(len)));
+        let base = chunk.as_ptr() as *mut u8 as usize;
+        let start = base.next_multiple_of(layout.align()) - base;
+        self.used.set(start + layout.size());
+        chunks.push(chunk);
+        unsafe { chunk.cast::<u8>().add(start) }
+    }
+}
+
+impl Drop for Bump {
+    fn drop(&mut self) {
+        for chunk in self.chunks.get_mut().drain(..) {
+            drop(unsafe { Box::from_raw(chunk.as_ptr()) });
+        }
+    }
+}
+
+/// Associates a per-thread `Bump` arena to a type.
+/// Use the `assoc_arena!()` macro for implementing this trait on types.
+pub trait AssocArena {
+    /// Returns the associated thread local arena of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_arena() -> *const RefCell<Bump>;
+
+    /// Calls 'f' with the associated arena, allocations can not escape the closure.
+    ///
+    /// # Panics
+    /// When `reset_arena()` is called from within 'f'.
+    fn with_arena<R>(f: impl FnOnce(&Bump) -> R) -> R {
+        f(&*unsafe { (*Self::the_arena()).borrow() })
+    }
+
+    /// Releases all allocations of the associated arena.
+    ///
+    /// # Panics
+    /// When called from within `with_arena()`.
+    fn reset_arena() {
+        unsafe { (*Self::the_arena()).borrow_mut().reset() }
+    }
+}
+
+/// Associates a per-thread bump arena to a type.
+///
+///  * 'T' is the type you want have a thread local arena associated to
+///  * 'bump' selects the arena kind, currently only bump allocation is available
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct MyPass;
+/// assoc_arena!(MyPass, bump);
+///
+/// let sum = MyPass::with_arena(|arena| {
+///     let a = arena.alloc(40);
+///     let b = arena.alloc(2);
+///     *a + *b
+/// });
+/// assert_eq!(sum, 42);
+///
+/// MyPass::reset_arena();
+/// ```
+#[macro_export]
+macro_rules! assoc_arena {
+    ($T:ty, bump) => {
+        impl $crate::AssocArena for $T {
+            unsafe fn the_arena() -> *const std::cell::RefCell<$crate::Bump> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_ARENA: (
+                        std::cell::RefCell<$crate::Bump>,
+                        std::marker::PhantomData<$T>,
+                    ) = (
+                        std::cell::RefCell::new($crate::Bump::new()),
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_ARENA.with(|l| &l.0 as *const std::cell::RefCell<$crate::Bump>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocArena, Bump};
+
+    #[test]
+    fn alignment() {
+        let bump = Bump::new();
+        let _ = bump.alloc(1u8);
+        let x = bump.alloc(7u64);
+        assert_eq!(x as *const u64 as usize % std::mem::align_of::<u64>(), 0);
+        assert_eq!(*x, 7);
+    }
+
+    #[test]
+    fn grows_and_resets() {
+        let mut bump = Bump::new();
+        for i in 0..1000u64 {
+            assert_eq!(*bump.alloc(i), i);
+        }
+        let big = bump.alloc([0u8; 4096]);
+        assert_eq!(big.len(), 4096);
+        bump.reset();
+        assert_eq!(bump.chunks.borrow().len(), 1);
+    }
+
+    struct TestPass;
+    assoc_arena!(TestPass, bump);
+
+    #[test]
+    fn associated_arena() {
+        let s = TestPass::with_arena(|arena| arena.alloc(String::from("arena")).len());
+        assert_eq!(s, 5);
+        TestPass::reset_arena();
+        assert!(TestPass::with_arena(|arena| arena.capacity()) > 0);
+    }
+}
@@ -0,0 +1,199 @@
+//! Fixed size arrays of per-thread values.
+//!
+//! Small tables of per-thread values, e.g. one per priority or log level, are one
+//! association with `N` slots instead of `N` associations with distinct tags.  The slots
+//! are stored side by side in one thread local and accessed by index.
+
+use std::cell::Cell;
+
+/// 'LEN' thread local values of type 'T' associated to a type.
+/// Use the `assoc_threadlocal_array!()` macro for implementing this trait on types.
+pub trait AssocThreadLocalArray<T: Copy, TAG = ()> {
+    /// The number of slots.
+    const LEN: usize;
+
+    #[doc(hidden)]
+    unsafe fn the_slots() -> *const [Cell<T>];
+
+    /// Returns the initial value of every slot.
+    fn threadlocal_array_init() -> T;
+
+    /// Returns the current threads value at 'index'.
+    ///
+    /// # Panics
+    /// When 'index' is not less than 'LEN'.
+    #[inline]
+    fn get_threadlocal_at(index: usize) -> T {
+        unsafe { (&(*Self::the_slots()))[index].get() }
+    }
+
+    /// Returns the current threads value at 'index', `None` when it is out of bounds.
+    #[inline]
+    fn try_get_threadlocal_at(index: usize) -> Option<T> {
+        unsafe { (&(*Self::the_slots())).get(index).map(Cell::get) }
+    }
+
+    /// Sets the current threads value at 'index'.
+    ///
+    /// # Panics
+    /// When 'index' is not less than 'LEN'.
+    #[inline]
+    fn set_threadlocal_at(index: usize, value: T) {
+        unsafe { (&(*Self::the_slots()))[index].set(value) }
+    }
+
+    /// Sets the current threads value at 'index' and returns the old one.
+    ///
+    /// # Panics
+    /// When 'index' is not less than 'LEN'.
+    fn replace_threadlocal_at(index: usize, value: T) -> T {
+        unsafe { (&(*Self::the_slots()))[index].replace(value) }
+    }
+
+    /// Resets all of the current threads values to INIT.
+    fn reset_threadlocal_array() {
+        let init = Self::threadlocal_array_init();
+        unsafe {
+            (&(*Self::the_slots()))
+                .iter()
+                .for_each(|slot| slot.set(init))
+        }
+    }
+
+    /// Returns a copy of all of the current threads values.
+    fn get_threadlocal_array() -> Vec<T> {
+        unsafe { (&(*Self::the_slots())).iter().map(Cell::get).collect() }
+    }
+}
+
+/// Associates an array of 'N' thread local values to a type.
+///
+///  * 'TAG' is an optional tag, `()` when left out
+///  * 'T' is the type you want have the values associated to
+///  * 'TARGET' is the type of the values
+///  * 'INIT' is the initial value of every slot, the targets `Default` when left out
+///  * 'N' is the number of slots, given as `N = 8` or just `8`
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Clone, Copy)]
+/// enum Level { Error, Warn, Info }
+///
+/// struct Logger;
+/// assoc_threadlocal_array!(Logger, u32; N = 3);
+///
+/// fn log(level: Level) {
+///     let index = level as usize;
+///     Logger::set_threadlocal_at(index, Logger::get_threadlocal_at(index) + 1);
+/// }
+///
+/// log(Level::Warn);
+/// log(Level::Warn);
+/// assert_eq!(Logger::get_threadlocal_array(), [0, 2, 0]);
+/// assert_eq!(Logger::try_get_threadlocal_at(3), None);
+/// ```
+#[macro_export]
+macro_rules! assoc_threadlocal_array {
+    ($TAG:ty: $T:ty, $TARGET:ty = $INIT:expr; N = $N:expr) => {
+        impl $crate::AssocThreadLocalArray<$TARGET, $TAG> for $T {
+            const LEN: usize = $N;
+
+            unsafe fn the_slots() -> *const [std::cell::Cell<$TARGET>] {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_THREADLOCALS: (
+                        [std::cell::Cell<$TARGET>; $N],
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = (
+                        std::array::from_fn(|_| {
+                            std::cell::Cell::new(
+                                <$T as $crate::AssocThreadLocalArray<$TARGET, $TAG>>::threadlocal_array_init(),
+                            )
+                        }),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_THREADLOCALS.with(|l| &l.0[..] as *const [std::cell::Cell<$TARGET>])
+            }
+
+            fn threadlocal_array_init() -> $TARGET {
+                $INIT
+            }
+        }
+    };
+    ($TAG:ty: $T:ty, $TARGET:ty; N = $N:expr) => {
+        $crate::assoc_threadlocal_array!($TAG: $T, $TARGET = <$TARGET>::default(); N = $N);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr; N = $N:expr) => {
+        $crate::assoc_threadlocal_array!((): $T, $TARGET = $INIT; N = $N);
+    };
+    ($T:ty, $TARGET:ty; N = $N:expr) => {
+        $crate::assoc_threadlocal_array!((): $T, $TARGET = <$TARGET>::default(); N = $N);
+    };
+    // the length without 'N =', after the arms above since 'N = 8' is an expression too
+    ($TAG:ty: $T:ty, $TARGET:ty = $INIT:expr; $N:expr) => {
+        $crate::assoc_threadlocal_array!($TAG: $T, $TARGET = $INIT; N = $N);
+    };
+    ($TAG:ty: $T:ty, $TARGET:ty; $N:expr) => {
+        $crate::assoc_threadlocal_array!($TAG: $T, $TARGET = <$TARGET>::default(); N = $N);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr; $N:expr) => {
+        $crate::assoc_threadlocal_array!((): $T, $TARGET = $INIT; N = $N);
+    };
+    ($T:ty, $TARGET:ty; $N:expr) => {
+        $crate::assoc_threadlocal_array!((): $T, $TARGET = <$TARGET>::default(); N = $N);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocThreadLocalArray;
+
+    struct Table;
+    struct Limits;
+    assoc_threadlocal_array!(Table, u8 = 1; N = 4);
+    assoc_threadlocal_array!(Limits: Table, u8; 2);
+    assoc_threadlocal_array!(Limits: Table, u16 = 5; N = 3);
+
+    #[test]
+    fn per_slot() {
+        assert_eq!(<Table as AssocThreadLocalArray<u8>>::LEN, 4);
+        assert_eq!(
+            <Table as AssocThreadLocalArray<u16, Limits>>::get_threadlocal_array(),
+            [5; 3]
+        );
+        <Table as AssocThreadLocalArray<u8>>::set_threadlocal_at(3, 7);
+        assert_eq!(
+            <Table as AssocThreadLocalArray<u8>>::replace_threadlocal_at(0, 2),
+            1
+        );
+        <Table as AssocThreadLocalArray<u8, Limits>>::set_threadlocal_at(1, 9);
+        assert_eq!(
+            <Table as AssocThreadLocalArray<u8>>::get_threadlocal_array(),
+            [2, 1, 1, 7]
+        );
+        assert_eq!(
+            <Table as AssocThreadLocalArray<u8, Limits>>::get_threadlocal_array(),
+            [0, 9]
+        );
+        assert_eq!(
+            std::thread::spawn(|| <Table as AssocThreadLocalArray<u8>>::get_threadlocal_at(3))
+                .join()
+                .unwrap(),
+            1
+        );
+        <Table as AssocThreadLocalArray<u8>>::reset_threadlocal_array();
+        assert_eq!(
+            <Table as AssocThreadLocalArray<u8>>::get_threadlocal_array(),
+            [1; 4]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds() {
+        <Table as AssocThreadLocalArray<u8>>::set_threadlocal_at(4, 0);
+    }
+}
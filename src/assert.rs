@@ -0,0 +1,34 @@
+//! Compile time checks emitted by the macros before the actual expansion, so that common
+//! mistakes are reported with a targeted message instead of unsatisfied trait bounds deep
+//! inside the generated code.
+
+use std::marker::PhantomData;
+
+/// Implemented for every type that can be the target of an association.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can not be the target of a thread local association because it is not `Copy`",
+    label = "not `Copy`",
+    note = "values are copied in and out of the thread local on every access",
+    note = "use `assoc_refcell!()` for values that are not `Copy`, it keeps them in a `RefCell`"
+)]
+pub trait Target {}
+
+#[diagnostic::do_not_recommend]
+impl<T: Copy + 'static> Target for T {}
+
+/// Implemented for every type that can be used as tag of an association.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can not be used as tag of a thread local association",
+    label = "not a sized 'static type",
+    note = "tags are marker types, usually a unit struct: `struct MyTag;`"
+)]
+pub trait Tag {}
+
+#[diagnostic::do_not_recommend]
+impl<T: 'static> Tag for T {}
+
+/// Naming this type asserts that 'TAG' and 'TARGET' can be used for an association.
+pub struct Assertion<TAG: ?Sized + Tag, TARGET: ?Sized + Target>(
+    PhantomData<TAG>,
+    PhantomData<TARGET>,
+);
@@ -0,0 +1,189 @@
+//! Per-thread exponential backoff.
+//!
+//! Retry loops need to remember how often an operation failed in a row to compute the
+//! delay before the next attempt.  Associated to the client type this state lives per
+//! thread next to the client instead of in ad-hoc statics.  The delay doubles with every
+//! failure, starting at the base and capped at the maximum, a success resets it.
+
+use crate::AssocThreadLocal;
+use std::time::Duration;
+
+/// Tag for the thread local number of consecutive failures of a backoff.
+pub struct BackoffState;
+
+/// A per-thread exponential backoff.
+/// Use the `assoc_backoff!()` macro for implementing this trait on types.
+pub trait AssocBackoff: AssocThreadLocal<u32, BackoffState> {
+    /// The delay after the first failure.
+    const BASE: Duration;
+    /// The longest delay.
+    const MAX: Duration;
+
+    /// Returns how long to wait before the next attempt on the current thread, zero when
+    /// the last attempt did not fail.
+    fn next_delay() -> Duration {
+        match Self::get_threadlocal() {
+            0 => Duration::ZERO,
+            failures => {
+                let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+                Self::BASE.saturating_mul(factor).min(Self::MAX)
+            }
+        }
+    }
+
+    /// Counts a failed attempt on the current thread and returns the delay before the next
+    /// one.
+    fn record_failure() -> Duration {
+        Self::set_threadlocal(Self::get_threadlocal().saturating_add(1));
+        Self::next_delay()
+    }
+
+    /// Resets the backoff of the current thread after a successful attempt.
+    fn record_success() {
+        Self::set_threadlocal(0)
+    }
+
+    /// Returns the number of consecutive failures on the current thread.
+    fn failures() -> u32 {
+        Self::get_threadlocal()
+    }
+}
+
+/// Parses durations like `10ms`, `5s` or `2min` as written in `assoc_backoff!()`.
+/// Supported units are `ns`, `us`, `ms`, `s`, `min` and `h`.
+///
+/// # Panics
+/// On a missing number or unknown unit, at compile time when used in a const.
+#[doc(hidden)]
+pub const fn parse_duration(text: &str) -> Duration {
+    const fn unit_is(bytes: &[u8], start: usize, unit: &[u8]) -> bool {
+        if bytes.len() - start != unit.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < unit.len() {
+            if bytes[start + i] != unit[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    let bytes = text.as_bytes();
+    let mut value: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'_') {
+        if bytes[i] != b'_' {
+            value = value * 10 + (bytes[i] - b'0') as u64;
+        }
+        i += 1;
+    }
+    assert!(i > 0, "backoff durations start with a number");
+    if unit_is(bytes, i, b"ns") {
+        Duration::from_nanos(value)
+    } else if unit_is(bytes, i, b"us") {
+        Duration::from_micros(value)
+    } else if unit_is(bytes, i, b"ms") {
+        Duration::from_millis(value)
+    } else if unit_is(bytes, i, b"s") {
+        Duration::from_secs(value)
+    } else if unit_is(bytes, i, b"min") {
+        Duration::from_secs(value * 60)
+    } else if unit_is(bytes, i, b"h") {
+        Duration::from_secs(value * 3600)
+    } else {
+        panic!("backoff durations end in ns, us, ms, s, min or h")
+    }
+}
+
+/// Associates a per-thread exponential backoff to a type.
+///
+///  * 'T' is the type you want have a thread local backoff associated to
+///  * 'base' is the delay after the first failure, e.g. `10ms`
+///  * 'max' is the longest delay, e.g. `5s`
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::time::Duration;
+///
+/// struct HttpClient;
+/// assoc_backoff!(HttpClient, base = 10ms, max = 5s);
+///
+/// fn fetch(attempt: u32) -> Result<&'static str, ()> {
+///     if attempt < 3 { Err(()) } else { Ok("body") }
+/// }
+///
+/// let mut attempt = 0;
+/// let body = loop {
+///     std::thread::sleep(HttpClient::next_delay());
+///     match fetch(attempt) {
+///         Ok(body) => {
+///             HttpClient::record_success();
+///             break body;
+///         }
+///         Err(()) => {
+///             attempt += 1;
+///             let delay = HttpClient::record_failure();
+///             assert_eq!(delay, Duration::from_millis(10 << (attempt - 1)));
+///         }
+///     }
+/// };
+/// assert_eq!(body, "body");
+/// assert_eq!(HttpClient::next_delay(), Duration::ZERO);
+/// ```
+#[macro_export]
+macro_rules! assoc_backoff {
+    ($T:ty, base = $BASE:tt, max = $MAX:tt) => {
+        $crate::assoc_threadlocal!($crate::BackoffState:$T, u32 = const 0);
+
+        impl $crate::AssocBackoff for $T {
+            const BASE: std::time::Duration = $crate::backoff::parse_duration(stringify!($BASE));
+            const MAX: std::time::Duration = $crate::backoff::parse_duration(stringify!($MAX));
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+    use crate::AssocBackoff;
+    use std::time::Duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250us"), Duration::from_micros(250));
+        assert_eq!(parse_duration("1_500ms"), Duration::from_millis(1500));
+        assert_eq!(parse_duration("2min"), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h"), Duration::from_secs(3600));
+    }
+
+    #[test]
+    #[should_panic]
+    fn unknown_unit() {
+        parse_duration("3d");
+    }
+
+    struct Client;
+    assoc_backoff!(Client, base = 100ms, max = 1s);
+
+    #[test]
+    fn doubles_up_to_max() {
+        let delays: Vec<_> = (0..6).map(|_| Client::record_failure()).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(Client::failures(), 6);
+        for _ in 0..40 {
+            Client::record_failure();
+        }
+        assert_eq!(Client::next_delay(), Duration::from_secs(1));
+        assert_eq!(
+            std::thread::spawn(Client::next_delay).join().unwrap(),
+            Duration::ZERO
+        );
+        Client::record_success();
+        assert_eq!(Client::failures(), 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! Per-thread budgets.
+//!
+//! A budget limits how much of some resource, e.g. bytes or work items, the current thread
+//! may consume.  Deeply nested code consumes from it without the limit being passed down
+//! as parameter and backs off when it is exceeded.
+
+use crate::AssocThreadLocal;
+use std::fmt;
+
+/// Tag for the thread local remaining amount of a budget.
+pub struct BudgetState;
+
+/// Error returned by `AssocBudget::try_consume()` when less than requested is left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exceeded {
+    /// The amount that was requested.
+    pub requested: u64,
+    /// The amount that was left.
+    pub remaining: u64,
+    /// The unit of the budget.
+    pub unit: &'static str,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "budget exceeded: requested {} {}, {} remaining",
+            self.requested, self.unit, self.remaining
+        )
+    }
+}
+
+impl std::error::Error for Exceeded {}
+
+/// A per-thread budget.
+/// Use the `assoc_budget!()` macro for implementing this trait on types.
+pub trait AssocBudget: AssocThreadLocal<u64, BudgetState> {
+    /// The amount every thread starts with.
+    const LIMIT: u64;
+    /// The unit of the budget, used in error messages.
+    const UNIT: &'static str;
+
+    /// Consumes 'n' from the current threads budget, either all or nothing.
+    fn try_consume(n: u64) -> Result<(), Exceeded> {
+        let remaining = Self::get_threadlocal();
+        match remaining.checked_sub(n) {
+            Some(left) => {
+                Self::set_threadlocal(left);
+                Ok(())
+            }
+            None => Err(Exceeded {
+                requested: n,
+                remaining,
+                unit: Self::UNIT,
+            }),
+        }
+    }
+
+    /// Returns the amount left on the current thread.
+    fn remaining() -> u64 {
+        Self::get_threadlocal()
+    }
+
+    /// Returns the amount consumed on the current thread.
+    fn consumed() -> u64 {
+        Self::LIMIT.saturating_sub(Self::get_threadlocal())
+    }
+
+    /// Gives 'n' back to the current threads budget, it never grows beyond `LIMIT`.
+    fn refund(n: u64) {
+        Self::set_threadlocal(Self::get_threadlocal().saturating_add(n).min(Self::LIMIT))
+    }
+}
+
+/// Associates a per-thread budget to a type.
+///
+///  * 'T' is the type you want have a thread local budget associated to
+///  * 'UNIT' names what is counted, e.g. `bytes`, it is only used in error messages
+///  * 'LIMIT' is the amount every thread starts with
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Allocator;
+/// assoc_budget!(Allocator, bytes = 10_000_000);
+///
+/// fn allocate(size: u64) -> Result<Vec<u8>, Exceeded> {
+///     Allocator::try_consume(size)?;
+///     Ok(vec![0; size as usize])
+/// }
+///
+/// assert!(allocate(4_000_000).is_ok());
+/// assert!(allocate(4_000_000).is_ok());
+/// let error = allocate(4_000_000).unwrap_err();
+/// assert_eq!(error.to_string(), "budget exceeded: requested 4000000 bytes, 2000000 remaining");
+/// Allocator::refund(4_000_000);
+/// assert_eq!(Allocator::remaining(), 6_000_000);
+/// ```
+#[macro_export]
+macro_rules! assoc_budget {
+    ($T:ty, $UNIT:ident = $LIMIT:expr) => {
+        $crate::assoc_threadlocal!($crate::BudgetState:$T, u64 = $LIMIT);
+
+        impl $crate::AssocBudget for $T {
+            const LIMIT: u64 = $LIMIT;
+            const UNIT: &'static str = stringify!($UNIT);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Exceeded;
+    use crate::{AssocBudget, BudgetState, SetAssocThreadLocal};
+
+    struct Work;
+    assoc_budget!(Work, items = 10);
+
+    #[test]
+    fn all_or_nothing() {
+        assert_eq!(Work::try_consume(7), Ok(()));
+        assert_eq!(
+            Work::try_consume(4),
+            Err(Exceeded {
+                requested: 4,
+                remaining: 3,
+                unit: "items"
+            })
+        );
+        assert_eq!(Work::consumed(), 7);
+        Work::refund(100);
+        assert_eq!(Work::remaining(), 10);
+        assert_eq!(Work::try_consume(10), Ok(()));
+        <Work as SetAssocThreadLocal<u64, BudgetState>>::reset_threadlocal();
+        assert_eq!(Work::remaining(), 10);
+    }
+
+    #[test]
+    fn per_thread() {
+        Work::try_consume(10).unwrap();
+        assert_eq!(std::thread::spawn(Work::remaining).join().unwrap(), 10);
+    }
+}
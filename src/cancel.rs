@@ -0,0 +1,215 @@
+//! Per-thread cancellation flags.
+//!
+//! Long running computations poll `checkpoint()` and stop with `Err(Cancelled)` once the
+//! flag of their thread is raised.  The flag is raised on the thread itself, through a
+//! `CancelHandle` handed out to another thread or, with the `registry` feature, by thread
+//! id from a supervisor.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Error returned by `AssocCancel::checkpoint()` when the computation was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Raises the cancellation flag of one thread from any thread.
+#[derive(Debug, Clone)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Cancels the computation of the thread this handle was taken from.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Returns whether the computation was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// A per-thread cancellation flag.
+/// Use the `assoc_cancel!()` macro for implementing this trait on types.
+pub trait AssocCancel: Sized + 'static {
+    /// Returns the associated thread local cancellation flag of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_cancel_flag() -> *const Arc<AtomicBool>;
+
+    /// Cancels the computation of the current thread.
+    fn cancel_current_thread() {
+        unsafe { (*Self::the_cancel_flag()).store(true, Ordering::Release) }
+    }
+
+    /// Returns whether the computation of the current thread was cancelled.
+    fn is_cancelled() -> bool {
+        unsafe { (*Self::the_cancel_flag()).load(Ordering::Acquire) }
+    }
+
+    /// Returns `Err(Cancelled)` when the computation of the current thread was cancelled,
+    /// for bailing out with `?`.
+    fn checkpoint() -> Result<(), Cancelled> {
+        if Self::is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lowers the flag of the current thread again, before it starts the next computation.
+    fn clear_cancelled() {
+        unsafe { (*Self::the_cancel_flag()).store(false, Ordering::Release) }
+    }
+
+    /// Returns a handle that cancels the computation of the current thread from elsewhere.
+    fn cancel_handle() -> CancelHandle {
+        CancelHandle(unsafe { (*Self::the_cancel_flag()).clone() })
+    }
+
+    /// Cancels the computation of the thread 'thread'.  Returns `false` when that thread
+    /// never accessed the flag or exited.
+    #[cfg(feature = "registry")]
+    fn cancel_thread(thread: std::thread::ThreadId) -> bool {
+        workers::cancel::<Self>(thread)
+    }
+}
+
+/// Creates the flag of the current thread, used by the `assoc_cancel!()` macro.
+#[doc(hidden)]
+pub fn new_flag<T: 'static>() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    #[cfg(feature = "registry")]
+    workers::insert::<T>(&flag);
+    flag
+}
+
+#[cfg(feature = "registry")]
+mod workers {
+    use std::any::TypeId;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, Weak};
+    use std::thread::ThreadId;
+
+    type Workers = HashMap<(TypeId, ThreadId), Weak<AtomicBool>>;
+
+    static WORKERS: Mutex<Option<Workers>> = Mutex::new(None);
+
+    pub(super) fn insert<T: 'static>(flag: &Arc<AtomicBool>) {
+        let mut workers = WORKERS.lock().unwrap_or_else(|e| e.into_inner());
+        let workers = workers.get_or_insert_with(HashMap::new);
+        // flags of exited threads are dropped with their thread locals
+        workers.retain(|_, flag| flag.strong_count() > 0);
+        workers.insert(
+            (TypeId::of::<T>(), std::thread::current().id()),
+            Arc::downgrade(flag),
+        );
+    }
+
+    pub(super) fn cancel<T: 'static>(thread: ThreadId) -> bool {
+        let workers = WORKERS.lock().unwrap_or_else(|e| e.into_inner());
+        match workers
+            .as_ref()
+            .and_then(|workers| workers.get(&(TypeId::of::<T>(), thread)))
+            .and_then(Weak::upgrade)
+        {
+            Some(flag) => {
+                flag.store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Associates a per-thread cancellation flag to a type.
+///
+///  * 'T' is the type you want have a thread local cancellation flag associated to
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct LongComputation;
+/// assoc_cancel!(LongComputation);
+///
+/// fn compute() -> Result<u64, Cancelled> {
+///     let mut sum = 0;
+///     for i in 0.. {
+///         LongComputation::checkpoint()?;
+///         sum += i;
+///     }
+///     Ok(sum)
+/// }
+///
+/// let (handle_tx, handle_rx) = std::sync::mpsc::channel();
+/// let worker = std::thread::spawn(move || {
+///     handle_tx.send(LongComputation::cancel_handle()).unwrap();
+///     compute()
+/// });
+/// handle_rx.recv().unwrap().cancel();
+/// assert_eq!(worker.join().unwrap(), Err(Cancelled));
+/// ```
+#[macro_export]
+macro_rules! assoc_cancel {
+    ($T:ty) => {
+        impl $crate::AssocCancel for $T {
+            unsafe fn the_cancel_flag() -> *const std::sync::Arc<std::sync::atomic::AtomicBool> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_CANCEL_FLAG: (
+                        std::sync::Arc<std::sync::atomic::AtomicBool>,
+                        std::marker::PhantomData<$T>,
+                    ) = ($crate::cancel::new_flag::<$T>(), std::marker::PhantomData);
+                );
+                ASSOCIATED_CANCEL_FLAG
+                    .with(|l| &l.0 as *const std::sync::Arc<std::sync::atomic::AtomicBool>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cancelled;
+    use crate::AssocCancel;
+
+    struct Job;
+    assoc_cancel!(Job);
+
+    #[test]
+    fn current_thread() {
+        assert_eq!(Job::checkpoint(), Ok(()));
+        Job::cancel_current_thread();
+        assert!(Job::is_cancelled());
+        assert_eq!(Job::checkpoint(), Err(Cancelled));
+        assert!(!std::thread::spawn(Job::is_cancelled).join().unwrap());
+        Job::clear_cancelled();
+        assert_eq!(Job::checkpoint(), Ok(()));
+    }
+
+    #[cfg(feature = "registry")]
+    #[test]
+    fn supervisor_cancels_worker() {
+        let (id_tx, id_rx) = std::sync::mpsc::channel();
+        let worker = std::thread::spawn(move || {
+            Job::checkpoint().unwrap();
+            id_tx.send(std::thread::current().id()).unwrap();
+            while Job::checkpoint().is_ok() {
+                std::thread::yield_now();
+            }
+        });
+        let id = id_rx.recv().unwrap();
+        assert!(Job::cancel_thread(id));
+        worker.join().unwrap();
+        assert!(!Job::cancel_thread(std::thread::current().id()));
+    }
+}
@@ -0,0 +1,165 @@
+//! Per-thread time sources.
+//!
+//! Time dependent code asks the ambient clock of a type for the current time instead of
+//! calling `Instant::now()` directly.  Normally that is the system clock, tests install a
+//! mock on their thread and advance it explicitly, other threads keep the real time.
+
+use crate::{AssocThreadLocal, ThreadLocalGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Tag for the thread local mock clock.
+pub struct ClockState;
+
+/// A mock time, advanced only explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockClock {
+    instant: Instant,
+    system: SystemTime,
+}
+
+impl MockClock {
+    /// Creates a mock starting at the current real time.
+    pub fn now() -> Self {
+        MockClock {
+            instant: Instant::now(),
+            system: SystemTime::now(),
+        }
+    }
+
+    /// Creates a mock starting at the real monotonic time and at 'system' wall clock time.
+    pub fn at(system: SystemTime) -> Self {
+        MockClock {
+            instant: Instant::now(),
+            system,
+        }
+    }
+
+    /// Returns the mock advanced by 'duration'.
+    #[must_use]
+    pub fn advanced(self, duration: Duration) -> Self {
+        MockClock {
+            instant: self.instant + duration,
+            system: self.system + duration,
+        }
+    }
+}
+
+/// A per-thread time source.
+/// Use the `assoc_clock!()` macro for implementing this trait on types.
+pub trait AssocClock: AssocThreadLocal<Option<MockClock>, ClockState> + Sized {
+    /// Returns the current monotonic time of the current threads clock.
+    fn now() -> Instant {
+        Self::get_threadlocal().map_or_else(Instant::now, |mock| mock.instant)
+    }
+
+    /// Returns the current wall clock time of the current threads clock.
+    fn system_now() -> SystemTime {
+        Self::get_threadlocal().map_or_else(SystemTime::now, |mock| mock.system)
+    }
+
+    /// Returns whether a mock is installed on the current thread.
+    fn is_mocked() -> bool {
+        Self::get_threadlocal().is_some()
+    }
+
+    /// Installs 'mock' on the current thread until the returned guard is dropped.
+    fn install_mock_scoped(
+        mock: MockClock,
+    ) -> ThreadLocalGuard<Self, Option<MockClock>, ClockState> {
+        Self::set_threadlocal_scoped(Some(mock))
+    }
+
+    /// Advances the mock of the current thread by 'duration'.
+    ///
+    /// # Panics
+    /// When no mock is installed, the real time can not be advanced.
+    fn advance(duration: Duration) {
+        let mock = Self::get_threadlocal().expect("advance() without a mock clock installed");
+        Self::set_threadlocal(Some(mock.advanced(duration)));
+    }
+
+    /// Sleeps for 'duration', advances the mock instead when one is installed.
+    fn sleep(duration: Duration) {
+        match Self::get_threadlocal() {
+            Some(mock) => Self::set_threadlocal(Some(mock.advanced(duration))),
+            None => std::thread::sleep(duration),
+        }
+    }
+}
+
+/// Associates a per-thread time source to a type.
+///
+///  * 'T' is the type you want have a thread local clock associated to
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::time::Duration;
+///
+/// struct Cache;
+/// assoc_clock!(Cache);
+///
+/// struct Entry {
+///     expires: std::time::Instant,
+/// }
+///
+/// impl Entry {
+///     fn new(ttl: Duration) -> Self {
+///         Entry { expires: Cache::now() + ttl }
+///     }
+///
+///     fn is_expired(&self) -> bool {
+///         Cache::now() >= self.expires
+///     }
+/// }
+///
+/// let _mock = Cache::install_mock_scoped(MockClock::now());
+/// let entry = Entry::new(Duration::from_secs(60));
+/// Cache::advance(Duration::from_secs(59));
+/// assert!(!entry.is_expired());
+/// Cache::advance(Duration::from_secs(1));
+/// assert!(entry.is_expired());
+/// ```
+#[macro_export]
+macro_rules! assoc_clock {
+    ($T:ty) => {
+        $crate::assoc_threadlocal!(
+            $crate::ClockState:$T,
+            Option<$crate::MockClock> = None
+        );
+
+        impl $crate::AssocClock for $T {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockClock;
+    use crate::AssocClock;
+    use std::time::{Duration, Instant, SystemTime};
+
+    struct Timer;
+    assoc_clock!(Timer);
+
+    #[test]
+    fn mocked_per_thread() {
+        assert!(!Timer::is_mocked());
+        let epoch = SystemTime::UNIX_EPOCH;
+        {
+            let _mock = Timer::install_mock_scoped(MockClock::at(epoch));
+            let start = Timer::now();
+            Timer::sleep(Duration::from_secs(3600));
+            assert_eq!(Timer::now() - start, Duration::from_secs(3600));
+            assert_eq!(Timer::system_now(), epoch + Duration::from_secs(3600));
+            let now = std::thread::spawn(Timer::system_now).join().unwrap();
+            assert!(now > epoch + Duration::from_secs(3600));
+        }
+        assert!(!Timer::is_mocked());
+        assert!(Timer::now() <= Instant::now());
+    }
+
+    #[test]
+    #[should_panic(expected = "without a mock clock")]
+    fn advance_real_time() {
+        Timer::advance(Duration::from_secs(1));
+    }
+}
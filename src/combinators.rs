@@ -0,0 +1,119 @@
+//! Operations on two associations of the same implementor.
+//!
+//! Keeping state derived from other associations in sync otherwise needs a turbofished
+//! get and set per association.  These combinators name the tags and the target once.
+//! The untagged association is addressed with the tag `()`.
+
+use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+
+/// Combinators over two associations of the Self type, available on every type.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Window;
+/// struct Width;
+/// struct Height;
+/// assoc_threadlocal!(Width:Window, u32 = 640);
+/// assoc_threadlocal!(Height:Window, u32 = 480);
+///
+/// struct Saved;
+/// assoc_threadlocal!(Saved:Window, u32 = 0);
+///
+/// assert_eq!(Window::zip_threadlocals::<Width, Height, u32, _>(|w, h| w * h), 307_200);
+///
+/// Window::copy_between::<Width, Saved, u32>();
+/// assert_eq!(<Window as GetAssocThreadLocal<u32, Saved>>::get_threadlocal(), 640);
+///
+/// Window::swap_between::<Width, Height, u32>();
+/// assert_eq!(<Window as GetAssocThreadLocal<u32, Width>>::get_threadlocal(), 480);
+/// ```
+pub trait AssocCombinators {
+    /// Calls 'f' with the current threads values of the associations of 'T' with the
+    /// tags 'A' and 'B'.
+    fn zip_threadlocals<A, B, T: Copy, R>(f: impl FnOnce(T, T) -> R) -> R
+    where
+        Self: GetAssocThreadLocal<T, A> + GetAssocThreadLocal<T, B>,
+    {
+        f(
+            <Self as GetAssocThreadLocal<T, A>>::get_threadlocal(),
+            <Self as GetAssocThreadLocal<T, B>>::get_threadlocal(),
+        )
+    }
+
+    /// Sets the association with tag 'DST' to the value of the association with tag
+    /// 'SRC'.
+    fn copy_between<SRC, DST, T: Copy>()
+    where
+        Self: GetAssocThreadLocal<T, SRC> + SetAssocThreadLocal<T, DST>,
+    {
+        let value = <Self as GetAssocThreadLocal<T, SRC>>::get_threadlocal();
+        <Self as SetAssocThreadLocal<T, DST>>::set_threadlocal(value);
+    }
+
+    /// Sets the association with tag 'DST' to 'f' applied to the values of the
+    /// associations with tags 'SRC' and 'DST', returns the new value.
+    fn update_between<SRC, DST, T: Copy>(f: impl FnOnce(T, T) -> T) -> T
+    where
+        Self: GetAssocThreadLocal<T, SRC> + SetAssocThreadLocal<T, DST>,
+    {
+        let value = f(
+            <Self as GetAssocThreadLocal<T, SRC>>::get_threadlocal(),
+            <Self as GetAssocThreadLocal<T, DST>>::get_threadlocal(),
+        );
+        <Self as SetAssocThreadLocal<T, DST>>::set_threadlocal(value);
+        value
+    }
+
+    /// Exchanges the values of the associations with tags 'A' and 'B'.
+    fn swap_between<A, B, T: Copy>()
+    where
+        Self: SetAssocThreadLocal<T, A> + SetAssocThreadLocal<T, B>,
+    {
+        let a = <Self as GetAssocThreadLocal<T, A>>::get_threadlocal();
+        let b = <Self as GetAssocThreadLocal<T, B>>::get_threadlocal();
+        <Self as SetAssocThreadLocal<T, A>>::set_threadlocal(b);
+        <Self as SetAssocThreadLocal<T, B>>::set_threadlocal(a);
+    }
+}
+
+impl<S: ?Sized> AssocCombinators for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::AssocCombinators;
+    use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct Totals;
+    struct Batch;
+    crate::assoc_threadlocal!(Totals, u64 = 0);
+    crate::assoc_threadlocal!(Batch:Totals, u64 = 5);
+
+    #[test]
+    fn untagged_and_tagged() {
+        assert_eq!(
+            Totals::update_between::<Batch, (), u64>(|batch, total| total + batch),
+            5
+        );
+        assert_eq!(
+            Totals::update_between::<Batch, (), u64>(|batch, total| total + batch),
+            10
+        );
+        <Totals as SetAssocThreadLocal<u64, Batch>>::set_threadlocal(1);
+        assert!(Totals::zip_threadlocals::<(), Batch, u64, _>(
+            |total, batch| total > batch
+        ));
+        Totals::copy_between::<(), Batch, u64>();
+        assert_eq!(
+            <Totals as GetAssocThreadLocal<u64, Batch>>::get_threadlocal(),
+            10
+        );
+        <Totals as SetAssocThreadLocal<u64, Batch>>::set_threadlocal(3);
+        Totals::swap_between::<Batch, (), u64>();
+        assert_eq!(<Totals as GetAssocThreadLocal<u64>>::get_threadlocal(), 3);
+        assert_eq!(
+            <Totals as GetAssocThreadLocal<u64, Batch>>::get_threadlocal(),
+            10
+        );
+    }
+}
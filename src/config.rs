@@ -0,0 +1,573 @@
+//! Association defaults from configuration files (requires the `config` feature).
+//!
+//! An association declared with `config = "key"` takes its default from the value loaded
+//! under that key, falling back to the declared INIT when there is none.  Defaults are
+//! process wide and inherited by every thread that initializes the association afterwards.
+//! Load the configuration at startup, before spawning workers, to centralize per-thread
+//! tunables in deployment config.
+//!
+//! Loading a value that differs from the one loaded before starts a new epoch.  Threads
+//! that already initialized the association pick up the changed value on their next read,
+//! replacing what they set themselves, like the global value of a 'static' association.
+//! `watch_defaults()` reloads a file whenever it changes, so tunables like log levels or
+//! sampling rates can be changed at runtime without restarting workers.
+//!
+//! The configuration is a TOML table of scalars.  `[section]` headers prefix the keys that
+//! follow with 'section.', values are strings, numbers or booleans and are converted to the
+//! target with `FromStr`.  Arrays, inline tables and multi line strings are not supported.
+
+use crate::AssocThreadLocal;
+use std::any::TypeId;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// the values with the epoch they last changed in
+static DEFAULTS: RwLock<Option<HashMap<String, (String, u64)>>> = RwLock::new(None);
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+type Validator = fn(&str) -> Result<(), String>;
+
+// the conversions of the associations initialized so far, checked before loading
+static VALIDATORS: Mutex<Vec<(&str, TypeId, Validator)>> = Mutex::new(Vec::new());
+
+// values loaded before the association using them was initialized and rejected then
+static REJECTED: Mutex<Vec<ConfigError>> = Mutex::new(Vec::new());
+
+/// Error returned by `load_defaults_from()`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Reading the configuration failed.
+    Io(std::io::Error),
+    /// The configuration is malformed at 'line', counted from one.
+    Syntax {
+        /// The line of the error.
+        line: usize,
+        /// What is wrong with it.
+        message: &'static str,
+    },
+    /// The value of 'key' can not be converted to the target of an association using it.
+    Invalid {
+        /// The key of the value.
+        key: String,
+        /// The conversion error.
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "reading config failed: {error}"),
+            ConfigError::Syntax { line, message } => write!(f, "config line {line}: {message}"),
+            ConfigError::Invalid { key, message } => {
+                write!(f, "invalid config value for '{key}': {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(error) => Some(error),
+            ConfigError::Syntax { .. } | ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(error: std::io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+/// Reads a configuration from 'reader' and installs its values as defaults, replacing
+/// values loaded earlier under the same keys.  Returns the number of values read.
+///
+/// Nothing is installed when the configuration is malformed or a value can not be
+/// converted to the target of an association that was initialized already.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Pool;
+/// assoc_threadlocal!(Pool, usize = 4, config = "pool.batch");
+///
+/// let config = "
+/// [pool]
+/// batch = 16 # per worker
+/// ";
+/// assert_eq!(config::load_defaults_from(config.as_bytes()).unwrap(), 1);
+/// assert_eq!(std::thread::spawn(Pool::get_threadlocal).join().unwrap(), 16);
+/// ```
+pub fn load_defaults_from(mut reader: impl Read) -> Result<usize, ConfigError> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    let values = parse(&text)?;
+    validate(&values)?;
+    let count = values.len();
+    let mut defaults = DEFAULTS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let defaults = defaults.get_or_insert_with(HashMap::new);
+    let epoch = EPOCH.load(Ordering::Relaxed) + 1;
+    let mut changed = false;
+    for (key, value) in values {
+        if defaults.get(&key).map(|(loaded, _)| loaded) != Some(&value) {
+            defaults.insert(key, (value, epoch));
+            changed = true;
+        }
+    }
+    if changed {
+        // published while the values are still locked, readers seeing the new epoch
+        // see the new values
+        EPOCH.store(epoch, Ordering::Release);
+    }
+    Ok(count)
+}
+
+/// Returns the loaded default under 'key' as written in the configuration, strings
+/// without their quotes.
+pub fn loaded_default(key: &str) -> Option<String> {
+    DEFAULTS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()?
+        .get(key)
+        .map(|(value, _)| value.clone())
+}
+
+/// Forgets all loaded defaults, threads keep their values.
+pub fn clear_defaults() {
+    *DEFAULTS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+/// Returns the current epoch, incremented by every load that changed a value.
+pub fn epoch() -> u64 {
+    EPOCH.load(Ordering::Acquire)
+}
+
+/// Returns and forgets the loaded values that were rejected when the first thread
+/// initialized an association using them.  Values loaded before an association was
+/// initialized can not be checked by the load, such associations fall back to their
+/// declared INIT and the error is kept for this function.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Workers;
+/// assoc_threadlocal!(Workers, u8 = 4, config = "doc.workers");
+///
+/// config::load_defaults_from("doc.workers = 1000".as_bytes()).unwrap();
+/// assert_eq!(Workers::get_threadlocal(), 4);
+/// let rejected = config::take_rejected_defaults();
+/// assert!(matches!(&rejected[..], [ConfigError::Invalid { key, .. }] if key == "doc.workers"));
+/// ```
+pub fn take_rejected_defaults() -> Vec<ConfigError> {
+    std::mem::take(
+        &mut *REJECTED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    )
+}
+
+fn validate(values: &[(String, String)]) -> Result<(), ConfigError> {
+    let validators = VALIDATORS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (key, value) in values {
+        for (_, _, validate) in validators.iter().filter(|(k, _, _)| k == key) {
+            validate(value).map_err(|message| ConfigError::Invalid {
+                key: key.clone(),
+                message,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn changed_since(key: &str, seen: u64) -> Option<String> {
+    DEFAULTS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()?
+        .get(key)
+        .filter(|(_, changed)| *changed > seen)
+        .map(|(value, _)| value.clone())
+}
+
+/// An association taking its default from the loaded configuration.
+/// Use the `config = "key"` option of `assoc_threadlocal!()` for implementing this trait.
+pub trait AssocConfig<T, TAG = ()>: AssocThreadLocal<T, TAG> + 'static
+where
+    T: Copy + FromStr + 'static,
+    T::Err: fmt::Display,
+{
+    /// The key the default is loaded from.
+    const KEY: &'static str;
+
+    /// Returns the epoch the current thread has seen.
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_seen_epoch() -> *const Cell<u64>;
+
+    /// Returns the loaded default, 'init()' when there is none or it can not be converted
+    /// to 'T'.  Called when a thread initializes the association.
+    #[doc(hidden)]
+    fn config_init(init: impl FnOnce() -> T) -> T {
+        // the epoch first, a value changing meanwhile is applied again on the next read
+        unsafe { (*Self::the_seen_epoch()).set(epoch()) };
+        let mut validators = VALIDATORS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let id = TypeId::of::<T>();
+        let registering = !validators
+            .iter()
+            .any(|(k, t, _)| *k == Self::KEY && *t == id);
+        if registering {
+            validators.push((Self::KEY, id, |value| {
+                value.parse::<T>().map(drop).map_err(|e| e.to_string())
+            }));
+        }
+        // later loads are validated, only values loaded before registering can be invalid
+        let parsed = loaded_default(Self::KEY).map(|value| value.parse::<T>());
+        drop(validators);
+        match parsed {
+            Some(Ok(value)) => value,
+            Some(Err(error)) => {
+                if registering {
+                    let error = ConfigError::Invalid {
+                        key: Self::KEY.to_string(),
+                        message: error.to_string(),
+                    };
+                    REJECTED
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .push(error);
+                }
+                init()
+            }
+            None => init(),
+        }
+    }
+
+    /// Applies a value changed by a reload since the last refresh to the current threads
+    /// association.  Called by the readers of the association.
+    #[inline]
+    fn refresh_from_config() {
+        let epoch = epoch();
+        let seen = unsafe { &*Self::the_seen_epoch() };
+        if seen.get() != epoch {
+            let since = seen.replace(epoch);
+            if let Some(value) = changed_since(Self::KEY, since).and_then(|v| v.parse().ok()) {
+                Self::set_threadlocal(value);
+            }
+        }
+    }
+}
+
+/// Reloads a configuration file whenever it changes, returned by `watch_defaults()`.
+/// Watching stops when dropped.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    last_error: Arc<Mutex<Option<String>>>,
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ConfigWatcher {
+    /// Returns why the last reload failed, `None` when it succeeded.  The previously
+    /// loaded values stay in effect when a reload fails.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Stops watching and waits for the watcher thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // dropping the sender wakes the watcher
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Loads the configuration file at 'path' and reloads it whenever its modification time
+/// or length changes, checking once per second.
+pub fn watch_defaults(path: impl AsRef<Path>) -> Result<ConfigWatcher, ConfigError> {
+    watch_defaults_every(path, Duration::from_secs(1))
+}
+
+/// Loads the configuration file at 'path' and reloads it whenever its modification time
+/// or length changes, checking every 'interval'.  Fails when the initial load fails.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::time::Duration;
+///
+/// struct Sampling;
+/// assoc_threadlocal!(Sampling, u32 = 100, config = "doc.sampling");
+///
+/// let path = std::env::temp_dir().join(format!("assoc-watch-{}.toml", std::process::id()));
+/// std::fs::write(&path, "doc.sampling = 10").unwrap();
+/// let watcher = config::watch_defaults_every(&path, Duration::from_millis(5)).unwrap();
+/// assert_eq!(Sampling::get_threadlocal(), 10);
+///
+/// std::fs::write(&path, "doc.sampling = 1").unwrap();
+/// while Sampling::get_threadlocal() != 1 {
+///     std::thread::sleep(Duration::from_millis(1));
+/// }
+/// watcher.stop();
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn watch_defaults_every(
+    path: impl AsRef<Path>,
+    interval: Duration,
+) -> Result<ConfigWatcher, ConfigError> {
+    let path = path.as_ref().to_path_buf();
+    let mut stamp = stamp(&path)?;
+    load_defaults_from(std::fs::File::open(&path)?)?;
+    let last_error = Arc::new(Mutex::new(None));
+    let errors = last_error.clone();
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = thread::Builder::new()
+        .name(String::from("assoc_threadlocal config watcher"))
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let result = match changed(&path, &mut stamp) {
+                    Ok(false) => continue,
+                    Ok(true) => std::fs::File::open(&path)
+                        .map_err(ConfigError::from)
+                        .and_then(load_defaults_from),
+                    Err(error) => Err(error.into()),
+                };
+                *errors
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                    result.err().map(|error| error.to_string());
+            }
+        })
+        .expect("spawning the config watcher thread");
+    Ok(ConfigWatcher {
+        last_error,
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+fn stamp(path: &Path) -> std::io::Result<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+fn changed(path: &Path, seen: &mut (SystemTime, u64)) -> std::io::Result<bool> {
+    let stamp = stamp(path)?;
+    Ok(std::mem::replace(seen, stamp) != stamp)
+}
+
+fn parse(text: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut values = Vec::new();
+    let mut section = String::new();
+    for (index, line) in text.lines().enumerate() {
+        let syntax = |message| ConfigError::Syntax {
+            line: index + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let (name, rest) = header
+                .split_once(']')
+                .ok_or_else(|| syntax("unterminated section header"))?;
+            if !is_comment(rest) {
+                return Err(syntax("trailing characters after section header"));
+            }
+            section = parse_key(name).ok_or_else(|| syntax("invalid section name"))?;
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| syntax("expected 'key = value'"))?;
+        let key = parse_key(key).ok_or_else(|| syntax("invalid key"))?;
+        let value = parse_value(value.trim()).ok_or_else(|| syntax("invalid value"))?;
+        let key = if section.is_empty() {
+            key
+        } else {
+            format!("{section}.{key}")
+        };
+        values.push((key, value));
+    }
+    Ok(values)
+}
+
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+fn parse_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    let valid = !key.is_empty()
+        && key.split('.').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        });
+    valid.then(|| key.to_string())
+}
+
+fn parse_value(value: &str) -> Option<String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut unescaped = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return is_comment(chars.as_str()).then_some(unescaped),
+                '\\' => unescaped.push(match chars.next()? {
+                    'n' => '\n',
+                    't' => '\t',
+                    '"' => '"',
+                    '\\' => '\\',
+                    _ => return None,
+                }),
+                c => unescaped.push(c),
+            }
+        }
+        None
+    } else if let Some(literal) = value.strip_prefix('\'') {
+        let (literal, rest) = literal.split_once('\'')?;
+        is_comment(rest).then(|| literal.to_string())
+    } else {
+        let bare = value.split('#').next().unwrap_or_default().trim();
+        let valid = !bare.is_empty()
+            && bare
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-._".contains(c));
+        // TOML allows underscores between digits
+        valid.then(|| bare.replace('_', ""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ConfigError};
+
+    #[test]
+    fn tables_and_values() {
+        let values = parse(
+            r#"
+            # tunables
+            top = true
+            [net.pool]
+            size = 1_000   # comment
+            name = "a \"b\" # c"
+            path = 'C:\tmp'
+            "#,
+        )
+        .unwrap();
+        let values: Vec<(&str, &str)> = values
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("top", "true"),
+                ("net.pool.size", "1000"),
+                ("net.pool.name", "a \"b\" # c"),
+                ("net.pool.path", "C:\\tmp"),
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        for (text, line) in [
+            ("a = 1\nb", 2),
+            ("[open", 1),
+            ("a = \"unterminated", 1),
+            ("a = [1, 2]", 1),
+            ("bad key = 1", 1),
+        ] {
+            match parse(text) {
+                Err(ConfigError::Syntax {
+                    line: error_line, ..
+                }) => assert_eq!(error_line, line),
+                other => panic!("{text:?} parsed as {other:?}"),
+            }
+        }
+    }
+
+    struct Worker;
+    crate::assoc_threadlocal!(Worker, u32 = 1, config = "tests.worker.retries");
+    struct Name;
+    crate::assoc_threadlocal!(Name: Worker, bool = false, config = "tests.worker.verbose");
+
+    #[test]
+    fn inherited_by_new_threads() {
+        use crate::GetAssocThreadLocal;
+        super::load_defaults_from("[tests.worker]\nretries = 5\nverbose = true".as_bytes())
+            .unwrap();
+        let (retries, verbose) = std::thread::spawn(|| {
+            (
+                <Worker as GetAssocThreadLocal<u32>>::get_threadlocal(),
+                <Worker as GetAssocThreadLocal<bool, Name>>::get_threadlocal(),
+            )
+        })
+        .join()
+        .unwrap();
+        assert_eq!((retries, verbose), (5, true));
+    }
+
+    struct Level;
+    crate::assoc_threadlocal!(Level, u8 = 0, config = "tests.level");
+
+    #[test]
+    fn reload_reaches_running_threads() {
+        use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+        let load = |text: &str| super::load_defaults_from(text.as_bytes());
+        load("tests.level = 1").unwrap();
+        assert_eq!(Level::get_threadlocal(), 1);
+        Level::set_threadlocal(9);
+        // unchanged values do not replace what the thread set
+        load("tests.level = 1\ntests.other = 2").unwrap();
+        assert_eq!(Level::get_threadlocal(), 9);
+        load("tests.level = 3").unwrap();
+        assert_eq!(Level::get_threadlocal(), 3);
+        // rejected as a whole since 'Level' was initialized already
+        match load("tests.other = 4\ntests.level = 300") {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "tests.level"),
+            other => panic!("loaded {other:?}"),
+        }
+        assert_eq!(super::loaded_default("tests.other").as_deref(), Some("2"));
+        assert_eq!(Level::get_threadlocal(), 3);
+    }
+}
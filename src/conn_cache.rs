@@ -0,0 +1,194 @@
+//! Per-thread connection caches.
+//!
+//! Worker threads commonly keep one database or service connection each, opened on first
+//! use and reused afterwards.  A connection associated to a type is opened lazily on every
+//! thread, checked for health before each use and reopened when the check fails or an
+//! operation on it returned an error.
+
+use std::cell::RefCell;
+use std::error::Error;
+
+/// The error type of opening connections and of the operations on them.
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// A per-thread cached connection of type 'C' associated to a type.
+/// Use the `assoc_connection!()` macro for implementing this trait on types.
+pub trait AssocConnection<C: 'static>: Sized {
+    /// Returns the associated thread local connection slot of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    #[doc(hidden)]
+    unsafe fn the_connection() -> *const RefCell<Option<C>>;
+
+    /// Opens a new connection.
+    fn connect() -> Result<C, BoxError>;
+
+    /// Returns whether a cached connection can still be used, a failed check reopens it.
+    fn is_healthy(_connection: &mut C) -> bool {
+        true
+    }
+
+    /// Calls 'f' with the current threads connection, opening it first when there is
+    /// none or the cached one is not healthy.  When 'f' fails the connection is dropped
+    /// and the next call opens a new one.
+    ///
+    /// The connection is taken out of the cache while 'f' runs, nested calls from within
+    /// 'f' open a second connection.
+    fn with_conn<R, E: Into<BoxError>>(
+        f: impl FnOnce(&mut C) -> Result<R, E>,
+    ) -> Result<R, BoxError> {
+        let cached = unsafe { (*Self::the_connection()).borrow_mut().take() };
+        let mut connection = match cached {
+            Some(mut connection) => {
+                if Self::is_healthy(&mut connection) {
+                    connection
+                } else {
+                    drop(connection);
+                    Self::connect()?
+                }
+            }
+            None => Self::connect()?,
+        };
+        let result = f(&mut connection).map_err(Into::into)?;
+        unsafe { *(*Self::the_connection()).borrow_mut() = Some(connection) };
+        Ok(result)
+    }
+
+    /// Returns whether the current thread has a cached connection.
+    fn is_connected() -> bool {
+        unsafe { (*Self::the_connection()).borrow().is_some() }
+    }
+
+    /// Drops the current threads connection, the next `with_conn()` opens a new one.
+    fn disconnect() {
+        let connection = unsafe { (*Self::the_connection()).borrow_mut().take() };
+        drop(connection);
+    }
+}
+
+/// Associates a lazily opened per-thread connection to a type.
+///
+///  * 'T' is the type you want have a thread local connection associated to
+///  * 'C' is the type of the connection
+///  * 'CONNECT' opens a connection, errors can be returned with `?`
+///  * 'healthy' optionally checks a cached connection before it is used
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// static OPENED: AtomicUsize = AtomicUsize::new(0);
+///
+/// struct Conn {
+///     broken: bool,
+/// }
+///
+/// fn connect() -> Result<Conn, std::io::Error> {
+///     OPENED.fetch_add(1, Ordering::Relaxed);
+///     Ok(Conn { broken: false })
+/// }
+///
+/// struct Db;
+/// assoc_connection!(Db, Conn = connect()?, healthy = |conn| !conn.broken);
+///
+/// let query = |conn: &mut Conn| Ok::<_, std::io::Error>(42);
+/// assert_eq!(Db::with_conn(query).unwrap(), 42);
+/// assert_eq!(Db::with_conn(query).unwrap(), 42);
+/// assert_eq!(OPENED.load(Ordering::Relaxed), 1);
+///
+/// // a connection failing its health check is reopened
+/// Db::with_conn(|conn| {
+///     conn.broken = true;
+///     Ok::<_, std::io::Error>(())
+/// })
+/// .unwrap();
+/// Db::with_conn(query).unwrap();
+/// assert_eq!(OPENED.load(Ordering::Relaxed), 2);
+///
+/// // every worker thread has its own connection
+/// std::thread::spawn(move || Db::with_conn(query).unwrap()).join().unwrap();
+/// assert_eq!(OPENED.load(Ordering::Relaxed), 3);
+/// ```
+#[macro_export]
+macro_rules! assoc_connection {
+    ($T:ty, $C:ty = $CONNECT:expr $(, healthy = $CHECK:expr)?) => {
+        impl $crate::AssocConnection<$C> for $T {
+            unsafe fn the_connection() -> *const std::cell::RefCell<Option<$C>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_CONNECTION: (
+                        std::cell::RefCell<Option<$C>>,
+                        std::marker::PhantomData<$T>,
+                    ) = const {
+                        (std::cell::RefCell::new(None), std::marker::PhantomData)
+                    };
+                );
+                ASSOCIATED_CONNECTION.with(|l| &l.0 as *const std::cell::RefCell<Option<$C>>)
+            }
+
+            fn connect() -> Result<$C, $crate::conn_cache::BoxError> {
+                Ok($CONNECT)
+            }
+
+            $(
+                fn is_healthy(connection: &mut $C) -> bool {
+                    let check: fn(&mut $C) -> bool = $CHECK;
+                    check(connection)
+                }
+            )?
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocConnection;
+    use std::cell::Cell;
+
+    std::thread_local!(static OPENED: Cell<u32> = const { Cell::new(0) });
+
+    struct Handle(u32);
+
+    fn open(fail: bool) -> Result<Handle, String> {
+        if fail {
+            return Err(String::from("unreachable"));
+        }
+        let id = OPENED.with(|opened| opened.replace(opened.get() + 1));
+        Ok(Handle(id))
+    }
+
+    std::thread_local!(static FAIL: Cell<bool> = const { Cell::new(false) });
+
+    struct Cache;
+    assoc_connection!(Cache, Handle = open(FAIL.with(Cell::get))?);
+
+    #[test]
+    fn reopens_after_error() {
+        let id = |handle: &mut Handle| Ok::<_, String>(handle.0);
+        assert!(!Cache::is_connected());
+        assert_eq!(Cache::with_conn(id).unwrap(), 0);
+        assert_eq!(Cache::with_conn(id).unwrap(), 0);
+
+        let error = Cache::with_conn(|_| Err::<(), _>("query failed")).unwrap_err();
+        assert_eq!(error.to_string(), "query failed");
+        assert!(!Cache::is_connected());
+        assert_eq!(Cache::with_conn(id).unwrap(), 1);
+
+        Cache::disconnect();
+        FAIL.with(|fail| fail.set(true));
+        let error = Cache::with_conn(id).unwrap_err();
+        assert_eq!(error.to_string(), "unreachable");
+        FAIL.with(|fail| fail.set(false));
+        assert_eq!(Cache::with_conn(id).unwrap(), 2);
+    }
+
+    #[test]
+    fn nested_opens_second() {
+        let outer = Cache::with_conn(|outer| {
+            let inner = Cache::with_conn(|inner| Ok::<_, String>(inner.0)).unwrap();
+            Ok::<_, String>((outer.0, inner))
+        });
+        assert_eq!(outer.unwrap(), (0, 1));
+        assert!(Cache::is_connected());
+    }
+}
@@ -0,0 +1,292 @@
+//! Typed per-thread contexts.
+//!
+//! A configuration context is a `Copy` struct that is associated to itself. Each thread
+//! sees its own instance which starts as the declared defaults and can be changed through a
+//! builder, either permanently or for the lifetime of a guard.
+//!
+//! Ambient contexts are stacks of values per tag type. Entering pushes a value and returns a
+//! guard that pops it again, `current()` returns the innermost value.
+
+use crate::AssocThreadLocal;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+
+/// A per-thread configuration context.
+/// Use the `thread_local_context!{}` macro for defining contexts.
+pub trait ThreadLocalContext: AssocThreadLocal<Self> + Copy + 'static {
+    /// Returns the context of the current thread.
+    fn current() -> Self {
+        Self::get_threadlocal()
+    }
+
+    /// Makes 'self' the context of the current thread.
+    fn apply(self) {
+        Self::set_threadlocal(self);
+    }
+
+    /// Makes 'self' the context of the current thread until the returned guard is dropped.
+    fn apply_scoped(self) -> ContextGuard<Self> {
+        let previous = Self::get_threadlocal();
+        Self::set_threadlocal(self);
+        ContextGuard {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Restores the previous context of the current thread when dropped.
+#[must_use = "the context is restored immediately when the guard is not kept"]
+pub struct ContextGuard<C: ThreadLocalContext> {
+    previous: C,
+    // guards must be dropped on the thread that created them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<C: ThreadLocalContext> Drop for ContextGuard<C> {
+    fn drop(&mut self) {
+        C::set_threadlocal(self.previous);
+    }
+}
+
+/// Defines a per-thread configuration context.
+///
+/// Generates the struct, its association, a getter for each field returning the current
+/// threads value and a builder that starts from the current threads context.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// thread_local_context! {
+///     #[derive(Debug)]
+///     pub struct Ctx: CtxBuilder {
+///         pub verbosity: u32 = 0,
+///         pub name: &'static str = "default",
+///     }
+/// }
+///
+/// assert_eq!(Ctx::verbosity(), 0);
+/// {
+///     let _guard = Ctx::builder().verbosity(3).apply_scoped();
+///     assert_eq!(Ctx::verbosity(), 3);
+///     assert_eq!(Ctx::name(), "default");
+/// }
+/// assert_eq!(Ctx::verbosity(), 0);
+/// ```
+#[macro_export]
+macro_rules! thread_local_context {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $NAME:ident: $BUILDER:ident {
+            $($fvis:vis $FIELD:ident: $FTYPE:ty = $FINIT:expr),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy)]
+        $vis struct $NAME {
+            $($fvis $FIELD: $FTYPE,)*
+        }
+
+        $crate::assoc_threadlocal!($NAME, $NAME = $NAME { $($FIELD: $FINIT,)* });
+
+        impl $crate::ThreadLocalContext for $NAME {}
+
+        #[allow(dead_code)]
+        impl $NAME {
+            /// Returns a builder initialized from the current threads context.
+            $vis fn builder() -> $BUILDER {
+                $BUILDER(<$NAME as $crate::ThreadLocalContext>::current())
+            }
+
+            $(
+                /// Returns the value of this field in the current threads context.
+                $fvis fn $FIELD() -> $FTYPE {
+                    <$NAME as $crate::ThreadLocalContext>::current().$FIELD
+                }
+            )*
+        }
+
+        /// Builder for changing a thread local context.
+        #[must_use]
+        $vis struct $BUILDER($NAME);
+
+        #[allow(dead_code)]
+        impl $BUILDER {
+            $(
+                /// Sets this field.
+                $fvis fn $FIELD(mut self, value: $FTYPE) -> Self {
+                    self.0.$FIELD = value;
+                    self
+                }
+            )*
+
+            /// Returns the built context without applying it.
+            $vis fn build(self) -> $NAME {
+                self.0
+            }
+
+            /// Makes the built context the current threads context.
+            $vis fn apply(self) {
+                $crate::ThreadLocalContext::apply(self.0)
+            }
+
+            /// Makes the built context the current threads context until the guard is dropped.
+            $vis fn apply_scoped(self) -> $crate::ContextGuard<$NAME> {
+                $crate::ThreadLocalContext::apply_scoped(self.0)
+            }
+        }
+    };
+}
+
+/// A tag for a per-thread stack of ambient context values.
+/// Use the `context_tag!()` macro for implementing this trait on types.
+pub trait ContextTag: 'static {
+    /// Type of the values on the stack.
+    type Value: Clone;
+
+    /// Returns the thread local stack of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_stack() -> *const RefCell<Vec<Self::Value>>;
+}
+
+/// Pushes 'value' to the current threads context stack of 'Tag'.
+/// The value is popped when the returned guard is dropped.
+pub fn enter<Tag: ContextTag>(value: Tag::Value) -> ContextEntry<Tag> {
+    let mut stack = unsafe { (*Tag::the_stack()).borrow_mut() };
+    stack.push(value);
+    ContextEntry {
+        depth: stack.len() - 1,
+        _marker: PhantomData,
+    }
+}
+
+/// Returns the innermost context value of 'Tag' on the current thread.
+pub fn current<Tag: ContextTag>() -> Option<Tag::Value> {
+    unsafe { (*Tag::the_stack()).borrow().last().cloned() }
+}
+
+/// Calls 'f' with all context values of 'Tag' on the current thread, outermost first.
+pub fn with_stack<Tag: ContextTag, R>(f: impl FnOnce(&[Tag::Value]) -> R) -> R {
+    let stack = unsafe { (*Tag::the_stack()).borrow().clone() };
+    f(&stack)
+}
+
+/// Pops a context value when dropped.
+#[must_use = "the context is left immediately when the guard is not kept"]
+pub struct ContextEntry<Tag: ContextTag> {
+    depth: usize,
+    // the value must be popped on the thread that pushed it
+    _marker: PhantomData<(Tag, *const ())>,
+}
+
+impl<Tag: ContextTag> Drop for ContextEntry<Tag> {
+    fn drop(&mut self) {
+        // truncating also leaves inner contexts whose guards were leaked
+        unsafe { (*Tag::the_stack()).borrow_mut().truncate(self.depth) }
+    }
+}
+
+/// Makes a type a tag for ambient context values.
+///
+///  * 'TAG' is the type identifying the context
+///  * 'V' is the type of the values
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use assoc_threadlocal::context::{current, enter};
+///
+/// struct RequestId;
+/// context_tag!(RequestId: u64);
+///
+/// assert_eq!(current::<RequestId>(), None);
+/// let _outer = enter::<RequestId>(1);
+/// {
+///     let _inner = enter::<RequestId>(2);
+///     assert_eq!(current::<RequestId>(), Some(2));
+/// }
+/// assert_eq!(current::<RequestId>(), Some(1));
+/// ```
+#[macro_export]
+macro_rules! context_tag {
+    ($TAG:ty: $V:ty) => {
+        impl $crate::ContextTag for $TAG {
+            type Value = $V;
+
+            unsafe fn the_stack() -> *const std::cell::RefCell<Vec<$V>> {
+                $crate::__assoc_thread_local!(
+                    static CONTEXT_STACK: (
+                        std::cell::RefCell<Vec<$V>>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = (
+                        std::cell::RefCell::new(Vec::new()),
+                        std::marker::PhantomData,
+                    );
+                );
+                CONTEXT_STACK.with(|l| &l.0 as *const std::cell::RefCell<Vec<$V>>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, enter, with_stack};
+    use crate::ThreadLocalContext;
+
+    thread_local_context! {
+        struct TestCtx: TestCtxBuilder {
+            level: u8 = 1,
+            label: &'static str = "init",
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(TestCtx::level(), 1);
+        assert_eq!(TestCtx::label(), "init");
+    }
+
+    #[test]
+    fn nested_scopes() {
+        let outer = TestCtx::builder().level(2).apply_scoped();
+        {
+            let _inner = TestCtx::builder().label("inner").apply_scoped();
+            assert_eq!(TestCtx::level(), 2);
+            assert_eq!(TestCtx::label(), "inner");
+        }
+        assert_eq!(TestCtx::label(), "init");
+        drop(outer);
+        assert_eq!(TestCtx::level(), 1);
+    }
+
+    #[test]
+    fn apply_permanent() {
+        TestCtx::builder().level(5).apply();
+        assert_eq!(TestCtx::current().level, 5);
+        std::thread::spawn(|| assert_eq!(TestCtx::level(), 1))
+            .join()
+            .unwrap();
+    }
+
+    struct Section;
+    context_tag!(Section: &'static str);
+
+    #[test]
+    fn context_stack() {
+        let _a = enter::<Section>("a");
+        let b = enter::<Section>("b");
+        assert_eq!(with_stack::<Section, _>(|s| s.join("/")), "a/b");
+        drop(b);
+        assert_eq!(current::<Section>(), Some("a"));
+    }
+
+    #[test]
+    fn leaked_inner_entry() {
+        let outer = enter::<Section>("outer");
+        std::mem::forget(enter::<Section>("leaked"));
+        drop(outer);
+        assert_eq!(current::<Section>(), None);
+    }
+}
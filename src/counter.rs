@@ -0,0 +1,129 @@
+//! Generic per-thread counters.
+//!
+//! Every association whose target is an unsigned integer is a counter, instrumentation
+//! code can be generic over anything countable per thread regardless of the concrete
+//! integer type or tag.
+
+use crate::AssocThreadLocal;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Unsigned integer types usable as counter, implemented for `u8` to `u128` and `usize`.
+pub trait Unsigned: Copy + Ord + sealed::Sealed {
+    /// The value zero.
+    const ZERO: Self;
+    /// The value one.
+    const ONE: Self;
+
+    /// Adds 'rhs', saturating at the maximum.
+    fn saturating_add(self, rhs: Self) -> Self;
+
+    /// Subtracts 'rhs', saturating at zero.
+    fn saturating_sub(self, rhs: Self) -> Self;
+
+    /// Converts to `u128` for reporting.
+    fn to_u128(self) -> u128;
+}
+
+macro_rules! impl_unsigned {
+    ($($T:ty),*) => {
+        $(
+            impl sealed::Sealed for $T {}
+
+            impl Unsigned for $T {
+                const ZERO: Self = 0;
+                const ONE: Self = 1;
+
+                fn saturating_add(self, rhs: Self) -> Self {
+                    <$T>::saturating_add(self, rhs)
+                }
+
+                fn saturating_sub(self, rhs: Self) -> Self {
+                    <$T>::saturating_sub(self, rhs)
+                }
+
+                fn to_u128(self) -> u128 {
+                    self as u128
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128, usize);
+
+/// A per-thread counter, implemented for every association with an unsigned integer
+/// target.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Requests;
+/// struct Errors;
+/// assoc_threadlocal!(Requests, u64 = 0);
+/// assoc_threadlocal!(Errors:Requests, u8 = 0);
+///
+/// fn count<C: AssocCounter<T, TAG>, T: Unsigned, TAG>() {
+///     C::inc_counter();
+/// }
+///
+/// count::<Requests, u64, ()>();
+/// count::<Requests, u8, Errors>();
+/// <Requests as AssocCounter<u64>>::add_counter(2);
+/// assert_eq!(<Requests as AssocCounter<u64>>::report_counter(), 3);
+/// assert_eq!(<Requests as AssocCounter<u8, Errors>>::reset_counter(), 1);
+/// ```
+pub trait AssocCounter<T: Unsigned, TAG = ()>: AssocThreadLocal<T, TAG> {
+    /// Increments the current threads counter, returns the new value.
+    fn inc_counter() -> T {
+        Self::add_counter(T::ONE)
+    }
+
+    /// Decrements the current threads counter saturating at zero, returns the new value.
+    fn dec_counter() -> T {
+        let value = Self::get_threadlocal().saturating_sub(T::ONE);
+        Self::set_threadlocal(value);
+        value
+    }
+
+    /// Adds 'n' to the current threads counter saturating at the maximum, returns the new
+    /// value.
+    fn add_counter(n: T) -> T {
+        let value = Self::get_threadlocal().saturating_add(n);
+        Self::set_threadlocal(value);
+        value
+    }
+
+    /// Sets the current threads counter to zero, returns the old value.
+    fn reset_counter() -> T {
+        let value = Self::get_threadlocal();
+        Self::set_threadlocal(T::ZERO);
+        value
+    }
+
+    /// Returns the current threads counter.
+    fn report_counter() -> T {
+        Self::get_threadlocal()
+    }
+}
+
+impl<S: ?Sized + AssocThreadLocal<T, TAG>, T: Unsigned, TAG> AssocCounter<T, TAG> for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::{AssocCounter, Unsigned};
+
+    struct Hits;
+    crate::assoc_threadlocal!(Hits, u8 = 254);
+
+    #[test]
+    fn saturating() {
+        assert_eq!(Hits::inc_counter(), 255);
+        assert_eq!(Hits::inc_counter(), 255);
+        assert_eq!(Hits::reset_counter(), 255);
+        assert_eq!(Hits::dec_counter(), 0);
+        assert_eq!(Hits::report_counter().to_u128(), 0);
+    }
+}
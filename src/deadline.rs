@@ -0,0 +1,119 @@
+//! Per-thread deadlines.
+//!
+//! A caller sets a deadline for the work it calls, deeply nested code checks the time left
+//! and gives up cooperatively.  Nested deadlines can only shorten the time budget, never
+//! extend the one of an outer scope.
+
+use crate::{AssocThreadLocal, ThreadLocalGuard};
+use std::time::{Duration, Instant};
+
+/// Tag for the thread local deadline.
+pub struct DeadlineState;
+
+/// A per-thread deadline.
+/// Use the `assoc_deadline!()` macro for implementing this trait on types.
+pub trait AssocDeadline: AssocThreadLocal<Option<Instant>, DeadlineState> + Sized {
+    /// Sets the deadline of the current thread until the returned guard is dropped.  When
+    /// an earlier deadline is already set that one stays in effect.
+    fn set_deadline_scoped(
+        deadline: Instant,
+    ) -> ThreadLocalGuard<Self, Option<Instant>, DeadlineState> {
+        let deadline = match Self::get_threadlocal() {
+            Some(outer) => outer.min(deadline),
+            None => deadline,
+        };
+        Self::set_threadlocal_scoped(Some(deadline))
+    }
+
+    /// Sets the deadline of the current thread to 'timeout' from now until the returned
+    /// guard is dropped.
+    fn set_timeout_scoped(
+        timeout: Duration,
+    ) -> ThreadLocalGuard<Self, Option<Instant>, DeadlineState> {
+        Self::set_deadline_scoped(Instant::now() + timeout)
+    }
+
+    /// Returns the deadline of the current thread.
+    fn deadline() -> Option<Instant> {
+        Self::get_threadlocal()
+    }
+
+    /// Returns the time left until the deadline, zero when it passed and `None` when no
+    /// deadline is set.
+    fn remaining() -> Option<Duration> {
+        Self::get_threadlocal().map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns whether the deadline passed.
+    fn expired() -> bool {
+        Self::get_threadlocal().is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Associates a per-thread deadline to a type.
+///
+///  * 'T' is the type you want have a thread local deadline associated to
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::time::Duration;
+///
+/// struct Budget;
+/// assoc_deadline!(Budget);
+///
+/// fn deeply_nested() -> Result<(), &'static str> {
+///     if Budget::expired() {
+///         return Err("timed out");
+///     }
+///     Ok(())
+/// }
+///
+/// assert_eq!(Budget::remaining(), None);
+/// {
+///     let _budget = Budget::set_timeout_scoped(Duration::from_secs(10));
+///     assert!(deeply_nested().is_ok());
+///     let _expired = Budget::set_timeout_scoped(Duration::ZERO);
+///     assert!(deeply_nested().is_err());
+/// }
+/// assert!(!Budget::expired());
+/// ```
+#[macro_export]
+macro_rules! assoc_deadline {
+    ($T:ty) => {
+        $crate::assoc_threadlocal!(
+            $crate::DeadlineState:$T,
+            Option<std::time::Instant> = None
+        );
+
+        impl $crate::AssocDeadline for $T {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocDeadline;
+    use std::time::{Duration, Instant};
+
+    struct Call;
+    assoc_deadline!(Call);
+
+    #[test]
+    fn nested_only_shortens() {
+        let outer = Instant::now() + Duration::from_secs(1);
+        let _outer = Call::set_deadline_scoped(outer);
+        {
+            let _longer = Call::set_timeout_scoped(Duration::from_secs(60));
+            assert_eq!(Call::deadline(), Some(outer));
+            let _shorter = Call::set_timeout_scoped(Duration::from_millis(1));
+            assert!(Call::remaining().unwrap() <= Duration::from_millis(1));
+        }
+        assert_eq!(Call::deadline(), Some(outer));
+    }
+
+    #[test]
+    fn per_thread() {
+        let _guard = Call::set_timeout_scoped(Duration::ZERO);
+        assert!(Call::expired());
+        assert!(!std::thread::spawn(Call::expired).join().unwrap());
+    }
+}
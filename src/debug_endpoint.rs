@@ -0,0 +1,371 @@
+//! HTTP debug endpoint serving the published values of all threads (requires the
+//! `debug-endpoint` feature).
+//!
+//! `handle()` is framework agnostic, it maps the path below the mount point to a
+//! response, so any HTTP server can route a debug prefix to it:
+//!
+//! ```ignore
+//! // axum
+//! async fn debug(uri: axum::http::Uri) -> impl axum::response::IntoResponse {
+//!     let response = debug_endpoint::handle(uri.path().trim_start_matches("/debug/assoc"));
+//!     (
+//!         axum::http::StatusCode::from_u16(response.status).unwrap(),
+//!         [(axum::http::header::CONTENT_TYPE, response.content_type)],
+//!         response.body,
+//!     )
+//! }
+//! ```
+//!
+//! `serve()` runs a minimal standalone server on a std `TcpListener` instead.  Only
+//! values that threads published with `reporter::publish()` are shown, a thread local can
+//! not be read by other threads.
+
+use crate::reporter::{snapshot, Snapshot};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// A response of the debug endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugResponse {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The value of the content-type header.
+    pub content_type: &'static str,
+    /// The body.
+    pub body: String,
+}
+
+/// Answers a request for 'path', relative to where the endpoint is mounted, with the
+/// current `reporter::snapshot()`.  Query strings are ignored.
+///
+///  * '/' an HTML table of all threads and their values
+///  * '/json' the same as JSON
+///  * '/metrics' the numeric values in the Prometheus text format
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Tenant;
+/// assoc_threadlocal!(Tenant, &'static str = "acme");
+/// Tenant::get_threadlocal();
+/// reporter::publish();
+///
+/// let response = debug_endpoint::handle("/json");
+/// assert_eq!(response.status, 200);
+/// assert!(response.body.contains(r#""value":"\"acme\"""#));
+/// assert_eq!(debug_endpoint::handle("/nothing").status, 404);
+/// ```
+pub fn handle(path: &str) -> DebugResponse {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    match path.trim_end_matches('/') {
+        "" => DebugResponse {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: render_html(&snapshot()),
+        },
+        "/json" => DebugResponse {
+            status: 200,
+            content_type: "application/json",
+            body: render_json(&snapshot()),
+        },
+        "/metrics" => {
+            let mut body = String::new();
+            crate::prometheus::render_prometheus(&mut body);
+            DebugResponse {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body,
+            }
+        }
+        _ => DebugResponse {
+            status: 404,
+            content_type: "text/plain; charset=utf-8",
+            body: String::from("not found\n"),
+        },
+    }
+}
+
+/// Renders 'snapshot' as JSON object with the time it was taken in milliseconds since the
+/// unix epoch and an array of threads, each with its name, how many milliseconds ago it
+/// published and its values.
+pub fn render_json(snapshot: &Snapshot) -> String {
+    let mut json = String::new();
+    let taken = snapshot
+        .taken
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let _ = write!(json, "{{\"taken_ms\":{taken},\"threads\":[");
+    for (i, thread) in snapshot.threads.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"thread\":");
+        crate::registry::push_json_string(&mut json, &format!("{:?}", thread.thread));
+        json.push_str(",\"name\":");
+        match &thread.name {
+            Some(name) => crate::registry::push_json_string(&mut json, name),
+            None => json.push_str("null"),
+        }
+        let _ = write!(
+            json,
+            ",\"published_ms_ago\":{},\"values\":[",
+            thread.published.elapsed().as_millis()
+        );
+        for (i, value) in thread.values.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"implementor\":");
+            crate::registry::push_json_string(&mut json, value.implementor);
+            json.push_str(",\"tag\":");
+            crate::registry::push_json_string(&mut json, value.tag);
+            json.push_str(",\"target\":");
+            crate::registry::push_json_string(&mut json, value.target);
+            json.push_str(",\"value\":");
+            match &value.value {
+                Some(value) => crate::registry::push_json_string(&mut json, value),
+                None => json.push_str("null"),
+            }
+            json.push('}');
+        }
+        json.push_str("]}");
+    }
+    json.push_str("]}");
+    json
+}
+
+/// Renders 'snapshot' as HTML page with a table per thread.
+pub fn render_html(snapshot: &Snapshot) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>thread locals</title></head><body>\n",
+    );
+    if snapshot.threads.is_empty() {
+        html.push_str("<p>no thread published its values</p>\n");
+    }
+    for thread in &snapshot.threads {
+        let name = thread
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{:?}", thread.thread));
+        let _ = writeln!(
+            html,
+            "<h2>{}</h2>\n<p>published {} ms ago</p>\n<table>\n<tr><th>implementor</th><th>tag</th><th>target</th><th>value</th></tr>",
+            escape_html(&name),
+            thread.published.elapsed().as_millis()
+        );
+        for value in &thread.values {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(value.implementor),
+                escape_html(value.tag),
+                escape_html(value.target),
+                value
+                    .value
+                    .as_deref()
+                    .map_or_else(|| String::from("<i>no Debug</i>"), escape_html)
+            );
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Stops the server started by `serve()` when dropped.
+#[must_use = "the server stops immediately when the handle is not kept"]
+#[derive(Debug)]
+pub struct DebugServer {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DebugServer {
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops the server and waits for its thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::Release);
+            // wakes the blocking accept
+            let _ = TcpStream::connect(self.local_addr);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for DebugServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Serves `handle()` over HTTP on 'addr' from a background thread, one request per
+/// connection.  Meant for internal debug ports, there is no authentication.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::io::{Read, Write};
+///
+/// let server = debug_endpoint::serve("127.0.0.1:0").unwrap();
+/// let mut stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+/// stream.write_all(b"GET /json HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+/// let mut response = String::new();
+/// stream.read_to_string(&mut response).unwrap();
+/// assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+/// server.stop();
+/// ```
+pub fn serve(addr: impl ToSocketAddrs) -> io::Result<DebugServer> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    let thread = thread::Builder::new()
+        .name(String::from("assoc_threadlocal debug endpoint"))
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::Acquire) {
+                    break;
+                }
+                // a failing client does not stop the server
+                if let Ok(stream) = stream {
+                    let _ = respond(stream);
+                }
+            }
+        })?;
+    Ok(DebugServer {
+        local_addr,
+        stop,
+        thread: Some(thread),
+    })
+}
+
+fn respond(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed but must be consumed before answering
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => handle(path),
+        _ => DebugResponse {
+            status: 405,
+            content_type: "text/plain; charset=utf-8",
+            body: String::from("only GET is supported\n"),
+        },
+    };
+    let reason = match response.status {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_html, render_json};
+    use crate::reporter::{Snapshot, ThreadSnapshot, ValueSnapshot};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            taken: UNIX_EPOCH + Duration::from_millis(1500),
+            threads: vec![ThreadSnapshot {
+                thread: std::thread::current().id(),
+                name: Some(String::from("worker <1>")),
+                published: Instant::now(),
+                values: vec![
+                    ValueSnapshot {
+                        implementor: "app::Job",
+                        tag: "()",
+                        target: "&str",
+                        value: Some(String::from("\"a&b\"")),
+                    },
+                    ValueSnapshot {
+                        implementor: "app::Job",
+                        tag: "()",
+                        target: "app::Opaque",
+                        value: None,
+                    },
+                ],
+            }],
+        }
+    }
+
+    #[test]
+    fn json() {
+        let json = render_json(&snapshot());
+        assert!(json.starts_with(r#"{"taken_ms":1500,"threads":[{"thread":"ThreadId("#));
+        assert!(json.contains(r#""name":"worker <1>","published_ms_ago":"#));
+        assert!(json.ends_with(
+            r#""values":[{"implementor":"app::Job","tag":"()","target":"&str","value":"\"a&b\""},{"implementor":"app::Job","tag":"()","target":"app::Opaque","value":null}]}]}"#
+        ));
+    }
+
+    #[test]
+    fn html() {
+        let html = render_html(&snapshot());
+        assert!(html.contains("<h2>worker &lt;1&gt;</h2>"));
+        assert!(html.contains("<td>&quot;a&amp;b&quot;</td>"));
+        assert!(html.contains("<td><i>no Debug</i></td>"));
+    }
+
+    #[test]
+    fn serves_over_tcp() {
+        use std::io::{Read, Write};
+
+        let server = super::serve("127.0.0.1:0").unwrap();
+        for (request, status) in [
+            ("GET /?refresh=1 HTTP/1.0\r\n\r\n", "200 OK"),
+            ("GET /missing HTTP/1.0\r\n\r\n", "404 Not Found"),
+            ("POST / HTTP/1.0\r\n\r\n", "405 Method Not Allowed"),
+        ] {
+            let mut stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+                "{response}"
+            );
+        }
+        server.stop();
+    }
+}
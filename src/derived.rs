@@ -0,0 +1,131 @@
+//! Values derived from associations, cached per thread.
+//!
+//! A `Derived` caches the result of an expensive projection of an associations value,
+//! e.g. a filter compiled from the ambient configuration.  It is recomputed only when the
+//! generation of the association changed since it was cached.
+
+use crate::{GetAssocThreadLocal, ThreadLocalHandle};
+use std::cell::RefCell;
+use std::fmt;
+
+/// A value derived from an association of 'T', cached for the current thread.
+///
+/// The cache belongs to one thread, a `Derived` is declared in a `thread_local!()`:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Config;
+/// assoc_threadlocal!(Config, &'static str = "error,warn");
+///
+/// fn compile(levels: &'static str) -> Vec<String> {
+///     levels.split(',').map(str::to_uppercase).collect()
+/// }
+///
+/// std::thread_local!(
+///     static FILTER: Derived<&'static str, Vec<String>> = Derived::new(Config::handle(), compile);
+/// );
+///
+/// assert_eq!(FILTER.with(Derived::get), ["ERROR", "WARN"]);
+/// Config::set_threadlocal("info");
+/// assert_eq!(FILTER.with(|filter| filter.with(|levels| levels.len())), 1);
+/// ```
+pub struct Derived<T, U> {
+    source: fn() -> (T, u64),
+    derive: fn(T) -> U,
+    // the derived value and the generation of the source it was derived from
+    cached: RefCell<Option<(U, u64)>>,
+}
+
+impl<T: Copy, U> Derived<T, U> {
+    /// Creates a cache of 'derive' applied to the value of the association of 'handle'.
+    pub const fn new<S: GetAssocThreadLocal<T, TAG>, TAG>(
+        handle: ThreadLocalHandle<S, T, TAG>,
+        derive: fn(T) -> U,
+    ) -> Self {
+        let _ = handle;
+        Derived {
+            source: S::get_versioned,
+            derive,
+            cached: RefCell::new(None),
+        }
+    }
+
+    /// Calls 'f' with the derived value, it is recomputed first when the source changed.
+    /// 'f' and the derive function must not access this `Derived` again.
+    pub fn with<R>(&self, f: impl FnOnce(&U) -> R) -> R {
+        let (value, generation) = (self.source)();
+        let fresh = matches!(&*self.cached.borrow(), Some((_, cached)) if *cached == generation);
+        if !fresh {
+            // derived outside of the borrow, the derive function may use other caches
+            let derived = (self.derive)(value);
+            *self.cached.borrow_mut() = Some((derived, generation));
+        }
+        f(&self.cached.borrow().as_ref().expect("derived above").0)
+    }
+
+    /// Returns a clone of the derived value, it is recomputed first when the source
+    /// changed.
+    pub fn get(&self) -> U
+    where
+        U: Clone,
+    {
+        self.with(U::clone)
+    }
+
+    /// Drops the cached value, the next access recomputes it.
+    pub fn invalidate(&self) {
+        self.cached.borrow_mut().take();
+    }
+
+    /// Returns whether a value is cached, regardless of whether the source changed since.
+    pub fn is_cached(&self) -> bool {
+        self.cached.borrow().is_some()
+    }
+}
+
+impl<T, U: fmt::Debug> fmt::Debug for Derived<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Derived")
+            .field("cached", &self.cached)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Derived;
+    use crate::{AssocThreadLocal, SetAssocThreadLocal};
+    use std::cell::Cell;
+
+    struct Settings;
+    crate::assoc_threadlocal!(Settings, u32 = 2);
+
+    std::thread_local!(
+        static CALLS: Cell<u32> = const { Cell::new(0) };
+        static SQUARE: Derived<u32, u64> = Derived::new(Settings::handle(), |value| {
+            CALLS.with(|calls| calls.set(calls.get() + 1));
+            value as u64 * value as u64
+        });
+    );
+
+    #[test]
+    fn recomputed_on_change() {
+        assert_eq!(SQUARE.with(Derived::get), 4);
+        assert_eq!(SQUARE.with(Derived::get), 4);
+        assert_eq!(CALLS.with(Cell::get), 1);
+        // setting the same value again is a change as well
+        Settings::set_threadlocal(2);
+        assert_eq!(SQUARE.with(Derived::get), 4);
+        assert_eq!(CALLS.with(Cell::get), 2);
+        {
+            let _scoped = Settings::set_threadlocal_scoped(3);
+            assert_eq!(SQUARE.with(Derived::get), 9);
+        }
+        assert_eq!(SQUARE.with(Derived::get), 4);
+        assert_eq!(CALLS.with(Cell::get), 4);
+        SQUARE.with(Derived::invalidate);
+        assert!(!SQUARE.with(Derived::is_cached));
+        assert_eq!(SQUARE.with(Derived::get), 4);
+        assert_eq!(CALLS.with(Cell::get), 5);
+    }
+}
@@ -0,0 +1,199 @@
+//! Human readable dumps of thread local state (requires the `registry` feature).
+
+use crate::registry::{associations, AssocDescriptor};
+use std::any::TypeId;
+use std::fmt;
+
+/// Writes a table of all registered associations with their values on the current
+/// thread, for panic hooks and debug commands.
+///
+/// The columns are the implementor, tag, target type, the `Debug` representation of the
+/// value ('?' when there is none) and where the innermost active tracked override was set.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Server;
+/// assoc_threadlocal!(Server, u16 = 8080);
+///
+/// let _port = Server::set_threadlocal_scoped_tracked(9090);
+///
+/// let mut dump = String::new();
+/// diagnostics::dump_threadlocals(&mut dump).unwrap();
+/// let line = dump.lines().find(|line| line.contains("Server")).unwrap();
+/// assert!(line.contains("u16"));
+/// assert!(line.contains("9090"));
+/// assert!(line.contains(file!()));
+/// ```
+pub fn dump_threadlocals(w: &mut impl fmt::Write) -> fmt::Result {
+    dump_threadlocals_where(w, |_| true)
+}
+
+/// Writes the table of `dump_threadlocals()` for the associations selected by 'filter'.
+pub fn dump_threadlocals_where(
+    w: &mut impl fmt::Write,
+    mut filter: impl FnMut(&AssocDescriptor) -> bool,
+) -> fmt::Result {
+    // values are computed before writing, the widths depend on all rows
+    let rows: Vec<[String; 5]> = associations()
+        .into_iter()
+        .filter(|descriptor| filter(descriptor))
+        .map(|descriptor| {
+            [
+                descriptor.implementor_name().to_string(),
+                descriptor.tag_name().to_string(),
+                descriptor.target_name().to_string(),
+                descriptor
+                    .debug_value()
+                    .unwrap_or_else(|| String::from("?")),
+                descriptor
+                    .override_location()
+                    .map(|location| location.to_string())
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+
+    let header = ["IMPLEMENTOR", "TAG", "TARGET", "VALUE", "SET AT"].map(String::from);
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(widths).enumerate() {
+            if i > 0 {
+                line.push_str("  ");
+            }
+            line.push_str(cell);
+            if i + 1 < row.len() {
+                line.extend(std::iter::repeat_n(' ', width - cell.chars().count()));
+            }
+        }
+        writeln!(w, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// A tuple of implementor types, selects their associations for
+/// `install_panic_context_hook()`.
+pub trait Implementors: 'static {
+    /// Returns the `TypeId`s of the implementor types.
+    fn type_ids() -> Vec<TypeId>;
+}
+
+macro_rules! implementors_tuple {
+    ($($T:ident),+) => {
+        impl<$($T: 'static),+> Implementors for ($($T,)+) {
+            fn type_ids() -> Vec<TypeId> {
+                vec![$(TypeId::of::<$T>()),+]
+            }
+        }
+    };
+}
+
+implementors_tuple!(A);
+implementors_tuple!(A, B);
+implementors_tuple!(A, B, C);
+implementors_tuple!(A, B, C, D);
+implementors_tuple!(A, B, C, D, E);
+implementors_tuple!(A, B, C, D, E, F);
+implementors_tuple!(A, B, C, D, E, F, G);
+implementors_tuple!(A, B, C, D, E, F, G, H);
+
+/// Writes the table of `dump_threadlocals()` for the associations of the implementor
+/// types in 'L'.
+pub fn dump_threadlocals_of<L: Implementors>(w: &mut impl fmt::Write) -> fmt::Result {
+    let ids = L::type_ids();
+    dump_threadlocals_where(w, |descriptor| ids.contains(&descriptor.implementor_id()))
+}
+
+/// Installs a panic hook that calls the previously installed hook and then writes the
+/// values of the associations of the implementor types in 'L' on the panicking thread to
+/// stderr.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Request;
+/// struct Tenant;
+/// assoc_threadlocal!(Request, u64 = 0);
+/// assoc_threadlocal!(Tenant, &'static str = "");
+///
+/// diagnostics::install_panic_context_hook::<(Request, Tenant)>();
+///
+/// let result = std::thread::spawn(|| {
+///     Request::set_threadlocal(17);
+///     Tenant::set_threadlocal("acme");
+///     // the panic message is followed by a table holding 17 and "acme"
+///     panic!("request failed");
+/// })
+/// .join();
+/// assert!(result.is_err());
+/// ```
+pub fn install_panic_context_hook<L: Implementors>() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let mut dump = String::new();
+        if dump_threadlocals_of::<L>(&mut dump).is_ok() {
+            let thread = std::thread::current();
+            eprintln!(
+                "thread local context of thread '{}':\n{dump}",
+                thread.name().unwrap_or("<unnamed>")
+            );
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump_threadlocals, dump_threadlocals_of};
+    use crate::{AssocThreadLocal, GetAssocThreadLocal};
+
+    struct Dumped;
+    struct Mode;
+    crate::assoc_threadlocal!(Dumped, u8 = 7);
+    crate::assoc_threadlocal!(Mode:Dumped, char = 'x');
+
+    #[test]
+    fn table() {
+        <Dumped as GetAssocThreadLocal<u8>>::get_threadlocal();
+        let _mode = <Dumped as AssocThreadLocal<char, Mode>>::set_threadlocal_scoped_tracked('y');
+
+        let mut dump = String::new();
+        dump_threadlocals(&mut dump).unwrap();
+        let mut lines = dump.lines();
+        let header = lines.next().unwrap();
+        assert!(header.starts_with("IMPLEMENTOR"));
+        let value_column = header.find("VALUE").unwrap();
+
+        let row = lines
+            .clone()
+            .find(|line| line.contains("Dumped") && line.contains("u8"))
+            .unwrap();
+        assert_eq!(&row[value_column..].trim_end(), &"7");
+
+        let row = lines.find(|line| line.contains("Mode")).unwrap();
+        assert!(row[value_column..].starts_with("'y'"));
+        assert!(row.contains("diagnostics.rs"));
+    }
+
+    struct Selected;
+    crate::assoc_threadlocal!(Selected, i32 = -1);
+
+    #[test]
+    fn selected_implementors() {
+        <Selected as GetAssocThreadLocal<i32>>::get_threadlocal();
+        <Dumped as GetAssocThreadLocal<u8>>::get_threadlocal();
+
+        let mut dump = String::new();
+        dump_threadlocals_of::<(Selected,)>(&mut dump).unwrap();
+        let rows: Vec<&str> = dump.lines().skip(1).collect();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].contains("Selected") && rows[0].ends_with("-1"));
+    }
+}
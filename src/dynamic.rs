@@ -0,0 +1,189 @@
+//! Associations registered at runtime.
+//!
+//! The macro generated associations are the fast path, but they must be known at compile
+//! time.  Plugin systems can register associations here instead.  Registration is process
+//! wide, each thread lazily initializes its own value from the registered initializer on
+//! first access.  Lookups go through `TypeId` keyed maps.
+//!
+//! Scripting layers that can not even name tag types can attach values by name through
+//! `AssocNamed`, which is available on every `'static` type.
+
+use crate::extension::with_extension;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+
+type InitMap = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+type NamedMap = HashMap<&'static str, Box<dyn Any>>;
+
+static INITS: RwLock<Option<InitMap>> = RwLock::new(None);
+
+/// Error returned when accessing an association that was never registered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotRegistered;
+
+impl fmt::Display for NotRegistered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("association not registered")
+    }
+}
+
+impl std::error::Error for NotRegistered {}
+
+/// Registers an association of 'Target' to 'T' with 'Tag' and its initializer.
+/// Registering again replaces the initializer for threads that did not access it yet.
+pub fn register_assoc<T: 'static, Target: 'static, Tag: 'static>(init: fn() -> Target) {
+    INITS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(TypeId::of::<(T, Target, Tag)>(), Box::new(init));
+}
+
+/// Returns whether an association is registered.
+pub fn is_registered<T: 'static, Target: 'static, Tag: 'static>() -> bool {
+    registered_init::<T, Target, Tag>().is_some()
+}
+
+/// Returns the current threads value of a registered association.
+pub fn get_dyn<T: 'static, Target: Clone + 'static, Tag: 'static>() -> Option<Target> {
+    with_dyn::<T, Target, Tag, _>(|value| value.clone()).ok()
+}
+
+/// Sets the current threads value of a registered association.
+pub fn set_dyn<T: 'static, Target: 'static, Tag: 'static>(
+    value: Target,
+) -> Result<(), NotRegistered> {
+    with_dyn::<T, Target, Tag, _>(|slot| *slot = value)
+}
+
+/// Calls 'f' with the current threads value of a registered association.
+///
+/// The value is taken out of the thread while 'f' runs, 'f' may access other associations.
+/// Nested accesses to the same association see a freshly initialized value, its changes
+/// are overwritten when 'f' returns.  When 'f' panics the value is reinitialized on the
+/// next access.
+pub fn with_dyn<T: 'static, Target: 'static, Tag: 'static, R>(
+    f: impl FnOnce(&mut Target) -> R,
+) -> Result<R, NotRegistered> {
+    let taken = with_extension::<(T, Target, Tag), Option<Target>, _>(Option::take);
+    let mut value = match taken {
+        Some(value) => value,
+        // the initializer runs outside of the extension borrow, it may use associations
+        None => registered_init::<T, Target, Tag>().ok_or(NotRegistered)?(),
+    };
+    let result = f(&mut value);
+    with_extension::<(T, Target, Tag), Option<Target>, _>(|slot| *slot = Some(value));
+    Ok(result)
+}
+
+fn registered_init<T: 'static, Target: 'static, Tag: 'static>() -> Option<fn() -> Target> {
+    INITS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()?
+        .get(&TypeId::of::<(T, Target, Tag)>())
+        .and_then(|init| init.downcast_ref::<fn() -> Target>())
+        .copied()
+}
+
+/// Per-thread values attached to a type by name.
+///
+/// ```
+/// use assoc_threadlocal::dynamic::AssocNamed;
+///
+/// struct Script;
+///
+/// Script::set_named("answer", 42u32);
+/// assert_eq!(Script::get_named::<u32>("answer"), Some(42));
+/// // wrong type or unknown name
+/// assert_eq!(Script::get_named::<i64>("answer"), None);
+/// assert_eq!(Script::get_named::<u32>("question"), None);
+/// ```
+pub trait AssocNamed: 'static {
+    /// Returns a clone of the current threads value named 'key' if it has type 'V'.
+    fn get_named<V: Clone + 'static>(key: &str) -> Option<V> {
+        with_extension::<Self, NamedMap, _>(|map| map.get(key)?.downcast_ref::<V>().cloned())
+    }
+
+    /// Sets the current threads value named 'key', replacing any previous value.
+    fn set_named<V: 'static>(key: &'static str, value: V) {
+        with_extension::<Self, NamedMap, _>(|map| {
+            map.insert(key, Box::new(value));
+        })
+    }
+
+    /// Removes the current threads value named 'key', returns whether it existed.
+    fn remove_named(key: &str) -> bool {
+        with_extension::<Self, NamedMap, _>(|map| map.remove(key).is_some())
+    }
+
+    /// Returns the names of all values attached on the current thread.
+    fn named_keys() -> Vec<&'static str> {
+        with_extension::<Self, NamedMap, _>(|map| map.keys().copied().collect())
+    }
+}
+
+impl<T: 'static> AssocNamed for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Plugin;
+    struct Counter;
+
+    #[test]
+    fn unregistered() {
+        struct Unknown;
+        assert_eq!(get_dyn::<Plugin, u8, Unknown>(), None);
+        assert_eq!(set_dyn::<Plugin, u8, Unknown>(1), Err(NotRegistered));
+        assert!(!is_registered::<Plugin, u8, Unknown>());
+    }
+
+    #[test]
+    fn register_get_set() {
+        register_assoc::<Plugin, String, Counter>(|| String::from("init"));
+        assert_eq!(
+            get_dyn::<Plugin, String, Counter>().as_deref(),
+            Some("init")
+        );
+        set_dyn::<Plugin, String, Counter>(String::from("changed")).unwrap();
+        assert_eq!(
+            get_dyn::<Plugin, String, Counter>().as_deref(),
+            Some("changed")
+        );
+        let other = std::thread::spawn(get_dyn::<Plugin, String, Counter>)
+            .join()
+            .unwrap();
+        assert_eq!(other.as_deref(), Some("init"));
+    }
+
+    #[test]
+    fn nested_access() {
+        struct Outer;
+        struct Inner;
+        register_assoc::<Plugin, u32, Outer>(|| 1);
+        register_assoc::<Plugin, u32, Inner>(|| 10);
+        let sum = with_dyn::<Plugin, u32, Outer, _>(|outer| {
+            *outer += 1;
+            *outer + with_dyn::<Plugin, u32, Inner, _>(|inner| *inner).unwrap()
+        });
+        assert_eq!(sum, Ok(12));
+        assert_eq!(get_dyn::<Plugin, u32, Outer>(), Some(2));
+    }
+
+    #[test]
+    fn named_values() {
+        Plugin::set_named("level", 3u8);
+        Plugin::set_named("name", "plugin");
+        assert_eq!(Plugin::get_named::<&str>("name"), Some("plugin"));
+        assert_eq!(Counter::get_named::<u8>("level"), None);
+        let mut keys = Plugin::named_keys();
+        keys.sort();
+        assert_eq!(keys, ["level", "name"]);
+        assert!(Plugin::remove_named("level"));
+        assert!(!Plugin::remove_named("level"));
+    }
+}
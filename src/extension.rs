@@ -0,0 +1,29 @@
+//! Per-thread side table for state that generic provided methods need per association.
+//!
+//! Provided trait methods can not declare statics depending on their generic parameters,
+//! this keeps such state in a thread local map keyed by the `TypeId` of a key type
+//! (usually `(Self, T, TAG)`) and the stored value type.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+type ExtensionMap = HashMap<(TypeId, TypeId), Box<dyn Any>>;
+
+std::thread_local!(
+    static EXTENSIONS: RefCell<ExtensionMap> = RefCell::new(HashMap::new());
+);
+
+/// Calls 'f' with the current threads 'V' stored for key 'K', creating it with 'Default'
+/// if it does not exist.  'f' must not access extensions itself.
+pub(crate) fn with_extension<K: ?Sized + 'static, V: Default + 'static, R>(
+    f: impl FnOnce(&mut V) -> R,
+) -> R {
+    EXTENSIONS.with(|map| {
+        let mut map = map.borrow_mut();
+        let value = map
+            .entry((TypeId::of::<K>(), TypeId::of::<V>()))
+            .or_insert_with(|| Box::new(V::default()));
+        f(value.downcast_mut::<V>().expect("extension type mismatch"))
+    })
+}
@@ -0,0 +1,195 @@
+//! Per-thread fault injection.
+//!
+//! Code guards its error paths with `should_fail()` checks, tests inject the faults they
+//! want to exercise on the thread running the code under test.  Without the
+//! `fault-injection` feature injecting does nothing and the checks are constant `false`,
+//! so they compile to nothing in production builds.
+
+use std::marker::PhantomData;
+
+/// Whether faults are injected, set by the `fault-injection` feature.
+pub const ENABLED: bool = cfg!(feature = "fault-injection");
+
+/// A fault that can be injected, usually a unit struct or a fieldless enum.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Clone, Copy)]
+/// enum IoFail {
+///     Read,
+///     Write,
+/// }
+///
+/// impl Fault for IoFail {
+///     fn index(self) -> u32 {
+///         self as u32
+///     }
+/// }
+/// ```
+pub trait Fault: Copy + 'static {
+    /// Returns the bit index of the fault, must be less than 64 and distinct for all
+    /// values.  Types with a single value can keep the default.
+    fn index(self) -> u32 {
+        0
+    }
+}
+
+fn bit<F: Fault>(fault: F) -> u64 {
+    let index = fault.index();
+    assert!(index < 64, "fault index out of range");
+    1 << index
+}
+
+#[cfg(feature = "fault-injection")]
+fn replace_injected<K: 'static>(injected: Option<u64>) -> u64 {
+    crate::extension::with_extension::<K, u64, _>(|current| match injected {
+        Some(injected) => std::mem::replace(current, injected),
+        None => *current,
+    })
+}
+
+// same signature as above, nothing is stored without the feature
+#[cfg(not(feature = "fault-injection"))]
+#[inline(always)]
+#[allow(clippy::extra_unused_type_parameters)]
+fn replace_injected<K: 'static>(_injected: Option<u64>) -> u64 {
+    0
+}
+
+/// Tag for the thread local injected faults.
+pub struct FaultState;
+
+/// Faults injectable into the code paths of a type.
+/// Use the `assoc_fault!()` macro for implementing this trait on types.
+pub trait AssocFault<F: Fault>: Sized + 'static {
+    /// Returns whether 'fault' is injected on the current thread.  Always `false` without
+    /// the `fault-injection` feature.
+    #[inline(always)]
+    fn should_fail(fault: F) -> bool {
+        ENABLED && Self::injected() & bit(fault) != 0
+    }
+
+    /// Injects 'fault' on the current thread until the returned guard is dropped.
+    fn inject_scoped(fault: F) -> FaultGuard<Self, F> {
+        FaultGuard {
+            previous: Self::replace_injected(Self::injected() | bit(fault)),
+            _marker: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Injects 'fault' on the current thread until it is cleared.
+    fn inject(fault: F) {
+        Self::replace_injected(Self::injected() | bit(fault));
+    }
+
+    /// Removes all faults injected on the current thread.
+    fn clear_faults() {
+        Self::replace_injected(0);
+    }
+
+    #[doc(hidden)]
+    fn injected() -> u64 {
+        replace_injected::<(FaultState, Self, F)>(None)
+    }
+
+    #[doc(hidden)]
+    fn replace_injected(injected: u64) -> u64 {
+        replace_injected::<(FaultState, Self, F)>(Some(injected))
+    }
+}
+
+/// Restores the previously injected faults when dropped.
+#[must_use = "the fault is removed immediately when the guard is not kept"]
+pub struct FaultGuard<S: AssocFault<F>, F: Fault> {
+    previous: u64,
+    _marker: PhantomData<fn() -> (S, F)>,
+    // the faults must be restored on the thread that injected them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<S: AssocFault<F>, F: Fault> Drop for FaultGuard<S, F> {
+    fn drop(&mut self) {
+        S::replace_injected(self.previous);
+    }
+}
+
+/// Makes the faults 'F' injectable into the code paths of a type.
+///
+///  * 'T' is the type whose code paths check for faults
+///  * 'F' is the fault type implementing `Fault`
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Clone, Copy)]
+/// struct IoFail;
+/// impl Fault for IoFail {}
+///
+/// struct Storage;
+/// assoc_fault!(Storage, IoFail);
+///
+/// fn store(data: &[u8]) -> std::io::Result<usize> {
+///     if Storage::should_fail(IoFail) {
+///         return Err(std::io::Error::other("injected"));
+///     }
+///     Ok(data.len())
+/// }
+///
+/// assert!(store(b"data").is_ok());
+/// {
+///     let _fault = Storage::inject_scoped(IoFail);
+///     // only with the `fault-injection` feature
+///     assert_eq!(store(b"data").is_err(), fault::ENABLED);
+/// }
+/// assert!(store(b"data").is_ok());
+/// ```
+#[macro_export]
+macro_rules! assoc_fault {
+    ($T:ty, $F:ty) => {
+        impl $crate::AssocFault<$F> for $T {}
+    };
+}
+
+#[cfg(all(test, feature = "fault-injection"))]
+mod tests {
+    use super::{AssocFault, Fault};
+
+    #[derive(Clone, Copy, Debug)]
+    enum Net {
+        Connect,
+        Timeout,
+    }
+
+    impl Fault for Net {
+        fn index(self) -> u32 {
+            self as u32
+        }
+    }
+
+    struct Client;
+    assoc_fault!(Client, Net);
+
+    #[test]
+    fn scoped_and_per_thread() {
+        assert!(!Client::should_fail(Net::Connect));
+        {
+            let _connect = Client::inject_scoped(Net::Connect);
+            {
+                let _timeout = Client::inject_scoped(Net::Timeout);
+                assert!(Client::should_fail(Net::Connect));
+                assert!(Client::should_fail(Net::Timeout));
+            }
+            assert!(!Client::should_fail(Net::Timeout));
+            assert!(!std::thread::spawn(|| Client::should_fail(Net::Connect))
+                .join()
+                .unwrap());
+        }
+        assert!(!Client::should_fail(Net::Connect));
+        Client::inject(Net::Timeout);
+        assert!(Client::should_fail(Net::Timeout));
+        Client::clear_faults();
+        assert!(!Client::should_fail(Net::Timeout));
+    }
+}
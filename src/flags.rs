@@ -0,0 +1,283 @@
+//! Feature flags with per-thread overrides.
+//!
+//! A flag is resolved in layers: an override on the current thread wins over the process
+//! wide default which wins over the default of the flag itself.  This allows A/B tests
+//! and gradual rollouts where single requests or worker threads see a different set of
+//! flags than the rest of the process.
+
+use crate::AssocThreadLocal;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tag for the thread local overrides of a flags association.
+pub struct FlagsState;
+
+/// A feature flag, usually implemented on a fieldless enum.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Clone, Copy)]
+/// enum Feature {
+///     NewParser,
+///     Telemetry,
+/// }
+///
+/// impl Flag for Feature {
+///     fn index(self) -> u32 {
+///         self as u32
+///     }
+///
+///     fn default_enabled(self) -> bool {
+///         matches!(self, Feature::Telemetry)
+///     }
+/// }
+/// ```
+pub trait Flag: Copy {
+    /// Returns the bit index of the flag, must be less than 32 and distinct for all flags.
+    fn index(self) -> u32;
+
+    /// Returns whether the flag is enabled when no layer overrides it.
+    fn default_enabled(self) -> bool {
+        false
+    }
+}
+
+/// A set of flag overrides, each flag is either overridden to a value or left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FlagOverrides {
+    mask: u32,
+    values: u32,
+}
+
+impl FlagOverrides {
+    /// No flag overridden.
+    pub const NONE: FlagOverrides = FlagOverrides { mask: 0, values: 0 };
+
+    /// Returns the override of 'flag'.
+    pub fn get<F: Flag>(self, flag: F) -> Option<bool> {
+        let bit = Self::bit(flag);
+        (self.mask & bit != 0).then_some(self.values & bit != 0)
+    }
+
+    /// Returns these overrides with 'flag' overridden to 'value' or cleared for 'None'.
+    #[must_use]
+    pub fn with<F: Flag>(self, flag: F, value: Option<bool>) -> Self {
+        let bit = Self::bit(flag);
+        match value {
+            Some(value) => FlagOverrides {
+                mask: self.mask | bit,
+                values: if value {
+                    self.values | bit
+                } else {
+                    self.values & !bit
+                },
+            },
+            None => FlagOverrides {
+                mask: self.mask & !bit,
+                values: self.values & !bit,
+            },
+        }
+    }
+
+    /// Returns whether no flag is overridden.
+    pub fn is_empty(self) -> bool {
+        self.mask == 0
+    }
+
+    fn bit<F: Flag>(flag: F) -> u32 {
+        let index = flag.index();
+        assert!(index < 32, "flag index out of range");
+        1 << index
+    }
+
+    fn to_bits(self) -> u64 {
+        (self.mask as u64) << 32 | self.values as u64
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        FlagOverrides {
+            mask: (bits >> 32) as u32,
+            values: bits as u32,
+        }
+    }
+}
+
+/// The process wide default layer of a flags association.
+#[derive(Debug)]
+pub struct GlobalFlags(AtomicU64);
+
+impl GlobalFlags {
+    /// Creates a layer without overrides.
+    pub const fn new() -> Self {
+        GlobalFlags(AtomicU64::new(0))
+    }
+
+    /// Returns the current process wide overrides.
+    pub fn overrides(&self) -> FlagOverrides {
+        FlagOverrides::from_bits(self.0.load(Ordering::Acquire))
+    }
+
+    fn update(&self, f: impl Fn(FlagOverrides) -> FlagOverrides) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |bits| {
+                Some(f(FlagOverrides::from_bits(bits)).to_bits())
+            });
+    }
+}
+
+impl Default for GlobalFlags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Per-thread flag overrides on top of a process wide default layer.
+/// Use the `assoc_flags!()` macro for implementing this trait on types.
+pub trait AssocFlags<F: Flag>: AssocThreadLocal<FlagOverrides, FlagsState> + Sized {
+    /// Returns the process wide default layer.
+    fn the_global_flags() -> &'static GlobalFlags;
+
+    /// Returns whether 'flag' is enabled on the current thread.
+    fn is_enabled(flag: F) -> bool {
+        Self::get_threadlocal()
+            .get(flag)
+            .or_else(|| Self::the_global_flags().overrides().get(flag))
+            .unwrap_or_else(|| flag.default_enabled())
+    }
+
+    /// Overrides 'flag' on the current thread until the returned guard is dropped.
+    fn override_scoped(
+        flag: F,
+        enabled: bool,
+    ) -> crate::ThreadLocalGuard<Self, FlagOverrides, FlagsState> {
+        Self::set_threadlocal_scoped(Self::get_threadlocal().with(flag, Some(enabled)))
+    }
+
+    /// Overrides 'flag' on the current thread, 'None' removes the override.
+    fn set_override(flag: F, enabled: Option<bool>) {
+        Self::set_threadlocal(Self::get_threadlocal().with(flag, enabled))
+    }
+
+    /// Sets the process wide default of 'flag', 'None' falls back to the default of the
+    /// flag itself.  Threads overriding the flag are not affected.
+    fn set_global_default(flag: F, enabled: Option<bool>) {
+        Self::the_global_flags().update(|overrides| overrides.with(flag, enabled))
+    }
+}
+
+/// Associates feature flags with per-thread overrides to a type.
+///
+///  * 'T' is the type you want have flags associated to
+///  * 'F' is the flag type implementing `Flag`
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Clone, Copy)]
+/// enum Feature {
+///     NewParser,
+/// }
+///
+/// impl Flag for Feature {
+///     fn index(self) -> u32 {
+///         self as u32
+///     }
+/// }
+///
+/// struct Features;
+/// assoc_flags!(Features, Feature);
+///
+/// assert!(!Features::is_enabled(Feature::NewParser));
+/// Features::set_global_default(Feature::NewParser, Some(true));
+/// {
+///     let _legacy = Features::override_scoped(Feature::NewParser, false);
+///     assert!(!Features::is_enabled(Feature::NewParser));
+/// }
+/// assert!(Features::is_enabled(Feature::NewParser));
+/// ```
+#[macro_export]
+macro_rules! assoc_flags {
+    ($T:ty, $F:ty) => {
+        $crate::assoc_threadlocal!(
+            $crate::FlagsState:$T,
+            $crate::flags::FlagOverrides = $crate::flags::FlagOverrides::NONE
+        );
+
+        impl $crate::AssocFlags<$F> for $T {
+            fn the_global_flags() -> &'static $crate::flags::GlobalFlags {
+                static GLOBAL_FLAGS: $crate::flags::GlobalFlags = $crate::flags::GlobalFlags::new();
+                &GLOBAL_FLAGS
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Flag, FlagOverrides};
+    use crate::AssocFlags;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Feature {
+        Fast,
+        Safe,
+    }
+
+    impl Flag for Feature {
+        fn index(self) -> u32 {
+            self as u32
+        }
+
+        fn default_enabled(self) -> bool {
+            matches!(self, Feature::Safe)
+        }
+    }
+
+    #[test]
+    fn overrides() {
+        let overrides = FlagOverrides::NONE.with(Feature::Fast, Some(true));
+        assert_eq!(overrides.get(Feature::Fast), Some(true));
+        assert_eq!(overrides.get(Feature::Safe), None);
+        let overrides = overrides.with(Feature::Safe, Some(false));
+        assert_eq!(overrides.get(Feature::Safe), Some(false));
+        assert!(overrides
+            .with(Feature::Fast, None)
+            .with(Feature::Safe, None)
+            .is_empty());
+    }
+
+    struct Layers;
+    assoc_flags!(Layers, Feature);
+
+    #[test]
+    fn layered() {
+        assert!(Layers::is_enabled(Feature::Safe));
+        Layers::set_global_default(Feature::Safe, Some(false));
+        assert!(!Layers::is_enabled(Feature::Safe));
+        {
+            let _guard = Layers::override_scoped(Feature::Safe, true);
+            assert!(Layers::is_enabled(Feature::Safe));
+            assert!(!std::thread::spawn(|| Layers::is_enabled(Feature::Safe))
+                .join()
+                .unwrap());
+        }
+        assert!(!Layers::is_enabled(Feature::Safe));
+        Layers::set_override(Feature::Safe, Some(true));
+        assert!(Layers::is_enabled(Feature::Safe));
+        Layers::set_override(Feature::Safe, None);
+        Layers::set_global_default(Feature::Safe, None);
+        assert!(Layers::is_enabled(Feature::Safe));
+    }
+
+    struct Threads;
+    assoc_flags!(Threads, Feature);
+
+    #[test]
+    fn global_default_seen_by_threads() {
+        Threads::set_global_default(Feature::Fast, Some(true));
+        assert!(std::thread::spawn(|| Threads::is_enabled(Feature::Fast))
+            .join()
+            .unwrap());
+    }
+}
@@ -0,0 +1,170 @@
+//! Associations between types from other crates.
+//!
+//! `assoc_threadlocal!()` implements this crates traits on the implementor, which the
+//! orphan rules only allow when the implementor, the target or the tag is defined in the
+//! crate using the macro.  `Foreign<T, TAG>` is implemented for every implementor, tag and
+//! target up front, so associations of fully foreign types need no newtype.
+//!
+//! The values live in per-thread slots like the ones of generic implementors, accessing
+//! them costs a map lookup.  The INIT is the targets `Default`, `assoc_foreign_init!()`
+//! replaces it.
+
+use crate::generic::slot;
+use crate::init::init_or_override;
+use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+use std::marker::PhantomData;
+
+/// Implementor of the associations of the foreign type 'T' with the foreign tag 'TAG'.
+/// Never constructed, the associated values are accessed through the type.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::net::TcpStream;
+/// use std::time::Duration;
+///
+/// // the connect timeout of TcpStreams in milliseconds
+/// type ConnectTimeout = Foreign<TcpStream, Duration>;
+///
+/// let timeout: u64 = ConnectTimeout::get_threadlocal();
+/// assert_eq!(timeout, 0);
+/// ConnectTimeout::set_threadlocal(250u64);
+/// assert_eq!(<ConnectTimeout as GetAssocThreadLocal<u64, Duration>>::get_threadlocal(), 250);
+/// ```
+pub struct Foreign<T: ?Sized, TAG = ()> {
+    _marker: PhantomData<fn() -> Marker<T, TAG>>,
+}
+
+type Marker<T, TAG> = (PhantomData<T>, TAG);
+
+type Key<T, TAG, TARGET> = (Foreign<T, TAG>, TARGET);
+
+impl<T, TAG, TARGET> GetAssocThreadLocal<TARGET, TAG> for Foreign<T, TAG>
+where
+    T: ?Sized + 'static,
+    TAG: 'static,
+    TARGET: Copy + Default + 'static,
+{
+    #[inline]
+    unsafe fn the_threadlocal() -> *const std::cell::Cell<TARGET> {
+        unsafe {
+            (*slot::<Key<T, TAG, TARGET>, TARGET>(|| {
+                init_or_override::<Self, TARGET, TAG>(TARGET::default)
+            }))
+            .value()
+        }
+    }
+
+    fn threadlocal_generation() -> u64 {
+        unsafe {
+            (*slot::<Key<T, TAG, TARGET>, TARGET>(|| {
+                init_or_override::<Self, TARGET, TAG>(TARGET::default)
+            }))
+            .generation()
+        }
+    }
+}
+
+impl<T, TAG, TARGET> SetAssocThreadLocal<TARGET, TAG> for Foreign<T, TAG>
+where
+    T: ?Sized + 'static,
+    TAG: 'static,
+    TARGET: Copy + Default + 'static,
+{
+    #[inline]
+    fn set_threadlocal(value: TARGET) {
+        unsafe { (*slot::<Key<T, TAG, TARGET>, TARGET>(|| value)).set(value) }
+    }
+
+    fn reset_threadlocal() {
+        Self::set_threadlocal(init_or_override::<Self, TARGET, TAG>(TARGET::default))
+    }
+}
+
+/// Replaces the INIT of a `Foreign` association for all threads that access it for the
+/// first time afterwards, typically called early in `main()`.
+///
+///  * 'TAG' is the tag, `()` when left out
+///  * 'T' is the foreign type the value is associated to
+///  * 'TARGET' is the type of the value
+///  * 'INIT' is the initial value, it can not capture variables
+///
+/// The orphan rules forbid a definition time INIT, as no impl can be generated for the
+/// foreign types.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::net::TcpStream;
+/// use std::time::Duration;
+///
+/// assoc_foreign_init!(Duration: TcpStream, u64 = 500);
+///
+/// let timeout = std::thread::spawn(|| {
+///     <Foreign<TcpStream, Duration> as GetAssocThreadLocal<u64, Duration>>::get_threadlocal()
+/// });
+/// assert_eq!(timeout.join().unwrap(), 500);
+/// ```
+#[macro_export]
+macro_rules! assoc_foreign_init {
+    ($TAG:ty: $T:ty, $TARGET:ty = $INIT:expr) => {
+        <$crate::Foreign<$T, $TAG> as $crate::AssocThreadLocal<$TARGET, $TAG>>::set_threadlocal_init_override(
+            || $INIT,
+        )
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr) => {
+        $crate::assoc_foreign_init!((): $T, $TARGET = $INIT)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Foreign;
+    use crate::{AssocThreadLocal, GetAssocThreadLocal, SetAssocThreadLocal};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    type Capacity = Foreign<HashMap<String, String>>;
+
+    #[test]
+    fn per_target_and_tag() {
+        <Capacity as SetAssocThreadLocal<usize>>::set_threadlocal(16);
+        <Capacity as SetAssocThreadLocal<u8>>::set_threadlocal(1);
+        <Foreign<HashMap<String, String>, Duration> as SetAssocThreadLocal<usize, Duration>>::set_threadlocal(2);
+        assert_eq!(
+            <Capacity as GetAssocThreadLocal<usize>>::get_threadlocal(),
+            16
+        );
+        assert_eq!(<Capacity as GetAssocThreadLocal<u8>>::get_threadlocal(), 1);
+        assert_eq!(
+            std::thread::spawn(<Capacity as GetAssocThreadLocal<usize>>::get_threadlocal)
+                .join()
+                .unwrap(),
+            0
+        );
+        {
+            let _scoped = <Capacity as AssocThreadLocal<usize>>::set_threadlocal_scoped(32);
+            assert_eq!(
+                <Capacity as GetAssocThreadLocal<usize>>::get_threadlocal(),
+                32
+            );
+        }
+        assert_eq!(
+            <Capacity as GetAssocThreadLocal<usize>>::threadlocal_generation(),
+            3
+        );
+    }
+
+    #[test]
+    fn init_override() {
+        assoc_foreign_init!(Duration: str, u32 = 7);
+        let init = std::thread::spawn(|| {
+            <Foreign<str, Duration> as GetAssocThreadLocal<u32, Duration>>::get_threadlocal()
+        });
+        assert_eq!(init.join().unwrap(), 7);
+        <Foreign<str, Duration> as SetAssocThreadLocal<u32, Duration>>::set_threadlocal(1);
+        <Foreign<str, Duration> as SetAssocThreadLocal<u32, Duration>>::reset_threadlocal();
+        assert_eq!(
+            <Foreign<str, Duration> as GetAssocThreadLocal<u32, Duration>>::get_threadlocal(),
+            7
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Per-thread formatting preferences.
+//!
+//! Associate `FormatSettings` to a formatter type, `Display` implementations then consult
+//! the ambient settings the application established for the current thread.
+
+use crate::{AssocThreadLocal, ThreadLocalGuard};
+use std::fmt;
+
+/// Unit system preferred when displaying physical quantities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitSystem {
+    /// Meters, kilograms, ...
+    Metric,
+    /// Feet, pounds, ...
+    Imperial,
+}
+
+/// Formatting preferences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatSettings {
+    /// Character between integer and fractional part of numbers.
+    pub decimal_separator: char,
+    /// Minimum width of formatted numbers, `None` for no padding.
+    pub width: Option<usize>,
+    /// Number of fractional digits, `None` for as many as needed.
+    pub precision: Option<usize>,
+    /// Preferred unit system.
+    pub units: UnitSystem,
+}
+
+impl FormatSettings {
+    /// Settings used when nothing else is configured: '.' separator, no padding, metric.
+    pub const DEFAULT: Self = FormatSettings {
+        decimal_separator: '.',
+        width: None,
+        precision: None,
+        units: UnitSystem::Metric,
+    };
+
+    /// Returns a copy with another decimal separator.
+    pub const fn with_decimal_separator(mut self, separator: char) -> Self {
+        self.decimal_separator = separator;
+        self
+    }
+
+    /// Returns a copy with another minimum width.
+    pub const fn with_width(mut self, width: Option<usize>) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// Returns a copy with another precision.
+    pub const fn with_precision(mut self, precision: Option<usize>) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Returns a copy with another unit system.
+    pub const fn with_units(mut self, units: UnitSystem) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// Writes 'value' formatted according to these settings.
+    pub fn write_decimal(&self, f: &mut impl fmt::Write, value: f64) -> fmt::Result {
+        let number = match self.precision {
+            Some(precision) => format!("{value:.precision$}"),
+            None => format!("{value}"),
+        };
+        let number = if self.decimal_separator == '.' {
+            number
+        } else {
+            number.replace('.', self.decimal_separator.encode_utf8(&mut [0; 4]))
+        };
+        let width = self.width.unwrap_or(0);
+        write!(f, "{number:>width$}")
+    }
+}
+
+impl Default for FormatSettings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Helpers for associations with a `FormatSettings` target, implemented for all of these.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::fmt;
+///
+/// struct Meters(f64);
+/// assoc_threadlocal!(Meters, FormatSettings = FormatSettings::DEFAULT);
+///
+/// impl fmt::Display for Meters {
+///     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+///         Meters::format_settings().write_decimal(f, self.0)?;
+///         f.write_str(" m")
+///     }
+/// }
+///
+/// assert_eq!(Meters(1.5).to_string(), "1.5 m");
+/// {
+///     let _german = Meters::override_format_settings(
+///         FormatSettings::DEFAULT.with_decimal_separator(',').with_precision(Some(2)),
+///     );
+///     assert_eq!(Meters(1.5).to_string(), "1,50 m");
+/// }
+/// assert_eq!(Meters(1.5).to_string(), "1.5 m");
+/// ```
+pub trait AssocFormatSettings<TAG = ()>: AssocThreadLocal<FormatSettings, TAG> + Sized {
+    /// Returns the current threads formatting preferences.
+    fn format_settings() -> FormatSettings {
+        Self::get_threadlocal()
+    }
+
+    /// Changes the current threads formatting preferences.
+    fn set_format_settings(settings: FormatSettings) {
+        Self::set_threadlocal(settings)
+    }
+
+    /// Changes the current threads formatting preferences until the guard is dropped.
+    fn override_format_settings(
+        settings: FormatSettings,
+    ) -> ThreadLocalGuard<Self, FormatSettings, TAG> {
+        Self::set_threadlocal_scoped(settings)
+    }
+}
+
+impl<S, TAG> AssocFormatSettings<TAG> for S where S: AssocThreadLocal<FormatSettings, TAG> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocFormatSettings, FormatSettings, UnitSystem};
+
+    struct Report;
+    crate::assoc_threadlocal!(Report, FormatSettings = FormatSettings::DEFAULT);
+
+    fn render(value: f64) -> String {
+        let mut s = String::new();
+        Report::format_settings()
+            .write_decimal(&mut s, value)
+            .unwrap();
+        s
+    }
+
+    #[test]
+    fn width_and_precision() {
+        Report::set_format_settings(
+            FormatSettings::DEFAULT
+                .with_width(Some(6))
+                .with_precision(Some(1)),
+        );
+        assert_eq!(render(12.345), "  12.3");
+    }
+
+    #[test]
+    fn nested_overrides() {
+        let _outer = Report::override_format_settings(
+            FormatSettings::DEFAULT.with_units(UnitSystem::Imperial),
+        );
+        {
+            let _inner = Report::override_format_settings(
+                Report::format_settings().with_decimal_separator(','),
+            );
+            assert_eq!(render(0.5), "0,5");
+            assert_eq!(Report::format_settings().units, UnitSystem::Imperial);
+        }
+        assert_eq!(render(0.5), "0.5");
+    }
+}
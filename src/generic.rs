@@ -0,0 +1,59 @@
+//! Per-thread slots of associations with generic implementors.
+//!
+//! A `thread_local!` can not depend on the generic parameters of the impl declaring it.
+//! `assoc_threadlocal!(impl<W> Wrapper<W>, ...)` keeps the values in a per-thread map
+//! keyed by the `TypeId` of the association instead, thus every monomorphization
+//! (`Wrapper<u8>`, `Wrapper<String>`, ...) gets a slot of its own.  Accessing it costs a
+//! map lookup instead of a plain thread local access.
+
+use crate::extension::with_extension;
+use std::cell::Cell;
+
+/// The per-thread value of an association with a generic implementor and its generation.
+pub struct Slot<T> {
+    value: Cell<T>,
+    generation: Cell<u64>,
+}
+
+impl<T: Copy> Slot<T> {
+    /// Returns the value.
+    pub fn value(&self) -> &Cell<T> {
+        &self.value
+    }
+
+    /// Returns how often the value was set.
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+
+    /// Sets the value and advances the generation.
+    pub fn set(&self, value: T) {
+        self.value.set(value);
+        self.generation.set(self.generation.get().wrapping_add(1));
+    }
+}
+
+/// Returns the current threads slot for key 'K', initialized by 'init' on first access.
+/// Used by the `assoc_threadlocal!()` macro.
+///
+/// # Safety
+/// The returned pointer must be immediately used, not stored/passed somewhere else.
+#[doc(hidden)]
+pub unsafe fn slot<K: 'static, T: 'static>(init: impl FnOnce() -> T) -> *const Slot<T> {
+    let slot = with_extension::<K, Option<Box<Slot<T>>>, _>(|slot| {
+        slot.as_deref().map(|slot| slot as *const Slot<T>)
+    });
+    slot.unwrap_or_else(|| {
+        // the initializer runs outside of the extension borrow, it may use associations
+        let value = init();
+        with_extension::<K, Option<Box<Slot<T>>>, _>(|slot| {
+            // boxed, the address stays stable while the extension map grows
+            &**slot.get_or_insert_with(|| {
+                Box::new(Slot {
+                    value: Cell::new(value),
+                    generation: Cell::new(0),
+                })
+            }) as *const Slot<T>
+        })
+    })
+}
@@ -0,0 +1,300 @@
+//! Associations as first class values.
+
+use crate::{AssocThreadLocal, ThreadLocalGuard};
+use std::any::{type_name, Any};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A zero sized handle to the association of type 'T' with tag 'TAG' to 'S'.
+/// Obtained from `AssocThreadLocal::handle()`.
+///
+/// The handle type carries no trait bounds, it can be stored in structs and passed to
+/// generic code which only needs the bound where the value is accessed.  Accessing the
+/// value always refers to the value of the calling thread.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Verbosity;
+/// assoc_threadlocal!(Verbosity, u8 = 1);
+///
+/// struct Logger<H> {
+///     level: H,
+/// }
+///
+/// let logger = Logger { level: Verbosity::handle() };
+/// logger.level.set(3);
+/// assert_eq!(Verbosity::get_threadlocal(), 3);
+/// assert!(logger.level.with(|level| *level > 2));
+/// ```
+pub struct ThreadLocalHandle<S, T, TAG = ()> {
+    _marker: PhantomData<fn() -> (S, TAG)>,
+    _target: PhantomData<fn() -> T>,
+}
+
+impl<S, T, TAG> ThreadLocalHandle<S, T, TAG> {
+    /// Creates a handle, the association is checked when the value is accessed.
+    pub const fn new() -> Self {
+        ThreadLocalHandle {
+            _marker: PhantomData,
+            _target: PhantomData,
+        }
+    }
+}
+
+impl<S: AssocThreadLocal<T, TAG>, T: Copy, TAG> ThreadLocalHandle<S, T, TAG> {
+    /// Returns the current threads value.
+    #[inline]
+    pub fn get(self) -> T {
+        S::get_threadlocal()
+    }
+
+    /// Sets the current threads value.
+    #[inline]
+    pub fn set(self, value: T) {
+        S::set_threadlocal(value)
+    }
+
+    /// Calls 'f' with a reference to the current threads value.
+    pub fn with<R>(self, f: impl FnOnce(&T) -> R) -> R {
+        f(&S::get_threadlocal())
+    }
+
+    /// Replaces the current threads value by 'f' applied to it, returns the new value.
+    pub fn update(self, f: impl FnOnce(T) -> T) -> T {
+        let value = f(S::get_threadlocal());
+        S::set_threadlocal(value);
+        value
+    }
+
+    /// Sets the current threads value until the returned guard is dropped.
+    pub fn set_scoped(self, value: T) -> ThreadLocalGuard<S, T, TAG> {
+        S::set_threadlocal_scoped(value)
+    }
+}
+
+impl<S, T, TAG> Clone for ThreadLocalHandle<S, T, TAG> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S, T, TAG> Copy for ThreadLocalHandle<S, T, TAG> {}
+
+impl<S, T, TAG> Default for ThreadLocalHandle<S, T, TAG> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, T, TAG> fmt::Debug for ThreadLocalHandle<S, T, TAG> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadLocalHandle")
+            .field("implementor", &std::any::type_name::<S>())
+            .field("tag", &std::any::type_name::<TAG>())
+            .field("target", &std::any::type_name::<T>())
+            .finish()
+    }
+}
+
+type SetAnyFn = fn(Box<dyn Any>) -> Result<(), Box<dyn Any>>;
+
+/// A type erased handle to an association, for heterogeneous collections of
+/// associations used for diagnostics, resetting and propagating values between threads.
+///
+/// Created from a `ThreadLocalHandle` with `From` when the target implements `Debug`,
+/// otherwise with `AnyThreadLocalHandle::opaque()`.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Request;
+/// assoc_threadlocal!(Request, u64 = 0);
+/// assoc_threadlocal!(Request, bool = false);
+///
+/// let handles: Vec<AnyThreadLocalHandle> = vec![
+///     <Request as AssocThreadLocal<u64>>::handle().into(),
+///     <Request as AssocThreadLocal<bool>>::handle().into(),
+/// ];
+///
+/// SetAssocThreadLocal::<u64>::set_threadlocal_of(&Request, 42);
+/// let values: Vec<_> = handles.iter().map(|h| h.debug_value().unwrap()).collect();
+/// assert_eq!(values, ["42", "false"]);
+///
+/// handles.iter().for_each(AnyThreadLocalHandle::reset);
+/// assert_eq!(GetAssocThreadLocal::<u64>::get_threadlocal_from(&Request), 0);
+/// ```
+#[derive(Clone, Copy)]
+pub struct AnyThreadLocalHandle {
+    implementor_name: fn() -> &'static str,
+    tag_name: fn() -> &'static str,
+    target_name: fn() -> &'static str,
+    debug_value: fn() -> Option<String>,
+    reset: fn(),
+    get_any: fn() -> Box<dyn Any>,
+    set_any: SetAnyFn,
+}
+
+impl AnyThreadLocalHandle {
+    /// Erases the type of 'handle' for a target that does not implement `Debug`,
+    /// `debug_value()` returns `None` then.
+    pub fn opaque<S, T, TAG>(handle: ThreadLocalHandle<S, T, TAG>) -> Self
+    where
+        S: AssocThreadLocal<T, TAG> + 'static,
+        T: Copy + 'static,
+        TAG: 'static,
+    {
+        let _ = handle;
+        AnyThreadLocalHandle {
+            implementor_name: type_name::<S>,
+            tag_name: type_name::<TAG>,
+            target_name: type_name::<T>,
+            debug_value: || None,
+            reset: S::reset_threadlocal,
+            get_any: S::get_threadlocal_any,
+            set_any: S::set_threadlocal_any,
+        }
+    }
+
+    /// Returns the association in the syntax of the `assoc_threadlocal!()` macro, the tag
+    /// is omitted when it is `()`.
+    pub fn name(&self) -> String {
+        let tag = self.tag_name();
+        if tag == "()" {
+            format!("{}, {}", self.implementor_name(), self.target_name())
+        } else {
+            format!(
+                "{}:{}, {}",
+                tag,
+                self.implementor_name(),
+                self.target_name()
+            )
+        }
+    }
+
+    /// Returns the name of the type the value is associated to.
+    pub fn implementor_name(&self) -> &'static str {
+        (self.implementor_name)()
+    }
+
+    /// Returns the name of the tag type.
+    pub fn tag_name(&self) -> &'static str {
+        (self.tag_name)()
+    }
+
+    /// Returns the name of the target type.
+    pub fn target_name(&self) -> &'static str {
+        (self.target_name)()
+    }
+
+    /// Returns the `Debug` representation of the current threads value, `None` for
+    /// handles created with `opaque()`.
+    pub fn debug_value(&self) -> Option<String> {
+        (self.debug_value)()
+    }
+
+    /// Resets the current threads value to a freshly evaluated INIT.
+    pub fn reset(&self) {
+        (self.reset)()
+    }
+
+    /// Returns the current threads value as type erased box.
+    pub fn get_any(&self) -> Box<dyn Any> {
+        (self.get_any)()
+    }
+
+    /// Sets the current threads value from a box obtained by `get_any()`, usually on
+    /// another thread.  When the box holds another type it is given back as error.
+    pub fn set_any(&self, value: Box<dyn Any>) -> Result<(), Box<dyn Any>> {
+        (self.set_any)(value)
+    }
+}
+
+impl<S, T, TAG> From<ThreadLocalHandle<S, T, TAG>> for AnyThreadLocalHandle
+where
+    S: AssocThreadLocal<T, TAG> + 'static,
+    T: Copy + fmt::Debug + 'static,
+    TAG: 'static,
+{
+    fn from(handle: ThreadLocalHandle<S, T, TAG>) -> Self {
+        AnyThreadLocalHandle {
+            debug_value: || Some(format!("{:?}", S::get_threadlocal())),
+            ..Self::opaque(handle)
+        }
+    }
+}
+
+impl fmt::Debug for AnyThreadLocalHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AnyThreadLocalHandle")
+            .field(&self.name())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnyThreadLocalHandle, ThreadLocalHandle};
+    use crate::{AssocThreadLocal, GetAssocThreadLocal};
+
+    struct Counter;
+    struct Secondary;
+    crate::assoc_threadlocal!(Counter, u32 = 0);
+    crate::assoc_threadlocal!(Secondary:Counter, u32 = 100);
+
+    fn bump<S: AssocThreadLocal<u32, TAG>, TAG>(handle: ThreadLocalHandle<S, u32, TAG>) -> u32 {
+        handle.update(|n| n + 1)
+    }
+
+    #[test]
+    fn generic_access() {
+        let primary = <Counter as AssocThreadLocal<u32>>::handle();
+        let secondary = <Counter as AssocThreadLocal<u32, Secondary>>::handle();
+        assert_eq!(bump(primary), 1);
+        assert_eq!(bump(secondary), 101);
+        assert_eq!(primary.get(), 1);
+        {
+            let _guard = secondary.set_scoped(7);
+            assert_eq!(secondary.get(), 7);
+        }
+        assert_eq!(secondary.get(), 101);
+    }
+
+    #[derive(Clone, Copy)]
+    struct Opaque;
+    crate::assoc_threadlocal!(Counter, Opaque = Opaque);
+
+    #[test]
+    fn type_erased() {
+        let primary: AnyThreadLocalHandle = <Counter as AssocThreadLocal<u32>>::handle().into();
+        let secondary: AnyThreadLocalHandle =
+            <Counter as AssocThreadLocal<u32, Secondary>>::handle().into();
+        let opaque = AnyThreadLocalHandle::opaque(<Counter as AssocThreadLocal<Opaque>>::handle());
+
+        assert!(primary.name().ends_with("Counter, u32"));
+        assert!(secondary.name().contains("Secondary:"));
+        assert_eq!(opaque.debug_value(), None);
+
+        primary.set_any(Box::new(5u32)).unwrap();
+        assert!(primary.set_any(Box::new(5u8)).is_err());
+        assert_eq!(primary.debug_value().as_deref(), Some("5"));
+
+        // propagate to another thread
+        let value = *primary.get_any().downcast::<u32>().unwrap();
+        let value = std::thread::spawn(move || {
+            primary.set_any(Box::new(value)).unwrap();
+            primary.debug_value()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(value.as_deref(), Some("5"));
+
+        primary.reset();
+        assert_eq!(<Counter as GetAssocThreadLocal<u32>>::get_threadlocal(), 0);
+    }
+
+    #[test]
+    fn zero_sized() {
+        assert_eq!(std::mem::size_of::<ThreadLocalHandle<Counter, u32>>(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+//! Recent values of an association.
+//!
+//! An association declared with `history = N` remembers the last N values set on each
+//! thread together with the time and the callsite of the set.  When a value turns out wrong
+//! `recent_history()` tells who put it there and what preceded it.
+
+use crate::extension::with_extension;
+use crate::GetAssocThreadLocal;
+use std::collections::VecDeque;
+use std::panic::Location;
+use std::time::Instant;
+
+/// A value set on an association with history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryEntry<T> {
+    /// The value that was set.
+    pub value: T,
+    /// When it was set.
+    pub at: Instant,
+    /// Where it was set.
+    pub location: &'static Location<'static>,
+}
+
+/// An association that records its recently set values per thread.
+/// Use the `history = N` option of `assoc_threadlocal!()` for implementing this trait.
+pub trait AssocHistory<T: Copy + 'static, TAG: 'static = ()>:
+    GetAssocThreadLocal<T, TAG> + Sized + 'static
+{
+    /// The number of entries kept per thread.
+    const HISTORY_LEN: usize;
+
+    /// Returns the values recently set on the current thread, oldest first.
+    fn recent_history() -> Vec<HistoryEntry<T>> {
+        with_extension::<(T, TAG, Self), VecDeque<HistoryEntry<T>>, _>(|history| {
+            history.iter().copied().collect()
+        })
+    }
+
+    /// Forgets the values recorded on the current thread.
+    fn clear_history() {
+        with_extension::<(T, TAG, Self), VecDeque<HistoryEntry<T>>, _>(VecDeque::clear)
+    }
+
+    #[doc(hidden)]
+    fn record_history(value: T, location: &'static Location<'static>) {
+        if Self::HISTORY_LEN == 0 {
+            return;
+        }
+        with_extension::<(T, TAG, Self), VecDeque<HistoryEntry<T>>, _>(|history| {
+            if history.len() >= Self::HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(HistoryEntry {
+                value,
+                at: Instant::now(),
+                location,
+            });
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocHistory, AssocThreadLocal, SetAssocThreadLocal};
+
+    struct Level;
+    crate::assoc_threadlocal!(Level, u8 = 0, history = 3);
+
+    #[test]
+    fn bounded() {
+        for value in 1..=5 {
+            Level::set_threadlocal(value);
+        }
+        let history = Level::recent_history();
+        assert_eq!(
+            history.iter().map(|entry| entry.value).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        assert!(history[0].at <= history[2].at);
+        assert!(std::thread::spawn(Level::recent_history)
+            .join()
+            .unwrap()
+            .is_empty());
+        Level::clear_history();
+        assert!(Level::recent_history().is_empty());
+    }
+
+    #[test]
+    fn callsites() {
+        let line = line!() + 1;
+        Level::set_threadlocal(1);
+        {
+            let _guard = Level::set_threadlocal_scoped(2);
+        }
+        let history = Level::recent_history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].location.file(), file!());
+        assert_eq!(history[0].location.line(), line);
+        assert_eq!(history[1].location.line(), line + 2);
+        // restoring the guard records the previous value
+        assert_eq!(history[2].value, 1);
+        Level::reset_threadlocal();
+        assert_eq!(Level::recent_history().last().unwrap().value, 0);
+    }
+}
@@ -0,0 +1,152 @@
+//! Per-thread unique id generators.
+//!
+//! Each thread draws a distinct prefix once, ids are then generated by incrementing a thread
+//! local counter below that prefix.  Ids are unique across all threads of the process
+//! without touching an atomic on the hot path.  The counter is not a registered
+//! association, resetting or isolating thread locals never hands out an id again.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A per-thread id generator.
+/// Use the `assoc_id_gen!()` macro for implementing this trait on types.
+pub trait AssocIdGen {
+    /// Number of high bits used for the thread prefix.
+    const PREFIX_BITS: u32;
+
+    /// Returns the counter the thread prefixes are drawn from.
+    #[doc(hidden)]
+    fn prefixes() -> &'static AtomicU64;
+
+    /// Returns the current threads next id, starting at its prefix.
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    #[doc(hidden)]
+    unsafe fn the_next_id() -> *const Cell<u64>;
+
+    /// Returns the next id for the current thread.
+    ///
+    /// # Panics
+    /// When the current thread exhausted its id space.
+    fn next_id() -> u64 {
+        let counter = unsafe { &*Self::the_next_id() };
+        let id = counter.get();
+        let next = id.wrapping_add(1);
+        assert!(
+            next >> (u64::BITS - Self::PREFIX_BITS) == id >> (u64::BITS - Self::PREFIX_BITS),
+            "id space of thread exhausted"
+        );
+        counter.set(next);
+        id
+    }
+}
+
+/// Draws the next thread prefix from 'threads' and returns the first id for it.
+/// Used by the `assoc_id_gen!()` macro.
+///
+/// # Panics
+/// When more threads than 'prefix_bits' can encode requested a prefix.
+#[doc(hidden)]
+pub fn first_id(threads: &AtomicU64, prefix_bits: u32) -> u64 {
+    assert!(
+        (1..u64::BITS).contains(&prefix_bits),
+        "prefix_bits must be in 1..64"
+    );
+    let prefix = threads.fetch_add(1, Ordering::Relaxed);
+    assert!(prefix < 1 << prefix_bits, "thread prefixes exhausted");
+    prefix << (u64::BITS - prefix_bits)
+}
+
+/// Associates a per-thread id generator to a type.
+///
+///  * 'T' is the type you want have a thread local id generator associated to
+///  * 'prefix_bits' is the number of high bits used to distinguish threads, the
+///    remaining bits are available for the ids of each thread
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Request;
+/// assoc_id_gen!(Request, prefix_bits = 16);
+///
+/// let a = Request::next_id();
+/// let b = Request::next_id();
+/// assert_eq!(b, a + 1);
+///
+/// let other = std::thread::spawn(Request::next_id).join().unwrap();
+/// assert_ne!(a >> 48, other >> 48);
+/// ```
+#[macro_export]
+macro_rules! assoc_id_gen {
+    ($T:ty, prefix_bits = $BITS:expr) => {
+        impl $crate::AssocIdGen for $T {
+            const PREFIX_BITS: u32 = $BITS;
+
+            fn prefixes() -> &'static std::sync::atomic::AtomicU64 {
+                static PREFIXES: std::sync::atomic::AtomicU64 =
+                    std::sync::atomic::AtomicU64::new(0);
+                &PREFIXES
+            }
+
+            unsafe fn the_next_id() -> *const std::cell::Cell<u64> {
+                // not registered, resets must not hand out ids again
+                $crate::__assoc_thread_local!(
+                    static NEXT_ID: std::cell::Cell<u64> = std::cell::Cell::new(
+                        $crate::id_gen::first_id(<$T as $crate::AssocIdGen>::prefixes(), $BITS),
+                    );
+                );
+                NEXT_ID.with(|next| next as *const std::cell::Cell<u64>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocIdGen;
+    use std::collections::HashSet;
+
+    struct TestRequest;
+    assoc_id_gen!(TestRequest, prefix_bits = 8);
+
+    #[test]
+    fn monotonic() {
+        let first = TestRequest::next_id();
+        for n in 1..100 {
+            assert_eq!(TestRequest::next_id(), first + n);
+        }
+    }
+
+    #[cfg(feature = "registry")]
+    #[test]
+    fn increasing_across_resets() {
+        struct Isolated;
+        assoc_id_gen!(Isolated, prefix_bits = 8);
+
+        let mut last = Isolated::next_id();
+        for _ in 0..300 {
+            let _isolated = crate::registry::isolate_threadlocals();
+            let id = Isolated::next_id();
+            assert!(id > last);
+            last = id;
+        }
+        crate::registry::reset_all_threadlocals();
+        assert!(Isolated::next_id() > last);
+    }
+
+    #[test]
+    fn unique_across_threads() {
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| (0..100).map(|_| TestRequest::next_id()).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        for t in threads {
+            for id in t.join().unwrap() {
+                assert!(seen.insert(id));
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Process wide replacements for the INIT of associations.
+//!
+//! Overrides only affect threads that initialize the association afterwards, threads that
+//! already accessed it keep their values.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+type OverrideMap = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+static OVERRIDES: RwLock<Option<OverrideMap>> = RwLock::new(None);
+// keeps thread initialization lock free as long as no override was ever installed
+static ANY_OVERRIDE: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_override<S: ?Sized + 'static, T: 'static, TAG: 'static>(init: Option<fn() -> T>) {
+    let mut overrides = OVERRIDES.write().unwrap_or_else(|e| e.into_inner());
+    let overrides = overrides.get_or_insert_with(HashMap::new);
+    match init {
+        Some(init) => {
+            overrides.insert(TypeId::of::<(T, TAG, S)>(), Box::new(init));
+            ANY_OVERRIDE.store(true, Ordering::Release);
+        }
+        None => {
+            overrides.remove(&TypeId::of::<(T, TAG, S)>());
+        }
+    }
+}
+
+/// Returns the overridden initial value of an association or evaluates 'init'.
+/// Used by the `assoc_threadlocal!()` macro.
+#[doc(hidden)]
+pub fn init_or_override<S: ?Sized + 'static, T: 'static, TAG: 'static>(
+    init: impl FnOnce() -> T,
+) -> T {
+    if ANY_OVERRIDE.load(Ordering::Acquire) {
+        let init_override = OVERRIDES
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .and_then(|overrides| overrides.get(&TypeId::of::<(T, TAG, S)>()))
+            .and_then(|init| init.downcast_ref::<fn() -> T>())
+            .copied();
+        if let Some(init_override) = init_override {
+            return init_override();
+        }
+    }
+    init()
+}
@@ -0,0 +1,115 @@
+//! Errno-style per-thread last error associated to types.
+
+use std::cell::Cell;
+
+/// Associates a per-thread 'last error' slot of type E to a type.
+/// Use the `assoc_last_error!()` macro for implementing this trait on types.
+pub trait AssocLastError<E> {
+    /// Returns the associated thread local error slot of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_last_error() -> *const Cell<Option<E>>;
+
+    /// Stores 'error' as last error, replacing any previous one.
+    fn set_last_error(error: E) {
+        unsafe { (*Self::the_last_error()).set(Some(error)) }
+    }
+
+    /// Removes and returns the last error.
+    fn take_last_error() -> Option<E> {
+        unsafe { (*Self::the_last_error()).take() }
+    }
+
+    /// Calls 'f' with a reference to the last error without removing it.
+    fn with_last_error<R>(f: impl FnOnce(Option<&E>) -> R) -> R {
+        let error = Self::take_last_error();
+        let result = f(error.as_ref());
+        // 'f' may have set a new error, that one wins
+        unsafe {
+            let slot = &*Self::the_last_error();
+            if let Some(newer) = slot.take() {
+                slot.set(Some(newer));
+            } else {
+                slot.set(error);
+            }
+        }
+        result
+    }
+}
+
+/// Associates a per-thread last error slot to a type.
+///
+///  * 'T' is the type you want have a thread local error slot associated to
+///  * 'E' is the error type stored
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct MyApi;
+/// #[derive(Debug, PartialEq)]
+/// struct MyError(i32);
+/// assoc_last_error!(MyApi, MyError);
+///
+/// MyApi::set_last_error(MyError(2));
+/// assert!(MyApi::with_last_error(|e| e == Some(&MyError(2))));
+/// assert_eq!(MyApi::take_last_error(), Some(MyError(2)));
+/// assert_eq!(MyApi::take_last_error(), None);
+/// ```
+#[macro_export]
+macro_rules! assoc_last_error {
+    ($T:ty, $E:ty) => {
+        impl $crate::AssocLastError<$E> for $T {
+            unsafe fn the_last_error() -> *const std::cell::Cell<Option<$E>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_LAST_ERROR: (
+                        std::cell::Cell<Option<$E>>,
+                        std::marker::PhantomData<$T>,
+                    ) = (std::cell::Cell::new(None), std::marker::PhantomData);
+                );
+                ASSOCIATED_LAST_ERROR.with(|l| &l.0 as *const std::cell::Cell<Option<$E>>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocLastError;
+
+    struct TestApi;
+    assoc_last_error!(TestApi, String);
+    assoc_last_error!(TestApi, std::io::ErrorKind);
+
+    #[test]
+    fn set_take() {
+        assert_eq!(<TestApi as AssocLastError<String>>::take_last_error(), None);
+        TestApi::set_last_error(String::from("failed"));
+        assert_eq!(
+            <TestApi as AssocLastError<String>>::take_last_error(),
+            Some(String::from("failed"))
+        );
+    }
+
+    #[test]
+    fn with_keeps_error() {
+        TestApi::set_last_error(std::io::ErrorKind::NotFound);
+        let found =
+            <TestApi as AssocLastError<std::io::ErrorKind>>::with_last_error(|e| e.copied());
+        assert_eq!(found, Some(std::io::ErrorKind::NotFound));
+        assert_eq!(
+            <TestApi as AssocLastError<std::io::ErrorKind>>::take_last_error(),
+            Some(std::io::ErrorKind::NotFound)
+        );
+    }
+
+    #[test]
+    fn per_thread() {
+        TestApi::set_last_error(String::from("main"));
+        std::thread::spawn(|| {
+            assert_eq!(<TestApi as AssocLastError<String>>::take_last_error(), None);
+        })
+        .join()
+        .unwrap();
+    }
+}
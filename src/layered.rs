@@ -0,0 +1,270 @@
+//! Values resolved through a chain of layers.
+//!
+//! A layered association resolves its value from the first layer that has one: a scoped
+//! override, the value set on the thread, the value of the task the thread currently
+//! works on, the process wide default and finally the declared INIT.
+//! `get_threadlocal_with_source()` tells which layer supplied the value, which helps
+//! debugging precedence in large applications.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::RwLock;
+
+/// The layer a value of a layered association was resolved from, in order of precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Source {
+    /// A scoped override set with `set_scoped_layer()`.
+    Scoped,
+    /// The value set on the thread with `set_thread_layer()`.
+    Thread,
+    /// The value of the current task entered with `enter_task_layer()`.
+    Task,
+    /// The process wide default set with `set_global_layer()`.
+    Global,
+    /// The INIT given to `assoc_layered!()`.
+    Default,
+}
+
+/// The per-thread layers of a layered association.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Layers<T> {
+    scoped: Option<T>,
+    thread: Option<T>,
+    task: Option<T>,
+}
+
+impl<T: Copy> Layers<T> {
+    /// No layer set.
+    pub const EMPTY: Layers<T> = Layers {
+        scoped: None,
+        thread: None,
+        task: None,
+    };
+
+    /// Returns the value of a per-thread 'layer', `None` for `Source::Global` and
+    /// `Source::Default`.
+    pub fn get(&self, layer: Source) -> Option<T> {
+        match layer {
+            Source::Scoped => self.scoped,
+            Source::Thread => self.thread,
+            Source::Task => self.task,
+            Source::Global | Source::Default => None,
+        }
+    }
+
+    fn slot(&mut self, layer: Source) -> &mut Option<T> {
+        match layer {
+            Source::Scoped => &mut self.scoped,
+            Source::Thread => &mut self.thread,
+            Source::Task => &mut self.task,
+            Source::Global | Source::Default => unreachable!("not a per-thread layer"),
+        }
+    }
+
+    fn first(&self) -> Option<(T, Source)> {
+        [Source::Scoped, Source::Thread, Source::Task]
+            .into_iter()
+            .find_map(|layer| self.get(layer).map(|value| (value, layer)))
+    }
+}
+
+/// Associates a thread local object of type T resolved through layers to a type.
+/// Use the `assoc_layered!()` macro for implementing this trait on types.
+pub trait AssocLayered<T: Copy + 'static, TAG = ()>: Sized {
+    /// Returns the associated thread local layers of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_layers() -> *const Cell<Layers<T>>;
+
+    /// Returns the process wide default layer.
+    fn the_global_layer() -> &'static RwLock<Option<T>>;
+
+    /// Returns the value used when no layer has one.
+    fn layer_default() -> T;
+
+    /// Returns the value of the first layer that has one.
+    fn get_layered() -> T {
+        Self::get_threadlocal_with_source().0
+    }
+
+    /// Returns the value of the first layer that has one together with that layer.
+    fn get_threadlocal_with_source() -> (T, Source) {
+        unsafe { (*Self::the_layers()).get() }
+            .first()
+            .or_else(|| {
+                Self::the_global_layer()
+                    .read()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .map(|value| (value, Source::Global))
+            })
+            .unwrap_or_else(|| (Self::layer_default(), Source::Default))
+    }
+
+    /// Sets the value of the thread layer, `None` clears it.
+    fn set_thread_layer(value: Option<T>) {
+        Self::replace_layer(Source::Thread, value);
+    }
+
+    /// Sets the scoped override until the returned guard is dropped.
+    fn set_scoped_layer(value: T) -> LayerGuard<Self, T, TAG> {
+        LayerGuard {
+            layer: Source::Scoped,
+            previous: Self::replace_layer(Source::Scoped, Some(value)),
+            _marker: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Sets the task layer for the task the current thread starts working on until the
+    /// returned guard is dropped.
+    fn enter_task_layer(value: T) -> LayerGuard<Self, T, TAG> {
+        LayerGuard {
+            layer: Source::Task,
+            previous: Self::replace_layer(Source::Task, Some(value)),
+            _marker: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Sets the process wide default, `None` clears it.
+    fn set_global_layer(value: Option<T>) {
+        *Self::the_global_layer()
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = value;
+    }
+
+    /// Returns the current threads layers.
+    fn layers() -> Layers<T> {
+        unsafe { (*Self::the_layers()).get() }
+    }
+
+    #[doc(hidden)]
+    fn replace_layer(layer: Source, value: Option<T>) -> Option<T> {
+        let cell = unsafe { &*Self::the_layers() };
+        let mut layers = cell.get();
+        let previous = std::mem::replace(layers.slot(layer), value);
+        cell.set(layers);
+        previous
+    }
+}
+
+/// Restores the previous value of a layer when dropped.
+#[must_use = "the layer is restored immediately when the guard is not kept"]
+pub struct LayerGuard<S: AssocLayered<T, TAG>, T: Copy + 'static, TAG = ()> {
+    layer: Source,
+    previous: Option<T>,
+    _marker: PhantomData<fn() -> (S, TAG)>,
+    // the layer must be restored on the thread that set it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<S: AssocLayered<T, TAG>, T: Copy + 'static, TAG> Drop for LayerGuard<S, T, TAG> {
+    fn drop(&mut self) {
+        S::replace_layer(self.layer, self.previous);
+    }
+}
+
+/// Associates a thread local object resolved through layers to a type.
+///
+///  * 'TAG' is used to discriminate between different associations of the same type
+///  * 'T' is the type you want have a layered object associated to
+///  * 'TARGET' is the type of the object
+///  * 'INIT' is the value used when no layer has one
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Config;
+/// assoc_layered!(Config, u32 = 10);
+///
+/// assert_eq!(Config::get_threadlocal_with_source(), (10, Source::Default));
+/// Config::set_global_layer(Some(20));
+/// let task = Config::enter_task_layer(30);
+/// assert_eq!(Config::get_threadlocal_with_source(), (30, Source::Task));
+/// {
+///     let _override = Config::set_scoped_layer(40);
+///     assert_eq!(Config::get_threadlocal_with_source(), (40, Source::Scoped));
+/// }
+/// drop(task);
+/// assert_eq!(Config::get_threadlocal_with_source(), (20, Source::Global));
+/// ```
+#[macro_export]
+macro_rules! assoc_layered {
+    ($T:ty, $TARGET:ty = $INIT:expr) => {
+        $crate::assoc_layered!((): $T, $TARGET = $INIT);
+    };
+    ($TAG:ty: $T:ty, $TARGET:ty = $INIT:expr) => {
+        impl $crate::AssocLayered<$TARGET, $TAG> for $T {
+            unsafe fn the_layers() -> *const std::cell::Cell<$crate::layered::Layers<$TARGET>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_LAYERS: (
+                        std::cell::Cell<$crate::layered::Layers<$TARGET>>,
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = (
+                        std::cell::Cell::new($crate::layered::Layers::EMPTY),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_LAYERS
+                    .with(|l| &l.0 as *const std::cell::Cell<$crate::layered::Layers<$TARGET>>)
+            }
+
+            fn the_global_layer() -> &'static std::sync::RwLock<Option<$TARGET>> {
+                static GLOBAL_LAYER: std::sync::RwLock<Option<$TARGET>> =
+                    std::sync::RwLock::new(None);
+                &GLOBAL_LAYER
+            }
+
+            fn layer_default() -> $TARGET {
+                $INIT
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Source;
+    use crate::AssocLayered;
+
+    struct Limit;
+    assoc_layered!(Limit, u16 = 1);
+
+    #[test]
+    fn precedence() {
+        assert_eq!(Limit::get_threadlocal_with_source(), (1, Source::Default));
+        Limit::set_thread_layer(Some(2));
+        let _task = Limit::enter_task_layer(3);
+        assert_eq!(Limit::get_threadlocal_with_source(), (2, Source::Thread));
+        Limit::set_thread_layer(None);
+        assert_eq!(Limit::get_threadlocal_with_source(), (3, Source::Task));
+        {
+            let _outer = Limit::set_scoped_layer(4);
+            let _inner = Limit::set_scoped_layer(5);
+            assert_eq!(Limit::get_layered(), 5);
+        }
+        assert_eq!(Limit::layers().get(Source::Scoped), None);
+        assert_eq!(Limit::get_layered(), 3);
+    }
+
+    struct Shared;
+    struct Other;
+    assoc_layered!(Shared, u16 = 1);
+    assoc_layered!(Other: Shared, u16 = 7);
+
+    #[test]
+    fn global_layer() {
+        <Shared as AssocLayered<u16>>::set_global_layer(Some(9));
+        let seen = std::thread::spawn(<Shared as AssocLayered<u16>>::get_threadlocal_with_source)
+            .join()
+            .unwrap();
+        assert_eq!(seen, (9, Source::Global));
+        assert_eq!(
+            <Shared as AssocLayered<u16, Other>>::get_threadlocal_with_source(),
+            (7, Source::Default)
+        );
+    }
+}
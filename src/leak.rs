@@ -0,0 +1,260 @@
+//! Detection of per-thread state leaked past the end of a thread (requires the
+//! `leak-detection` feature).
+//!
+//! A forgotten restore of an override or state that was never cleaned up shows as a value
+//! that differs from the initial one when its thread exits.  With the `leak-detection`
+//! feature every association generated by `assoc_threadlocal!()` is watched from its first
+//! set on a thread.  When the thread exits the final value is compared to the value the
+//! thread started with and differing ones are reported to the leak hook, which writes a
+//! warning to stderr unless replaced by `set_leak_hook()`.
+//!
+//! Only targets implementing `PartialEq` are compared.  'strict' associations are not
+//! watched, being set is their normal state.  The main thread usually does not run thread
+//! local destructors when the process exits and is not checked.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::sync::RwLock;
+
+type Hook = Box<dyn Fn(&Leak) + Send + Sync>;
+
+// returns the debug representations of the current and the initial value when they differ
+type LeakCheck = Box<dyn Fn() -> Option<(Option<String>, Option<String>)>>;
+
+static HOOK: RwLock<Option<Hook>> = RwLock::new(None);
+
+/// An association whose value differed from its initial one when a thread exited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Leak {
+    /// Type name of the implementor.
+    pub implementor: &'static str,
+    /// Type name of the tag.
+    pub tag: &'static str,
+    /// Type name of the target.
+    pub target: &'static str,
+    /// Name of the exiting thread, if it has one.
+    pub thread: Option<String>,
+    /// `Debug` representation of the final value, `None` without `Debug`.
+    pub value: Option<String>,
+    /// `Debug` representation of initial value, `None` without `Debug`.
+    pub initial: Option<String>,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "thread '{}' exited with {} of {} changed",
+            self.thread.as_deref().unwrap_or("<unnamed>"),
+            self.target,
+            self.implementor
+        )?;
+        if let (Some(initial), Some(value)) = (&self.initial, &self.value) {
+            write!(f, ": {initial} -> {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the leak hook for all threads, it is called on the exiting thread for every
+/// leaked association.  The hook must not rely on thread locals, these may already be
+/// destroyed.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::sync::Mutex;
+///
+/// static LEAKS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+///
+/// struct Indent;
+/// assoc_threadlocal!(Indent, u32 = 0);
+///
+/// leak::set_leak_hook(|leak| {
+///     if leak.implementor.ends_with("Indent") {
+///         LEAKS.lock().unwrap().push(leak.to_string());
+///     }
+/// });
+///
+/// std::thread::Builder::new()
+///     .name(String::from("formatter"))
+///     .spawn(|| {
+///         Indent::set_threadlocal(4);
+///         // forgot to restore the indentation
+///     })
+///     .unwrap()
+///     .join()
+///     .unwrap();
+/// let leaks = LEAKS.lock().unwrap();
+/// assert!(leaks[0].starts_with("thread 'formatter' exited with u32 of "));
+/// assert!(leaks[0].ends_with("Indent changed: 0 -> 4"));
+/// ```
+pub fn set_leak_hook(hook: impl Fn(&Leak) + Send + Sync + 'static) {
+    *HOOK.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+}
+
+/// The default leak hook, writes a warning to stderr.
+pub fn default_leak_hook(leak: &Leak) {
+    eprintln!("warning: {leak}");
+}
+
+// the initial values of the associations set on this thread
+struct Watched {
+    thread: Option<String>,
+    checks: Vec<(&'static str, &'static str, &'static str, LeakCheck)>,
+}
+
+impl Drop for Watched {
+    fn drop(&mut self) {
+        for (implementor, tag, target, check) in self.checks.drain(..) {
+            if let Some((value, initial)) = check() {
+                report(&Leak {
+                    implementor,
+                    tag,
+                    target,
+                    thread: self.thread.clone(),
+                    value,
+                    initial,
+                });
+            }
+        }
+    }
+}
+
+std::thread_local!(
+    // set while this thread adds a watch
+    static WATCHING: Cell<bool> = const { Cell::new(false) };
+    static WATCHED: RefCell<Watched> = RefCell::new(Watched {
+        thread: std::thread::current().name().map(str::to_string),
+        checks: Vec::new(),
+    });
+);
+
+fn report(leak: &Leak) {
+    match &*HOOK.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(hook) => hook(leak),
+        None => default_leak_hook(leak),
+    }
+}
+
+/// Watches an association on the current thread, called on its first set.
+#[doc(hidden)]
+pub fn watch<T: ?Sized + 'static, TAG: 'static, TARGET: 'static>(
+    check: impl Fn() -> Option<(Option<String>, Option<String>)> + 'static,
+) {
+    // watching allocates, with a counting allocator that sets associations again
+    if WATCHING.try_with(|watching| watching.replace(true)) != Ok(false) {
+        return;
+    }
+    // sets made while the thread exits are not watched anymore
+    let _ = WATCHED.try_with(|watched| {
+        if let Ok(mut watched) = watched.try_borrow_mut() {
+            watched.checks.push((
+                std::any::type_name::<T>(),
+                std::any::type_name::<TAG>(),
+                std::any::type_name::<TARGET>(),
+                Box::new(check),
+            ));
+        }
+    });
+    let _ = WATCHING.try_with(|watching| watching.set(false));
+}
+
+/// Compares values with `PartialEq` when available, used by the `assoc_threadlocal!()`
+/// macro through autoref specialization together with `NoPartialEq`.
+#[doc(hidden)]
+pub struct EqProbe<'a, V>(pub &'a V, pub &'a V);
+
+#[doc(hidden)]
+pub trait ViaPartialEq {
+    fn differs(&self) -> Option<bool>;
+}
+
+impl<V: PartialEq> ViaPartialEq for EqProbe<'_, V> {
+    fn differs(&self) -> Option<bool> {
+        Some(self.0 != self.1)
+    }
+}
+
+#[doc(hidden)]
+pub trait NoPartialEq {
+    fn differs(&self) -> Option<bool>;
+}
+
+impl<V> NoPartialEq for &EqProbe<'_, V> {
+    fn differs(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Watches the association for leaks when its thread local 'L' is set the first time.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_leak_watch {
+    ($TAG:ty, $T:ty, $TARGET:ty, $L:ident) => {
+        if $L.1.get() == 0 {
+            let initial: $TARGET = $L.0.get();
+            $crate::leak::watch::<$T, $TAG, $TARGET>(move || {
+                #[allow(unused_imports)]
+                use $crate::leak::{NoPartialEq as _, ViaPartialEq as _};
+                #[allow(unused_imports)]
+                use $crate::registry::{NoDebug as _, ViaDebug as _};
+                let current: $TARGET = ASSOCIATED_THREADLOCAL.try_with(|l| l.0.get()).ok()?;
+                (&$crate::leak::EqProbe(&current, &initial))
+                    .differs()?
+                    .then(|| {
+                        (
+                            (&$crate::registry::DebugProbe(&current)).debug_probe(),
+                            (&$crate::registry::DebugProbe(&initial)).debug_probe(),
+                        )
+                    })
+            });
+        }
+    };
+}
+
+#[cfg(all(test, feature = "leak-detection"))]
+mod tests {
+    use super::Leak;
+    use crate::{AssocThreadLocal, SetAssocThreadLocal};
+    use std::sync::Mutex;
+
+    static LEAKS: Mutex<Vec<Leak>> = Mutex::new(Vec::new());
+
+    struct Leaky;
+    struct Restored;
+    #[derive(Clone, Copy)]
+    struct Opaque;
+    crate::assoc_threadlocal!(Leaky, u32 = 1);
+    crate::assoc_threadlocal!(Leaky, char = const 'a');
+    crate::assoc_threadlocal!(Leaky, Opaque = Opaque);
+    crate::assoc_threadlocal!(Restored, u32 = 1);
+
+    #[test]
+    fn reported_on_thread_exit() {
+        super::set_leak_hook(|leak| {
+            if leak.implementor.contains("leak::tests") {
+                LEAKS.lock().unwrap().push(leak.clone());
+            }
+        });
+        std::thread::spawn(|| {
+            <Leaky as SetAssocThreadLocal<u32>>::set_threadlocal(2);
+            <Leaky as SetAssocThreadLocal<char>>::set_threadlocal('b');
+            <Leaky as SetAssocThreadLocal<Opaque>>::set_threadlocal(Opaque);
+            let _scoped = <Restored as AssocThreadLocal<u32>>::set_threadlocal_scoped(5);
+        })
+        .join()
+        .unwrap();
+
+        let mut leaks = LEAKS.lock().unwrap().clone();
+        leaks.sort_by_key(|leak| leak.target);
+        assert_eq!(leaks.len(), 2);
+        assert_eq!(leaks[0].target, "char");
+        assert_eq!(
+            (leaks[0].initial.as_deref(), leaks[0].value.as_deref()),
+            (Some("'a'"), Some("'b'"))
+        );
+        assert!(leaks[1].implementor.ends_with("Leaky"));
+        assert_eq!(leaks[1].thread, None);
+        assert!(leaks[1].to_string().ends_with("changed: 1 -> 2"));
+    }
+}
@@ -2,25 +2,289 @@
 #![warn(missing_docs)]
 #![warn(rustdoc::missing_crate_level_docs)]
 
-/// Associates a static object of type T and a marker TAG.
+#[doc(hidden)]
+pub mod assert;
+mod extension;
+#[doc(hidden)]
+pub mod init;
+
+#[cfg(feature = "alloc-counter")]
+pub mod alloc_counter;
+#[cfg(feature = "alloc-counter")]
+pub use alloc_counter::{AllocCount, AssocAllocCounter, CountingAlloc};
+
+pub mod affinity;
+
+pub mod array;
+pub use array::AssocThreadLocalArray;
+
+pub mod arena;
+pub use arena::{AssocArena, Bump};
+
+pub mod backoff;
+pub use backoff::{AssocBackoff, BackoffState};
+
+pub mod budget;
+pub use budget::{AssocBudget, BudgetState, Exceeded};
+
+pub mod cancel;
+pub use cancel::{AssocCancel, CancelHandle, Cancelled};
+
+pub mod clock;
+pub use clock::{AssocClock, ClockState, MockClock};
+
+pub mod combinators;
+pub use combinators::AssocCombinators;
+
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "config")]
+pub use config::{AssocConfig, ConfigError, ConfigWatcher};
+
+pub mod conn_cache;
+pub use conn_cache::AssocConnection;
+
+pub mod context;
+pub use context::{ContextEntry, ContextGuard, ContextTag, ThreadLocalContext};
+
+pub mod counter;
+pub use counter::{AssocCounter, Unsigned};
+
+pub mod deadline;
+pub use deadline::{AssocDeadline, DeadlineState};
+
+pub mod derived;
+pub use derived::Derived;
+
+#[cfg(feature = "debug-endpoint")]
+pub mod debug_endpoint;
+
+#[cfg(feature = "registry")]
+pub mod diagnostics;
+
+pub mod dynamic;
+
+pub mod fault;
+pub use fault::{AssocFault, Fault, FaultGuard, FaultState};
+
+pub mod flags;
+pub use flags::{AssocFlags, Flag, FlagOverrides, FlagsState, GlobalFlags};
+
+pub mod foreign;
+pub use foreign::Foreign;
+
+pub mod format;
+pub use format::{AssocFormatSettings, FormatSettings, UnitSystem};
+
+pub mod generic;
+
+pub mod handle;
+pub use handle::{AnyThreadLocalHandle, ThreadLocalHandle};
+
+pub mod history;
+pub use history::{AssocHistory, HistoryEntry};
+
+pub mod id_gen;
+pub use id_gen::AssocIdGen;
+
+pub mod layered;
+pub use layered::{AssocLayered, LayerGuard, Layers, Source};
+
+pub mod last_error;
+pub use last_error::AssocLastError;
+
+#[cfg(feature = "leak-detection")]
+pub mod leak;
+
+pub mod log_fields;
+pub use log_fields::LogFields;
+
+pub mod mailbox;
+pub use mailbox::{AssocMailbox, Full, MailboxSender};
+
+pub mod mirror;
+pub use mirror::{AssocGlobalMirror, GlobalMirror};
+
+pub mod multi;
+pub use multi::{
+    AssocThreadLocals, ChangedAssoc, DiffTuple, Snapshot, SnapshotGuard, ThreadLocalTuple,
+    Transaction,
+};
+
+pub mod per_instance;
+pub use per_instance::{AssocThreadLocalPerInstance, PerInstance};
+
+#[cfg(feature = "reporter")]
+pub mod prometheus;
+
+pub mod profiling;
+pub use profiling::AccessStats;
+
+pub mod propagate;
+pub use propagate::{Captured, Propagate, Propagation};
+
+#[cfg(feature = "proptest")]
+pub mod proptest;
+
+pub mod range;
+pub use range::{AssocRange, OutOfRange, RangeMode};
+
+pub mod rate_limit;
+pub use rate_limit::{AssocRateLimit, RateLimitState};
+
+#[cfg(feature = "record")]
+pub mod record;
+#[cfg(feature = "record")]
+pub use record::{record_sets, RecordedSet, Recording};
+
+pub mod refcell;
+pub use refcell::{AssocRefCell, BorrowError, ThreadLocalRef, ThreadLocalRefMut};
+
+pub mod recursion;
+pub use recursion::{AssocRecursionGuard, DepthExceeded, RecursionDepth, RecursionGuard};
+
+pub mod reentrancy;
+pub use reentrancy::{AssocReentrancyFlag, Reentered, ReentrancyFlag, ReentrancyGuard};
+
+pub mod request;
+pub use request::{AssocRequestContext, RequestContext, RequestContextGuard};
+
+#[cfg(feature = "registry")]
+pub mod report;
+#[cfg(feature = "registry")]
+pub use report::{ErrReportExt, ThreadLocalReport};
+
+#[cfg(feature = "reporter")]
+pub mod reporter;
+
+pub mod resource;
+pub use resource::{AssocResource, ResourceState};
+
+pub mod rng;
+pub use rng::{AssocRng, RngState};
+
+#[cfg(feature = "registry")]
+pub mod registry;
+
+/// Isolates a test from thread local values left behind by other tests on the same
+/// thread (requires the `test-utils` feature).
+///
+/// ```
+/// use assoc_threadlocal::*;
+///
+/// struct Counter;
+/// assoc_threadlocal!(Counter, u32 = 0);
+///
+/// #[assoc_test]
+/// fn starts_at_zero() {
+///     assert_eq!(Counter::get_threadlocal(), 0);
+///     Counter::set_threadlocal(1);
+/// }
+/// ```
+#[cfg(feature = "test-utils")]
+pub use assoc_threadlocal_macros::assoc_test;
+
+pub mod scoped;
+pub use scoped::AssocScopedThreadLocal;
+
+pub mod service;
+pub use service::{AssocService, ServiceGuard};
+
+#[cfg(feature = "single-threaded")]
+#[doc(hidden)]
+pub mod single_threaded;
+
+pub mod slab;
+pub use slab::{AssocSlab, SlabKey};
+
+pub mod stack;
+pub use stack::{AssocStack, StackGuard};
+
+pub mod staged;
+pub use staged::{StageThreadLocals, Staged, StagedGuard};
+
+pub mod stats;
+pub use stats::{AssocStats, Sample, Stats};
+
+pub mod tag_enum;
+pub use tag_enum::{AssocTagEnum, TagEnum};
+
+pub mod timer;
+pub use timer::{AssocTimer, TimerGuard, TimerState};
+
+pub mod typed_map;
+pub use typed_map::{AssocTypedMap, TypedMap};
+
+/// Read access to a thread local object of type T associated with marker TAG.
 /// Use the `assoc_threadlocal!()` macro for implementing this trait on types.
-pub trait AssocThreadLocal<T: Copy, TAG = ()> {
+///
+/// Together with `SetAssocThreadLocal` this makes up `AssocThreadLocal`.  Generic code
+/// that only reads can be bound on this trait alone.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// pub fn verbosity<S: GetAssocThreadLocal<u8>>() -> u8 {
+///     S::get_threadlocal()
+/// }
+///
+/// struct Logger;
+/// assoc_threadlocal!(Logger, u8 = 2);
+/// assert_eq!(verbosity::<Logger>(), 2);
+/// ```
+pub trait GetAssocThreadLocal<T: Copy, TAG = ()> {
     /// Returns the associated thread local object of the Self type
     ///
     /// # Safety
     /// The returned pointer must be immediately used, not stored/passed somewhere else.
     unsafe fn the_threadlocal() -> *const std::cell::Cell<T>;
 
+    /// Initializes the associated thread local object of the Self type on the current
+    /// thread if not done already.  Lets first access costs and panics from a bad INIT
+    /// surface at a predictable point.
+    fn ensure_threadlocal_initialized() {
+        unsafe {
+            Self::the_threadlocal();
+        }
+    }
+
     /// Returns the associated thread local object of the Self type
+    #[inline]
     fn get_threadlocal() -> T {
         unsafe { (*Self::the_threadlocal()).get() }
     }
 
-    /// Sets the associated thread local object of the Self type
-    fn set_threadlocal(value: T) {
-        unsafe {
-            (*Self::the_threadlocal()).set(value);
-        }
+    /// Returns how often the associated thread local object of the Self type was set on the
+    /// current thread.  Implementations not generated by `assoc_threadlocal!()` always
+    /// return 0.
+    fn threadlocal_generation() -> u64 {
+        0
+    }
+
+    /// Returns the associated thread local object of the Self type together with its
+    /// generation.  Lets one cache data derived from the value and detect changes.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Example;
+    /// assoc_threadlocal!(Example, u32 = 1);
+    ///
+    /// let (value, generation) = Example::get_versioned();
+    /// assert_eq!(value, 1);
+    /// Example::set_threadlocal(1);
+    /// assert_ne!(Example::get_versioned().1, generation);
+    /// ```
+    fn get_versioned() -> (T, u64) {
+        (Self::get_threadlocal(), Self::threadlocal_generation())
+    }
+
+    /// Returns the associated thread local object of the Self type or an error when it can
+    /// not be accessed, i.e. a 'strict' association that was not set on the current thread
+    /// yet, a 'main_thread' association accessed from another thread or a 'teardown'
+    /// association accessed after it was torn down.
+    fn try_get_threadlocal() -> Result<T, AccessError> {
+        Ok(Self::get_threadlocal())
     }
 
     /// Returns the associated threadlocal object from an instance.
@@ -28,19 +292,407 @@ pub trait AssocThreadLocal<T: Copy, TAG = ()> {
         Self::get_threadlocal()
     }
 
+    /// Returns the associated thread local object of the Self type as type erased box.
+    fn get_threadlocal_any() -> Box<dyn std::any::Any>
+    where
+        T: 'static,
+    {
+        Box::new(Self::get_threadlocal())
+    }
+
+    /// Returns the name of the type the value is associated to, for labeling the
+    /// association in logs and tooling.  Implementations generated by `assoc_threadlocal!()`
+    /// return the type as written in the macro, others the full path of `type_name()`.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Logger;
+    /// struct Verbosity;
+    /// assoc_threadlocal!(Verbosity: Logger, u8 = 1);
+    ///
+    /// assert_eq!(<Logger as GetAssocThreadLocal<u8, Verbosity>>::assoc_name(), "Logger");
+    /// assert_eq!(<Logger as GetAssocThreadLocal<u8, Verbosity>>::tag_type_name(), "Verbosity");
+    /// assert_eq!(<Logger as GetAssocThreadLocal<u8, Verbosity>>::target_type_name(), "u8");
+    /// ```
+    fn assoc_name() -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Returns the name of the tag, like `assoc_name()`.
+    fn tag_type_name() -> &'static str {
+        std::any::type_name::<TAG>()
+    }
+
+    /// Returns the name of the target type, like `assoc_name()`.
+    fn target_type_name() -> &'static str {
+        std::any::type_name::<T>()
+    }
+}
+
+/// Write access to a thread local object of type T associated with marker TAG.
+/// Use the `assoc_threadlocal!()` macro for implementing this trait on types.
+pub trait SetAssocThreadLocal<T: Copy, TAG = ()>: GetAssocThreadLocal<T, TAG> {
+    /// Sets the associated thread local object of the Self type
+    #[inline]
+    fn set_threadlocal(value: T) {
+        unsafe {
+            (*Self::the_threadlocal()).set(value);
+        }
+    }
+
     /// Sets the associated threadlocal object from an instance.
     fn set_threadlocal_of(_this: &Self, value: T) {
         Self::set_threadlocal(value)
     }
+
+    /// Resets the associated thread local object of the Self type to a freshly evaluated
+    /// INIT.  Only implementations generated by `assoc_threadlocal!()` know their INIT,
+    /// others leave the value unchanged.
+    fn reset_threadlocal() {}
+
+    /// Sets the associated thread local object of the Self type from a type erased box.
+    /// When the box does not contain a 'T' it is given back as error.
+    fn set_threadlocal_any(value: Box<dyn std::any::Any>) -> Result<(), Box<dyn std::any::Any>>
+    where
+        T: 'static,
+    {
+        Self::set_threadlocal(*value.downcast::<T>()?);
+        Ok(())
+    }
+}
+
+/// Write access to a thread local object that requires a token, implemented instead of
+/// `SetAssocThreadLocal` by `assoc_threadlocal!(T, TARGET = INIT, set_requires = Token)`.
+///
+/// When the token type can only be constructed in the defining crate other crates can
+/// read the value but not change it.
+///
+/// ```
+/// mod library {
+///     use assoc_threadlocal::*;
+///
+///     pub struct Context;
+///     pub struct Token(());
+///     assoc_threadlocal!(Context, u32 = 0, set_requires = Token);
+///
+///     pub fn enter(id: u32) {
+///         Context::set_threadlocal_with(&Token(()), id);
+///     }
+/// }
+///
+/// use assoc_threadlocal::*;
+/// use library::Context;
+///
+/// library::enter(7);
+/// assert_eq!(Context::get_threadlocal(), 7);
+/// ```
+///
+/// Without the token the value can not be set:
+/// ```compile_fail
+/// use assoc_threadlocal::*;
+///
+/// struct Context;
+/// struct Token;
+/// assoc_threadlocal!(Context, u32 = 0, set_requires = Token);
+///
+/// Context::set_threadlocal(7);
+/// ```
+pub trait SetAssocThreadLocalWith<T: Copy, TAG = ()>: GetAssocThreadLocal<T, TAG> {
+    /// The token type required for setting.
+    type Token;
+
+    /// Sets the associated thread local object of the Self type.
+    fn set_threadlocal_with(token: &Self::Token, value: T);
+
+    /// Resets the associated thread local object of the Self type to a freshly evaluated
+    /// INIT.
+    fn reset_threadlocal_with(token: &Self::Token);
+}
+
+/// Associates a static object of type T and a marker TAG.
+/// Use the `assoc_threadlocal!()` macro for implementing this trait on types.
+///
+/// Implemented for all types that implement both `GetAssocThreadLocal` and
+/// `SetAssocThreadLocal`, provides the accessors building on both.
+pub trait AssocThreadLocal<T: Copy, TAG = ()>:
+    GetAssocThreadLocal<T, TAG> + SetAssocThreadLocal<T, TAG>
+{
+    /// Replaces the associated thread local object of the Self type by the result of 'f'
+    /// applied to it.  On error the old value is kept.  Returns the new value.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Budget;
+    /// assoc_threadlocal!(Budget, u32 = 10);
+    ///
+    /// let spend = |n: u32| Budget::try_update_threadlocal(|b| b.checked_sub(n).ok_or(b));
+    /// assert_eq!(spend(4), Ok(6));
+    /// assert_eq!(spend(7), Err(6));
+    /// assert_eq!(Budget::get_threadlocal(), 6);
+    /// ```
+    fn try_update_threadlocal<E>(f: impl FnOnce(T) -> Result<T, E>) -> Result<T, E> {
+        let value = f(Self::get_threadlocal())?;
+        Self::set_threadlocal(value);
+        Ok(value)
+    }
+
+    /// Sets the associated thread local object of the Self type until the returned guard
+    /// is dropped, then the previous value is restored.
+    #[track_caller]
+    fn set_threadlocal_scoped(value: T) -> ThreadLocalGuard<Self, T, TAG>
+    where
+        Self: Sized,
+    {
+        let previous = Self::get_threadlocal();
+        Self::set_threadlocal(value);
+        ThreadLocalGuard {
+            previous,
+            untrack: None,
+            _marker: std::marker::PhantomData,
+            _not_send: std::marker::PhantomData,
+        }
+    }
+
+    /// Like `set_threadlocal_scoped()` but also records the value and the callers
+    /// location, these are listed by `active_overrides()` while the guard is alive.
+    #[track_caller]
+    fn set_threadlocal_scoped_tracked(value: T) -> ThreadLocalGuard<Self, T, TAG>
+    where
+        Self: Sized + 'static,
+        T: 'static,
+        TAG: 'static,
+    {
+        let location = std::panic::Location::caller();
+        let depth =
+            extension::with_extension::<(T, TAG, Self), Vec<ScopedOverride<T>>, _>(|stack| {
+                stack.push(ScopedOverride { value, location });
+                stack.len() - 1
+            });
+        let previous = Self::get_threadlocal();
+        Self::set_threadlocal(value);
+        ThreadLocalGuard {
+            previous,
+            untrack: Some((depth, |depth| {
+                // like `StackGuard`, dropping an outer guard first also removes the inner entries
+                extension::with_extension::<(T, TAG, Self), Vec<ScopedOverride<T>>, _>(|stack| {
+                    stack.truncate(depth)
+                })
+            })),
+            _marker: std::marker::PhantomData,
+            _not_send: std::marker::PhantomData,
+        }
+    }
+
+    /// Returns the overrides made by `set_threadlocal_scoped_tracked()` that are active on
+    /// the current thread, innermost first.
+    fn active_overrides() -> std::vec::IntoIter<ScopedOverride<T>>
+    where
+        Self: Sized + 'static,
+        T: 'static,
+        TAG: 'static,
+    {
+        extension::with_extension::<(T, TAG, Self), Vec<ScopedOverride<T>>, _>(|stack| {
+            stack.iter().rev().copied().collect::<Vec<_>>().into_iter()
+        })
+    }
+
+    /// Returns a zero sized handle to this association which can be passed around as value.
+    fn handle() -> ThreadLocalHandle<Self, T, TAG>
+    where
+        Self: Sized,
+    {
+        ThreadLocalHandle::new()
+    }
+
+    /// Copies the current value into the association of the same target and tag of
+    /// another type.
+    fn copy_threadlocal_to<O: AssocThreadLocal<T, TAG>>() {
+        O::set_threadlocal(Self::get_threadlocal())
+    }
+
+    /// Copies the current value into the association of the same target but another tag
+    /// of another type.
+    fn copy_threadlocal_to_tagged<O: AssocThreadLocal<T, OTAG>, OTAG>() {
+        O::set_threadlocal(Self::get_threadlocal())
+    }
+
+    /// Replaces the INIT of this association for all threads that access it for the first
+    /// time afterwards.  Threads that already accessed it keep their values.
+    fn set_threadlocal_init_override(init: fn() -> T)
+    where
+        Self: Sized + 'static,
+        T: 'static,
+        TAG: 'static,
+    {
+        init::set_override::<Self, T, TAG>(Some(init))
+    }
+
+    /// Removes an override installed by `set_threadlocal_init_override()`.
+    fn clear_threadlocal_init_override()
+    where
+        Self: Sized + 'static,
+        T: 'static,
+        TAG: 'static,
+    {
+        init::set_override::<Self, T, TAG>(None)
+    }
+
+    /// Maximum number of values `set_threadlocal_undoable()` remembers per thread.
+    const UNDO_LIMIT: usize = 32;
+
+    /// Sets the associated thread local object of the Self type and remembers the
+    /// previous value so that it can be restored with `undo_threadlocal()`.
+    #[track_caller]
+    fn set_threadlocal_undoable(value: T)
+    where
+        Self: Sized + 'static,
+        T: 'static,
+        TAG: 'static,
+    {
+        let previous = Self::get_threadlocal();
+        extension::with_extension::<(Self, T, TAG), std::collections::VecDeque<T>, _>(|history| {
+            if history.len() >= Self::UNDO_LIMIT {
+                history.pop_front();
+            }
+            history.push_back(previous);
+        });
+        Self::set_threadlocal(value);
+    }
+
+    /// Restores the value before the last `set_threadlocal_undoable()` and returns it.
+    /// Returns `None` when there is nothing to undo.
+    fn undo_threadlocal() -> Option<T>
+    where
+        Self: Sized + 'static,
+        T: 'static,
+        TAG: 'static,
+    {
+        let previous = extension::with_extension::<(Self, T, TAG), std::collections::VecDeque<T>, _>(
+            |history| history.pop_back(),
+        )?;
+        Self::set_threadlocal(previous);
+        Some(previous)
+    }
+
+    /// Returns how many undoable changes are remembered on the current thread.
+    fn undo_depth() -> usize
+    where
+        Self: Sized + 'static,
+        T: 'static,
+        TAG: 'static,
+    {
+        extension::with_extension::<(Self, T, TAG), std::collections::VecDeque<T>, _>(|history| {
+            history.len()
+        })
+    }
+}
+
+impl<S: ?Sized + SetAssocThreadLocal<T, TAG>, T: Copy, TAG> AssocThreadLocal<T, TAG> for S {}
+
+/// The INIT of an association as constant, implemented by the `assoc_threadlocal!()` macro
+/// when the INIT is given as `const EXPR`.  Lets generic code use it in const contexts.
+///
+/// Such associations are stored in a const initialized thread local that needs no lazy
+/// initialization, `get_threadlocal()` compiles to a single TLS load and a move of the
+/// value.  For that they don't apply init overrides and join the registry on the first set
+/// instead of the first access.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Buffer;
+/// assoc_threadlocal!(Buffer, usize = const 16);
+///
+/// fn zeroed<S: AssocConstInit<usize>>() -> [u8; 16] {
+///     assert_eq!(S::INIT, 16);
+///     [0; 16]
+/// }
+///
+/// const SIZE: usize = <Buffer as AssocConstInit<usize>>::INIT;
+/// let buffer = [0u8; SIZE];
+/// assert_eq!(buffer, zeroed::<Buffer>());
+/// assert_eq!(Buffer::get_threadlocal(), SIZE);
+/// ```
+pub trait AssocConstInit<T: Copy, TAG = ()>: AssocThreadLocal<T, TAG> {
+    /// The initial value of the association.
+    const INIT: T;
+}
+
+/// Restores the previous value of an association when dropped.
+/// Returned by `AssocThreadLocal::set_threadlocal_scoped()`.
+#[must_use = "the previous value is restored immediately when the guard is not kept"]
+pub struct ThreadLocalGuard<S: AssocThreadLocal<T, TAG>, T: Copy, TAG = ()> {
+    previous: T,
+    // the depth of the entry in the active overrides of tracked guards and its removal
+    untrack: Option<(usize, fn(usize))>,
+    _marker: std::marker::PhantomData<fn() -> (S, TAG)>,
+    // the value must be restored on the thread that set it
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl<S: AssocThreadLocal<T, TAG>, T: Copy, TAG> Drop for ThreadLocalGuard<S, T, TAG> {
+    fn drop(&mut self) {
+        S::set_threadlocal(self.previous);
+        if let Some((depth, untrack)) = self.untrack {
+            untrack(depth);
+        }
+    }
+}
+
+/// An active override made by `AssocThreadLocal::set_threadlocal_scoped_tracked()`.
+#[derive(Clone, Copy, Debug)]
+pub struct ScopedOverride<T> {
+    /// The value that was set.
+    pub value: T,
+    /// Where it was set.
+    pub location: &'static std::panic::Location<'static>,
+}
+
+/// Returns where the innermost active tracked override of an association was set, used
+/// by the registry.  Also works for associations without setter, these have none.
+#[doc(hidden)]
+pub fn __override_location<S: ?Sized + 'static, T: Copy + 'static, TAG: 'static>(
+) -> Option<&'static std::panic::Location<'static>> {
+    extension::with_extension::<(T, TAG, S), Vec<ScopedOverride<T>>, _>(|stack| {
+        stack.last().map(|active| active.location)
+    })
+}
+
+/// Error returned by `GetAssocThreadLocal::try_get_threadlocal()` when the value can not
+/// be accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AccessError {
+    /// A 'strict' association was not set on the current thread.
+    NotSet,
+    /// A 'main_thread' association was accessed from another thread than its owner.
+    WrongThread,
+    /// A 'teardown' association was accessed after `teardown_threadlocal()`.
+    TornDown,
+}
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            AccessError::NotSet => "thread local value read before it was set",
+            AccessError::WrongThread => "thread local value accessed outside its owner thread",
+            AccessError::TornDown => "thread local value accessed after it was torn down",
+        })
+    }
 }
 
+impl std::error::Error for AccessError {}
+
 /// Helper macro doing the boilerplate implementation.
 /// This must be a macro because we can not use generic parameters from the outer scope.
 ///
 ///  * 'TAG' A type marker to discriminate this implementation, defaults to ()
 ///  * 'T' is the type you want have a thread local object associated to
 ///  * 'TARGET' is the type of the thread local object
-///  * 'INIT' is used to initialize the thread local object
+///  * 'INIT' is used to initialize the thread local object, `const INIT` additionally
+///    implements `AssocConstInit` and gives the cheapest read path, see there
 ///
 /// The simple case, associate something to some local type:
 /// ```
@@ -55,7 +707,64 @@ pub trait AssocThreadLocal<T: Copy, TAG = ()> {
 ///
 /// // get it from an object
 /// let example = Example;
-/// assert_eq!(AssocThreadLocal::get_threadlocal_from(&example), "&str associated to Example");
+/// assert_eq!(GetAssocThreadLocal::get_threadlocal_from(&example), "&str associated to Example");
+/// ```
+///
+/// Unsized objects are associated behind a `&'static` reference, `ref TARGET` is a
+/// shorthand for `&'static TARGET`.  This associates per-thread pluggable trait objects
+/// and slices without boxing them:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// trait Codec {
+///     fn name(&self) -> &'static str;
+/// }
+///
+/// struct Json;
+/// impl Codec for Json {
+///     fn name(&self) -> &'static str {
+///         "json"
+///     }
+/// }
+///
+/// struct Cbor;
+/// impl Codec for Cbor {
+///     fn name(&self) -> &'static str {
+///         "cbor"
+///     }
+/// }
+///
+/// struct Wire;
+/// assoc_threadlocal!(Wire, ref dyn Codec = &Json);
+///
+/// struct Ports;
+/// assoc_threadlocal!(Ports, ref [u16] = &[80, 443]);
+///
+/// assert_eq!(Wire::get_threadlocal().name(), "json");
+/// {
+///     let _cbor = Wire::set_threadlocal_scoped(&Cbor);
+///     assert_eq!(Wire::get_threadlocal().name(), "cbor");
+/// }
+/// assert_eq!(Ports::get_threadlocal(), [80, 443]);
+/// ```
+///
+/// A generic implementor is declared with `impl<...>`, every monomorphization gets a
+/// per-thread value of its own, see `generic`.  Bounds the implementor requires are given
+/// on the parameters, which are always `'static`:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Wrapper<W>(W);
+/// assoc_threadlocal!(impl<W> Wrapper<W>, u32 = 0);
+///
+/// struct Buffer<W>(Vec<W>);
+/// struct Capacity;
+/// assoc_threadlocal!(Capacity: impl<W: Clone> Buffer<W>, usize = 64 / std::mem::size_of::<W>());
+///
+/// Wrapper::<u8>::set_threadlocal(1);
+/// assert_eq!(Wrapper::<u8>::get_threadlocal(), 1);
+/// assert_eq!(Wrapper::<String>::get_threadlocal(), 0);
+/// assert_eq!(<Buffer<u64> as GetAssocThreadLocal<usize, Capacity>>::get_threadlocal(), 8);
 /// ```
 ///
 /// The 'TAG' is required when one needs to disambiguate between different target values of
@@ -81,8 +790,8 @@ pub trait AssocThreadLocal<T: Copy, TAG = ()> {
 /// let example = Example;
 ///
 /// // resolve the ambiguity with a turbofish
-/// assert_eq!(AssocThreadLocal::<_, Hello>::get_threadlocal_from(&example), "Hello World!");
-/// assert_eq!(AssocThreadLocal::<_, ExplainType>::get_threadlocal_from(&example), "This is 'struct Example'");
+/// assert_eq!(GetAssocThreadLocal::<_, Hello>::get_threadlocal_from(&example), "Hello World!");
+/// assert_eq!(GetAssocThreadLocal::<_, ExplainType>::get_threadlocal_from(&example), "This is 'struct Example'");
 /// ```
 ///
 /// Make an association between foreign types:
@@ -94,69 +803,1162 @@ pub trait AssocThreadLocal<T: Copy, TAG = ()> {
 /// assoc_threadlocal!(I32ExampleStr:i32, &'static str = "&str associated to i32");
 ///
 /// // get it
-/// assert_eq!(AssocThreadLocal::get_threadlocal_from(&100i32), "&str associated to i32");
+/// assert_eq!(GetAssocThreadLocal::get_threadlocal_from(&100i32), "&str associated to i32");
 /// ```
-#[macro_export]
-macro_rules! assoc_threadlocal {
-    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr) => {
-        impl $crate::AssocThreadLocal<$TARGET, $TAG> for $T {
-            unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
-                std::thread_local!(
-                    static ASSOCIATED_THREADLOCAL: (
-                        std::cell::Cell<$TARGET>,
-                        std::marker::PhantomData<$T>,
-                        std::marker::PhantomData<$TAG>,
-                    ) = (
-                        std::cell::Cell::new($INIT),
-                        std::marker::PhantomData,
-                        std::marker::PhantomData,
-                    );
-                );
-                ASSOCIATED_THREADLOCAL.with(|l| &l.0 as *const std::cell::Cell<$TARGET>)
-            }
-        }
-    };
-    ($T:ty, $TARGET:ty = $INIT:expr) => {
-        impl $crate::AssocThreadLocal<$TARGET, ()> for $T {
-            unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
-                std::thread_local!(
-                    static ASSOCIATED_THREADLOCAL: (
-                        std::cell::Cell<$TARGET>,
-                        std::marker::PhantomData<$T>,
-                        std::marker::PhantomData<()>,
-                    ) = (
-                        std::cell::Cell::new($INIT),
-                        std::marker::PhantomData,
-                        std::marker::PhantomData,
-                    );
-                );
-                ASSOCIATED_THREADLOCAL.with(|l| &l.0 as *const std::cell::Cell<$TARGET>)
-            }
-        }
-    };
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::AssocThreadLocal;
-
-    struct TestType1;
-    assoc_threadlocal!(TestType1, &'static str = "This is the first test type");
-
-    #[test]
-    fn get_threadlocal() {
-        assert_eq!(TestType1::get_threadlocal(), "This is the first test type");
-    }
-
-    #[test]
-    fn set_threadlocal() {
-        TestType1::set_threadlocal("This is the first test type, set to a new value");
-        assert_eq!(
-            TestType1::get_threadlocal(),
-            "This is the first test type, set to a new value"
-        );
-    }
-
+///
+/// A tagged association can inherit from the untagged association of the same type and
+/// target. It returns the untagged value until it was set on the current thread:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Example;
+/// assoc_threadlocal!(Example, u32 = 1);
+///
+/// struct Special;
+/// assoc_threadlocal!(Special:Example, u32, fallback);
+///
+/// assert_eq!(GetAssocThreadLocal::<u32, Special>::get_threadlocal_from(&Example), 1);
+/// SetAssocThreadLocal::<u32>::set_threadlocal_of(&Example, 2);
+/// assert_eq!(GetAssocThreadLocal::<u32, Special>::get_threadlocal_from(&Example), 2);
+/// SetAssocThreadLocal::<u32, Special>::set_threadlocal_of(&Example, 3);
+/// assert_eq!(GetAssocThreadLocal::<u32, Special>::get_threadlocal_from(&Example), 3);
+/// assert_eq!(GetAssocThreadLocal::<u32>::get_threadlocal_from(&Example), 2);
+/// ```
+///
+/// Trait objects can be implementors as well.  The value is associated to the trait
+/// object type, shared by all types implementing the trait, and reachable through
+/// `&dyn Trait` receivers.  Note that `dyn Trait + Send` is a distinct type.  The scoped
+/// setters return guards naming the implementor and need a sized one:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// trait Shape {
+///     fn area(&self) -> f64;
+/// }
+///
+/// struct Square(f64);
+/// impl Shape for Square {
+///     fn area(&self) -> f64 {
+///         self.0 * self.0
+///     }
+/// }
+///
+/// struct Precision;
+/// assoc_threadlocal!(Precision: dyn Shape, u32 = 2);
+///
+/// let shape: &dyn Shape = &Square(1.5);
+/// let precision = GetAssocThreadLocal::<u32, Precision>::get_threadlocal_from(shape);
+/// assert_eq!(format!("{:.*}", precision as usize, shape.area()), "2.25");
+/// ```
+///
+/// Numeric targets can be restricted to a range, values set out of it are clamped into it.
+/// With 'reject' setting out of range values panics, `AssocRange::try_set_in_range()` is
+/// the fallible alternative:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Verbosity;
+/// assoc_threadlocal!(Verbosity, u8 in 0..=9 = 3);
+///
+/// Verbosity::set_threadlocal(12);
+/// assert_eq!(Verbosity::get_threadlocal(), 9);
+///
+/// struct Retries;
+/// assoc_threadlocal!(Retries, u32 in 1..=5 = 3, reject);
+///
+/// assert!(Retries::try_set_in_range(0).is_err());
+/// assert_eq!(Retries::get_threadlocal(), 3);
+/// ```
+///
+/// A 'strict' association starts logically unset on every thread.  Reading it panics
+/// until it was set on the current thread, `try_get_threadlocal()` returns an error
+/// instead.  Resetting makes it unset again.  INIT is only a placeholder that is never
+/// observed through the safe accessors:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Session;
+/// assoc_threadlocal!(Session, u64 = 0, strict);
+///
+/// assert_eq!(Session::try_get_threadlocal(), Err(AccessError::NotSet));
+/// Session::set_threadlocal(42);
+/// assert_eq!(Session::get_threadlocal(), 42);
+/// ```
+///
+/// A 'main_thread' association may only be accessed on the thread that accessed it
+/// first, typically the main or UI thread.  Accessing it from any other thread panics,
+/// `try_get_threadlocal()` returns `AccessError::WrongThread` there:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Clone, Copy)]
+/// struct WindowHandle(usize);
+///
+/// struct Ui;
+/// assoc_threadlocal!(Ui, WindowHandle = WindowHandle(1), main_thread);
+///
+/// assert_eq!(Ui::get_threadlocal().0, 1);
+/// let elsewhere = std::thread::spawn(|| Ui::try_get_threadlocal().err()).join().unwrap();
+/// assert_eq!(elsewhere, Some(AccessError::WrongThread));
+/// ```
+///
+/// A 'teardown' association holds an external resource that is released explicitly by
+/// `AssocResource::teardown_threadlocal()` with the given destructor, see `AssocResource`.
+///
+/// Every access copies the whole target.  'max_inline_size' rejects targets larger than the
+/// given number of bytes at compile time, so a growing struct does not silently turn each
+/// get into a multi cacheline copy:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Small;
+/// assoc_threadlocal!(Small, [u64; 2] = [0; 2], max_inline_size = 16);
+/// ```
+///
+/// ```compile_fail
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Large;
+/// assoc_threadlocal!(Large, [u64; 64] = [0; 64], max_inline_size = 64);
+/// ```
+///
+/// Targets must be `Copy` and tags sized 'static types, violations are reported with a
+/// message naming the offending type before any error from the generated code:
+/// ```compile_fail
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Names;
+/// // error: `Vec<String>` can not be the target of a thread local association because it is not `Copy`
+/// assoc_threadlocal!(Names, Vec<String> = Vec::new());
+/// ```
+///
+/// 'history' records the last values set on each thread together with when and where they
+/// were set, see `AssocHistory`:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Retries;
+/// assoc_threadlocal!(Retries, u32 = 0, history = 8);
+///
+/// Retries::set_threadlocal(3);
+/// let last = Retries::recent_history().pop().unwrap();
+/// assert_eq!(last.value, 3);
+/// assert_eq!(last.location.file(), file!());
+/// ```
+///
+/// A 'config' association takes its default from the value loaded under the given key
+/// by `config::load_defaults_from()` and picks up changed values on reloads, see
+/// `AssocConfig` (requires the `config` feature).  The target must implement `FromStr`.
+///
+/// A 'debug_only' association behaves like a plain one in builds with debug assertions.
+/// Without them it has no storage, getting returns INIT and setting does nothing, so
+/// associations that only help debugging cost nothing in release builds:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Parser;
+/// assoc_threadlocal!(Parser, u32 = 0, debug_only);
+///
+/// Parser::set_threadlocal(Parser::get_threadlocal() + 1);
+/// assert_eq!(Parser::get_threadlocal(), if cfg!(debug_assertions) { 1 } else { 0 });
+/// ```
+///
+/// A 'proxy' struct with accessors bound to exactly one association gives it a name that
+/// can be imported and called without the trait in scope:
+/// ```
+/// mod logging {
+///     pub struct Logger;
+///     pub struct Verbosity;
+///     assoc_threadlocal::assoc_threadlocal!(Verbosity:Logger, u8 = 1, proxy = pub VerbosityCtl);
+/// }
+///
+/// use logging::VerbosityCtl;
+///
+/// VerbosityCtl::set(2);
+/// {
+///     let _quiet = VerbosityCtl::scoped(0);
+///     assert_eq!(VerbosityCtl::get(), 0);
+/// }
+/// assert_eq!(VerbosityCtl::get(), 2);
+/// ```
+///
+/// A 'guard' type is a nameable override guard of one association, e.g. for struct
+/// fields and function signatures.  Dropping it restores the previous value:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Verbosity;
+/// assoc_threadlocal!(Verbosity, u8 = 1, guard = pub VerbosityOverride);
+///
+/// struct QuietSection {
+///     _verbosity: VerbosityOverride,
+/// }
+///
+/// let section = QuietSection { _verbosity: VerbosityOverride::set(0) };
+/// assert_eq!(Verbosity::get_threadlocal(), 0);
+/// drop(section);
+/// assert_eq!(Verbosity::get_threadlocal(), 1);
+/// ```
+#[macro_export]
+macro_rules! assoc_threadlocal {
+    (impl<$($G:ident $(: $BOUND:path)?),+> $T:ty, $TARGET:ty = $INIT:expr) => {
+        $crate::assoc_threadlocal!((): impl<$($G $(: $BOUND)?),+> $T, $TARGET = $INIT);
+    };
+    ($TAG:ty: impl<$($G:ident $(: $BOUND:path)?),+> $T:ty, $TARGET:ty = $INIT:expr) => {
+        impl<$($G: 'static $(+ $BOUND)?),+> $crate::GetAssocThreadLocal<$TARGET, $TAG> for $T {
+            $crate::__assoc_names!($TAG, $T, $TARGET);
+
+            #[inline]
+            unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
+                (*$crate::generic::slot::<($T, $TARGET, $TAG), $TARGET>(|| {
+                    $crate::init::init_or_override::<$T, $TARGET, $TAG>(|| $INIT)
+                }))
+                .value()
+            }
+
+            fn threadlocal_generation() -> u64 {
+                unsafe {
+                    (*$crate::generic::slot::<($T, $TARGET, $TAG), $TARGET>(|| {
+                        $crate::init::init_or_override::<$T, $TARGET, $TAG>(|| $INIT)
+                    }))
+                    .generation()
+                }
+            }
+        }
+
+        impl<$($G: 'static $(+ $BOUND)?),+> $crate::SetAssocThreadLocal<$TARGET, $TAG> for $T {
+            #[inline]
+            fn set_threadlocal(value: $TARGET) {
+                unsafe {
+                    (*$crate::generic::slot::<($T, $TARGET, $TAG), $TARGET>(|| value)).set(value)
+                }
+            }
+
+            fn reset_threadlocal() {
+                <Self as $crate::SetAssocThreadLocal<$TARGET, $TAG>>::set_threadlocal(
+                    $crate::init::init_or_override::<$T, $TARGET, $TAG>(|| $INIT),
+                )
+            }
+        }
+    };
+    (_:$T:ty, $($REST:tt)*) => {
+        compile_error!("the tag of a thread local association must name a type, `_` is reserved");
+    };
+    ($TAG:ty:$T:ty, ref $REF:ty = $($REST:tt)*) => {
+        $crate::assoc_threadlocal!($TAG:$T, &'static $REF = $($REST)*);
+    };
+    ($TAG:ty:$T:ty, ref $REF:ty, fallback) => {
+        $crate::assoc_threadlocal!($TAG:$T, &'static $REF, fallback);
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = const $INIT:expr) => {
+        $crate::__assoc_assert!($TAG, $TARGET);
+        impl $crate::AssocConstInit<$TARGET, $TAG> for $T {
+            const INIT: $TARGET = $INIT;
+        }
+
+        const _: () = {
+            // const initialized and without destructor, reading is a single TLS load
+            $crate::__assoc_thread_local!(
+                // the value and its generation
+                static ASSOCIATED_THREADLOCAL: (
+                    std::cell::Cell<$TARGET>,
+                    std::cell::Cell<u64>,
+                    std::marker::PhantomData<$T>,
+                    std::marker::PhantomData<$TAG>,
+                ) = const {
+                    (
+                        std::cell::Cell::new(<$T as $crate::AssocConstInit<$TARGET, $TAG>>::INIT),
+                        std::cell::Cell::new(0),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    )
+                };
+            );
+
+            // without a destructor the const thread local needs no registration state, reads
+            // stay a single TLS load
+            const _: () = assert!(
+                !std::mem::needs_drop::<(
+                    std::cell::Cell<$TARGET>,
+                    std::cell::Cell<u64>,
+                    std::marker::PhantomData<$T>,
+                    std::marker::PhantomData<$TAG>,
+                )>(),
+                "const associations must not need drop"
+            );
+
+            // there is no lazy initialization, the association registers on the first set
+            #[cold]
+            #[inline(never)]
+            fn register() {
+                $crate::__assoc_register!($TAG, $T, $TARGET, reset = reset, restore = |value| {
+                    set(*value.downcast().expect("restored value of another type"))
+                });
+            }
+
+            #[inline]
+            #[track_caller]
+            fn set(value: $TARGET) {
+                $crate::profiling::count_write::<$T, $TAG, $TARGET>();
+                ASSOCIATED_THREADLOCAL.with(|l| {
+                    $crate::__assoc_leak_watch!($TAG, $T, $TARGET, l);
+                    l.0.set(value);
+                    let generation = l.1.get();
+                    if generation == 0 {
+                        register();
+                    }
+                    l.1.set(generation.wrapping_add(1));
+                });
+                $crate::__assoc_record!($TAG, $T, $TARGET, value, set);
+            }
+
+            #[track_caller]
+            fn reset() {
+                set(<$T as $crate::AssocConstInit<$TARGET, $TAG>>::INIT)
+            }
+
+            impl $crate::GetAssocThreadLocal<$TARGET, $TAG> for $T {
+                $crate::__assoc_names!($TAG, $T, $TARGET);
+
+                #[inline]
+                unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
+                    ASSOCIATED_THREADLOCAL.with(|l| &l.0 as *const std::cell::Cell<$TARGET>)
+                }
+
+                #[inline(always)]
+                fn get_threadlocal() -> $TARGET {
+                    $crate::profiling::count_read::<$T, $TAG, $TARGET>();
+                    ASSOCIATED_THREADLOCAL.with(|l| l.0.get())
+                }
+
+                #[inline]
+                fn threadlocal_generation() -> u64 {
+                    ASSOCIATED_THREADLOCAL.with(|l| l.1.get())
+                }
+
+                #[inline]
+                fn get_versioned() -> ($TARGET, u64) {
+                    $crate::profiling::count_read::<$T, $TAG, $TARGET>();
+                    ASSOCIATED_THREADLOCAL.with(|l| (l.0.get(), l.1.get()))
+                }
+            }
+
+            $crate::__assoc_setter!($TAG:$T, $TARGET);
+        };
+    };
+    ($TAG:ty:$T:ty, $TARGET:ident in $MIN:literal ..= $MAX:literal = $INIT:expr) => {
+        $crate::assoc_threadlocal!($TAG:$T, $TARGET in $MIN..=$MAX = $INIT, clamp);
+    };
+    ($TAG:ty:$T:ty, $TARGET:ident in $MIN:literal ..= $MAX:literal = $INIT:expr, $MODE:ident) => {
+        impl $crate::AssocRange<$TARGET, $TAG> for $T {
+            const RANGE_MIN: $TARGET = $MIN;
+            const RANGE_MAX: $TARGET = $MAX;
+            const RANGE_MODE: $crate::RangeMode = $crate::__range_mode!($MODE);
+        }
+        $crate::assoc_threadlocal!(
+            @impl $TAG:$T,
+            $TARGET = $INIT,
+            check = <$T as $crate::AssocRange<$TARGET, $TAG>>::check_range,
+            refresh = || {}
+        );
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, proxy = $VIS:vis $PROXY:ident) => {
+        $crate::assoc_threadlocal!($TAG:$T, $TARGET = $INIT);
+
+        /// Accessors for the association of
+        #[doc = concat!("`", stringify!($TARGET), "` to `", stringify!($T), "`.")]
+        #[derive(Clone, Copy, Debug, Default)]
+        $VIS struct $PROXY;
+
+        #[allow(dead_code)]
+        impl $PROXY {
+            /// Returns the current threads value.
+            #[inline]
+            $VIS fn get() -> $TARGET {
+                <$T as $crate::GetAssocThreadLocal<$TARGET, $TAG>>::get_threadlocal()
+            }
+
+            /// Sets the current threads value.
+            #[inline]
+            $VIS fn set(value: $TARGET) {
+                <$T as $crate::SetAssocThreadLocal<$TARGET, $TAG>>::set_threadlocal(value)
+            }
+
+            /// Sets the current threads value until the returned guard is dropped.
+            $VIS fn scoped(value: $TARGET) -> $crate::ThreadLocalGuard<$T, $TARGET, $TAG> {
+                <$T as $crate::AssocThreadLocal<$TARGET, $TAG>>::set_threadlocal_scoped(value)
+            }
+
+            /// Returns a handle to the association.
+            $VIS const fn handle() -> $crate::ThreadLocalHandle<$T, $TARGET, $TAG> {
+                $crate::ThreadLocalHandle::new()
+            }
+        }
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, guard = $VIS:vis $GUARD:ident) => {
+        $crate::assoc_threadlocal!($TAG:$T, $TARGET = $INIT);
+
+        /// Overrides the current threads value of the association of
+        #[doc = concat!("`", stringify!($TARGET), "` to `", stringify!($T), "`")]
+        /// and restores the previous value when dropped.
+        #[must_use = "the previous value is restored immediately when the guard is not kept"]
+        $VIS struct $GUARD(
+            // only held for restoring on drop
+            #[allow(dead_code)] $crate::ThreadLocalGuard<$T, $TARGET, $TAG>,
+        );
+
+        #[allow(dead_code)]
+        impl $GUARD {
+            /// Sets the current threads value until the returned guard is dropped.
+            $VIS fn set(value: $TARGET) -> Self {
+                $GUARD(<$T as $crate::AssocThreadLocal<$TARGET, $TAG>>::set_threadlocal_scoped(value))
+            }
+        }
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, static) => {
+        impl $crate::AssocGlobalMirror<$TARGET, $TAG> for $T {
+            fn the_global() -> &'static $crate::GlobalMirror<$TARGET> {
+                static GLOBAL: $crate::GlobalMirror<$TARGET> = $crate::GlobalMirror::new();
+                &GLOBAL
+            }
+
+            unsafe fn the_seen_version() -> *const std::cell::Cell<u64> {
+                $crate::__assoc_thread_local!(
+                    static SEEN_VERSION: (
+                        std::cell::Cell<u64>,
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = const {
+                        (
+                            std::cell::Cell::new(0),
+                            std::marker::PhantomData,
+                            std::marker::PhantomData,
+                        )
+                    };
+                );
+                SEEN_VERSION.with(|l| &l.0 as *const std::cell::Cell<u64>)
+            }
+        }
+        $crate::assoc_threadlocal!(
+            @impl $TAG:$T,
+            $TARGET = $INIT,
+            check = std::convert::identity,
+            refresh = <$T as $crate::AssocGlobalMirror<$TARGET, $TAG>>::refresh_threadlocal
+        );
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, set_requires = $TOKEN:ty) => {
+        $crate::assoc_threadlocal!(
+            @impl $TAG:$T,
+            $TARGET = $INIT,
+            check = std::convert::identity,
+            refresh = || {},
+            access = || Ok(()),
+            set_requires = [$TOKEN]
+        );
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, main_thread) => {
+        const _: () = {
+            static OWNER: $crate::affinity::OwnerThread = $crate::affinity::OwnerThread::new();
+
+            $crate::assoc_threadlocal!(
+                @impl $TAG:$T,
+                $TARGET = $INIT,
+                check = std::convert::identity,
+                refresh = || {},
+                access = || OWNER.claim(),
+                set_requires = []
+            );
+        };
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, teardown = $TEARDOWN:expr) => {
+        const _: () = {
+            $crate::__assoc_thread_local!(
+                static RESOURCE_STATE: std::cell::Cell<$crate::ResourceState> =
+                    const { std::cell::Cell::new($crate::ResourceState::Unused) };
+            );
+
+            impl $crate::AssocResource<$TARGET, $TAG> for $T {
+                unsafe fn the_resource_state() -> *const std::cell::Cell<$crate::ResourceState> {
+                    RESOURCE_STATE.with(|l| l as *const std::cell::Cell<$crate::ResourceState>)
+                }
+
+                fn teardown_value(value: $TARGET) {
+                    ($TEARDOWN)(value)
+                }
+            }
+
+            $crate::assoc_threadlocal!(
+                @impl $TAG:$T,
+                $TARGET = $INIT,
+                // runs on initialization and every set, marks the resource live
+                check = |value| {
+                    RESOURCE_STATE.with(|state| {
+                        if state.get() == $crate::ResourceState::Unused {
+                            state.set($crate::ResourceState::Live)
+                        }
+                    });
+                    value
+                },
+                refresh = || {},
+                access = || match RESOURCE_STATE.with(std::cell::Cell::get) {
+                    $crate::ResourceState::TornDown => Err($crate::AccessError::TornDown),
+                    _ => Ok(()),
+                },
+                set_requires = []
+            );
+        };
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, max_inline_size = $MAX:expr) => {
+        const _: () = assert!(
+            std::mem::size_of::<$TARGET>() <= $MAX,
+            concat!(
+                "thread local target `",
+                stringify!($TARGET),
+                "` exceeds max_inline_size, every access copies the whole value"
+            )
+        );
+        $crate::assoc_threadlocal!($TAG:$T, $TARGET = $INIT);
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, history = $LEN:expr) => {
+        impl $crate::AssocHistory<$TARGET, $TAG> for $T {
+            const HISTORY_LEN: usize = $LEN;
+        }
+        $crate::assoc_threadlocal!(
+            @impl $TAG:$T,
+            $TARGET = $INIT,
+            check = std::convert::identity,
+            refresh = || {},
+            access = || Ok(()),
+            set_requires = [],
+            on_set = <$T as $crate::AssocHistory<$TARGET, $TAG>>::record_history
+        );
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, config = $KEY:literal) => {
+        impl $crate::AssocConfig<$TARGET, $TAG> for $T {
+            const KEY: &'static str = $KEY;
+
+            unsafe fn the_seen_epoch() -> *const std::cell::Cell<u64> {
+                $crate::__assoc_thread_local!(
+                    static SEEN_EPOCH: (
+                        std::cell::Cell<u64>,
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = const {
+                        (
+                            std::cell::Cell::new(0),
+                            std::marker::PhantomData,
+                            std::marker::PhantomData,
+                        )
+                    };
+                );
+                SEEN_EPOCH.with(|l| &l.0 as *const std::cell::Cell<u64>)
+            }
+        }
+        $crate::assoc_threadlocal!(
+            @impl $TAG:$T,
+            $TARGET = <$T as $crate::AssocConfig<$TARGET, $TAG>>::config_init(|| $INIT),
+            check = std::convert::identity,
+            refresh = <$T as $crate::AssocConfig<$TARGET, $TAG>>::refresh_from_config
+        );
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, debug_only) => {
+        #[cfg(debug_assertions)]
+        $crate::assoc_threadlocal!($TAG:$T, $TARGET = $INIT);
+
+        // evaluated in the crate using the macro, i.e. by its build profile
+        #[cfg(not(debug_assertions))]
+        const _: () = {
+            $crate::__assoc_assert!($TAG, $TARGET);
+
+            impl $crate::GetAssocThreadLocal<$TARGET, $TAG> for $T {
+                $crate::__assoc_names!($TAG, $T, $TARGET);
+
+                // only reached by code bypassing get_threadlocal(), sees INIT and writes
+                // to it are lost
+                unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
+                    $crate::__assoc_thread_local!(
+                        static SCRATCH: std::cell::Cell<$TARGET> = std::cell::Cell::new($INIT);
+                    );
+                    SCRATCH.with(|l| {
+                        l.set($INIT);
+                        l as *const std::cell::Cell<$TARGET>
+                    })
+                }
+
+                #[inline(always)]
+                fn ensure_threadlocal_initialized() {}
+
+                #[inline(always)]
+                fn get_threadlocal() -> $TARGET {
+                    $INIT
+                }
+            }
+
+            impl $crate::SetAssocThreadLocal<$TARGET, $TAG> for $T {
+                #[inline(always)]
+                fn set_threadlocal(_value: $TARGET) {}
+            }
+        };
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, strict) => {
+        const _: () = {
+            $crate::__assoc_assert!($TAG, $TARGET);
+
+            $crate::__assoc_thread_local!(
+                // the value, whether it was set on this thread and its generation
+                static ASSOCIATED_THREADLOCAL: (
+                    std::cell::Cell<$TARGET>,
+                    std::cell::Cell<bool>,
+                    std::cell::Cell<u64>,
+                    std::marker::PhantomData<$T>,
+                    std::marker::PhantomData<$TAG>,
+                ) = {
+                    $crate::__assoc_register!(
+                        $TAG,
+                        $T,
+                        $TARGET,
+                        reset = reset,
+                        restore = |value| match *value.downcast().expect("restored value of another type") {
+                            Some(value) => set(value),
+                            None => reset(),
+                        },
+                        capture = || Box::new(
+                            <$T as $crate::GetAssocThreadLocal<$TARGET, $TAG>>::try_get_threadlocal().ok()
+                        )
+                    );
+                    (
+                        std::cell::Cell::new($INIT),
+                        std::cell::Cell::new(false),
+                        std::cell::Cell::new(0),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    )
+                };
+            );
+
+            fn set(value: $TARGET) {
+                $crate::profiling::count_write::<$T, $TAG, $TARGET>();
+                ASSOCIATED_THREADLOCAL.with(|l| {
+                    l.0.set(value);
+                    l.1.set(true);
+                    l.2.set(l.2.get().wrapping_add(1));
+                });
+                $crate::__assoc_record!($TAG, $T, $TARGET, value, set);
+            }
+
+            fn reset() {
+                ASSOCIATED_THREADLOCAL.with(|l| {
+                    l.1.set(false);
+                    l.2.set(l.2.get().wrapping_add(1));
+                })
+            }
+
+            impl $crate::GetAssocThreadLocal<$TARGET, $TAG> for $T {
+                $crate::__assoc_names!($TAG, $T, $TARGET);
+
+                unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
+                    ASSOCIATED_THREADLOCAL.with(|l| &l.0 as *const std::cell::Cell<$TARGET>)
+                }
+
+                fn get_threadlocal() -> $TARGET {
+                    match Self::try_get_threadlocal() {
+                        Ok(value) => value,
+                        Err(_) => panic!(
+                            "strict thread local {} of {} read before it was set",
+                            std::any::type_name::<$TARGET>(),
+                            std::any::type_name::<$T>(),
+                        ),
+                    }
+                }
+
+                fn try_get_threadlocal() -> Result<$TARGET, $crate::AccessError> {
+                    $crate::profiling::count_read::<$T, $TAG, $TARGET>();
+                    ASSOCIATED_THREADLOCAL
+                        .with(|l| l.1.get().then(|| l.0.get()).ok_or($crate::AccessError::NotSet))
+                }
+
+                fn threadlocal_generation() -> u64 {
+                    ASSOCIATED_THREADLOCAL.with(|l| l.2.get())
+                }
+            }
+
+            $crate::__assoc_setter!($TAG:$T, $TARGET);
+        };
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty = $INIT:expr) => {
+        $crate::assoc_threadlocal!(
+            @impl $TAG:$T,
+            $TARGET = $INIT,
+            check = std::convert::identity,
+            refresh = || {}
+        );
+    };
+    (@impl $TAG:ty:$T:ty, $TARGET:ty = $INIT:expr, check = $CHECK:expr, refresh = $REFRESH:expr) => {
+        $crate::assoc_threadlocal!(
+            @impl $TAG:$T,
+            $TARGET = $INIT,
+            check = $CHECK,
+            refresh = $REFRESH,
+            access = || Ok(()),
+            set_requires = []
+        );
+    };
+    (
+        @impl $TAG:ty:$T:ty,
+        $TARGET:ty = $INIT:expr,
+        check = $CHECK:expr,
+        refresh = $REFRESH:expr,
+        access = $ACCESS:expr,
+        set_requires = [$($TOKEN:ty)?]
+        $(, on_set = $ON_SET:expr)?
+    ) => {
+        const _: () = {
+            $crate::__assoc_assert!($TAG, $TARGET);
+
+            // initialization is outlined, the access path stays small enough to inline
+            #[cold]
+            #[inline(never)]
+            fn init() -> $TARGET {
+                // inaccessible values are skipped by the registry
+                $crate::__assoc_register!(
+                    $TAG,
+                    $T,
+                    $TARGET,
+                    reset = || if accessible().is_ok() { reset() },
+                    restore = |value| {
+                        if let Some(value) = *value
+                            .downcast::<Option<$TARGET>>()
+                            .expect("restored value of another type")
+                        {
+                            set(value)
+                        }
+                    },
+                    capture = || Box::new(
+                        <$T as $crate::GetAssocThreadLocal<$TARGET, $TAG>>::try_get_threadlocal().ok()
+                    )
+                );
+                ($CHECK)($crate::init::init_or_override::<$T, $TARGET, $TAG>(|| $INIT))
+            }
+
+            $crate::__assoc_thread_local!(
+                // the value and its generation
+                static ASSOCIATED_THREADLOCAL: (
+                    std::cell::Cell<$TARGET>,
+                    std::cell::Cell<u64>,
+                    std::marker::PhantomData<$T>,
+                    std::marker::PhantomData<$TAG>,
+                ) = (
+                    std::cell::Cell::new(init()),
+                    std::cell::Cell::new(0),
+                    std::marker::PhantomData,
+                    std::marker::PhantomData,
+                );
+            );
+
+            #[inline]
+            fn accessible() -> Result<(), $crate::AccessError> {
+                ($ACCESS)()
+            }
+
+            // panics when the value may not be accessed on this thread
+            #[inline]
+            fn access() {
+                if let Err(error) = accessible() {
+                    access_failed(error)
+                }
+            }
+
+            #[cold]
+            #[inline(never)]
+            fn access_failed(error: $crate::AccessError) -> ! {
+                panic!(
+                    "{} of {}: {}",
+                    std::any::type_name::<$TARGET>(),
+                    std::any::type_name::<$T>(),
+                    error
+                )
+            }
+
+            #[inline]
+            #[track_caller]
+            fn set(value: $TARGET) {
+                access();
+                $crate::profiling::count_write::<$T, $TAG, $TARGET>();
+                let value = ($CHECK)(value);
+                ASSOCIATED_THREADLOCAL.with(|l| {
+                    $crate::__assoc_leak_watch!($TAG, $T, $TARGET, l);
+                    l.0.set(value);
+                    l.1.set(l.1.get().wrapping_add(1));
+                });
+                $crate::__assoc_record!($TAG, $T, $TARGET, value, set);
+                $(($ON_SET)(value, std::panic::Location::caller());)?
+            }
+
+            #[track_caller]
+            fn reset() {
+                set($crate::init::init_or_override::<$T, $TARGET, $TAG>(|| $INIT))
+            }
+
+            impl $crate::GetAssocThreadLocal<$TARGET, $TAG> for $T {
+                $crate::__assoc_names!($TAG, $T, $TARGET);
+
+                #[inline]
+                unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
+                    ASSOCIATED_THREADLOCAL.with(|l| &l.0 as *const std::cell::Cell<$TARGET>)
+                }
+
+                #[inline]
+                fn get_threadlocal() -> $TARGET {
+                    access();
+                    $crate::profiling::count_read::<$T, $TAG, $TARGET>();
+                    ($REFRESH)();
+                    ASSOCIATED_THREADLOCAL.with(|l| l.0.get())
+                }
+
+                #[inline]
+                fn threadlocal_generation() -> u64 {
+                    access();
+                    ($REFRESH)();
+                    ASSOCIATED_THREADLOCAL.with(|l| l.1.get())
+                }
+
+                #[inline]
+                fn get_versioned() -> ($TARGET, u64) {
+                    access();
+                    $crate::profiling::count_read::<$T, $TAG, $TARGET>();
+                    ($REFRESH)();
+                    ASSOCIATED_THREADLOCAL.with(|l| (l.0.get(), l.1.get()))
+                }
+
+                fn try_get_threadlocal() -> Result<$TARGET, $crate::AccessError> {
+                    accessible()?;
+                    Ok(<Self as $crate::GetAssocThreadLocal<$TARGET, $TAG>>::get_threadlocal())
+                }
+            }
+
+            $crate::__assoc_setter!($TAG:$T, $TARGET $(, $TOKEN)?);
+        };
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty, fallback) => {
+        const _: () = {
+            $crate::__assoc_assert!($TAG, $TARGET);
+
+            $crate::__assoc_thread_local!(
+                // the value, whether it was set on this thread and its generation
+                static ASSOCIATED_THREADLOCAL: (
+                    std::cell::Cell<$TARGET>,
+                    std::cell::Cell<bool>,
+                    std::cell::Cell<u64>,
+                    std::marker::PhantomData<$T>,
+                    std::marker::PhantomData<$TAG>,
+                ) = {
+                    // an unset value is captured as `None` and restored by falling back again
+                    $crate::__assoc_register!(
+                        $TAG,
+                        $T,
+                        $TARGET,
+                        reset = <$T as $crate::SetAssocThreadLocal<$TARGET, $TAG>>::reset_threadlocal,
+                        restore = |value| match *value
+                            .downcast::<Option<$TARGET>>()
+                            .expect("restored value of another type")
+                        {
+                            Some(value) => {
+                                <$T as $crate::SetAssocThreadLocal<$TARGET, $TAG>>::set_threadlocal(value)
+                            }
+                            None => {
+                                <$T as $crate::SetAssocThreadLocal<$TARGET, $TAG>>::reset_threadlocal()
+                            }
+                        },
+                        capture = || Box::new(
+                            ASSOCIATED_THREADLOCAL.with(|l| l.1.get().then(|| l.0.get()))
+                        )
+                    );
+                    (
+                        std::cell::Cell::new(
+                            <$T as $crate::GetAssocThreadLocal<$TARGET, ()>>::get_threadlocal(),
+                        ),
+                        std::cell::Cell::new(false),
+                        std::cell::Cell::new(0),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    )
+                };
+            );
+
+            impl $crate::GetAssocThreadLocal<$TARGET, $TAG> for $T {
+                $crate::__assoc_names!($TAG, $T, $TARGET);
+
+                unsafe fn the_threadlocal() -> *const std::cell::Cell<$TARGET> {
+                    ASSOCIATED_THREADLOCAL.with(|l| &l.0 as *const std::cell::Cell<$TARGET>)
+                }
+
+                fn get_threadlocal() -> $TARGET {
+                    if let Some(value) = ASSOCIATED_THREADLOCAL.with(|l| l.1.get().then(|| l.0.get())) {
+                        value
+                    } else {
+                        <$T as $crate::GetAssocThreadLocal<$TARGET, ()>>::get_threadlocal()
+                    }
+                }
+
+                // changes of the untagged association count as well
+                fn threadlocal_generation() -> u64 {
+                    ASSOCIATED_THREADLOCAL.with(|l| l.2.get()).wrapping_add(
+                        <$T as $crate::GetAssocThreadLocal<$TARGET, ()>>::threadlocal_generation(),
+                    )
+                }
+            }
+
+            impl $crate::SetAssocThreadLocal<$TARGET, $TAG> for $T {
+                fn set_threadlocal(value: $TARGET) {
+                    ASSOCIATED_THREADLOCAL.with(|l| {
+                        l.0.set(value);
+                        l.1.set(true);
+                        l.2.set(l.2.get().wrapping_add(1));
+                    })
+                }
+
+                // falls back to the untagged association again
+                fn reset_threadlocal() {
+                    ASSOCIATED_THREADLOCAL.with(|l| {
+                        l.1.set(false);
+                        l.2.set(l.2.get().wrapping_add(1));
+                    })
+                }
+            }
+        };
+    };
+    ($T:ty, ref $REF:ty = $($REST:tt)*) => {
+        $crate::assoc_threadlocal!(():$T, &'static $REF = $($REST)*);
+    };
+    ($T:ty, $TARGET:ty = const $INIT:expr) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = const $INIT);
+    };
+    ($T:ty, $TARGET:ident in $MIN:literal ..= $MAX:literal = $INIT:expr $(, $MODE:ident)?) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET in $MIN..=$MAX = $INIT $(, $MODE)?);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, proxy = $VIS:vis $PROXY:ident) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, proxy = $VIS $PROXY);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, guard = $VIS:vis $GUARD:ident) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, guard = $VIS $GUARD);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, set_requires = $TOKEN:ty) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, set_requires = $TOKEN);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, static) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, static);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, main_thread) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, main_thread);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, teardown = $TEARDOWN:expr) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, teardown = $TEARDOWN);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, max_inline_size = $MAX:expr) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, max_inline_size = $MAX);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, history = $LEN:expr) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, history = $LEN);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, config = $KEY:literal) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, config = $KEY);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, debug_only) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, debug_only);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr, strict) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT, strict);
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr) => {
+        $crate::assoc_threadlocal!(():$T, $TARGET = $INIT);
+    };
+}
+
+/// Eagerly initializes associations on the main thread before `main()` runs
+/// (requires the `ctor` feature).
+///
+/// Supported on ELF platforms, macOS and Windows.  A panicking INIT aborts the program
+/// before `main()`.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+///
+/// static INITIALIZED: AtomicBool = AtomicBool::new(false);
+///
+/// struct Example;
+/// assoc_threadlocal!(Example, u32 = {
+///     INITIALIZED.store(true, Ordering::Relaxed);
+///     42
+/// });
+/// eager_threadlocal!(Example, u32);
+///
+/// // initialized before main() started
+/// assert!(INITIALIZED.load(Ordering::Relaxed));
+/// ```
+#[cfg(feature = "ctor")]
+#[macro_export]
+macro_rules! eager_threadlocal {
+    ($TAG:ty:$T:ty, $TARGET:ty) => {
+        const _: () = {
+            extern "C" fn eager_init() {
+                <$T as $crate::GetAssocThreadLocal<$TARGET, $TAG>>::ensure_threadlocal_initialized();
+            }
+
+            #[used]
+            #[cfg_attr(
+                any(target_os = "linux", target_os = "android", target_os = "freebsd",
+                    target_os = "netbsd", target_os = "openbsd", target_os = "illumos"),
+                link_section = ".init_array"
+            )]
+            #[cfg_attr(any(target_os = "macos", target_os = "ios"), link_section = "__DATA,__mod_init_func")]
+            #[cfg_attr(windows, link_section = ".CRT$XCU")]
+            static EAGER_INIT: extern "C" fn() = eager_init;
+        };
+    };
+    ($T:ty, $TARGET:ty) => {
+        $crate::eager_threadlocal!(():$T, $TARGET);
+    };
+}
+
+/// Registers the association when the registry feature is enabled.
+#[cfg(not(feature = "registry"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_register {
+    ($TAG:ty, $T:ty, $TARGET:ty, reset = $RESET:expr, restore = $RESTORE:expr $(, capture = $CAPTURE:expr)?) => {};
+}
+
+/// Records sets for `record::record_sets()` when the record feature is enabled.
+#[cfg(not(feature = "record"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_record {
+    ($TAG:ty, $T:ty, $TARGET:ty, $VALUE:ident, $SET:ident) => {};
+}
+
+/// Watches associations for leaks when the leak-detection feature is enabled.
+#[cfg(not(feature = "leak-detection"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_leak_watch {
+    ($TAG:ty, $T:ty, $TARGET:ty, $L:ident) => {};
+}
+
+/// Reports the common mistakes with a targeted message before the rest of the expansion.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_assert {
+    ($TAG:ty, $TARGET:ty) => {
+        const _: () = {
+            #[allow(dead_code)]
+            fn assert(_: $crate::assert::Assertion<$TAG, $TARGET>) {}
+        };
+    };
+}
+
+/// Declares the storage of an association, a `thread_local!()` unless the
+/// `single-threaded` feature is enabled.
+#[cfg(not(feature = "single-threaded"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_thread_local {
+    ($($DECL:tt)*) => {
+        std::thread_local!($($DECL)*);
+    };
+}
+
+/// Declares the storage of an association as plain static, see `single_threaded`.
+#[cfg(feature = "single-threaded")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_thread_local {
+    (static $NAME:ident: $TY:ty = const $INIT:block $(;)?) => {
+        static $NAME: $crate::single_threaded::Local<$TY> =
+            $crate::single_threaded::Local::new(|| $INIT);
+    };
+    (static $NAME:ident: $TY:ty = $INIT:expr $(;)?) => {
+        static $NAME: $crate::single_threaded::Local<$TY> =
+            $crate::single_threaded::Local::new(|| $INIT);
+    };
+}
+
+/// Implements the introspection names of an association as written in the macro.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_names {
+    ($TAG:ty, $T:ty, $TARGET:ty) => {
+        fn assoc_name() -> &'static str {
+            stringify!($T)
+        }
+
+        fn tag_type_name() -> &'static str {
+            stringify!($TAG)
+        }
+
+        fn target_type_name() -> &'static str {
+            stringify!($TARGET)
+        }
+    };
+}
+
+/// Implements the setter of an association, generates `SetAssocThreadLocal` or
+/// `SetAssocThreadLocalWith` when a token type is given.  Expects `set()` and `reset()`
+/// functions in scope.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_setter {
+    ($TAG:ty:$T:ty, $TARGET:ty) => {
+        impl $crate::SetAssocThreadLocal<$TARGET, $TAG> for $T {
+            #[inline]
+            #[track_caller]
+            fn set_threadlocal(value: $TARGET) {
+                set(value)
+            }
+
+            #[track_caller]
+            fn reset_threadlocal() {
+                reset()
+            }
+        }
+    };
+    ($TAG:ty:$T:ty, $TARGET:ty, $TOKEN:ty) => {
+        impl $crate::SetAssocThreadLocalWith<$TARGET, $TAG> for $T {
+            type Token = $TOKEN;
+
+            #[inline]
+            fn set_threadlocal_with(_token: &$TOKEN, value: $TARGET) {
+                set(value)
+            }
+
+            fn reset_threadlocal_with(_token: &$TOKEN) {
+                reset()
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocThreadLocal, GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct TestType1;
+    assoc_threadlocal!(TestType1, &'static str = "This is the first test type");
+
+    #[test]
+    fn get_threadlocal() {
+        assert_eq!(TestType1::get_threadlocal(), "This is the first test type");
+    }
+
+    #[test]
+    fn set_threadlocal() {
+        TestType1::set_threadlocal("This is the first test type, set to a new value");
+        assert_eq!(
+            TestType1::get_threadlocal(),
+            "This is the first test type, set to a new value"
+        );
+    }
+
     struct TestType2;
     assoc_threadlocal!(TestType2, &'static str = "This is the second test type");
     assoc_threadlocal!(TestType2, u32 = 42);
@@ -164,20 +1966,146 @@ mod tests {
     #[test]
     fn multiple_threadlocals() {
         assert_eq!(
-            <TestType2 as AssocThreadLocal<&str, ()>>::get_threadlocal(),
+            <TestType2 as GetAssocThreadLocal<&str, ()>>::get_threadlocal(),
             "This is the second test type"
         );
         assert_eq!(
-            <TestType2 as AssocThreadLocal<u32, ()>>::get_threadlocal(),
+            <TestType2 as GetAssocThreadLocal<u32, ()>>::get_threadlocal(),
             42
         );
     }
 
+    trait Driver: Sync {
+        fn id(&self) -> u8;
+    }
+
+    struct Primary;
+    impl Driver for Primary {
+        fn id(&self) -> u8 {
+            1
+        }
+    }
+
+    struct Replica;
+    impl Driver for Replica {
+        fn id(&self) -> u8 {
+            2
+        }
+    }
+
+    static REPLICAS: [&dyn Driver; 2] = [&Replica, &Primary];
+
+    struct Storage;
+    struct Reads;
+    struct Fallback;
+    assoc_threadlocal!(Storage, ref dyn Driver = &Primary);
+    assoc_threadlocal!(Reads:Storage, ref [&'static dyn Driver] = &REPLICAS, strict);
+    assoc_threadlocal!(Fallback:Storage, ref dyn Driver, fallback);
+
+    #[test]
+    fn unsized_targets() {
+        assert_eq!(
+            <Storage as GetAssocThreadLocal<&dyn Driver>>::get_threadlocal().id(),
+            1
+        );
+        assert!(
+            <Storage as GetAssocThreadLocal<&[&dyn Driver], Reads>>::try_get_threadlocal().is_err()
+        );
+        <Storage as SetAssocThreadLocal<&[&dyn Driver], Reads>>::set_threadlocal(&REPLICAS[..1]);
+        let reads = <Storage as GetAssocThreadLocal<&[&dyn Driver], Reads>>::get_threadlocal();
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].id(), 2);
+        <Storage as SetAssocThreadLocal<&dyn Driver>>::set_threadlocal(&Replica);
+        assert_eq!(
+            <Storage as GetAssocThreadLocal<&dyn Driver, Fallback>>::get_threadlocal().id(),
+            2
+        );
+    }
+
+    struct Wrapper<W>(W);
+    struct Depth;
+    assoc_threadlocal!(impl<W> Wrapper<W>, u32 = 0);
+    assoc_threadlocal!(Depth: impl<W: Copy> Wrapper<W>, u8 = std::mem::size_of::<W>() as u8);
+
+    #[test]
+    fn per_monomorphization() {
+        <Wrapper<u8> as SetAssocThreadLocal<u32>>::set_threadlocal(1);
+        {
+            let _scoped = <Wrapper<u16> as AssocThreadLocal<u32>>::set_threadlocal_scoped(2);
+            assert_eq!(
+                <Wrapper<u16> as GetAssocThreadLocal<u32>>::get_threadlocal(),
+                2
+            );
+            assert_eq!(
+                <Wrapper<u8> as GetAssocThreadLocal<u32>>::get_threadlocal(),
+                1
+            );
+        }
+        assert_eq!(
+            <Wrapper<u16> as GetAssocThreadLocal<u32>>::get_threadlocal(),
+            0
+        );
+        assert_eq!(
+            <Wrapper<u8> as GetAssocThreadLocal<u32>>::threadlocal_generation(),
+            1
+        );
+        assert_eq!(
+            std::thread::spawn(<Wrapper<u8> as GetAssocThreadLocal<u32>>::get_threadlocal)
+                .join()
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(
+            <Wrapper<u32> as GetAssocThreadLocal<u8, Depth>>::get_threadlocal(),
+            4
+        );
+        <Wrapper<u32> as SetAssocThreadLocal<u8, Depth>>::set_threadlocal(9);
+        <Wrapper<u32> as SetAssocThreadLocal<u8, Depth>>::reset_threadlocal();
+        assert_eq!(
+            <Wrapper<u32> as GetAssocThreadLocal<u8, Depth>>::get_threadlocal(),
+            4
+        );
+    }
+
+    trait Node {}
+    impl Node for TestType1 {}
+    impl Node for TestType2 {}
+
+    struct Visits;
+    assoc_threadlocal!(Visits: dyn Node, u32 = 0);
+    assoc_threadlocal!(dyn Node, &'static str = "node");
+
+    #[test]
+    fn trait_object_implementor() {
+        let nodes: [&dyn Node; 2] = [&TestType1, &TestType2];
+        for node in nodes {
+            let visits = GetAssocThreadLocal::<u32, Visits>::get_threadlocal_from(node);
+            SetAssocThreadLocal::<u32, Visits>::set_threadlocal_of(node, visits + 1);
+        }
+        assert_eq!(
+            <dyn Node as GetAssocThreadLocal<u32, Visits>>::get_threadlocal(),
+            2
+        );
+        <dyn Node as SetAssocThreadLocal<&str>>::set_threadlocal("renamed");
+        assert_eq!(
+            GetAssocThreadLocal::<&str>::get_threadlocal_from(nodes[1]),
+            "renamed"
+        );
+        <dyn Node as AssocThreadLocal<&str>>::try_update_threadlocal(|_| Ok::<_, ()>("updated"))
+            .unwrap();
+        <dyn Node as SetAssocThreadLocal<&str>>::reset_threadlocal();
+        assert_eq!(
+            <dyn Node as GetAssocThreadLocal<&str>>::get_threadlocal(),
+            "node"
+        );
+    }
+
     #[test]
     fn from_instance() {
         let test = TestType1;
         assert_eq!(
-            AssocThreadLocal::get_threadlocal_from(&test),
+            GetAssocThreadLocal::get_threadlocal_from(&test),
             "This is the first test type"
         );
     }
@@ -186,9 +2114,411 @@ mod tests {
     fn from_instance_multiple() {
         let test = TestType2;
         assert_eq!(
-            AssocThreadLocal::<&str, _>::get_threadlocal_from(&test),
+            GetAssocThreadLocal::<&str, _>::get_threadlocal_from(&test),
             "This is the second test type"
         );
-        assert_eq!(AssocThreadLocal::<u32, _>::get_threadlocal_from(&test), 42);
+        assert_eq!(
+            GetAssocThreadLocal::<u32, _>::get_threadlocal_from(&test),
+            42
+        );
+    }
+
+    #[test]
+    fn set_threadlocal_scoped() {
+        {
+            let _guard = <TestType2 as AssocThreadLocal<u32>>::set_threadlocal_scoped(7);
+            assert_eq!(
+                <TestType2 as GetAssocThreadLocal<u32>>::get_threadlocal(),
+                7
+            );
+        }
+        assert_eq!(
+            <TestType2 as GetAssocThreadLocal<u32>>::get_threadlocal(),
+            42
+        );
+    }
+
+    struct Tracked;
+    assoc_threadlocal!(Tracked, u8 = 0);
+
+    #[test]
+    fn active_overrides() {
+        assert_eq!(Tracked::active_overrides().len(), 0);
+        let _outer = Tracked::set_threadlocal_scoped_tracked(1);
+        let line = line!() - 1;
+        {
+            let _inner = Tracked::set_threadlocal_scoped_tracked(2);
+            let overrides: Vec<_> = Tracked::active_overrides().collect();
+            assert_eq!(overrides.len(), 2);
+            assert_eq!(overrides[0].value, 2);
+            assert_eq!(overrides[1].value, 1);
+            assert_eq!(overrides[1].location.line(), line);
+            assert_eq!(overrides[1].location.file(), file!());
+        }
+        let overrides: Vec<_> = Tracked::active_overrides().map(|o| o.value).collect();
+        assert_eq!(overrides, [1]);
+        assert_eq!(Tracked::get_threadlocal(), 1);
+    }
+
+    #[test]
+    fn tracked_out_of_order() {
+        struct Unordered;
+        assoc_threadlocal!(Unordered, u8 = 0);
+
+        let values = || {
+            Unordered::active_overrides()
+                .map(|o| o.value)
+                .collect::<Vec<_>>()
+        };
+        let first = Unordered::set_threadlocal_scoped_tracked(1);
+        let second = Unordered::set_threadlocal_scoped_tracked(2);
+        let third = Unordered::set_threadlocal_scoped_tracked(3);
+        drop(second);
+        assert_eq!(values(), [1]);
+        drop(third);
+        assert_eq!(values(), [1]);
+        drop(first);
+        assert!(values().is_empty());
+    }
+
+    struct Versioned;
+    struct VersionedTag;
+    assoc_threadlocal!(Versioned, u8 = 0);
+    assoc_threadlocal!(VersionedTag:Versioned, u8, fallback);
+
+    #[test]
+    fn versioned() {
+        let (_, start) = <Versioned as GetAssocThreadLocal<u8>>::get_versioned();
+        <Versioned as SetAssocThreadLocal<u8>>::set_threadlocal(3);
+        assert_eq!(
+            <Versioned as GetAssocThreadLocal<u8>>::get_versioned(),
+            (3, start + 1)
+        );
+
+        let (value, tagged) = <Versioned as GetAssocThreadLocal<u8, VersionedTag>>::get_versioned();
+        assert_eq!(value, 3);
+        <Versioned as SetAssocThreadLocal<u8>>::set_threadlocal(4);
+        let (value, changed) =
+            <Versioned as GetAssocThreadLocal<u8, VersionedTag>>::get_versioned();
+        assert_eq!(value, 4);
+        assert_ne!(changed, tagged);
+    }
+
+    #[test]
+    fn try_update() {
+        struct Fallible;
+        assoc_threadlocal!(Fallible, i8 = 120);
+
+        let (_, generation) = Fallible::get_versioned();
+        assert_eq!(
+            Fallible::try_update_threadlocal(|v| v.checked_add(10).ok_or("overflow")),
+            Err("overflow")
+        );
+        assert_eq!(Fallible::get_versioned(), (120, generation));
+        assert_eq!(
+            Fallible::try_update_threadlocal(|v| v.checked_add(7).ok_or("overflow")),
+            Ok(127)
+        );
+        assert_eq!(Fallible::get_threadlocal(), 127);
+    }
+
+    struct Proxied;
+    assoc_threadlocal!(Proxied, u16 = 7, proxy = ProxiedCtl);
+
+    #[test]
+    fn proxy() {
+        assert_eq!(ProxiedCtl::get(), 7);
+        ProxiedCtl::set(8);
+        assert_eq!(ProxiedCtl::handle().get(), 8);
+        assert_eq!(<Proxied as GetAssocThreadLocal<u16>>::get_threadlocal(), 8);
+    }
+
+    struct Guarded;
+    struct Nested;
+    assoc_threadlocal!(Guarded, u16 = 7, guard = GuardedOverride);
+    assoc_threadlocal!(Nested: Guarded, u16 = 1, guard = pub(crate) NestedOverride);
+
+    #[test]
+    fn guard() {
+        {
+            let _outer = GuardedOverride::set(8);
+            let inner = GuardedOverride::set(9);
+            let _nested = NestedOverride::set(2);
+            assert_eq!(<Guarded as GetAssocThreadLocal<u16>>::get_threadlocal(), 9);
+            drop(inner);
+            assert_eq!(<Guarded as GetAssocThreadLocal<u16>>::get_threadlocal(), 8);
+            assert_eq!(
+                <Guarded as GetAssocThreadLocal<u16, Nested>>::get_threadlocal(),
+                2
+            );
+        }
+        assert_eq!(<Guarded as GetAssocThreadLocal<u16>>::get_threadlocal(), 7);
+        assert_eq!(
+            <Guarded as GetAssocThreadLocal<u16, Nested>>::get_threadlocal(),
+            1
+        );
+    }
+
+    struct Gated;
+    struct GateToken;
+    assoc_threadlocal!(Gated, u32 = 1, set_requires = GateToken);
+
+    #[test]
+    fn set_requires_token() {
+        use crate::SetAssocThreadLocalWith;
+
+        Gated::set_threadlocal_with(&GateToken, 5);
+        assert_eq!(Gated::get_versioned(), (5, 1));
+        Gated::reset_threadlocal_with(&GateToken);
+        assert_eq!(Gated::get_threadlocal(), 1);
+    }
+
+    #[test]
+    fn type_erased() {
+        let value = <TestType2 as GetAssocThreadLocal<u32>>::get_threadlocal_any();
+        assert_eq!(value.downcast_ref::<u32>(), Some(&42));
+        assert!(
+            <TestType2 as SetAssocThreadLocal<u32>>::set_threadlocal_any(Box::new(7u32)).is_ok()
+        );
+        assert_eq!(
+            <TestType2 as GetAssocThreadLocal<u32>>::get_threadlocal(),
+            7
+        );
+        let rejected =
+            <TestType2 as SetAssocThreadLocal<u32>>::set_threadlocal_any(Box::new("wrong"));
+        assert_eq!(rejected.unwrap_err().downcast_ref::<&str>(), Some(&"wrong"));
+    }
+
+    struct Frontend;
+    struct Backend;
+    struct BackendLevel;
+    assoc_threadlocal!(Frontend, u32 = 1);
+    assoc_threadlocal!(Backend, u32 = 2);
+    assoc_threadlocal!(BackendLevel:Backend, u32 = 3);
+
+    #[test]
+    fn copy_to_other_type() {
+        Frontend::set_threadlocal(10);
+        Frontend::copy_threadlocal_to::<Backend>();
+        assert_eq!(<Backend as GetAssocThreadLocal<u32>>::get_threadlocal(), 10);
+        Frontend::copy_threadlocal_to_tagged::<Backend, BackendLevel>();
+        assert_eq!(
+            <Backend as GetAssocThreadLocal<u32, BackendLevel>>::get_threadlocal(),
+            10
+        );
+    }
+
+    struct General;
+    struct Inherited;
+    assoc_threadlocal!(General, u32 = 1);
+    assoc_threadlocal!(Inherited:General, u32, fallback);
+
+    #[test]
+    fn fallback_to_untagged() {
+        assert_eq!(
+            <General as GetAssocThreadLocal<u32, Inherited>>::get_threadlocal(),
+            1
+        );
+        <General as SetAssocThreadLocal<u32>>::set_threadlocal(5);
+        assert_eq!(
+            <General as GetAssocThreadLocal<u32, Inherited>>::get_threadlocal(),
+            5
+        );
+        {
+            let _scoped = <General as AssocThreadLocal<u32, Inherited>>::set_threadlocal_scoped(9);
+            assert_eq!(
+                <General as GetAssocThreadLocal<u32, Inherited>>::get_threadlocal(),
+                9
+            );
+        }
+        // restoring sets the previous value, which was inherited
+        assert_eq!(
+            <General as GetAssocThreadLocal<u32, Inherited>>::get_threadlocal(),
+            5
+        );
+    }
+
+    #[cfg(feature = "registry")]
+    #[test]
+    fn fallback_after_isolate() {
+        struct Source;
+        struct Follower;
+        assoc_threadlocal!(Source, u32 = 1);
+        assoc_threadlocal!(Follower:Source, u32, fallback);
+
+        <Source as SetAssocThreadLocal<u32>>::set_threadlocal(5);
+        assert_eq!(
+            <Source as GetAssocThreadLocal<u32, Follower>>::get_threadlocal(),
+            5
+        );
+        {
+            let _isolated = crate::registry::isolate_threadlocals();
+            <Source as SetAssocThreadLocal<u32>>::set_threadlocal(7);
+            assert_eq!(
+                <Source as GetAssocThreadLocal<u32, Follower>>::get_threadlocal(),
+                7
+            );
+        }
+        assert_eq!(
+            <Source as GetAssocThreadLocal<u32, Follower>>::get_threadlocal(),
+            5
+        );
+        // still unset, follows the untagged association
+        <Source as SetAssocThreadLocal<u32>>::set_threadlocal(6);
+        assert_eq!(
+            <Source as GetAssocThreadLocal<u32, Follower>>::get_threadlocal(),
+            6
+        );
+
+        <Source as SetAssocThreadLocal<u32, Follower>>::set_threadlocal(9);
+        drop(crate::registry::isolate_threadlocals());
+        <Source as SetAssocThreadLocal<u32>>::set_threadlocal(8);
+        assert_eq!(
+            <Source as GetAssocThreadLocal<u32, Follower>>::get_threadlocal(),
+            9
+        );
+    }
+
+    struct LateConfig;
+    assoc_threadlocal!(LateConfig, u32 = 1);
+
+    #[test]
+    fn init_override() {
+        assert_eq!(LateConfig::get_threadlocal(), 1);
+        LateConfig::set_threadlocal_init_override(|| 2);
+        // this thread keeps its value, new threads see the override
+        assert_eq!(LateConfig::get_threadlocal(), 1);
+        assert_eq!(
+            std::thread::spawn(LateConfig::get_threadlocal)
+                .join()
+                .unwrap(),
+            2
+        );
+        LateConfig::clear_threadlocal_init_override();
+        assert_eq!(
+            std::thread::spawn(LateConfig::get_threadlocal)
+                .join()
+                .unwrap(),
+            1
+        );
+    }
+
+    struct Eager;
+    assoc_threadlocal!(Eager, u32 = 3);
+    #[cfg(feature = "ctor")]
+    eager_threadlocal!(Eager, u32);
+
+    #[test]
+    fn ensure_initialized() {
+        Eager::ensure_threadlocal_initialized();
+        assert_eq!(Eager::get_threadlocal(), 3);
+    }
+
+    struct TestUndo;
+    assoc_threadlocal!(TestUndo, u32 = 0);
+
+    #[test]
+    fn undo() {
+        TestUndo::set_threadlocal_undoable(1);
+        TestUndo::set_threadlocal_undoable(2);
+        assert_eq!(TestUndo::undo_depth(), 2);
+        assert_eq!(TestUndo::undo_threadlocal(), Some(1));
+        assert_eq!(TestUndo::get_threadlocal(), 1);
+        assert_eq!(TestUndo::undo_threadlocal(), Some(0));
+        assert_eq!(TestUndo::undo_threadlocal(), None);
+        assert_eq!(TestUndo::get_threadlocal(), 0);
+    }
+
+    #[test]
+    fn undo_is_bounded() {
+        for n in 1..=TestUndo::UNDO_LIMIT as u32 + 10 {
+            TestUndo::set_threadlocal_undoable(n);
+        }
+        assert_eq!(TestUndo::undo_depth(), TestUndo::UNDO_LIMIT);
+    }
+
+    struct TestStrict;
+    assoc_threadlocal!(TestStrict, u32 = 0, strict);
+
+    #[test]
+    fn strict() {
+        assert_eq!(
+            TestStrict::try_get_threadlocal(),
+            Err(crate::AccessError::NotSet)
+        );
+        TestStrict::set_threadlocal(5);
+        assert_eq!(TestStrict::get_threadlocal(), 5);
+        assert!(std::thread::spawn(TestStrict::try_get_threadlocal)
+            .join()
+            .unwrap()
+            .is_err());
+        TestStrict::reset_threadlocal();
+        assert!(TestStrict::try_get_threadlocal().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "read before it was set")]
+    fn strict_unset_panics() {
+        TestStrict::get_threadlocal();
+    }
+
+    struct TestMainThread;
+    assoc_threadlocal!(TestMainThread, u32 = 1, main_thread);
+
+    #[test]
+    fn main_thread() {
+        // the test thread is the first to access it
+        TestMainThread::set_threadlocal(2);
+        assert_eq!(TestMainThread::try_get_threadlocal(), Ok(2));
+        let result = std::thread::spawn(|| {
+            let try_get = TestMainThread::try_get_threadlocal();
+            let get = std::panic::catch_unwind(TestMainThread::get_threadlocal);
+            let set = std::panic::catch_unwind(|| TestMainThread::set_threadlocal(3));
+            (try_get, get.is_err(), set.is_err())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(result, (Err(crate::AccessError::WrongThread), true, true));
+    }
+
+    struct TestDebugOnly;
+    assoc_threadlocal!(TestDebugOnly, u32 = 5, debug_only);
+
+    #[test]
+    fn debug_only() {
+        {
+            let _scoped = TestDebugOnly::set_threadlocal_scoped(7);
+            assert_eq!(
+                TestDebugOnly::get_threadlocal(),
+                if cfg!(debug_assertions) { 7 } else { 5 }
+            );
+        }
+        assert_eq!(TestDebugOnly::get_threadlocal(), 5);
+    }
+
+    struct TestNames;
+    assoc_threadlocal!(TestNames, Option<&'static str> = None);
+    assoc_threadlocal!(TestNames: TestNames, [u8; 4] = const [0; 4]);
+
+    #[test]
+    fn names() {
+        type Untagged = TestNames;
+        assert_eq!(
+            <Untagged as GetAssocThreadLocal<Option<&str>>>::target_type_name(),
+            "Option<&'static str>"
+        );
+        assert_eq!(
+            <Untagged as GetAssocThreadLocal<Option<&str>>>::tag_type_name(),
+            "()"
+        );
+        assert_eq!(
+            <TestNames as GetAssocThreadLocal<[u8; 4], TestNames>>::target_type_name(),
+            "[u8; 4]"
+        );
+        // implementations not generated by the macro report full paths
+        assert_eq!(
+            <crate::Foreign<TestNames> as GetAssocThreadLocal<u8>>::assoc_name(),
+            std::any::type_name::<crate::Foreign<TestNames>>()
+        );
     }
 }
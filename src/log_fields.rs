@@ -0,0 +1,251 @@
+//! Associations as structured key/values of log records.
+//!
+//! A `LogFields` set names the associations that carry ambient context like request ids or
+//! tenants together with the keys they are logged under.  Once installed with `install()`,
+//! a logging backend adapter calls `for_each_current()` for every record it emits and adds
+//! the current threads values, so the context reaches the logs without touching every log
+//! call.  With the `log` crate this is a wrapper around the actual logger:
+//!
+//! ```ignore
+//! impl<L: log::Log> log::Log for Enriched<L> {
+//!     fn log(&self, record: &log::Record) {
+//!         let context = log_fields::format_current();
+//!         self.0.log(
+//!             &record
+//!                 .to_builder()
+//!                 .args(format_args!("{} {}", record.args(), context))
+//!                 .build(),
+//!         )
+//!     }
+//!     // enabled() and flush() forward to self.0
+//! }
+//! ```
+//!
+//! A tracing subscriber layer records them as fields of each event in `on_event()` the
+//! same way.
+
+use crate::{AssocThreadLocal, ThreadLocalHandle};
+use std::fmt::{self, Debug, Display, Write};
+use std::sync::RwLock;
+
+static INSTALLED: RwLock<Option<LogFields>> = RwLock::new(None);
+
+#[derive(Clone, Copy)]
+struct Field {
+    key: &'static str,
+    value: fn() -> Option<String>,
+}
+
+/// A set of associations logged as key/values.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Request;
+/// struct Tenant;
+/// assoc_threadlocal!(Request, Option<u64> = None);
+/// assoc_threadlocal!(Tenant, &'static str = "none");
+///
+/// let fields = LogFields::new()
+///     .with_optional("request_id", Request::handle())
+///     .with("tenant", Tenant::handle());
+///
+/// assert_eq!(fields.format(), "tenant=none");
+/// Request::set_threadlocal(Some(42));
+/// Tenant::set_threadlocal("acme corp");
+/// assert_eq!(fields.format(), "request_id=42 tenant=\"acme corp\"");
+/// ```
+#[derive(Clone, Default)]
+pub struct LogFields {
+    fields: Vec<Field>,
+}
+
+impl LogFields {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the association of 'handle' logged under 'key' with its `Display`
+    /// representation.
+    #[must_use]
+    pub fn with<S, T, TAG>(self, key: &'static str, handle: ThreadLocalHandle<S, T, TAG>) -> Self
+    where
+        S: AssocThreadLocal<T, TAG>,
+        T: Copy + Display,
+    {
+        let _ = handle;
+        self.field(key, || Some(S::get_threadlocal().to_string()))
+    }
+
+    /// Adds the association of 'handle' logged under 'key' with its `Debug`
+    /// representation.
+    #[must_use]
+    pub fn with_debug<S, T, TAG>(
+        self,
+        key: &'static str,
+        handle: ThreadLocalHandle<S, T, TAG>,
+    ) -> Self
+    where
+        S: AssocThreadLocal<T, TAG>,
+        T: Copy + Debug,
+    {
+        let _ = handle;
+        self.field(key, || Some(format!("{:?}", S::get_threadlocal())))
+    }
+
+    /// Adds the optional association of 'handle' logged under 'key', left out of records
+    /// while it is `None`.
+    #[must_use]
+    pub fn with_optional<S, T, TAG>(
+        self,
+        key: &'static str,
+        handle: ThreadLocalHandle<S, Option<T>, TAG>,
+    ) -> Self
+    where
+        S: AssocThreadLocal<Option<T>, TAG>,
+        T: Copy + Display,
+    {
+        let _ = handle;
+        self.field(key, || S::get_threadlocal().map(|value| value.to_string()))
+    }
+
+    fn field(mut self, key: &'static str, value: fn() -> Option<String>) -> Self {
+        self.fields.push(Field { key, value });
+        self
+    }
+
+    /// Returns the number of associations in the set.
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Calls 'f' with each key and the current threads value.
+    pub fn for_each(&self, mut f: impl FnMut(&'static str, &str)) {
+        for field in &self.fields {
+            if let Some(value) = (field.value)() {
+                f(field.key, &value);
+            }
+        }
+    }
+
+    /// Returns the keys with the current threads values.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::with_capacity(self.fields.len());
+        self.for_each(|key, value| fields.push((key, value.to_string())));
+        fields
+    }
+
+    /// Formats the current threads values as space separated 'key=value' pairs, values
+    /// that are empty or contain whitespace, quotes or '=' are quoted.
+    pub fn format(&self) -> String {
+        let mut formatted = String::new();
+        self.for_each(|key, value| {
+            if !formatted.is_empty() {
+                formatted.push(' ');
+            }
+            let quote = value.is_empty()
+                || value
+                    .chars()
+                    .any(|c| c.is_whitespace() || c == '"' || c == '=');
+            let _ = if quote {
+                write!(formatted, "{key}={value:?}")
+            } else {
+                write!(formatted, "{key}={value}")
+            };
+        });
+        formatted
+    }
+}
+
+impl fmt::Debug for LogFields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.fields.iter().map(|field| field.key))
+            .finish()
+    }
+}
+
+/// Installs 'fields' as the process wide set used by logging adapters, replacing the
+/// set installed before.
+pub fn install(fields: LogFields) {
+    *INSTALLED
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(fields);
+}
+
+/// Removes the installed set.
+pub fn uninstall() {
+    *INSTALLED
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+/// Calls 'f' with each key and the current threads value of the installed set, for
+/// logging adapters.  Does nothing when no set is installed.
+pub fn for_each_current(f: impl FnMut(&'static str, &str)) {
+    if let Some(fields) = INSTALLED
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()
+    {
+        fields.for_each(f);
+    }
+}
+
+/// Formats the current threads values of the installed set like `LogFields::format()`,
+/// empty when no set is installed.
+pub fn format_current() -> String {
+    INSTALLED
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .as_ref()
+        .map(LogFields::format)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogFields;
+    use crate::{AssocThreadLocal, SetAssocThreadLocal};
+
+    struct Job;
+    crate::assoc_threadlocal!(Job, Option<u32> = None);
+    crate::assoc_threadlocal!(Job, &'static str = "");
+
+    fn fields() -> LogFields {
+        LogFields::new()
+            .with_optional("job", <Job as AssocThreadLocal<Option<u32>>>::handle())
+            .with_debug("stage", <Job as AssocThreadLocal<&str>>::handle())
+    }
+
+    #[test]
+    fn per_thread_values() {
+        <Job as SetAssocThreadLocal<Option<u32>>>::set_threadlocal(Some(3));
+        assert_eq!(
+            fields().fields(),
+            [("job", String::from("3")), ("stage", String::from("\"\""))]
+        );
+        assert_eq!(
+            std::thread::spawn(|| fields().format()).join().unwrap(),
+            "stage=\"\\\"\\\"\""
+        );
+    }
+
+    #[test]
+    fn installed() {
+        super::install(fields());
+        <Job as SetAssocThreadLocal<&str>>::set_threadlocal("parse");
+        let mut seen = Vec::new();
+        super::for_each_current(|key, value| seen.push(format!("{key}:{value}")));
+        assert_eq!(seen, ["stage:\"parse\""]);
+        assert_eq!(super::format_current(), "stage=\"\\\"parse\\\"\"");
+        super::uninstall();
+        assert_eq!(super::format_current(), "");
+    }
+}
@@ -0,0 +1,329 @@
+//! Per-thread bounded event queues.
+//!
+//! A mailbox collects events posted on its thread until the thread drains them, e.g. from
+//! its event loop.  A `MailboxSender` posts into the mailbox of a thread from other
+//! threads.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Error returned when posting to a full mailbox, gives the event back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full<E>(pub E);
+
+impl<E> fmt::Display for Full<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("mailbox full")
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for Full<E> {}
+
+// events are numbered when posted, draining merges both queues in posting order
+type Queue<E> = VecDeque<(u64, E)>;
+
+// the part of a mailbox shared with its senders
+struct Remote<E> {
+    queue: Mutex<Queue<E>>,
+    sequence: AtomicU64,
+    // the events in both queues, posts from any thread count against the capacity
+    len: AtomicUsize,
+}
+
+impl<E> Remote<E> {
+    fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // reserves room for one event, fails when 'capacity' events are queued
+    fn reserve(&self, capacity: usize) -> bool {
+        self.len
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |len| {
+                (len < capacity).then_some(len + 1)
+            })
+            .is_ok()
+    }
+}
+
+/// The mailbox of one thread, created by the `assoc_mailbox!()` macro.
+pub struct Mailbox<E> {
+    capacity: usize,
+    local: RefCell<Queue<E>>,
+    remote: Arc<Remote<E>>,
+}
+
+impl<E> Mailbox<E> {
+    /// Creates an empty mailbox holding up to 'capacity' events.
+    pub fn new(capacity: usize) -> Self {
+        Mailbox {
+            capacity,
+            local: RefCell::new(VecDeque::new()),
+            remote: Arc::new(Remote {
+                queue: Mutex::new(VecDeque::new()),
+                sequence: AtomicU64::new(0),
+                len: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.remote.len.load(Ordering::Acquire)
+    }
+
+    fn post(&self, event: E) -> Result<(), Full<E>> {
+        if !self.remote.reserve(self.capacity) {
+            return Err(Full(event));
+        }
+        let sequence = self.remote.next_sequence();
+        self.local.borrow_mut().push_back((sequence, event));
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<E> {
+        let mut remote = lock(&self.remote.queue);
+        let mut local = self.local.borrow_mut();
+        let event = match (local.front(), remote.front()) {
+            (Some((local_sequence, _)), Some((remote_sequence, _)))
+                if remote_sequence < local_sequence =>
+            {
+                remote.pop_front()
+            }
+            (Some(_), _) => local.pop_front(),
+            (None, _) => remote.pop_front(),
+        };
+        let (_, event) = event?;
+        self.remote.len.fetch_sub(1, Ordering::AcqRel);
+        Some(event)
+    }
+}
+
+impl<E> fmt::Debug for Mailbox<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mailbox")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+fn lock<E>(queue: &Mutex<Queue<E>>) -> std::sync::MutexGuard<'_, Queue<E>> {
+    queue.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Posts events into the mailbox of another thread.
+/// Obtained with `AssocMailbox::sender()` on the thread owning the mailbox.
+pub struct MailboxSender<E> {
+    capacity: usize,
+    // only the remote part, the local part of the mailbox belongs to its thread
+    remote: Arc<Remote<E>>,
+}
+
+impl<E> MailboxSender<E> {
+    /// Posts 'event' into the mailbox, fails when it is full.
+    pub fn post(&self, event: E) -> Result<(), Full<E>> {
+        if !self.remote.reserve(self.capacity) {
+            return Err(Full(event));
+        }
+        let mut queue = lock(&self.remote.queue);
+        // numbered under the lock, the queue stays sorted
+        queue.push_back((self.remote.next_sequence(), event));
+        Ok(())
+    }
+}
+
+impl<E> Clone for MailboxSender<E> {
+    fn clone(&self) -> Self {
+        MailboxSender {
+            capacity: self.capacity,
+            remote: self.remote.clone(),
+        }
+    }
+}
+
+impl<E> fmt::Debug for MailboxSender<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MailboxSender")
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Associates a per-thread mailbox for events of type E to a type.
+/// Use the `assoc_mailbox!()` macro for implementing this trait on types.
+pub trait AssocMailbox<E>: Sized {
+    /// Returns the associated thread local mailbox of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_mailbox() -> *const Mailbox<E>;
+
+    /// Posts 'event' to the current threads mailbox.
+    fn post(event: E) -> Result<(), Full<E>> {
+        unsafe { (*Self::the_mailbox()).post(event) }
+    }
+
+    /// Calls 'f' with every event in the current threads mailbox in the order they were
+    /// posted, returns the number of events.  Events posted by 'f' are drained as well.
+    fn drain(mut f: impl FnMut(E)) -> usize {
+        let mut drained = 0;
+        // the mailbox is not borrowed while 'f' runs
+        while let Some(event) = unsafe { (*Self::the_mailbox()).pop() } {
+            f(event);
+            drained += 1;
+        }
+        drained
+    }
+
+    /// Returns the number of events in the current threads mailbox.
+    fn mailbox_len() -> usize {
+        unsafe { (*Self::the_mailbox()).len() }
+    }
+
+    /// Returns a sender posting into the current threads mailbox from other threads.
+    fn sender() -> MailboxSender<E> {
+        let mailbox = unsafe { &*Self::the_mailbox() };
+        MailboxSender {
+            capacity: mailbox.capacity,
+            remote: mailbox.remote.clone(),
+        }
+    }
+}
+
+/// Associates a per-thread mailbox to a type.
+///
+///  * 'T' is the type you want have a thread local mailbox associated to
+///  * 'E' is the type of the events
+///  * 'cap' is the number of events a mailbox holds, posting to a full mailbox fails
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Debug, PartialEq)]
+/// enum Event {
+///     Click(u32, u32),
+///     Quit,
+/// }
+///
+/// struct UiEvents;
+/// assoc_mailbox!(UiEvents, Event, cap = 2);
+///
+/// UiEvents::post(Event::Click(1, 2)).unwrap();
+/// UiEvents::post(Event::Quit).unwrap();
+/// assert_eq!(UiEvents::post(Event::Quit), Err(Full(Event::Quit)));
+///
+/// let mut events = Vec::new();
+/// assert_eq!(UiEvents::drain(|event| events.push(event)), 2);
+/// assert_eq!(events, [Event::Click(1, 2), Event::Quit]);
+/// ```
+#[macro_export]
+macro_rules! assoc_mailbox {
+    ($T:ty, $E:ty, cap = $CAP:expr) => {
+        impl $crate::AssocMailbox<$E> for $T {
+            unsafe fn the_mailbox() -> *const $crate::mailbox::Mailbox<$E> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_MAILBOX: (
+                        $crate::mailbox::Mailbox<$E>,
+                        std::marker::PhantomData<$T>,
+                    ) = (
+                        $crate::mailbox::Mailbox::new($CAP),
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_MAILBOX.with(|l| &l.0 as *const $crate::mailbox::Mailbox<$E>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Full;
+    use crate::AssocMailbox;
+
+    struct Jobs;
+    assoc_mailbox!(Jobs, u32, cap = 3);
+
+    #[test]
+    fn drain_reposting() {
+        Jobs::post(1).unwrap();
+        Jobs::post(2).unwrap();
+        let mut seen = Vec::new();
+        let drained = Jobs::drain(|job| {
+            if job == 1 {
+                Jobs::post(10).unwrap();
+            }
+            seen.push(job);
+        });
+        assert_eq!(drained, 3);
+        assert_eq!(seen, [1, 2, 10]);
+        assert_eq!(Jobs::mailbox_len(), 0);
+    }
+
+    #[test]
+    fn per_thread() {
+        for job in 0..3 {
+            Jobs::post(job).unwrap();
+        }
+        assert_eq!(Jobs::post(3), Err(Full(3)));
+        assert_eq!(std::thread::spawn(|| Jobs::post(3)).join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn sender() {
+        let sender = Jobs::sender();
+        std::thread::spawn(move || {
+            for job in 0..4 {
+                let _ = sender.post(job);
+            }
+        })
+        .join()
+        .unwrap();
+        Jobs::post(7).unwrap_err();
+        let mut seen = Vec::new();
+        Jobs::drain(|job| seen.push(job));
+        assert_eq!(seen, [0, 1, 2]);
+    }
+
+    struct Ordered;
+    assoc_mailbox!(Ordered, &'static str, cap = 8);
+
+    #[test]
+    fn shared_capacity() {
+        struct Bounded;
+        assoc_mailbox!(Bounded, u32, cap = 2);
+
+        let sender = Bounded::sender();
+        Bounded::post(1).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                assert_eq!(sender.post(2), Ok(()));
+                assert_eq!(sender.post(3), Err(Full(3)));
+            });
+        });
+        assert_eq!(Bounded::post(4), Err(Full(4)));
+        assert_eq!(Bounded::mailbox_len(), 2);
+        let mut seen = Vec::new();
+        Bounded::drain(|event| seen.push(event));
+        assert_eq!(seen, [1, 2]);
+        assert_eq!(sender.post(5), Ok(()));
+    }
+
+    #[test]
+    fn posting_order() {
+        let sender = Ordered::sender();
+        Ordered::post("local 1").unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| sender.post("remote 1").unwrap());
+        });
+        Ordered::post("local 2").unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| sender.post("remote 2").unwrap());
+        });
+        let mut seen = Vec::new();
+        Ordered::drain(|event| seen.push(event));
+        assert_eq!(seen, ["local 1", "remote 1", "local 2", "remote 2"]);
+    }
+}
@@ -0,0 +1,122 @@
+//! Process wide authoritative values mirrored into the thread local associations.
+//!
+//! Associations defined with `assoc_threadlocal!(T, TARGET = INIT, static)` additionally
+//! have a global value.  Setting it bumps a version, each thread refreshes its own copy
+//! on the next read when it sees a new version.  Thread local sets stay in effect until
+//! the global value changes again.
+
+use crate::AssocThreadLocal;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// The global value and its version, created by the `assoc_threadlocal!()` macro.
+#[derive(Debug)]
+pub struct GlobalMirror<T> {
+    // 'None' until set, threads start with INIT then
+    value: RwLock<Option<T>>,
+    version: AtomicU64,
+}
+
+impl<T> GlobalMirror<T> {
+    #[doc(hidden)]
+    pub const fn new() -> Self {
+        GlobalMirror {
+            value: RwLock::new(None),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns how often the global value was set.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+}
+
+/// Access to the global value of an association.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Config;
+/// assoc_threadlocal!(Config, u32 = 1, static);
+///
+/// Config::set_global(5);
+/// std::thread::spawn(|| {
+///     assert_eq!(Config::get_threadlocal(), 5);
+///     // only changes this threads copy
+///     Config::set_threadlocal(6);
+/// })
+/// .join()
+/// .unwrap();
+/// assert_eq!(Config::get_threadlocal(), 5);
+/// ```
+pub trait AssocGlobalMirror<T: Copy + 'static, TAG = ()>: AssocThreadLocal<T, TAG> {
+    /// Returns the global value of the association.
+    fn the_global() -> &'static GlobalMirror<T>;
+
+    /// Returns the version of the global value the current thread has seen.
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_seen_version() -> *const Cell<u64>;
+
+    /// Sets the global value, all threads pick it up on their next read.
+    fn set_global(value: T) {
+        let global = Self::the_global();
+        *global.value.write().unwrap_or_else(|e| e.into_inner()) = Some(value);
+        global.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns the global value, `None` when it was never set.
+    fn get_global() -> Option<T> {
+        *Self::the_global()
+            .value
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Copies the global value into the current threads association when it changed
+    /// since the last refresh.  Called by the readers of the association.
+    #[inline]
+    fn refresh_threadlocal() {
+        let version = Self::the_global().version();
+        let seen = unsafe { &*Self::the_seen_version() };
+        if seen.get() != version {
+            seen.set(version);
+            if let Some(value) = Self::get_global() {
+                Self::set_threadlocal(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocGlobalMirror, GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct Limits;
+    crate::assoc_threadlocal!(Limits, u64 = 100, static);
+
+    #[test]
+    fn mirror() {
+        assert_eq!(Limits::get_global(), None);
+        Limits::set_threadlocal(1);
+        assert_eq!(Limits::get_threadlocal(), 1);
+
+        Limits::set_global(200);
+        let (value, generation) = Limits::get_versioned();
+        assert_eq!(value, 200);
+        assert_eq!(Limits::get_versioned(), (200, generation));
+
+        std::thread::spawn(|| {
+            assert_eq!(Limits::get_threadlocal(), 200);
+            Limits::set_threadlocal(3);
+            assert_eq!(Limits::get_threadlocal(), 3);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(Limits::get_threadlocal(), 200);
+        assert_eq!(Limits::the_global().version(), 1);
+    }
+}
@@ -0,0 +1,464 @@
+//! Reading and updating several associations of a type at once.
+
+use crate::{AssocThreadLocal, GetAssocThreadLocal, SetAssocThreadLocal};
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A tuple of target types that are all associated to 'S' with tag 'TAG'.
+/// Implemented for tuples of up to 8 elements.
+pub trait ThreadLocalTuple<S: ?Sized, TAG = ()>: Sized {
+    /// Reads all associations.
+    fn get_all() -> Self;
+
+    /// Writes all associations.
+    fn set_all(self);
+}
+
+/// Tuples of targets whose values can be compared, implemented for tuples of up to 8
+/// targets that are `PartialEq` and `Debug`.
+pub trait DiffTuple<S: ?Sized, TAG = ()>: ThreadLocalTuple<S, TAG> {
+    /// Returns the associations whose values differ between 'self' and 'other'.
+    fn diff_all(&self, other: &Self) -> Vec<ChangedAssoc>;
+}
+
+/// An association whose value differs between two snapshots, returned by
+/// `Snapshot::diff()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedAssoc {
+    /// The position of the target in the tuple of the snapshot.
+    pub index: usize,
+    /// The name of the type the value is associated to.
+    pub implementor: &'static str,
+    /// The name of the tag.
+    pub tag: &'static str,
+    /// The name of the target type.
+    pub target: &'static str,
+    /// The `Debug` representation of the value in the first snapshot.
+    pub before: String,
+    /// The `Debug` representation of the value in the other snapshot.
+    pub after: String,
+}
+
+impl fmt::Display for ChangedAssoc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {}: {} -> {}",
+            self.target, self.implementor, self.before, self.after
+        )
+    }
+}
+
+macro_rules! impl_threadlocal_tuple {
+    ($($A:ident $I:tt),+) => {
+        impl<S: ?Sized, TAG, $($A: Copy),+> ThreadLocalTuple<S, TAG> for ($($A,)+)
+        where
+            $(S: AssocThreadLocal<$A, TAG>,)+
+        {
+            fn get_all() -> Self {
+                ($(<S as GetAssocThreadLocal<$A, TAG>>::get_threadlocal(),)+)
+            }
+
+            #[allow(non_snake_case)]
+            fn set_all(self) {
+                let ($($A,)+) = self;
+                $(<S as SetAssocThreadLocal<$A, TAG>>::set_threadlocal($A);)+
+            }
+        }
+
+        impl<S: ?Sized, TAG, $($A: Copy + PartialEq + fmt::Debug),+> DiffTuple<S, TAG> for ($($A,)+)
+        where
+            $(S: AssocThreadLocal<$A, TAG>,)+
+        {
+            fn diff_all(&self, other: &Self) -> Vec<ChangedAssoc> {
+                let mut changed = Vec::new();
+                $(
+                    if self.$I != other.$I {
+                        changed.push(ChangedAssoc {
+                            index: $I,
+                            implementor: <S as GetAssocThreadLocal<$A, TAG>>::assoc_name(),
+                            tag: <S as GetAssocThreadLocal<$A, TAG>>::tag_type_name(),
+                            target: <S as GetAssocThreadLocal<$A, TAG>>::target_type_name(),
+                            before: format!("{:?}", self.$I),
+                            after: format!("{:?}", other.$I),
+                        });
+                    }
+                )+
+                changed
+            }
+        }
+    };
+}
+
+impl_threadlocal_tuple!(A 0);
+impl_threadlocal_tuple!(A 0, B 1);
+impl_threadlocal_tuple!(A 0, B 1, C 2);
+impl_threadlocal_tuple!(A 0, B 1, C 2, D 3);
+impl_threadlocal_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_threadlocal_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_threadlocal_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_threadlocal_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+/// Accessors for several associations of a type at once, implemented for all types.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Example;
+/// assoc_threadlocal!(Example, u32 = 3);
+/// assoc_threadlocal!(Example, &'static str = "debug");
+///
+/// let line = Example::with_threadlocals::<(u32, &'static str), _>(|(level, mode)| {
+///     format!("{mode}:{level}")
+/// });
+/// assert_eq!(line, "debug:3");
+/// ```
+pub trait AssocThreadLocals {
+    /// Reads the associations of all targets in 'Tup' and passes them to 'f'.
+    fn with_threadlocals<Tup: ThreadLocalTuple<Self>, R>(f: impl FnOnce(Tup) -> R) -> R {
+        f(Tup::get_all())
+    }
+
+    /// Reads the associations of all targets in 'Tup' with tag 'TAG' and passes them to 'f'.
+    fn with_threadlocals_tagged<Tup: ThreadLocalTuple<Self, TAG>, TAG, R>(
+        f: impl FnOnce(Tup) -> R,
+    ) -> R {
+        f(Tup::get_all())
+    }
+
+    /// Reads the associations of all targets in 'Tup'.
+    fn get_threadlocals<Tup: ThreadLocalTuple<Self>>() -> Tup {
+        Tup::get_all()
+    }
+
+    /// Writes the associations of all targets in 'Tup'.
+    fn set_threadlocals<Tup: ThreadLocalTuple<Self>>(values: Tup) {
+        values.set_all()
+    }
+
+    /// Captures the current threads values of the associations of all targets in 'Tup'.
+    /// The snapshot is `Send` when the targets are and can be applied on another thread.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Request;
+    /// assoc_threadlocal!(Request, u64 = 0);
+    /// assoc_threadlocal!(Request, &'static str = "anonymous");
+    ///
+    /// Request::set_threadlocals((42u64, "alice"));
+    /// let snapshot = Request::capture_threadlocals::<(u64, &str)>();
+    /// std::thread::spawn(move || {
+    ///     {
+    ///         let _applied = snapshot.apply_scoped();
+    ///         assert_eq!(Request::get_threadlocals::<(u64, &str)>(), (42, "alice"));
+    ///     }
+    ///     assert_eq!(Request::get_threadlocals::<(u64, &str)>(), (0, "anonymous"));
+    /// })
+    /// .join()
+    /// .unwrap();
+    /// ```
+    fn capture_threadlocals<Tup: ThreadLocalTuple<Self>>() -> Snapshot<Self, Tup> {
+        Snapshot::capture()
+    }
+
+    /// Captures the current threads values of the associations of all targets in 'Tup'
+    /// with tag 'TAG'.
+    fn capture_threadlocals_tagged<Tup: ThreadLocalTuple<Self, TAG>, TAG>(
+    ) -> Snapshot<Self, Tup, TAG> {
+        Snapshot::capture()
+    }
+
+    /// Stages changes to associations in a `Transaction` and applies them all when 'f'
+    /// returns `Ok`, none of them when it returns `Err`.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Session;
+    /// assoc_threadlocal!(Session, u32 = 0);
+    /// assoc_threadlocal!(Session, &'static str = "anonymous");
+    ///
+    /// let failed: Result<(), &str> = Session::transaction(|tx| {
+    ///     tx.set::<u32>(5);
+    ///     Err("login failed")?;
+    ///     tx.set::<&str>("user");
+    ///     Ok(())
+    /// });
+    /// assert!(failed.is_err());
+    /// assert_eq!(Session::get_threadlocals::<(u32, &str)>(), (0, "anonymous"));
+    ///
+    /// Session::transaction(|tx| {
+    ///     tx.set::<u32>(5);
+    ///     tx.set::<&str>("user");
+    ///     Ok::<_, ()>(())
+    /// })
+    /// .unwrap();
+    /// assert_eq!(Session::get_threadlocals::<(u32, &str)>(), (5, "user"));
+    /// ```
+    fn transaction<'a, R, E>(
+        f: impl FnOnce(&mut Transaction<'a, Self>) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let mut tx = Transaction {
+            staged: Vec::new(),
+            _marker: PhantomData,
+        };
+        let result = f(&mut tx)?;
+        tx.staged.into_iter().for_each(|apply| apply());
+        Ok(result)
+    }
+}
+
+impl<S: ?Sized> AssocThreadLocals for S {}
+
+/// Values of several associations of 'S' captured by
+/// `AssocThreadLocals::capture_threadlocals()`, to be applied on the same or another
+/// thread.
+pub struct Snapshot<S: ?Sized, Tup, TAG = ()> {
+    values: Tup,
+    _marker: PhantomData<fn() -> (*const S, TAG)>,
+}
+
+impl<S: ?Sized, Tup: ThreadLocalTuple<S, TAG>, TAG> Snapshot<S, Tup, TAG> {
+    /// Captures the current threads values.
+    pub fn capture() -> Self {
+        Self::from_values(Tup::get_all())
+    }
+
+    /// Creates a snapshot of explicitly given values.
+    pub fn from_values(values: Tup) -> Self {
+        Snapshot {
+            values,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the captured values.
+    pub fn values(&self) -> Tup
+    where
+        Tup: Copy,
+    {
+        self.values
+    }
+
+    /// Sets the captured values on the current thread.
+    pub fn apply(&self)
+    where
+        Tup: Copy,
+    {
+        self.values.set_all()
+    }
+
+    /// Sets the captured values on the current thread until the returned guard is dropped,
+    /// which restores the values from before.
+    pub fn apply_scoped(&self) -> SnapshotGuard<S, Tup, TAG>
+    where
+        Tup: Copy,
+    {
+        let previous = Tup::get_all();
+        self.apply();
+        SnapshotGuard {
+            previous: Some(previous),
+            _marker: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<S: ?Sized, Tup: DiffTuple<S, TAG>, TAG> Snapshot<S, Tup, TAG> {
+    /// Returns the associations whose values differ between this and the 'other'
+    /// snapshot, in the order of the tuple.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Logger;
+    /// assoc_threadlocal!(Logger, u8 = 1);
+    /// assoc_threadlocal!(Logger, bool = false);
+    ///
+    /// fn quiet() {
+    ///     Logger::set_threadlocal(0u8);
+    /// }
+    ///
+    /// let before = Logger::capture_threadlocals::<(u8, bool)>();
+    /// quiet();
+    /// let changed = before.diff(&Logger::capture_threadlocals());
+    /// assert_eq!(changed.len(), 1);
+    /// assert_eq!(changed[0].to_string(), "u8 of Logger: 1 -> 0");
+    /// ```
+    pub fn diff(&self, other: &Self) -> Vec<ChangedAssoc> {
+        self.values.diff_all(&other.values)
+    }
+}
+
+impl<S: ?Sized, Tup: Clone, TAG> Clone for Snapshot<S, Tup, TAG> {
+    fn clone(&self) -> Self {
+        Snapshot {
+            values: self.values.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S: ?Sized, Tup: Copy, TAG> Copy for Snapshot<S, Tup, TAG> {}
+
+impl<S: ?Sized, Tup: fmt::Debug, TAG> fmt::Debug for Snapshot<S, Tup, TAG> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Snapshot").field(&self.values).finish()
+    }
+}
+
+/// Restores the values from before `Snapshot::apply_scoped()` when dropped.
+#[must_use = "the values are restored immediately when the guard is not kept"]
+pub struct SnapshotGuard<S: ?Sized, Tup: ThreadLocalTuple<S, TAG>, TAG = ()> {
+    previous: Option<Tup>,
+    _marker: PhantomData<fn() -> (*const S, TAG)>,
+    // the values must be restored on the thread that applied them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<S: ?Sized, Tup: ThreadLocalTuple<S, TAG>, TAG> Drop for SnapshotGuard<S, Tup, TAG> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            previous.set_all();
+        }
+    }
+}
+
+/// Changes to associations of 'S' staged by `AssocThreadLocals::transaction()`.
+/// Reads within the transaction still return the values from before it.
+pub struct Transaction<'a, S: ?Sized> {
+    staged: Vec<Box<dyn FnOnce() + 'a>>,
+    _marker: PhantomData<fn() -> *const S>,
+}
+
+impl<'a, S: ?Sized> Transaction<'a, S> {
+    /// Stages setting the association of type 'T'.
+    pub fn set<T: Copy + 'a>(&mut self, value: T)
+    where
+        S: AssocThreadLocal<T>,
+    {
+        self.set_tagged::<T, ()>(value)
+    }
+
+    /// Stages setting the association of type 'T' with tag 'TAG'.
+    pub fn set_tagged<T: Copy + 'a, TAG>(&mut self, value: T)
+    where
+        S: AssocThreadLocal<T, TAG>,
+    {
+        self.staged
+            .push(Box::new(move || S::set_threadlocal(value)));
+    }
+
+    /// Returns the number of staged changes.
+    pub fn len(&self) -> usize {
+        self.staged.len()
+    }
+
+    /// Returns whether no changes are staged.
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocThreadLocals;
+
+    struct Ambient;
+    struct Other;
+    crate::assoc_threadlocal!(Ambient, u8 = 1);
+    crate::assoc_threadlocal!(Ambient, char = 'a');
+    crate::assoc_threadlocal!(Ambient, bool = false);
+    crate::assoc_threadlocal!(Other:Ambient, u8 = 10);
+    crate::assoc_threadlocal!(Other:Ambient, char = 'z');
+
+    #[test]
+    fn get_set_all() {
+        Ambient::set_threadlocals((2u8, 'b', true));
+        assert_eq!(
+            Ambient::get_threadlocals::<(u8, char, bool)>(),
+            (2, 'b', true)
+        );
+    }
+
+    #[test]
+    fn snapshot() {
+        Ambient::set_threadlocals((3u8, 'c'));
+        assert_eq!(
+            Ambient::capture_threadlocals_tagged::<(u8, char), Other>().values(),
+            (10, 'z')
+        );
+        let snapshot = super::Snapshot::<Ambient, _, Other>::from_values((11u8, 'y'));
+        let plain = Ambient::capture_threadlocals::<(u8, char)>();
+        let applied = std::thread::spawn(move || {
+            let _scoped = snapshot.apply_scoped();
+            plain.apply();
+            (
+                Ambient::get_threadlocals::<(u8, char)>(),
+                Ambient::with_threadlocals_tagged::<(u8, char), Other, _>(|tup| tup),
+            )
+        })
+        .join()
+        .unwrap();
+        assert_eq!(applied, ((3, 'c'), (11, 'y')));
+
+        {
+            let _scoped = super::Snapshot::<Ambient, _>::from_values((7u8, 'x')).apply_scoped();
+            assert_eq!(Ambient::get_threadlocals::<(u8, char)>(), (7, 'x'));
+        }
+        assert_eq!(Ambient::get_threadlocals::<(u8, char)>(), (3, 'c'));
+    }
+
+    #[test]
+    fn diff() {
+        use super::{ChangedAssoc, Snapshot};
+
+        let before = Snapshot::<Ambient, _, Other>::from_values((1u8, 'a'));
+        let after = Snapshot::<Ambient, _, Other>::from_values((1u8, 'b'));
+        assert_eq!(
+            before.diff(&after),
+            [ChangedAssoc {
+                index: 1,
+                implementor: "Ambient",
+                tag: "Other",
+                target: "char",
+                before: String::from("'a'"),
+                after: String::from("'b'"),
+            }]
+        );
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn transaction() {
+        Ambient::set_threadlocals((1u8, 'a'));
+        let result: Result<(), ()> = Ambient::transaction(|tx| {
+            tx.set(5u8);
+            tx.set_tagged::<char, Other>('q');
+            assert_eq!(tx.len(), 2);
+            Err(())
+        });
+        assert!(result.is_err());
+        assert_eq!(Ambient::get_threadlocals::<(u8, char)>(), (1, 'a'));
+
+        let len = Ambient::transaction(|tx| {
+            tx.set(5u8);
+            tx.set_tagged::<char, Other>('q');
+            Ok::<_, ()>(tx.len())
+        });
+        assert_eq!(len, Ok(2));
+        assert_eq!(Ambient::get_threadlocals::<(u8, char)>(), (5, 'a'));
+        assert_eq!(
+            Ambient::with_threadlocals_tagged::<(u8, char), Other, _>(|tup| tup),
+            (10, 'q')
+        );
+    }
+
+    #[test]
+    fn tagged() {
+        let sum =
+            Ambient::with_threadlocals_tagged::<(u8, char), Other, _>(|(n, c)| n as u32 + c as u32);
+        assert_eq!(sum, 10 + 'z' as u32);
+    }
+}
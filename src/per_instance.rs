@@ -0,0 +1,147 @@
+//! Thread local values per object instance.
+//!
+//! The associations of this crate are per type.  Objects that are shared between threads
+//! sometimes need their own thread affine state, e.g. caches.  `PerInstance` holds one
+//! lazily created value for each thread that accessed it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+
+/// One value of type T per accessing thread, owned by an object instance.
+///
+/// Values of threads that exited are kept until the `PerInstance` is dropped or
+/// cleared with `clear()`.
+pub struct PerInstance<T> {
+    init: fn() -> T,
+    // values are boxed to keep their address stable while the map grows
+    values: Mutex<HashMap<ThreadId, Box<T>>>,
+}
+
+// SAFETY: a value is only ever referenced by the thread that created it, other threads
+// only get at it through '&mut self', thus only moving (T: Send) is required.
+unsafe impl<T: Send> Sync for PerInstance<T> {}
+
+impl<T> PerInstance<T> {
+    /// Creates an empty instance, values are created by 'init' on first access per thread.
+    pub fn new(init: fn() -> T) -> Self {
+        PerInstance {
+            init,
+            values: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the value of the current thread.
+    pub fn get_local(&self) -> &T {
+        let mut values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        let value: *const T = &**values
+            .entry(thread::current().id())
+            .or_insert_with(|| Box::new((self.init)()));
+        // SAFETY: boxes are only removed through '&mut self'
+        unsafe { &*value }
+    }
+
+    /// Returns the value of the current thread if it was created already.
+    pub fn try_get_local(&self) -> Option<&T> {
+        let values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        let value: *const T = &**values.get(&thread::current().id())?;
+        // SAFETY: boxes are only removed through '&mut self'
+        Some(unsafe { &*value })
+    }
+
+    /// Iterates mutably over the values of all threads.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.values
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .values_mut()
+            .map(|v| &mut **v)
+    }
+
+    /// Drops the values of all threads.
+    pub fn clear(&mut self) {
+        self.values
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PerInstance<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PerInstance")
+            .field("local", &self.try_get_local())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Gives instances access to their own thread local values.
+///
+/// ```
+/// use assoc_threadlocal::*;
+/// use std::cell::Cell;
+/// use std::sync::Arc;
+///
+/// struct Service {
+///     calls: PerInstance<Cell<u32>>,
+/// }
+///
+/// impl AssocThreadLocalPerInstance<Cell<u32>> for Service {
+///     fn per_instance(&self) -> &PerInstance<Cell<u32>> {
+///         &self.calls
+///     }
+/// }
+///
+/// let service = Arc::new(Service { calls: PerInstance::new(|| Cell::new(0)) });
+/// service.get_local().set(5);
+///
+/// let shared = service.clone();
+/// std::thread::spawn(move || assert_eq!(shared.get_local().get(), 0)).join().unwrap();
+/// assert_eq!(service.get_local().get(), 5);
+/// ```
+pub trait AssocThreadLocalPerInstance<T> {
+    /// Returns the per thread storage of this instance.
+    fn per_instance(&self) -> &PerInstance<T>;
+
+    /// Returns this instances value of the current thread.
+    fn get_local(&self) -> &T {
+        self.per_instance().get_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PerInstance;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    #[test]
+    fn separate_per_thread() {
+        let cache = Arc::new(PerInstance::new(|| RefCell::new(Vec::new())));
+        cache.get_local().borrow_mut().push(1);
+        let other = cache.clone();
+        std::thread::spawn(move || {
+            assert!(other.try_get_local().is_none());
+            other.get_local().borrow_mut().push(2);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(*cache.get_local().borrow(), [1]);
+
+        let mut cache = Arc::try_unwrap(cache).unwrap();
+        let mut all: Vec<i32> = cache.iter_mut().flat_map(|v| v.get_mut().clone()).collect();
+        all.sort();
+        assert_eq!(all, [1, 2]);
+        cache.clear();
+        assert!(cache.try_get_local().is_none());
+    }
+
+    #[test]
+    fn separate_per_instance() {
+        let a = PerInstance::new(|| std::cell::Cell::new(0));
+        let b = PerInstance::new(|| std::cell::Cell::new(0));
+        a.get_local().set(1);
+        assert_eq!(b.get_local().get(), 0);
+    }
+}
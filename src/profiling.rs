@@ -0,0 +1,171 @@
+//! Per-thread read and write counters of associations.
+//!
+//! With the `profiling` feature every get and set of an association generated by
+//! `assoc_threadlocal!()` is counted on the current thread.  `access_stats()` lists the
+//! counts, so associations that are hammered on hot paths can be found and cached or
+//! restructured.  Without the feature counting compiles to nothing and no stats are
+//! collected.
+
+/// Whether accesses are counted, set by the `profiling` feature.
+pub const ENABLED: bool = cfg!(feature = "profiling");
+
+/// The access counts of one association on the current thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessStats {
+    /// Type name of the implementor.
+    pub implementor: &'static str,
+    /// Type name of the tag.
+    pub tag: &'static str,
+    /// Type name of the target.
+    pub target: &'static str,
+    /// How often the value was read.
+    pub reads: u64,
+    /// How often the value was set.
+    pub writes: u64,
+}
+
+#[cfg(feature = "profiling")]
+mod counters {
+    use super::AccessStats;
+    use std::any::{type_name, TypeId};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::marker::PhantomData;
+
+    std::thread_local!(
+        static COUNTERS: RefCell<HashMap<TypeId, AccessStats>> = RefCell::new(HashMap::new());
+    );
+
+    pub(super) fn count<T: ?Sized + 'static, TAG: 'static, TARGET: 'static>(
+        reads: u64,
+        writes: u64,
+    ) {
+        // accesses from destructors of other thread locals and the ones made while counting,
+        // e.g. by the allocation counter when the map grows, are not counted
+        let _ = COUNTERS.try_with(|counters| {
+            let Ok(mut counters) = counters.try_borrow_mut() else {
+                return;
+            };
+            let stats = counters
+                .entry(TypeId::of::<(PhantomData<T>, TAG, TARGET)>())
+                .or_insert_with(|| AccessStats {
+                    implementor: type_name::<T>(),
+                    tag: type_name::<TAG>(),
+                    target: type_name::<TARGET>(),
+                    reads: 0,
+                    writes: 0,
+                });
+            stats.reads += reads;
+            stats.writes += writes;
+        });
+    }
+
+    pub(super) fn stats() -> Vec<AccessStats> {
+        COUNTERS.with(|counters| counters.borrow().values().copied().collect())
+    }
+
+    pub(super) fn reset() {
+        COUNTERS.with(|counters| counters.borrow_mut().clear())
+    }
+}
+
+#[doc(hidden)]
+#[inline(always)]
+#[allow(clippy::extra_unused_type_parameters)]
+pub fn count_read<T: ?Sized + 'static, TAG: 'static, TARGET: 'static>() {
+    #[cfg(feature = "profiling")]
+    counters::count::<T, TAG, TARGET>(1, 0);
+}
+
+#[doc(hidden)]
+#[inline(always)]
+#[allow(clippy::extra_unused_type_parameters)]
+pub fn count_write<T: ?Sized + 'static, TAG: 'static, TARGET: 'static>() {
+    #[cfg(feature = "profiling")]
+    counters::count::<T, TAG, TARGET>(0, 1);
+}
+
+/// Returns the access counts of all associations accessed on the current thread, the most
+/// accessed first.  Always empty without the `profiling` feature.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Locale;
+/// assoc_threadlocal!(Locale, &'static str = "en");
+///
+/// for _ in 0..3 {
+///     Locale::get_threadlocal();
+/// }
+/// Locale::set_threadlocal("de");
+///
+/// let stats = profiling::access_stats();
+/// if profiling::ENABLED {
+///     assert_eq!((stats[0].reads, stats[0].writes), (3, 1));
+///     assert!(stats[0].implementor.ends_with("Locale"));
+/// } else {
+///     assert!(stats.is_empty());
+/// }
+/// ```
+pub fn access_stats() -> Vec<AccessStats> {
+    #[cfg(feature = "profiling")]
+    {
+        let mut stats = counters::stats();
+        stats.sort_by(|a, b| {
+            (b.reads + b.writes)
+                .cmp(&(a.reads + a.writes))
+                .then_with(|| a.implementor.cmp(b.implementor))
+        });
+        stats
+    }
+    #[cfg(not(feature = "profiling"))]
+    Vec::new()
+}
+
+/// Clears the access counts of the current thread, e.g. after warming up.
+pub fn reset_access_stats() {
+    #[cfg(feature = "profiling")]
+    counters::reset();
+}
+
+#[cfg(all(test, feature = "profiling"))]
+mod tests {
+    use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct Hot;
+    struct Cold;
+    crate::assoc_threadlocal!(Hot, u32 = 0);
+    crate::assoc_threadlocal!(Hot, u8 = const 0);
+    crate::assoc_threadlocal!(Cold, u32 = 0, strict);
+
+    fn counts(stats: &[super::AccessStats], implementor: &str, target: &str) -> (u64, u64) {
+        stats
+            .iter()
+            .find(|stats| stats.implementor.ends_with(implementor) && stats.target == target)
+            .map_or((0, 0), |stats| (stats.reads, stats.writes))
+    }
+
+    #[test]
+    fn per_thread_counts() {
+        super::reset_access_stats();
+        for value in 0..10 {
+            let current = <Hot as GetAssocThreadLocal<u32>>::get_threadlocal();
+            <Hot as SetAssocThreadLocal<u32>>::set_threadlocal(current + value);
+        }
+        <Hot as SetAssocThreadLocal<u8>>::set_threadlocal(1);
+        <Hot as GetAssocThreadLocal<u8>>::get_threadlocal();
+        Cold::set_threadlocal(1);
+
+        let stats = super::access_stats();
+        assert!(stats[0].implementor.ends_with("Hot"));
+        assert_eq!(counts(&stats, "Hot", "u32"), (10, 10));
+        assert_eq!(counts(&stats, "Hot", "u8"), (1, 1));
+        assert_eq!(counts(&stats, "Cold", "u32"), (0, 1));
+        assert!(std::thread::spawn(super::access_stats)
+            .join()
+            .unwrap()
+            .is_empty());
+        super::reset_access_stats();
+        assert!(super::access_stats().is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+//! Prometheus text exposition of published values (requires the `reporter` feature).
+//!
+//! Renders the registered associations with integer or floating point targets, so a
+//! `/metrics` endpoint only has to return the rendered text.  Every association is one
+//! series of a gauge family summed over all threads, per-thread series can be added.
+//!
+//! Thread local values can only be read by their own thread, the registry alone can not
+//! reach them.  What is rendered are the values threads recorded with `reporter::publish()`,
+//! threads that never publish are not part of the metrics.  Threads call it from time to
+//! time, e.g. between jobs, the metrics show the values of their latest publish.  Integer
+//! targets are summed exactly as 128 bit integers, saturating at their bounds.
+
+use crate::reporter::{snapshot, Snapshot};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const NUMERIC: &[&str] = &[
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "f32",
+    "f64",
+];
+
+// (implementor, tag, target) -> [(thread, value)]
+type Series<'a> = BTreeMap<(&'a str, &'a str, &'a str), Vec<(String, Number)>>;
+
+// a published value, integers are kept exact
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Unsigned(u128),
+    Signed(i128),
+    Float(f64),
+}
+
+impl Number {
+    fn parse(target: &str, value: &str) -> Option<Number> {
+        match target.as_bytes()[0] {
+            b'u' => value.parse().ok().map(Number::Unsigned),
+            b'i' => value.parse().ok().map(Number::Signed),
+            _ => value.parse().ok().map(Number::Float),
+        }
+    }
+
+    // all values of a series have the same target and thus the same variant
+    fn sum(values: impl Iterator<Item = Number>) -> Number {
+        values
+            .reduce(|sum, value| match (sum, value) {
+                (Number::Unsigned(a), Number::Unsigned(b)) => Number::Unsigned(a.saturating_add(b)),
+                (Number::Signed(a), Number::Signed(b)) => Number::Signed(a.saturating_add(b)),
+                (a, b) => Number::Float(a.as_f64() + b.as_f64()),
+            })
+            .unwrap_or(Number::Unsigned(0))
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Unsigned(value) => value as f64,
+            Number::Signed(value) => value as f64,
+            Number::Float(value) => value,
+        }
+    }
+}
+
+/// Options for `render_prometheus_with()`.
+#[derive(Debug, Clone)]
+pub struct PrometheusOptions {
+    prefix: String,
+    per_thread: bool,
+}
+
+impl PrometheusOptions {
+    /// Creates options rendering the summed series with the 'assoc_threadlocal' prefix.
+    pub fn new() -> Self {
+        PrometheusOptions {
+            prefix: String::from("assoc_threadlocal"),
+            per_thread: false,
+        }
+    }
+
+    /// Sets the prefix of the metric family names.
+    #[must_use]
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Sets whether a series per thread is rendered in addition to the sums.
+    #[must_use]
+    pub fn per_thread(mut self, per_thread: bool) -> Self {
+        self.per_thread = per_thread;
+        self
+    }
+}
+
+impl Default for PrometheusOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Appends the published numeric values summed over all threads to 'out'.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Requests;
+/// assoc_threadlocal!(Requests, u64 = 0);
+///
+/// for handled in [3, 4] {
+///     std::thread::spawn(move || {
+///         Requests::set_threadlocal(handled);
+///         reporter::publish();
+///         // keep the thread alive until its values were rendered
+///         std::thread::park();
+///     });
+/// }
+/// # while reporter::snapshot().threads.len() < 2 { std::thread::yield_now() }
+///
+/// let mut metrics = String::new();
+/// prometheus::render_prometheus(&mut metrics);
+/// assert!(metrics.contains("# TYPE assoc_threadlocal_value gauge\n"));
+/// assert!(metrics.contains("::Requests\",tag=\"()\",target=\"u64\"} 7\n"));
+/// ```
+pub fn render_prometheus(out: &mut String) {
+    render_prometheus_with(out, &PrometheusOptions::new())
+}
+
+/// Appends the published numeric values to 'out' as configured by 'options'.
+pub fn render_prometheus_with(out: &mut String, options: &PrometheusOptions) {
+    render_snapshot(out, &snapshot(), options)
+}
+
+/// Appends the numeric values of 'snapshot' to 'out' as configured by 'options'.
+pub fn render_snapshot(out: &mut String, snapshot: &Snapshot, options: &PrometheusOptions) {
+    // sorted for a stable output
+    let mut series: Series = BTreeMap::new();
+    for thread in &snapshot.threads {
+        // pool threads share names, the id keeps their series apart
+        let id = format!("{:?}", thread.thread);
+        let id = id
+            .strip_prefix("ThreadId(")
+            .and_then(|id| id.strip_suffix(')'))
+            .unwrap_or(&id);
+        let name = match &thread.name {
+            Some(name) => format!("{name}#{id}"),
+            None => format!("#{id}"),
+        };
+        for value in &thread.values {
+            if !NUMERIC.contains(&value.target) {
+                continue;
+            }
+            if let Some(number) = value
+                .value
+                .as_deref()
+                .and_then(|v| Number::parse(value.target, v))
+            {
+                series
+                    .entry((value.implementor, value.tag, value.target))
+                    .or_default()
+                    .push((name.clone(), number));
+            }
+        }
+    }
+    if series.is_empty() {
+        return;
+    }
+
+    let prefix = &options.prefix;
+    let _ = writeln!(
+        out,
+        "# HELP {prefix}_value Published values of thread local associations summed over all threads."
+    );
+    let _ = writeln!(out, "# TYPE {prefix}_value gauge");
+    for ((implementor, tag, target), values) in &series {
+        let sum = Number::sum(values.iter().map(|(_, value)| *value));
+        let _ = writeln!(
+            out,
+            "{prefix}_value{{{}}} {}",
+            labels(implementor, tag, target, None),
+            number(sum)
+        );
+    }
+
+    if options.per_thread {
+        let _ = writeln!(
+            out,
+            "# HELP {prefix}_thread_value Published values of thread local associations per thread."
+        );
+        let _ = writeln!(out, "# TYPE {prefix}_thread_value gauge");
+        for ((implementor, tag, target), values) in &series {
+            for (thread, value) in values {
+                let _ = writeln!(
+                    out,
+                    "{prefix}_thread_value{{{}}} {}",
+                    labels(implementor, tag, target, Some(thread)),
+                    number(*value)
+                );
+            }
+        }
+    }
+}
+
+fn labels(implementor: &str, tag: &str, target: &str, thread: Option<&str>) -> String {
+    let mut labels = format!(
+        "implementor=\"{}\",tag=\"{}\",target=\"{}\"",
+        escape(implementor),
+        escape(tag),
+        escape(target)
+    );
+    if let Some(thread) = thread {
+        let _ = write!(labels, ",thread=\"{}\"", escape(thread));
+    }
+    labels
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn number(value: Number) -> String {
+    match value {
+        Number::Unsigned(value) => value.to_string(),
+        Number::Signed(value) => value.to_string(),
+        Number::Float(value) if value.is_nan() => String::from("NaN"),
+        Number::Float(value) if value.is_infinite() => {
+            String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+        }
+        Number::Float(value) => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_snapshot, PrometheusOptions};
+    use crate::reporter::{Snapshot, ThreadSnapshot, ValueSnapshot};
+    use std::time::{Instant, SystemTime};
+
+    fn thread(name: &str, values: &[(&'static str, &'static str, Option<&str>)]) -> ThreadSnapshot {
+        ThreadSnapshot {
+            thread: std::thread::spawn(|| std::thread::current().id())
+                .join()
+                .unwrap(),
+            name: Some(name.to_string()),
+            published: Instant::now(),
+            values: values
+                .iter()
+                .map(|&(implementor, target, value)| ValueSnapshot {
+                    implementor,
+                    tag: "()",
+                    target,
+                    value: value.map(str::to_string),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn summed_and_per_thread() {
+        let snapshot = Snapshot {
+            taken: SystemTime::now(),
+            threads: vec![
+                thread(
+                    "a",
+                    &[
+                        ("app::Hits", "u64", Some("2")),
+                        ("app::Name", "&str", Some("\"x\"")),
+                    ],
+                ),
+                thread(
+                    "b\"1",
+                    &[
+                        ("app::Hits", "u64", Some("3")),
+                        ("app::Load", "f64", Some("0.5")),
+                    ],
+                ),
+            ],
+        };
+        let mut out = String::new();
+        render_snapshot(
+            &mut out,
+            &snapshot,
+            &PrometheusOptions::new().prefix("svc").per_thread(true),
+        );
+        let id = |index: usize| {
+            let id = format!("{:?}", snapshot.threads[index].thread);
+            id.trim_start_matches("ThreadId(")
+                .trim_end_matches(')')
+                .to_string()
+        };
+        let lines: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            [
+                String::from("svc_value{implementor=\"app::Hits\",tag=\"()\",target=\"u64\"} 5"),
+                String::from(
+                    "svc_value{implementor=\"app::Load\",tag=\"()\",target=\"f64\"} 0.5"
+                ),
+                format!(
+                    "svc_thread_value{{implementor=\"app::Hits\",tag=\"()\",target=\"u64\",thread=\"a#{}\"}} 2",
+                    id(0)
+                ),
+                format!(
+                    "svc_thread_value{{implementor=\"app::Hits\",tag=\"()\",target=\"u64\",thread=\"b\\\"1#{}\"}} 3",
+                    id(1)
+                ),
+                format!(
+                    "svc_thread_value{{implementor=\"app::Load\",tag=\"()\",target=\"f64\",thread=\"b\\\"1#{}\"}} 0.5",
+                    id(1)
+                ),
+            ]
+        );
+        assert!(out.contains("# TYPE svc_thread_value gauge\n"));
+    }
+
+    #[test]
+    fn targets_and_threads_kept_apart() {
+        let snapshot = Snapshot {
+            taken: SystemTime::now(),
+            threads: vec![
+                thread(
+                    "worker",
+                    &[
+                        ("app::Requests", "u64", Some("1000")),
+                        ("app::Requests", "u32", Some("1")),
+                    ],
+                ),
+                thread("worker", &[("app::Requests", "u64", Some("10"))]),
+            ],
+        };
+        let mut out = String::new();
+        render_snapshot(
+            &mut out,
+            &snapshot,
+            &PrometheusOptions::new().per_thread(true),
+        );
+        assert!(out.contains("target=\"u32\"} 1\n"));
+        assert!(out.contains("target=\"u64\"} 1010\n"));
+        let per_thread: Vec<&str> = out
+            .lines()
+            .filter(|line| line.starts_with("assoc_threadlocal_thread_value{"))
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect();
+        assert_eq!(per_thread.len(), 3);
+        let mut unique = per_thread.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 3);
+    }
+
+    #[test]
+    fn exact_integer_sums() {
+        let big = (1u64 << 60) + 1;
+        let snapshot = Snapshot {
+            taken: SystemTime::now(),
+            threads: vec![
+                thread("a", &[("app::Bytes", "u64", Some(&big.to_string()))]),
+                thread("b", &[("app::Bytes", "u64", Some("1"))]),
+                thread(
+                    "c",
+                    &[(
+                        "app::Delta",
+                        "i128",
+                        Some("-170141183460469231731687303715884105728"),
+                    )],
+                ),
+                thread("d", &[("app::Delta", "i128", Some("-1"))]),
+            ],
+        };
+        let mut out = String::new();
+        render_snapshot(&mut out, &snapshot, &PrometheusOptions::new());
+        assert!(out.contains(&format!("target=\"u64\"}} {}\n", big + 1)));
+        assert!(out.contains(&format!("target=\"i128\"}} {}\n", i128::MIN)));
+    }
+
+    #[test]
+    fn nothing_numeric() {
+        let mut out = String::new();
+        let snapshot = Snapshot {
+            taken: SystemTime::now(),
+            threads: vec![thread("a", &[("app::Flag", "bool", Some("true"))])],
+        };
+        render_snapshot(&mut out, &snapshot, &PrometheusOptions::new());
+        assert!(out.is_empty());
+    }
+}
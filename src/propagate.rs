@@ -0,0 +1,361 @@
+//! Carrying associations along with requests and futures.
+//!
+//! A `Propagation` names a set of associations.  When a request enters, `capture()` takes
+//! their values from the current thread; the resulting `Captured` is `Send` and re-applies
+//! the values on whichever thread continues processing, restoring that threads previous
+//! values afterwards.  `Propagation::wrap()` does this around every poll of a future, which
+//! is what a service middleware (e.g. a tower `Layer`) does with the response future of
+//! the inner service:
+//!
+//! ```ignore
+//! fn call(&mut self, request: Request) -> Self::Future {
+//!     self.propagation.wrap(self.inner.call(request))
+//! }
+//! ```
+//!
+//! `Captured::enter()` and `Captured::exit()` keep associations in sync with other scopes
+//! such as tracing spans, a subscriber layer calls them from its span enter and exit
+//! callbacks.
+
+use crate::{AssocThreadLocal, ThreadLocalHandle};
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type SwapFn = fn(Box<dyn Any + Send>) -> Box<dyn Any + Send>;
+
+fn swap<S, T, TAG>(value: Box<dyn Any + Send>) -> Box<dyn Any + Send>
+where
+    S: AssocThreadLocal<T, TAG>,
+    T: Copy + Send + 'static,
+{
+    let previous = S::get_threadlocal();
+    S::set_threadlocal(*value.downcast::<T>().expect("captured value type"));
+    Box::new(previous)
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    capture: fn() -> Box<dyn Any + Send>,
+    swap: SwapFn,
+}
+
+/// A set of associations propagated together.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Request;
+/// assoc_threadlocal!(Request, u64 = 0);
+///
+/// let propagation = Propagation::new().with(<Request as AssocThreadLocal<u64>>::handle());
+///
+/// Request::set_threadlocal(42);
+/// let mut captured = propagation.capture();
+/// std::thread::spawn(move || {
+///     assert_eq!(captured.scope(Request::get_threadlocal), 42);
+///     assert_eq!(Request::get_threadlocal(), 0);
+/// })
+/// .join()
+/// .unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct Propagation {
+    entries: Vec<Entry>,
+}
+
+impl Propagation {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the association of 'handle' to the set.
+    #[must_use]
+    pub fn with<S, T, TAG>(mut self, handle: ThreadLocalHandle<S, T, TAG>) -> Self
+    where
+        S: AssocThreadLocal<T, TAG> + 'static,
+        T: Copy + Send + 'static,
+        TAG: 'static,
+    {
+        let _ = handle;
+        self.entries.push(Entry {
+            capture: || Box::new(S::get_threadlocal()),
+            swap: swap::<S, T, TAG>,
+        });
+        self
+    }
+
+    /// Returns the number of associations in the set.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Captures the current threads values of the set.
+    pub fn capture(&self) -> Captured {
+        Captured {
+            values: self
+                .entries
+                .iter()
+                .map(|entry| (entry.swap, (entry.capture)()))
+                .collect(),
+            depth: 0,
+        }
+    }
+
+    /// Captures the current threads values and applies them around every poll of
+    /// 'future'.
+    pub fn wrap<F: Future>(&self, future: F) -> Propagate<F> {
+        self.capture().wrap(future)
+    }
+}
+
+impl fmt::Debug for Propagation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Propagation")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Values captured by `Propagation::capture()` or bound explicitly with `with_value()`.
+///
+/// `enter()` and `exit()` apply and restore the values at the boundaries of a scope that
+/// can not be expressed as closure, like the enter and exit callbacks of a tracing span:
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Tenant;
+/// assoc_threadlocal!(Tenant, u32 = 0);
+///
+/// // stored in the span extensions when the span is created
+/// let mut bound = Captured::new().with_value(Tenant::handle(), 7);
+///
+/// bound.enter(); // on span enter
+/// assert_eq!(Tenant::get_threadlocal(), 7);
+/// bound.exit(); // on span exit
+/// assert_eq!(Tenant::get_threadlocal(), 0);
+/// ```
+#[derive(Default)]
+pub struct Captured {
+    values: Vec<(SwapFn, Box<dyn Any + Send>)>,
+    depth: usize,
+}
+
+impl Captured {
+    /// Creates an empty set of values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds 'value' for the association of 'handle'.
+    #[must_use]
+    pub fn with_value<S, T, TAG>(mut self, handle: ThreadLocalHandle<S, T, TAG>, value: T) -> Self
+    where
+        S: AssocThreadLocal<T, TAG> + 'static,
+        T: Copy + Send + 'static,
+        TAG: 'static,
+    {
+        let _ = handle;
+        self.values.push((swap::<S, T, TAG>, Box::new(value)));
+        self
+    }
+
+    /// Applies the values, saving the current threads values.  Nested calls only count the
+    /// depth, the values are applied by the outermost call.
+    pub fn enter(&mut self) {
+        if self.depth == 0 {
+            self.swap_all();
+        }
+        self.depth += 1;
+    }
+
+    /// Restores the values saved by the matching `enter()` on the same thread, changes
+    /// made in between are kept for the next `enter()`.
+    ///
+    /// # Panics
+    /// When not entered.
+    pub fn exit(&mut self) {
+        assert!(self.depth > 0, "exit() without enter()");
+        self.depth -= 1;
+        if self.depth == 0 {
+            self.swap_all();
+        }
+    }
+
+    /// Applies the captured values while 'f' runs, the threads previous values are
+    /// restored afterwards, even when 'f' panics.  Changes made by 'f' are kept in the
+    /// captured values for the next scope.
+    pub fn scope<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        struct Entered<'a>(&'a mut Captured);
+
+        impl Drop for Entered<'_> {
+            fn drop(&mut self) {
+                self.0.exit();
+            }
+        }
+
+        self.enter();
+        let _entered = Entered(self);
+        f()
+    }
+
+    /// Applies the captured values around every poll of 'future'.
+    pub fn wrap<F: Future>(self, future: F) -> Propagate<F> {
+        Propagate {
+            captured: self,
+            future,
+        }
+    }
+
+    fn swap_all(&mut self) {
+        for (swap, value) in self.values.iter_mut() {
+            let placeholder: Box<dyn Any + Send> = Box::new(());
+            *value = swap(std::mem::replace(value, placeholder));
+        }
+    }
+}
+
+impl fmt::Debug for Captured {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Captured")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+/// A future polled with captured values applied, created by `Propagation::wrap()`.
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct Propagate<F> {
+    captured: Captured,
+    future: F,
+}
+
+impl<F: Future> Future for Propagate<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // SAFETY: 'future' is structurally pinned, 'captured' is never pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        this.captured.scope(|| future.poll(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Propagation;
+    use crate::{AssocThreadLocal, GetAssocThreadLocal, SetAssocThreadLocal};
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Request;
+    crate::assoc_threadlocal!(Request, u64 = 0);
+    crate::assoc_threadlocal!(Request, bool = false);
+
+    fn propagation() -> Propagation {
+        Propagation::new()
+            .with(<Request as AssocThreadLocal<u64>>::handle())
+            .with(<Request as AssocThreadLocal<bool>>::handle())
+    }
+
+    #[test]
+    fn scope_restores() {
+        <Request as SetAssocThreadLocal<u64>>::set_threadlocal(7);
+        let mut captured = propagation().capture();
+        <Request as SetAssocThreadLocal<u64>>::set_threadlocal(1);
+        captured.scope(|| {
+            assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 7);
+            <Request as SetAssocThreadLocal<u64>>::set_threadlocal(8);
+        });
+        assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 1);
+        // changes are kept for the next scope
+        captured.scope(|| {
+            assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 8);
+        });
+    }
+
+    #[test]
+    fn restored_on_panic() {
+        let mut captured = {
+            <Request as SetAssocThreadLocal<bool>>::set_threadlocal(true);
+            let captured = propagation().capture();
+            <Request as SetAssocThreadLocal<bool>>::set_threadlocal(false);
+            captured
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            captured.scope(|| panic!("boom"))
+        }));
+        assert!(result.is_err());
+        assert!(!<Request as GetAssocThreadLocal<bool>>::get_threadlocal());
+    }
+
+    #[test]
+    fn enter_exit_nested() {
+        let mut bound = super::Captured::new()
+            .with_value(<Request as AssocThreadLocal<u64>>::handle(), 5)
+            .with_value(<Request as AssocThreadLocal<bool>>::handle(), true);
+        bound.enter();
+        bound.enter();
+        assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 5);
+        <Request as SetAssocThreadLocal<u64>>::set_threadlocal(6);
+        bound.exit();
+        assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 6);
+        bound.exit();
+        assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 0);
+        assert!(!<Request as GetAssocThreadLocal<bool>>::get_threadlocal());
+        bound.scope(|| {
+            assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 6);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "exit() without enter()")]
+    fn exit_without_enter() {
+        super::Captured::new().exit();
+    }
+
+    struct Noop;
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn future_polled_on_other_thread() {
+        <Request as SetAssocThreadLocal<u64>>::set_threadlocal(42);
+        let mut first = true;
+        let future = propagation().wrap(std::future::poll_fn(move |cx| {
+            if std::mem::take(&mut first) {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            } else {
+                Poll::Ready(<Request as GetAssocThreadLocal<u64>>::get_threadlocal())
+            }
+        }));
+        let value = std::thread::spawn(move || {
+            let waker = Waker::from(Arc::new(Noop));
+            let mut cx = Context::from_waker(&waker);
+            let mut future = pin!(future);
+            loop {
+                if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                    assert_eq!(<Request as GetAssocThreadLocal<u64>>::get_threadlocal(), 0);
+                    break value;
+                }
+            }
+        })
+        .join()
+        .unwrap();
+        assert_eq!(value, 42);
+    }
+}
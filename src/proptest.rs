@@ -0,0 +1,404 @@
+//! Randomized initial values for property tests.
+//!
+//! Code that silently depends on the declared INIT of an association only breaks once some
+//! caller sets another value.  A `Randomized` set names the associations a test should not
+//! make assumptions about, `run()` then calls the test body many times with generated
+//! values installed on the current thread.  When a case fails the values are shrunk to a
+//! minimal failing combination which is reported together with the seed for replaying it.
+//!
+//! The seed is taken from the `ASSOC_THREADLOCAL_SEED` environment variable when set,
+//! otherwise from the clock.
+
+use crate::{AssocThreadLocal, ThreadLocalHandle};
+use std::any::{type_name, Any};
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Environment variable read for the seed of `Randomized::run()`.
+pub const SEED_VAR: &str = "ASSOC_THREADLOCAL_SEED";
+
+/// The source of randomness handed to `Arbitrary::arbitrary()`, SplitMix64.
+#[derive(Debug, Clone)]
+pub struct Gen {
+    state: u64,
+}
+
+impl Gen {
+    /// Creates a generator starting from 'seed'.
+    pub fn new(seed: u64) -> Self {
+        Gen { state: seed }
+    }
+
+    /// Returns the next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a random number less than 'bound', which must not be zero.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+/// Types whose values can be generated and shrunk for property tests.
+pub trait Arbitrary: Copy + fmt::Debug + 'static {
+    /// Returns a random value.
+    fn arbitrary(gen: &mut Gen) -> Self;
+
+    /// Returns simpler candidates for a failing value, simplest first.  An empty list ends
+    /// shrinking.
+    fn shrink(self) -> Vec<Self> {
+        Vec::new()
+    }
+}
+
+macro_rules! arbitrary_int {
+    ($($INT:ty),*) => {
+        $(
+            impl Arbitrary for $INT {
+                fn arbitrary(gen: &mut Gen) -> Self {
+                    gen.next_u64() as $INT
+                }
+
+                #[allow(unused_comparisons)]
+                fn shrink(self) -> Vec<Self> {
+                    let toward_zero = if self < 0 { self + 1 } else { self.wrapping_sub(1) };
+                    let mut candidates = Vec::new();
+                    for candidate in [0, self / 2, toward_zero] {
+                        if self != 0 && !candidates.contains(&candidate) {
+                            candidates.push(candidate);
+                        }
+                    }
+                    candidates
+                }
+            }
+        )*
+    };
+}
+
+arbitrary_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+impl Arbitrary for bool {
+    fn arbitrary(gen: &mut Gen) -> Self {
+        gen.next_u64() & 1 == 1
+    }
+
+    fn shrink(self) -> Vec<Self> {
+        if self {
+            vec![false]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Option<T> {
+    fn arbitrary(gen: &mut Gen) -> Self {
+        (gen.below(4) != 0).then(|| T::arbitrary(gen))
+    }
+
+    fn shrink(self) -> Vec<Self> {
+        match self {
+            Some(value) => std::iter::once(None)
+                .chain(value.shrink().into_iter().map(Some))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+type Value = Box<dyn Any>;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: fn() -> &'static str,
+    generate: fn(&mut Gen) -> Value,
+    shrink: fn(&Value) -> Vec<Value>,
+    copy: fn(&Value) -> Value,
+    swap: fn(Value) -> Value,
+    debug: fn(&Value) -> String,
+}
+
+fn generate<T: Arbitrary>(gen: &mut Gen) -> Value {
+    Box::new(T::arbitrary(gen))
+}
+
+fn shrink<T: Arbitrary>(value: &Value) -> Vec<Value> {
+    let value = *value.downcast_ref::<T>().expect("generated value type");
+    value
+        .shrink()
+        .into_iter()
+        .map(|candidate| Box::new(candidate) as Value)
+        .collect()
+}
+
+fn copy<T: Arbitrary>(value: &Value) -> Value {
+    Box::new(*value.downcast_ref::<T>().expect("generated value type"))
+}
+
+fn swap<S: AssocThreadLocal<T, TAG>, T: Arbitrary, TAG>(value: Value) -> Value {
+    let previous = S::get_threadlocal();
+    S::set_threadlocal(*value.downcast::<T>().expect("generated value type"));
+    Box::new(previous)
+}
+
+fn debug<T: Arbitrary>(value: &Value) -> String {
+    format!(
+        "{:?}",
+        value.downcast_ref::<T>().expect("generated value type")
+    )
+}
+
+/// Upper bound of shrink steps, guards against `shrink()` implementations that cycle.
+const MAX_SHRINK_STEPS: usize = 1024;
+
+/// A set of associations that get generated initial values in property tests.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use crate::assoc_threadlocal::proptest::Randomized;
+///
+/// struct Indent;
+/// assoc_threadlocal!(Indent, u8 = 0);
+///
+/// fn indented(line: &str) -> String {
+///     let indent = Indent::get_threadlocal() as usize;
+///     format!("{:indent$}{line}", "")
+/// }
+///
+/// Randomized::new().with(Indent::handle()).run(|| {
+///     assert!(indented("x").ends_with('x'));
+/// });
+/// assert_eq!(Indent::get_threadlocal(), 0);
+/// ```
+#[derive(Clone)]
+pub struct Randomized {
+    entries: Vec<Entry>,
+    cases: u32,
+    seed: Option<u64>,
+}
+
+impl Randomized {
+    /// Creates an empty set running 64 cases.
+    pub fn new() -> Self {
+        Randomized {
+            entries: Vec::new(),
+            cases: 64,
+            seed: None,
+        }
+    }
+
+    /// Adds the association of 'handle' to the set.
+    #[must_use]
+    pub fn with<S, T, TAG>(mut self, handle: ThreadLocalHandle<S, T, TAG>) -> Self
+    where
+        S: AssocThreadLocal<T, TAG> + 'static,
+        T: Arbitrary,
+        TAG: 'static,
+    {
+        let _ = handle;
+        self.entries.push(Entry {
+            name: type_name::<S>,
+            generate: generate::<T>,
+            shrink: shrink::<T>,
+            copy: copy::<T>,
+            swap: swap::<S, T, TAG>,
+            debug: debug::<T>,
+        });
+        self
+    }
+
+    /// Sets the number of cases `run()` tries.
+    #[must_use]
+    pub fn cases(mut self, cases: u32) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    /// Sets the seed, overriding the environment and the clock.
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Returns the number of associations in the set.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Calls 'test' once per case with generated values installed on the current thread,
+    /// the previous values are restored after each case.
+    ///
+    /// # Panics
+    /// When a case fails, with the shrunk values, the seed and the message of the failure.
+    pub fn run(&self, test: impl Fn()) {
+        let seed = self.seed.unwrap_or_else(seed_from_env);
+        let mut gen = Gen::new(seed);
+        for case in 1..=self.cases {
+            let values: Vec<Value> = self
+                .entries
+                .iter()
+                .map(|e| (e.generate)(&mut gen))
+                .collect();
+            if let Err(failure) = self.try_case(&values, &test) {
+                let (values, failure) = self.minimize(values, failure, &test);
+                panic!(
+                    "property failed in case {case} (seed {seed:#x}) with {}: {failure}",
+                    self.describe(&values)
+                );
+            }
+        }
+    }
+
+    fn try_case(&self, values: &[Value], test: &impl Fn()) -> Result<(), String> {
+        // the values stay owned by the case so they can be shrunk and reported afterwards
+        let mut saved: Vec<Value> = self
+            .entries
+            .iter()
+            .zip(values)
+            .map(|(entry, value)| (entry.swap)((entry.copy)(value)))
+            .collect();
+        let result = catch_unwind(AssertUnwindSafe(test));
+        for (entry, previous) in self.entries.iter().zip(saved.drain(..)).rev() {
+            (entry.swap)(previous);
+        }
+        result.map_err(|payload| {
+            payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("non string panic payload"))
+        })
+    }
+
+    fn minimize(
+        &self,
+        mut values: Vec<Value>,
+        mut failure: String,
+        test: &impl Fn(),
+    ) -> (Vec<Value>, String) {
+        let mut steps = 0;
+        'shrink: while steps < MAX_SHRINK_STEPS {
+            for (index, entry) in self.entries.iter().enumerate() {
+                for candidate in (entry.shrink)(&values[index]) {
+                    steps += 1;
+                    let previous = std::mem::replace(&mut values[index], candidate);
+                    match self.try_case(&values, test) {
+                        Err(message) => {
+                            failure = message;
+                            continue 'shrink;
+                        }
+                        Ok(()) => values[index] = previous,
+                    }
+                }
+            }
+            break;
+        }
+        (values, failure)
+    }
+
+    fn describe(&self, values: &[Value]) -> String {
+        let described: Vec<String> = self
+            .entries
+            .iter()
+            .zip(values)
+            .map(|(entry, value)| format!("{} = {}", (entry.name)(), (entry.debug)(value)))
+            .collect();
+        format!("[{}]", described.join(", "))
+    }
+}
+
+impl Default for Randomized {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Randomized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Randomized")
+            .field("len", &self.len())
+            .field("cases", &self.cases)
+            .field("seed", &self.seed)
+            .finish()
+    }
+}
+
+fn seed_from_env() -> u64 {
+    std::env::var(SEED_VAR)
+        .ok()
+        .and_then(|seed| {
+            let seed = seed.trim();
+            match seed.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => seed.parse().ok(),
+            }
+        })
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or_default()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arbitrary, Randomized};
+    use crate::AssocThreadLocal;
+
+    #[test]
+    fn shrink_candidates() {
+        assert_eq!(200u8.shrink(), [0, 100, 199]);
+        assert_eq!((-7i32).shrink(), [0, -3, -6]);
+        assert!(0u64.shrink().is_empty());
+        assert_eq!(Some(true).shrink(), [None, Some(false)]);
+    }
+
+    struct Width;
+    crate::assoc_threadlocal!(Width, u8 = 0);
+    crate::assoc_threadlocal!(Width, bool = false);
+
+    #[test]
+    fn passing() {
+        let seen = std::cell::Cell::new(0);
+        Randomized::new()
+            .with(<Width as AssocThreadLocal<u8>>::handle())
+            .cases(10)
+            .seed(1)
+            .run(|| seen.set(seen.get() + 1));
+        assert_eq!(seen.get(), 10);
+    }
+
+    #[test]
+    fn shrinks_to_minimal() {
+        let failure = std::panic::catch_unwind(|| {
+            Randomized::new()
+                .with(<Width as AssocThreadLocal<u8>>::handle())
+                .with(<Width as AssocThreadLocal<bool>>::handle())
+                .seed(7)
+                .run(|| {
+                    assert!(<Width as crate::GetAssocThreadLocal<u8>>::get_threadlocal() <= 10);
+                })
+        })
+        .unwrap_err();
+        let message = failure.downcast_ref::<String>().unwrap();
+        assert!(message.contains("(seed 0x7)"), "{message}");
+        assert!(message.contains("Width = 11, "), "{message}");
+        assert!(message.ends_with("= false]: assertion failed: <Width as crate::GetAssocThreadLocal<u8>>::get_threadlocal() <= 10"), "{message}");
+        assert_eq!(
+            <Width as crate::GetAssocThreadLocal<u8>>::get_threadlocal(),
+            0
+        );
+    }
+}
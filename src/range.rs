@@ -0,0 +1,136 @@
+//! Associations restricted to a range of values.
+
+use crate::AssocThreadLocal;
+use std::fmt;
+
+/// What setting a value out of range does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeMode {
+    /// The value is clamped into the range.
+    Clamp,
+    /// Setting panics.
+    Reject,
+}
+
+/// The range of values an association accepts.  Implemented by
+/// `assoc_threadlocal!(T, TARGET in MIN..=MAX = INIT)`.
+pub trait AssocRange<T: Copy + PartialOrd, TAG = ()>: AssocThreadLocal<T, TAG> {
+    /// Smallest valid value.
+    const RANGE_MIN: T;
+    /// Largest valid value.
+    const RANGE_MAX: T;
+    /// What happens to values out of range.
+    const RANGE_MODE: RangeMode;
+
+    /// Sets the associated thread local object when 'value' is in range, otherwise it
+    /// is kept and an error is returned.
+    fn try_set_in_range(value: T) -> Result<(), OutOfRange<T>> {
+        if Self::in_range(value) {
+            Self::set_threadlocal(value);
+            Ok(())
+        } else {
+            Err(OutOfRange {
+                value,
+                min: Self::RANGE_MIN,
+                max: Self::RANGE_MAX,
+            })
+        }
+    }
+
+    /// Returns whether 'value' is in the valid range.
+    fn in_range(value: T) -> bool {
+        Self::RANGE_MIN <= value && value <= Self::RANGE_MAX
+    }
+
+    /// Applies the `RANGE_MODE` to 'value', used by the setter.
+    fn check_range(value: T) -> T {
+        if value < Self::RANGE_MIN {
+            match Self::RANGE_MODE {
+                RangeMode::Clamp => Self::RANGE_MIN,
+                RangeMode::Reject => panic!("value out of range"),
+            }
+        } else if value > Self::RANGE_MAX {
+            match Self::RANGE_MODE {
+                RangeMode::Clamp => Self::RANGE_MAX,
+                RangeMode::Reject => panic!("value out of range"),
+            }
+        } else {
+            value
+        }
+    }
+}
+
+/// Error returned when a value is out of the range of an association.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfRange<T> {
+    /// The rejected value.
+    pub value: T,
+    /// Smallest valid value.
+    pub min: T,
+    /// Largest valid value.
+    pub max: T,
+}
+
+impl<T: fmt::Debug> fmt::Display for OutOfRange<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is out of range {:?}..={:?}",
+            self.value, self.min, self.max
+        )
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for OutOfRange<T> {}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __range_mode {
+    (clamp) => {
+        $crate::RangeMode::Clamp
+    };
+    (reject) => {
+        $crate::RangeMode::Reject
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocRange, GetAssocThreadLocal, OutOfRange, SetAssocThreadLocal};
+
+    struct Tunable;
+    struct Strict;
+    crate::assoc_threadlocal!(Tunable, i16 in -10..=10 = 0);
+    crate::assoc_threadlocal!(Strict:Tunable, i16 in -10..=10 = 0, reject);
+
+    #[test]
+    fn clamp() {
+        <Tunable as SetAssocThreadLocal<i16>>::set_threadlocal(-50);
+        assert_eq!(
+            <Tunable as GetAssocThreadLocal<i16>>::get_threadlocal(),
+            -10
+        );
+        <Tunable as SetAssocThreadLocal<i16>>::set_threadlocal(5);
+        assert_eq!(<Tunable as GetAssocThreadLocal<i16>>::get_threadlocal(), 5);
+        assert_eq!(
+            <Tunable as AssocRange<i16>>::try_set_in_range(11),
+            Err(OutOfRange {
+                value: 11,
+                min: -10,
+                max: 10
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "value out of range")]
+    fn reject() {
+        <Tunable as SetAssocThreadLocal<i16, Strict>>::set_threadlocal(11);
+    }
+
+    #[test]
+    fn display() {
+        let err = <Tunable as AssocRange<i16, Strict>>::try_set_in_range(-11).unwrap_err();
+        assert_eq!(err.to_string(), "-11 is out of range -10..=10");
+    }
+}
@@ -0,0 +1,276 @@
+//! Per-thread token bucket rate limiting.
+//!
+//! Every thread has its own bucket, throttling each thread independently without
+//! synchronization.  Optionally the threads additionally draw from a process wide pool
+//! with its own refill rate, limiting the component as a whole.
+
+use crate::AssocThreadLocal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Tag for the thread local token bucket of a rate limiter.
+pub struct RateLimitState;
+
+/// State of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// Creates a bucket holding 'tokens', refilling starts with the first acquire.
+    pub const fn new(tokens: f64) -> Self {
+        TokenBucket {
+            tokens,
+            last_refill: None,
+        }
+    }
+
+    /// Returns the number of tokens currently available.
+    pub fn tokens(&self) -> f64 {
+        self.tokens
+    }
+
+    /// Adds the tokens accumulated since the last refill, capped at 'burst'.
+    pub fn refill(&mut self, now: Instant, rate_per_sec: f64, burst: f64) {
+        if let Some(last) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate_per_sec).min(burst);
+        }
+        self.last_refill = Some(now);
+    }
+
+    /// Takes 'n' tokens if available.
+    pub fn try_take(&mut self, n: f64) -> bool {
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A process wide token pool shared by the threads of a rate limiter, created by the
+/// `assoc_rate_limit!()` macro with the 'shared' option.  It starts full.
+#[derive(Debug)]
+pub struct SharedPool {
+    rate_per_sec: f64,
+    burst: f64,
+    // f64 bits
+    tokens: AtomicU64,
+    // nanoseconds since 'epoch'
+    last_refill: AtomicU64,
+    epoch: OnceLock<Instant>,
+}
+
+impl SharedPool {
+    #[doc(hidden)]
+    pub const fn new(rate_per_sec: f64, burst: f64) -> Self {
+        SharedPool {
+            rate_per_sec,
+            burst,
+            tokens: AtomicU64::new(burst.to_bits()),
+            last_refill: AtomicU64::new(0),
+            epoch: OnceLock::new(),
+        }
+    }
+
+    /// Returns the number of tokens currently available in the pool.
+    pub fn tokens(&self) -> f64 {
+        self.refill();
+        f64::from_bits(self.tokens.load(Ordering::Acquire))
+    }
+
+    /// Adds the tokens accumulated since the last refill by any thread, capped at the
+    /// burst.
+    fn refill(&self) {
+        let now = self.epoch.get_or_init(Instant::now).elapsed().as_nanos() as u64;
+        // every interval is accounted by exactly one thread
+        let last = self.last_refill.fetch_max(now, Ordering::AcqRel);
+        if now > last {
+            let added = (now - last) as f64 / 1e9 * self.rate_per_sec;
+            let _ = self
+                .tokens
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| {
+                    Some((f64::from_bits(tokens) + added).min(self.burst).to_bits())
+                });
+        }
+    }
+
+    /// Takes 'n' tokens if available.
+    fn try_take(&self, n: f64) -> bool {
+        self.refill();
+        self.tokens
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| {
+                let tokens = f64::from_bits(tokens);
+                (tokens >= n).then(|| (tokens - n).to_bits())
+            })
+            .is_ok()
+    }
+}
+
+/// A per-thread token bucket rate limiter.
+/// Use the `assoc_rate_limit!()` macro for implementing this trait on types.
+pub trait AssocRateLimit: AssocThreadLocal<TokenBucket, RateLimitState> {
+    /// Tokens added per second.
+    const RATE_PER_SEC: f64;
+    /// Maximum number of tokens a bucket holds.
+    const BURST: f64;
+
+    /// Returns the pool shared by all threads, `None` when the threads are only limited by
+    /// their own buckets.
+    fn shared_pool() -> Option<&'static SharedPool> {
+        None
+    }
+
+    /// Takes one token from the current threads bucket.
+    fn try_acquire() -> bool {
+        Self::try_acquire_n(1)
+    }
+
+    /// Takes 'n' tokens from the current threads bucket and the shared pool, either all or
+    /// none.
+    fn try_acquire_n(n: u32) -> bool {
+        let mut bucket = Self::get_threadlocal();
+        bucket.refill(Instant::now(), Self::RATE_PER_SEC, Self::BURST);
+        let n = n as f64;
+        let acquired = bucket.tokens() >= n
+            && Self::shared_pool().is_none_or(|pool| pool.try_take(n))
+            && bucket.try_take(n);
+        Self::set_threadlocal(bucket);
+        acquired
+    }
+
+    /// Returns the tokens available on the current thread, limited by the shared pool.
+    fn available_tokens() -> f64 {
+        let mut bucket = Self::get_threadlocal();
+        bucket.refill(Instant::now(), Self::RATE_PER_SEC, Self::BURST);
+        Self::set_threadlocal(bucket);
+        match Self::shared_pool() {
+            Some(pool) => bucket.tokens().min(pool.tokens()),
+            None => bucket.tokens(),
+        }
+    }
+}
+
+/// Associates a per-thread token bucket rate limiter to a type.
+///
+///  * 'T' is the type you want have a thread local rate limiter associated to
+///  * 'rate' is the refill rate, `N/s`, `N/min` or `N/h`
+///  * 'burst' is the capacity of the bucket, each thread starts with a full bucket
+///  * 'shared' optionally is the refill rate of a pool shared by all threads, holding up
+///    to 'burst' tokens, every acquire takes from both the threads bucket and the pool
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct OutboundApi;
+/// assoc_rate_limit!(OutboundApi, rate = 1/h, burst = 2);
+///
+/// assert!(OutboundApi::try_acquire());
+/// assert!(OutboundApi::try_acquire());
+/// assert!(!OutboundApi::try_acquire());
+///
+/// // every thread may send 3 requests at once, all threads together too
+/// struct Upstream;
+/// assoc_rate_limit!(Upstream, rate = 3/h, burst = 3, shared = 1/h);
+///
+/// assert!(Upstream::try_acquire_n(2));
+/// assert!(std::thread::spawn(Upstream::try_acquire).join().unwrap());
+/// assert!(!std::thread::spawn(Upstream::try_acquire).join().unwrap());
+/// ```
+#[macro_export]
+macro_rules! assoc_rate_limit {
+    ($T:ty, rate = $RATE:literal / $UNIT:ident, burst = $BURST:expr $(, shared = $SHARED:literal / $SHARED_UNIT:ident)?) => {
+        $crate::assoc_threadlocal!(
+            $crate::RateLimitState:$T,
+            $crate::rate_limit::TokenBucket = $crate::rate_limit::TokenBucket::new($BURST as f64)
+        );
+
+        impl $crate::AssocRateLimit for $T {
+            const RATE_PER_SEC: f64 = $crate::__rate_per_sec!($RATE / $UNIT);
+            const BURST: f64 = $BURST as f64;
+
+            $(
+                fn shared_pool() -> Option<&'static $crate::rate_limit::SharedPool> {
+                    static POOL: $crate::rate_limit::SharedPool = $crate::rate_limit::SharedPool::new(
+                        $crate::__rate_per_sec!($SHARED / $SHARED_UNIT),
+                        $BURST as f64,
+                    );
+                    Some(&POOL)
+                }
+            )?
+        }
+    };
+}
+
+/// Converts the rates written in `assoc_rate_limit!()` to tokens per second.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __rate_per_sec {
+    ($RATE:literal / s) => {
+        $RATE as f64
+    };
+    ($RATE:literal / min) => {
+        $RATE as f64 / 60.0
+    };
+    ($RATE:literal / h) => {
+        $RATE as f64 / 3600.0
+    };
+    ($RATE:literal / $UNIT:ident) => {
+        compile_error!(concat!(
+            "rate limits are given per s, min or h, not per ",
+            stringify!($UNIT)
+        ))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocRateLimit;
+    use std::time::Duration;
+
+    struct Fast;
+    assoc_rate_limit!(Fast, rate = 1000 / s, burst = 5);
+
+    #[test]
+    fn burst_then_refill() {
+        assert!(Fast::try_acquire_n(5));
+        assert!(!Fast::try_acquire());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(Fast::try_acquire());
+        assert!(Fast::available_tokens() <= 5.0);
+    }
+
+    struct Pooled;
+    assoc_rate_limit!(Pooled, rate = 1 / h, burst = 4, shared = 1 / h);
+
+    #[test]
+    fn shared_pool() {
+        assert!(Pooled::try_acquire_n(3));
+        let other = std::thread::spawn(|| {
+            // the threads bucket is full, but the pool holds only one more token
+            let too_many = Pooled::try_acquire_n(2);
+            let available = Pooled::available_tokens();
+            (too_many, available, Pooled::try_acquire())
+        });
+        let (too_many, available, acquired) = other.join().unwrap();
+        assert!(!too_many);
+        assert!((available - 1.0).abs() < 0.01);
+        assert!(acquired);
+        assert!(!Pooled::try_acquire());
+        assert!(Pooled::available_tokens() < 0.01);
+        assert!(Pooled::shared_pool().unwrap().tokens() < 0.01);
+    }
+
+    #[test]
+    fn per_thread_buckets() {
+        assert!(Fast::try_acquire_n(5));
+        assert!(std::thread::spawn(|| Fast::try_acquire_n(5))
+            .join()
+            .unwrap());
+    }
+}
@@ -0,0 +1,273 @@
+//! Recording and replaying the sets of associations (requires the `record` feature).
+//!
+//! `record_sets()` runs a closure and records every set of an association generated by
+//! `assoc_threadlocal!()` on the current thread, in order and with the values.  The
+//! `Recording` characterizes what a piece of code does to the ambient state of a thread,
+//! `Recording::replay()` reproduces it later on the same thread.  Recordings hold the
+//! values, which need not be `Send`, thus they stay on their thread.  Outside of
+//! `record_sets()` a set only checks whether a recording is active, without the feature
+//! sets do not check anything.
+
+use std::any::{type_name, Any};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+std::thread_local!(
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+    static RECORDED: RefCell<Vec<RecordedSet>> = const { RefCell::new(Vec::new()) };
+);
+
+/// One set of an association, recorded by `record_sets()`.
+#[derive(Clone)]
+pub struct RecordedSet {
+    /// Type name of the implementor.
+    pub implementor: &'static str,
+    /// Type name of the tag.
+    pub tag: &'static str,
+    /// Type name of the target.
+    pub target: &'static str,
+    value: Rc<dyn Any>,
+    debug_value: Option<String>,
+    apply: Rc<dyn Fn()>,
+}
+
+impl RecordedSet {
+    /// Returns the value that was set, `None` when it is not a 'V'.
+    pub fn value<V: 'static>(&self) -> Option<&V> {
+        self.value.downcast_ref()
+    }
+
+    /// Returns the `Debug` representation of the value, `None` when the target type does
+    /// not implement `Debug`.
+    pub fn debug_value(&self) -> Option<&str> {
+        self.debug_value.as_deref()
+    }
+
+    /// Sets the association to the recorded value again, on the current thread.
+    pub fn replay(&self) {
+        (self.apply)()
+    }
+}
+
+impl fmt::Debug for RecordedSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RecordedSet")
+            .field("implementor", &self.implementor)
+            .field("tag", &self.tag)
+            .field("target", &self.target)
+            .field("value", &self.debug_value)
+            .finish()
+    }
+}
+
+/// The sets recorded by `record_sets()`, in the order they happened.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    sets: Vec<RecordedSet>,
+}
+
+impl Recording {
+    /// Returns the recorded sets.
+    pub fn sets(&self) -> &[RecordedSet] {
+        &self.sets
+    }
+
+    /// Returns the number of recorded sets.
+    pub fn len(&self) -> usize {
+        self.sets.len()
+    }
+
+    /// Returns whether nothing was set.
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    /// Applies all recorded sets in order on the current thread.  Replaying inside of
+    /// `record_sets()` is recorded again.
+    pub fn replay(&self) {
+        self.sets.iter().for_each(RecordedSet::replay)
+    }
+}
+
+impl<'a> IntoIterator for &'a Recording {
+    type Item = &'a RecordedSet;
+    type IntoIter = std::slice::Iter<'a, RecordedSet>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.sets.iter()
+    }
+}
+
+/// Calls 'f' and returns every set of an association it made on the current thread.
+/// Nested calls record into their own `Recording`, their sets are part of the outer
+/// recording as well.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Request;
+/// assoc_threadlocal!(Request, u32 = 0);
+/// assoc_threadlocal!(Request, &'static str = "");
+///
+/// fn handle() {
+///     Request::set_threadlocal(7u32);
+///     Request::set_threadlocal("GET");
+///     Request::set_threadlocal(8u32);
+/// }
+///
+/// let recording = record_sets(handle);
+/// let values: Vec<_> = recording.sets().iter().filter_map(|set| set.debug_value()).collect();
+/// assert_eq!(values, ["7", "\"GET\"", "8"]);
+///
+/// // reproduce the same ambient state later
+/// <Request as SetAssocThreadLocal<u32>>::reset_threadlocal();
+/// <Request as SetAssocThreadLocal<&str>>::reset_threadlocal();
+/// recording.replay();
+/// assert_eq!(<Request as GetAssocThreadLocal<u32>>::get_threadlocal(), 8);
+/// assert_eq!(<Request as GetAssocThreadLocal<&str>>::get_threadlocal(), "GET");
+/// ```
+pub fn record_sets(f: impl FnOnce()) -> Recording {
+    // restores the outer recording on unwinding as well
+    struct Restore {
+        active: bool,
+        outer: Option<Vec<RecordedSet>>,
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Some(outer) = self.outer.take() {
+                finish(self.active, outer);
+            }
+        }
+    }
+
+    let mut restore = Restore {
+        active: ACTIVE.with(|active| active.replace(true)),
+        outer: Some(RECORDED.with(|recorded| std::mem::take(&mut *recorded.borrow_mut()))),
+    };
+    f();
+    let outer = restore.outer.take().expect("recording finished twice");
+    Recording {
+        sets: finish(restore.active, outer),
+    }
+}
+
+// puts the outer recording back and returns the inner one
+fn finish(active: bool, outer: Vec<RecordedSet>) -> Vec<RecordedSet> {
+    ACTIVE.with(|current| current.set(active));
+    RECORDED.with(|recorded| {
+        let mut recorded = recorded.borrow_mut();
+        let inner = std::mem::replace(&mut *recorded, outer);
+        if active {
+            recorded.extend(inner.iter().cloned());
+        }
+        inner
+    })
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn record_set<T: ?Sized + 'static, TAG: 'static, TARGET: Copy + 'static>(
+    value: TARGET,
+    set: fn(TARGET),
+    debug_value: impl FnOnce(&TARGET) -> Option<String>,
+) {
+    if ACTIVE.try_with(Cell::get).unwrap_or(false) {
+        record::<T, TAG, TARGET>(value, set, debug_value(&value))
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn record<T: ?Sized + 'static, TAG: 'static, TARGET: Copy + 'static>(
+    value: TARGET,
+    set: fn(TARGET),
+    debug_value: Option<String>,
+) {
+    // sets made while recording, e.g. by a counting allocator, are not recorded
+    let _ = RECORDED.try_with(|recorded| {
+        if let Ok(mut recorded) = recorded.try_borrow_mut() {
+            recorded.push(RecordedSet {
+                implementor: type_name::<T>(),
+                tag: type_name::<TAG>(),
+                target: type_name::<TARGET>(),
+                value: Rc::new(value),
+                debug_value,
+                apply: Rc::new(move || set(value)),
+            });
+        }
+    });
+}
+
+/// Records a set for `record::record_sets()`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_record {
+    ($TAG:ty, $T:ty, $TARGET:ty, $VALUE:ident, $SET:ident) => {
+        $crate::record::record_set::<$T, $TAG, $TARGET>($VALUE, $SET, |value| {
+            #[allow(unused_imports)]
+            use $crate::registry::{NoDebug as _, ViaDebug as _};
+            (&$crate::registry::DebugProbe(value)).debug_probe()
+        })
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::record_sets;
+    use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct Recorded;
+    struct Delayed;
+    crate::assoc_threadlocal!(Recorded, u32 = 0);
+    crate::assoc_threadlocal!(Recorded, u8 = const 0);
+    crate::assoc_threadlocal!(Recorded, i8 = 0, strict);
+    crate::assoc_threadlocal!(Delayed: Recorded, u32 = 0);
+
+    #[test]
+    fn nested_and_replayed() {
+        <Recorded as SetAssocThreadLocal<u32>>::set_threadlocal(99);
+        let mut inner = None;
+        let outer = record_sets(|| {
+            <Recorded as SetAssocThreadLocal<u32>>::set_threadlocal(1);
+            inner = Some(record_sets(|| {
+                <Recorded as SetAssocThreadLocal<u8>>::set_threadlocal(2);
+                <Recorded as SetAssocThreadLocal<i8>>::set_threadlocal(3);
+            }));
+            <Recorded as SetAssocThreadLocal<u32, Delayed>>::set_threadlocal(4);
+        });
+        let inner = inner.unwrap();
+        assert_eq!(inner.len(), 2);
+        assert_eq!(inner.sets()[0].value::<u8>(), Some(&2));
+        assert_eq!(outer.len(), 4);
+        assert_eq!(outer.sets()[3].tag, std::any::type_name::<Delayed>());
+        assert!(outer.sets()[3].implementor.ends_with("Recorded"));
+        assert_eq!(outer.sets()[3].value::<u8>(), None);
+
+        // not recording anymore
+        <Recorded as SetAssocThreadLocal<u32>>::set_threadlocal(5);
+        assert!(record_sets(|| ()).is_empty());
+
+        outer.replay();
+        assert_eq!(<Recorded as GetAssocThreadLocal<u32>>::get_threadlocal(), 1);
+        assert_eq!(<Recorded as GetAssocThreadLocal<u8>>::get_threadlocal(), 2);
+        assert_eq!(<Recorded as GetAssocThreadLocal<i8>>::get_threadlocal(), 3);
+        assert_eq!(
+            <Recorded as GetAssocThreadLocal<u32, Delayed>>::get_threadlocal(),
+            4
+        );
+    }
+
+    #[test]
+    fn restored_on_panic() {
+        let _ = std::panic::catch_unwind(|| {
+            record_sets(|| {
+                <Recorded as SetAssocThreadLocal<u32>>::set_threadlocal(1);
+                panic!("recorded");
+            })
+        });
+        assert!(!super::ACTIVE.with(|active| active.get()));
+        assert!(super::RECORDED.with(|recorded| recorded.borrow().is_empty()));
+    }
+}
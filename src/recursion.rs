@@ -0,0 +1,121 @@
+//! Per-thread recursion depth limits.
+
+use crate::AssocThreadLocal;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Tag for the thread local depth counter of a recursion guard.
+pub struct RecursionDepth;
+
+/// Error returned when entering would exceed the maximum recursion depth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthExceeded {
+    /// The configured maximum depth.
+    pub max: usize,
+}
+
+impl fmt::Display for DepthExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "maximum recursion depth of {} exceeded", self.max)
+    }
+}
+
+impl std::error::Error for DepthExceeded {}
+
+/// A per-thread recursion depth counter with an upper limit.
+/// Use the `assoc_recursion_guard!()` macro for implementing this trait on types.
+pub trait AssocRecursionGuard: AssocThreadLocal<usize, RecursionDepth> + Sized {
+    /// Maximum depth that can be entered.
+    const MAX_DEPTH: usize;
+
+    /// Enters one level, the level is left when the guard is dropped.
+    fn enter() -> Result<RecursionGuard<Self>, DepthExceeded> {
+        let depth = Self::get_threadlocal();
+        if depth >= Self::MAX_DEPTH {
+            return Err(DepthExceeded {
+                max: Self::MAX_DEPTH,
+            });
+        }
+        Self::set_threadlocal(depth + 1);
+        Ok(RecursionGuard {
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the current depth on this thread.
+    fn depth() -> usize {
+        Self::get_threadlocal()
+    }
+}
+
+/// Leaves one recursion level when dropped.
+#[must_use = "the level is left immediately when the guard is not kept"]
+pub struct RecursionGuard<T: AssocRecursionGuard> {
+    // the level must be left on the thread that entered it
+    _marker: PhantomData<(T, *const ())>,
+}
+
+impl<T: AssocRecursionGuard> Drop for RecursionGuard<T> {
+    fn drop(&mut self) {
+        T::set_threadlocal(T::get_threadlocal() - 1);
+    }
+}
+
+/// Associates a per-thread recursion depth limit to a type.
+///
+///  * 'T' is the type you want have a thread local depth counter associated to
+///  * 'max' is the maximum depth that can be entered
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct MySerializer;
+/// assoc_recursion_guard!(MySerializer, max = 3);
+///
+/// fn nest(n: usize) -> Result<usize, DepthExceeded> {
+///     let _level = MySerializer::enter()?;
+///     if n == 0 { Ok(MySerializer::depth()) } else { nest(n - 1) }
+/// }
+///
+/// assert_eq!(nest(2), Ok(3));
+/// assert_eq!(nest(3), Err(DepthExceeded { max: 3 }));
+/// assert_eq!(MySerializer::depth(), 0);
+/// ```
+#[macro_export]
+macro_rules! assoc_recursion_guard {
+    ($T:ty, max = $MAX:expr) => {
+        $crate::assoc_threadlocal!($crate::RecursionDepth:$T, usize = 0);
+
+        impl $crate::AssocRecursionGuard for $T {
+            const MAX_DEPTH: usize = $MAX;
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocRecursionGuard;
+
+    struct TestWalker;
+    assoc_recursion_guard!(TestWalker, max = 2);
+
+    #[test]
+    fn limit() {
+        let a = TestWalker::enter().unwrap();
+        let b = TestWalker::enter().unwrap();
+        assert_eq!(TestWalker::enter().err().map(|e| e.max), Some(2));
+        drop(b);
+        assert_eq!(TestWalker::depth(), 1);
+        drop(a);
+        assert_eq!(TestWalker::depth(), 0);
+    }
+
+    #[test]
+    fn released_on_unwind() {
+        let _ = std::panic::catch_unwind(|| {
+            let _level = TestWalker::enter().unwrap();
+            panic!("unwinding");
+        });
+        assert_eq!(TestWalker::depth(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+//! Per-thread reentrancy detection.
+
+use crate::AssocThreadLocal;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Tag for the thread local flag of a reentrancy guard.
+pub struct ReentrancyFlag;
+
+/// Error returned when a non-reentrant section is entered again on the same thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reentered;
+
+impl fmt::Display for Reentered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("reentrant call into non-reentrant code")
+    }
+}
+
+impl std::error::Error for Reentered {}
+
+/// A per-thread flag rejecting reentrant calls.
+/// Use the `assoc_reentrancy_flag!()` macro for implementing this trait on types.
+pub trait AssocReentrancyFlag: AssocThreadLocal<bool, ReentrancyFlag> + Sized {
+    /// Enters the non-reentrant section, it is left when the guard is dropped.
+    fn enter() -> Result<ReentrancyGuard<Self>, Reentered> {
+        if Self::get_threadlocal() {
+            return Err(Reentered);
+        }
+        Self::set_threadlocal(true);
+        Ok(ReentrancyGuard {
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns whether the current thread is inside the section.
+    fn is_entered() -> bool {
+        Self::get_threadlocal()
+    }
+}
+
+/// Leaves the non-reentrant section when dropped.
+#[must_use = "the section is left immediately when the guard is not kept"]
+pub struct ReentrancyGuard<T: AssocReentrancyFlag> {
+    // the section must be left on the thread that entered it
+    _marker: PhantomData<(T, *const ())>,
+}
+
+impl<T: AssocReentrancyFlag> Drop for ReentrancyGuard<T> {
+    fn drop(&mut self) {
+        T::set_threadlocal(false);
+    }
+}
+
+/// Associates a per-thread reentrancy flag to a type.
+///
+///  * 'T' is the type you want have a thread local reentrancy flag associated to
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct MyCallbackHost;
+/// assoc_reentrancy_flag!(MyCallbackHost);
+///
+/// fn dispatch(callback: impl FnOnce()) -> Result<(), Reentered> {
+///     let _busy = MyCallbackHost::enter()?;
+///     callback();
+///     Ok(())
+/// }
+///
+/// assert_eq!(dispatch(|| assert_eq!(dispatch(|| {}), Err(Reentered))), Ok(()));
+/// ```
+#[macro_export]
+macro_rules! assoc_reentrancy_flag {
+    ($T:ty) => {
+        $crate::assoc_threadlocal!($crate::ReentrancyFlag:$T, bool = false);
+
+        impl $crate::AssocReentrancyFlag for $T {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocReentrancyFlag, Reentered};
+
+    struct TestHost;
+    assoc_reentrancy_flag!(TestHost);
+
+    #[test]
+    fn rejects_reentry() {
+        let guard = TestHost::enter().unwrap();
+        assert!(TestHost::is_entered());
+        assert_eq!(TestHost::enter().err(), Some(Reentered));
+        drop(guard);
+        assert!(TestHost::enter().is_ok());
+    }
+
+    #[test]
+    fn other_threads_may_enter() {
+        let _guard = TestHost::enter().unwrap();
+        assert!(std::thread::spawn(|| TestHost::enter().is_ok())
+            .join()
+            .unwrap());
+    }
+}
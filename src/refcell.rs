@@ -0,0 +1,500 @@
+//! Thread local objects that are not `Copy`.
+//!
+//! Targets that can not be copied out are kept in a `RefCell`.  Short accesses go through
+//! `with_threadlocal()` and `with_threadlocal_mut()`, code that needs to hold on to the
+//! value across a few statements borrows it with `borrow_threadlocal()` or
+//! `borrow_threadlocal_mut()`.  The returned guards are not `Send`, they can not leave
+//! the thread that borrowed the value.
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::ptr::NonNull;
+
+type Caller = Option<&'static Location<'static>>;
+
+struct Shared<T> {
+    value: RefCell<T>,
+    borrowed_at: BorrowedAt,
+}
+
+// where the innermost live borrow was taken, only recorded with the `borrow-location`
+// feature
+#[derive(Default)]
+struct BorrowedAt {
+    #[cfg(feature = "borrow-location")]
+    location: std::cell::Cell<Caller>,
+}
+
+impl BorrowedAt {
+    #[cfg(feature = "borrow-location")]
+    fn get(&self) -> Caller {
+        self.location.get()
+    }
+
+    #[cfg(feature = "borrow-location")]
+    fn replace(&self, location: Caller) -> Caller {
+        self.location.replace(location)
+    }
+
+    #[cfg(not(feature = "borrow-location"))]
+    fn get(&self) -> Caller {
+        None
+    }
+
+    #[cfg(not(feature = "borrow-location"))]
+    fn replace(&self, _location: Caller) -> Caller {
+        None
+    }
+}
+
+/// The thread local storage of a RefCell association, created by the `assoc_refcell!()`
+/// macro.
+///
+/// The value lives in its own allocation.  When the thread exits while a guard is still
+/// alive, e.g. one stored in another thread local, the value is leaked instead of dropped,
+/// so the guard never dangles.
+pub struct RefSlot<T> {
+    shared: NonNull<Shared<T>>,
+}
+
+impl<T> RefSlot<T> {
+    #[doc(hidden)]
+    pub fn new(value: T) -> Self {
+        RefSlot {
+            shared: NonNull::from(Box::leak(Box::new(Shared {
+                value: RefCell::new(value),
+                borrowed_at: BorrowedAt::default(),
+            }))),
+        }
+    }
+
+    // only freed when no guard is alive, see Drop
+    fn shared(&self) -> &'static Shared<T>
+    where
+        T: 'static,
+    {
+        unsafe { self.shared.as_ref() }
+    }
+
+    fn try_borrow(
+        &self,
+        location: &'static Location<'static>,
+    ) -> Result<ThreadLocalRef<T>, BorrowError>
+    where
+        T: 'static,
+    {
+        let shared = self.shared();
+        match shared.value.try_borrow() {
+            Ok(borrow) => Ok(ThreadLocalRef {
+                borrow,
+                previous: shared.borrowed_at.replace(Some(location)),
+                borrowed_at: &shared.borrowed_at,
+            }),
+            Err(_) => Err(BorrowError {
+                mutable: false,
+                conflict: shared.borrowed_at.get(),
+            }),
+        }
+    }
+
+    fn try_borrow_mut(
+        &self,
+        location: &'static Location<'static>,
+    ) -> Result<ThreadLocalRefMut<T>, BorrowError>
+    where
+        T: 'static,
+    {
+        let shared = self.shared();
+        match shared.value.try_borrow_mut() {
+            Ok(borrow) => Ok(ThreadLocalRefMut {
+                borrow,
+                previous: shared.borrowed_at.replace(Some(location)),
+                borrowed_at: &shared.borrowed_at,
+            }),
+            Err(_) => Err(BorrowError {
+                mutable: true,
+                conflict: shared.borrowed_at.get(),
+            }),
+        }
+    }
+}
+
+impl<T> Drop for RefSlot<T> {
+    fn drop(&mut self) {
+        if unsafe { self.shared.as_ref() }
+            .value
+            .try_borrow_mut()
+            .is_ok()
+        {
+            drop(unsafe { Box::from_raw(self.shared.as_ptr()) });
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RefSlot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&unsafe { self.shared.as_ref() }.value, f)
+    }
+}
+
+/// Shared borrow of a RefCell association, returned by
+/// `AssocRefCell::borrow_threadlocal()`.
+pub struct ThreadLocalRef<T: 'static> {
+    borrow: Ref<'static, T>,
+    previous: Caller,
+    borrowed_at: &'static BorrowedAt,
+}
+
+impl<T: 'static> Deref for ThreadLocalRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.borrow
+    }
+}
+
+impl<T: 'static> Drop for ThreadLocalRef<T> {
+    fn drop(&mut self) {
+        self.borrowed_at.replace(self.previous);
+    }
+}
+
+impl<T: fmt::Debug + 'static> fmt::Debug for ThreadLocalRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.borrow, f)
+    }
+}
+
+/// Exclusive borrow of a RefCell association, returned by
+/// `AssocRefCell::borrow_threadlocal_mut()`.
+pub struct ThreadLocalRefMut<T: 'static> {
+    borrow: RefMut<'static, T>,
+    previous: Caller,
+    borrowed_at: &'static BorrowedAt,
+}
+
+impl<T: 'static> Deref for ThreadLocalRefMut<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.borrow
+    }
+}
+
+impl<T: 'static> DerefMut for ThreadLocalRefMut<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.borrow
+    }
+}
+
+impl<T: 'static> Drop for ThreadLocalRefMut<T> {
+    fn drop(&mut self) {
+        self.borrowed_at.replace(self.previous);
+    }
+}
+
+impl<T: fmt::Debug + 'static> fmt::Debug for ThreadLocalRefMut<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.borrow, f)
+    }
+}
+
+/// Error returned when a RefCell association can not be borrowed because of a conflicting
+/// borrow on the current thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BorrowError {
+    /// Whether the failed borrow was a mutable one.
+    pub mutable: bool,
+    /// Where the conflicting borrow was taken, only known with the `borrow-location`
+    /// feature.  With several shared borrows alive this is the most recent one.
+    pub conflict: Option<&'static Location<'static>>,
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.mutable {
+            f.write_str("thread local association already borrowed")?;
+        } else {
+            f.write_str("thread local association already mutably borrowed")?;
+        }
+        match self.conflict {
+            Some(location) => write!(f, " at {location}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for BorrowError {}
+
+/// Associates a thread local object of type T, which does not need to be `Copy`, to a type.
+/// Use the `assoc_refcell!()` macro for implementing this trait on types.
+pub trait AssocRefCell<T: 'static, TAG = ()>: Sized {
+    /// Returns the associated thread local slot of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_slot() -> *const RefSlot<T>;
+
+    /// Calls 'f' with a shared reference to the current threads value.
+    ///
+    /// # Panics
+    /// When the value is mutably borrowed.
+    #[track_caller]
+    fn with_threadlocal<R>(f: impl FnOnce(&T) -> R) -> R {
+        f(&Self::borrow_threadlocal())
+    }
+
+    /// Calls 'f' with an exclusive reference to the current threads value.
+    ///
+    /// # Panics
+    /// When the value is borrowed.
+    #[track_caller]
+    fn with_threadlocal_mut<R>(f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut Self::borrow_threadlocal_mut())
+    }
+
+    /// Borrows the current threads value until the returned guard is dropped.
+    ///
+    /// # Panics
+    /// When the value is mutably borrowed.  With the `borrow-location` feature the message
+    /// tells where the conflicting borrow was taken.
+    #[track_caller]
+    fn borrow_threadlocal() -> ThreadLocalRef<T> {
+        Self::try_borrow_threadlocal().unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    /// Mutably borrows the current threads value until the returned guard is dropped.
+    ///
+    /// # Panics
+    /// When the value is borrowed.  With the `borrow-location` feature the message tells
+    /// where the conflicting borrow was taken.
+    #[track_caller]
+    fn borrow_threadlocal_mut() -> ThreadLocalRefMut<T> {
+        Self::try_borrow_threadlocal_mut().unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    /// Borrows the current threads value like `borrow_threadlocal()`, returns an error
+    /// instead of panicking when it is mutably borrowed.
+    #[track_caller]
+    fn try_borrow_threadlocal() -> Result<ThreadLocalRef<T>, BorrowError> {
+        unsafe { (*Self::the_slot()).try_borrow(Location::caller()) }
+    }
+
+    /// Mutably borrows the current threads value like `borrow_threadlocal_mut()`, returns
+    /// an error instead of panicking when it is borrowed.
+    #[track_caller]
+    fn try_borrow_threadlocal_mut() -> Result<ThreadLocalRefMut<T>, BorrowError> {
+        unsafe { (*Self::the_slot()).try_borrow_mut(Location::caller()) }
+    }
+
+    /// Replaces the current threads value, returns the old one.
+    ///
+    /// # Panics
+    /// When the value is borrowed.
+    #[track_caller]
+    fn replace_threadlocal(value: T) -> T {
+        std::mem::replace(&mut *Self::borrow_threadlocal_mut(), value)
+    }
+
+    /// Takes the current threads value, leaving the default in its place.
+    ///
+    /// # Panics
+    /// When the value is borrowed.
+    #[track_caller]
+    fn take_threadlocal() -> T
+    where
+        T: Default,
+    {
+        Self::replace_threadlocal(T::default())
+    }
+}
+
+/// Associates a thread local object that does not need to be `Copy` to a type.
+///
+///  * 'TAG' is used to discriminate between different associations of the same type
+///  * 'T' is the type you want have a thread local object associated to
+///  * 'TARGET' is the type of the object
+///  * 'INIT' is the value each thread starts with
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Log;
+/// assoc_refcell!(Log, Vec<String> = Vec::new());
+///
+/// Log::with_threadlocal_mut(|log| log.push(String::from("started")));
+/// {
+///     let mut log = Log::borrow_threadlocal_mut();
+///     log.push(String::from("working"));
+///     log.retain(|line| line != "started");
+/// }
+/// assert_eq!(*Log::borrow_threadlocal(), ["working"]);
+/// assert_eq!(Log::take_threadlocal().len(), 1);
+/// ```
+///
+/// Reentrant code that may run while the value is borrowed uses the `try_` variants:
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Listeners;
+/// assoc_refcell!(Listeners, Vec<fn()> = Vec::new());
+///
+/// fn subscribe(listener: fn()) -> Result<(), refcell::BorrowError> {
+///     Listeners::try_borrow_threadlocal_mut()?.push(listener);
+///     Ok(())
+/// }
+///
+/// subscribe(|| assert!(subscribe(|| ()).is_err())).unwrap();
+/// for listener in Listeners::borrow_threadlocal().iter() {
+///     listener();
+/// }
+/// ```
+///
+/// Guards can not be sent to other threads:
+/// ```compile_fail
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Log;
+/// assoc_refcell!(Log, Vec<String> = Vec::new());
+///
+/// let log = Log::borrow_threadlocal();
+/// std::thread::spawn(move || log.len());
+/// ```
+#[macro_export]
+macro_rules! assoc_refcell {
+    ($TAG:ty: $T:ty, $TARGET:ty = $INIT:expr) => {
+        impl $crate::AssocRefCell<$TARGET, $TAG> for $T {
+            unsafe fn the_slot() -> *const $crate::refcell::RefSlot<$TARGET> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_SLOT: (
+                        $crate::refcell::RefSlot<$TARGET>,
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = (
+                        $crate::refcell::RefSlot::new($INIT),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_SLOT.with(|l| &l.0 as *const $crate::refcell::RefSlot<$TARGET>)
+            }
+        }
+    };
+    ($T:ty, $TARGET:ty = $INIT:expr) => {
+        $crate::assoc_refcell!((): $T, $TARGET = $INIT);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocRefCell;
+    use std::collections::HashMap;
+
+    struct Names;
+    struct Counts;
+    assoc_refcell!(Names, Vec<&'static str> = vec!["init"]);
+    assoc_refcell!(Counts: Names, HashMap<&'static str, u32> = HashMap::new());
+
+    #[test]
+    fn guards() {
+        {
+            let first = <Names as AssocRefCell<Vec<&str>>>::borrow_threadlocal();
+            let second = <Names as AssocRefCell<Vec<&str>>>::borrow_threadlocal();
+            assert_eq!(first.len(), second.len());
+        }
+        {
+            let mut counts =
+                <Names as AssocRefCell<HashMap<&str, u32>, Counts>>::borrow_threadlocal_mut();
+            *counts.entry("a").or_default() += 2;
+            // distinct associations borrow independently
+            <Names as AssocRefCell<Vec<&str>>>::with_threadlocal_mut(|names| names.push("a"));
+        }
+        assert_eq!(
+            <Names as AssocRefCell<Vec<&str>>>::replace_threadlocal(Vec::new()),
+            ["init", "a"]
+        );
+        assert_eq!(
+            std::thread::spawn(|| <Names as AssocRefCell<Vec<&str>>>::with_threadlocal(Vec::len))
+                .join()
+                .unwrap(),
+            1
+        );
+        <Names as AssocRefCell<HashMap<&str, u32>, Counts>>::with_threadlocal(|counts| {
+            assert_eq!(counts["a"], 2)
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn conflicting_borrow() {
+        let _shared = <Names as AssocRefCell<Vec<&str>>>::borrow_threadlocal();
+        <Names as AssocRefCell<Vec<&str>>>::borrow_threadlocal_mut();
+    }
+
+    #[test]
+    fn try_borrow() {
+        let shared = <Names as AssocRefCell<Vec<&str>>>::try_borrow_threadlocal().unwrap();
+        let error = <Names as AssocRefCell<Vec<&str>>>::try_borrow_threadlocal_mut().unwrap_err();
+        assert!(error.mutable);
+        assert_eq!(error.conflict.is_some(), cfg!(feature = "borrow-location"));
+        assert!(<Names as AssocRefCell<Vec<&str>>>::try_borrow_threadlocal().is_ok());
+        drop(shared);
+        let _exclusive = <Names as AssocRefCell<Vec<&str>>>::try_borrow_threadlocal_mut().unwrap();
+        assert!(<Names as AssocRefCell<Vec<&str>>>::try_borrow_threadlocal()
+            .unwrap_err()
+            .to_string()
+            .starts_with("thread local association already mutably borrowed"));
+    }
+
+    #[cfg(feature = "borrow-location")]
+    #[test]
+    fn conflict_location() {
+        let outer = <Names as AssocRefCell<Vec<&str>>>::borrow_threadlocal();
+        let line = line!() + 1;
+        let inner = <Names as AssocRefCell<Vec<&str>>>::borrow_threadlocal();
+        let error = <Names as AssocRefCell<Vec<&str>>>::try_borrow_threadlocal_mut().unwrap_err();
+        assert_eq!(error.conflict.unwrap().line(), line);
+        drop(inner);
+        let error = <Names as AssocRefCell<Vec<&str>>>::try_borrow_threadlocal_mut().unwrap_err();
+        assert_eq!(error.conflict.unwrap().line(), line - 2);
+        drop(outer);
+
+        let panic = std::panic::catch_unwind(|| {
+            let _exclusive = <Names as AssocRefCell<Vec<&str>>>::borrow_threadlocal_mut();
+            <Names as AssocRefCell<Vec<&str>>>::with_threadlocal(|_| ());
+        });
+        let message = panic.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("thread local association already mutably borrowed at "));
+        assert!(message.contains(file!()));
+    }
+
+    struct Held;
+    assoc_refcell!(Held, String = String::from("kept"));
+
+    struct Holder(Option<crate::refcell::ThreadLocalRef<String>>);
+
+    impl Drop for Holder {
+        fn drop(&mut self) {
+            // may run after the slot of Held was destroyed
+            assert_eq!(self.0.as_deref().map(String::as_str), Some("kept"));
+        }
+    }
+
+    std::thread_local!(static HOLDER: std::cell::RefCell<Holder> = const {
+        std::cell::RefCell::new(Holder(None))
+    });
+
+    #[test]
+    fn guard_outliving_slot() {
+        std::thread::spawn(|| {
+            // the holder is registered first and destroyed last
+            HOLDER.with(|_| ());
+            let guard = Held::borrow_threadlocal();
+            HOLDER.with(|holder| holder.borrow_mut().0 = Some(guard));
+        })
+        .join()
+        .unwrap();
+    }
+}
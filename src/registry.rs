@@ -0,0 +1,599 @@
+//! Process wide registry of associations (requires the `registry` feature).
+//!
+//! Every association defined with `assoc_threadlocal!()` registers a descriptor the first
+//! time any thread initializes it.  Descriptors give access to the current threads value
+//! without knowing the concrete types, for diagnostics and bulk operations.  Descriptors
+//! also record which threads initialized their association.
+
+use std::any::{Any, TypeId};
+use std::cell::Cell;
+use std::fmt;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::ThreadId;
+use std::time::Instant;
+
+static REGISTRY: Mutex<Vec<&'static AssocDescriptor>> = Mutex::new(Vec::new());
+
+std::thread_local!(
+    // set while this thread records an initialization
+    static RECORDING: Cell<bool> = const { Cell::new(false) };
+);
+
+/// A thread that initialized an association, returned by
+/// `AssocDescriptor::initialized_threads()`.
+#[derive(Clone, Debug)]
+pub struct ThreadInit {
+    /// The id of the thread.
+    pub thread: ThreadId,
+    /// The name of the thread, if it has one.
+    pub name: Option<String>,
+    /// When the association was initialized on the thread.
+    pub at: Instant,
+}
+
+/// Describes one association, created by the `assoc_threadlocal!()` macro.
+pub struct AssocDescriptor {
+    implementor_id: fn() -> TypeId,
+    implementor_name: fn() -> &'static str,
+    tag_id: fn() -> TypeId,
+    tag_name: fn() -> &'static str,
+    target_name: fn() -> &'static str,
+    debug_value: fn() -> Option<String>,
+    reset: fn(),
+    capture: fn() -> Box<dyn Any>,
+    restore: fn(Box<dyn Any>),
+    override_location: fn() -> Option<&'static Location<'static>>,
+    registered: AtomicBool,
+    threads: Mutex<Vec<ThreadInit>>,
+}
+
+impl AssocDescriptor {
+    // only called by the macro, named fields would not make that more readable
+    #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
+    pub const fn new(
+        implementor_id: fn() -> TypeId,
+        implementor_name: fn() -> &'static str,
+        tag_id: fn() -> TypeId,
+        tag_name: fn() -> &'static str,
+        target_name: fn() -> &'static str,
+        debug_value: fn() -> Option<String>,
+        reset: fn(),
+        capture: fn() -> Box<dyn Any>,
+        restore: fn(Box<dyn Any>),
+        override_location: fn() -> Option<&'static Location<'static>>,
+    ) -> Self {
+        AssocDescriptor {
+            implementor_id,
+            implementor_name,
+            tag_id,
+            tag_name,
+            target_name,
+            debug_value,
+            reset,
+            capture,
+            restore,
+            override_location,
+            registered: AtomicBool::new(false),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the `TypeId` of the type the value is associated to.
+    pub fn implementor_id(&self) -> TypeId {
+        (self.implementor_id)()
+    }
+
+    /// Returns the name of the type the value is associated to.
+    pub fn implementor_name(&self) -> &'static str {
+        (self.implementor_name)()
+    }
+
+    /// Returns the `TypeId` of the tag type.
+    pub fn tag_id(&self) -> TypeId {
+        (self.tag_id)()
+    }
+
+    /// Returns the name of the tag type.
+    pub fn tag_name(&self) -> &'static str {
+        (self.tag_name)()
+    }
+
+    /// Returns the name of the target type.
+    pub fn target_name(&self) -> &'static str {
+        (self.target_name)()
+    }
+
+    /// Returns the `Debug` representation of the current threads value, `None` when the
+    /// target type does not implement `Debug` or a strict association is not set.
+    pub fn debug_value(&self) -> Option<String> {
+        (self.debug_value)()
+    }
+
+    /// Resets the current threads value to a freshly evaluated INIT.
+    pub fn reset(&self) {
+        (self.reset)()
+    }
+
+    /// Returns the current threads value as type erased box.
+    pub fn capture(&self) -> Box<dyn Any> {
+        (self.capture)()
+    }
+
+    /// Sets the current threads value from a box obtained by `capture()`.
+    ///
+    /// # Panics
+    /// When the box holds a value of another type.
+    pub fn restore(&self, value: Box<dyn Any>) {
+        (self.restore)(value)
+    }
+
+    /// Returns where the innermost active override made by
+    /// `AssocThreadLocal::set_threadlocal_scoped_tracked()` was set on the current thread.
+    pub fn override_location(&self) -> Option<&'static Location<'static>> {
+        (self.override_location)()
+    }
+
+    /// Returns the threads that initialized the association so far, in the order they did,
+    /// including threads that exited since.  Associations with a `const` INIT count as
+    /// initialized on a thread when they were first set there.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Worker;
+    /// assoc_threadlocal!(Worker, u32 = 0);
+    ///
+    /// let workers: Vec<_> = (0..2)
+    ///     .map(|i| {
+    ///         std::thread::Builder::new()
+    ///             .name(format!("worker-{i}"))
+    ///             .spawn(|| Worker::ensure_threadlocal_initialized())
+    ///             .unwrap()
+    ///     })
+    ///     .collect();
+    /// let ids: Vec<_> = workers.iter().map(|w| w.thread().id()).collect();
+    /// workers.into_iter().for_each(|w| w.join().unwrap());
+    ///
+    /// let descriptor = registry::associations_of::<Worker>().next().unwrap();
+    /// assert!(ids.iter().all(|&id| descriptor.is_initialized_on(id)));
+    /// assert!(!descriptor.is_initialized_on(std::thread::current().id()));
+    /// let mut names: Vec<_> = descriptor
+    ///     .initialized_threads()
+    ///     .into_iter()
+    ///     .filter_map(|init| init.name)
+    ///     .collect();
+    /// names.sort();
+    /// assert_eq!(names, ["worker-0", "worker-1"]);
+    /// ```
+    pub fn initialized_threads(&self) -> Vec<ThreadInit> {
+        self.threads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Returns whether the association was initialized on the thread 'thread'.
+    pub fn is_initialized_on(&self, thread: ThreadId) -> bool {
+        self.threads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .any(|init| init.thread == thread)
+    }
+
+    // recording allocates, with a counting allocator that initializes associations again
+    fn record_thread(&self) {
+        if RECORDING.with(|recording| recording.replace(true)) {
+            return;
+        }
+        let current = std::thread::current();
+        let mut threads = self.threads.lock().unwrap_or_else(|e| e.into_inner());
+        if !threads.iter().any(|init| init.thread == current.id()) {
+            threads.push(ThreadInit {
+                thread: current.id(),
+                name: current.name().map(str::to_string),
+                at: Instant::now(),
+            });
+        }
+        drop(threads);
+        RECORDING.with(|recording| recording.set(false));
+    }
+}
+
+impl fmt::Debug for AssocDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AssocDescriptor")
+            .field("implementor", &self.implementor_name())
+            .field("tag", &self.tag_name())
+            .field("target", &self.target_name())
+            .finish()
+    }
+}
+
+/// Registers 'descriptor' unless it is already registered and records the current thread
+/// as initializing it.
+#[doc(hidden)]
+pub fn register(descriptor: &'static AssocDescriptor) {
+    // an atomic flag and not a 'Once' because registering may allocate, which can recurse
+    // into associations when a counting allocator is installed
+    if !descriptor.registered.swap(true, Ordering::AcqRel) {
+        REGISTRY
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(descriptor);
+    }
+    descriptor.record_thread();
+}
+
+/// Returns all associations that were initialized on any thread so far.
+pub fn associations() -> Vec<&'static AssocDescriptor> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Returns the registered associations of the implementor type 'T'.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Example;
+/// struct Name;
+/// assoc_threadlocal!(Example, u32 = 42);
+/// assoc_threadlocal!(Name:Example, &'static str = "example");
+///
+/// // associations register on first access
+/// GetAssocThreadLocal::<u32>::get_threadlocal_from(&Example);
+/// GetAssocThreadLocal::<&str, Name>::get_threadlocal_from(&Example);
+///
+/// let mut values: Vec<_> = registry::associations_of::<Example>()
+///     .map(|d| d.debug_value().unwrap())
+///     .collect();
+/// values.sort();
+/// assert_eq!(values, ["\"example\"", "42"]);
+/// ```
+pub fn associations_of<T: 'static>() -> impl Iterator<Item = &'static AssocDescriptor> {
+    associations()
+        .into_iter()
+        .filter(|d| d.implementor_id() == TypeId::of::<T>())
+}
+
+/// Resets every registered association on the calling thread to a freshly evaluated INIT.
+///
+/// Useful between jobs of long lived pool threads.  Associations that were never
+/// initialized on any thread are not registered yet, but these hold their INIT anyway.
+pub fn reset_all_threadlocals() {
+    reset_threadlocals_where(|_| true)
+}
+
+/// Resets the registered associations of the implementor type 'T' on the calling thread.
+pub fn reset_threadlocals_of<T: 'static>() {
+    reset_threadlocals_where(|d| d.implementor_id() == TypeId::of::<T>())
+}
+
+/// Resets the registered associations with tag 'TAG' on the calling thread.
+pub fn reset_threadlocals_tagged<TAG: 'static>() {
+    reset_threadlocals_where(|d| d.tag_id() == TypeId::of::<TAG>())
+}
+
+/// Resets the registered associations selected by 'filter' on the calling thread.
+pub fn reset_threadlocals_where(mut filter: impl FnMut(&AssocDescriptor) -> bool) {
+    // resetting runs INIT which may register more associations, don't hold the lock
+    for descriptor in associations() {
+        if filter(descriptor) {
+            descriptor.reset();
+        }
+    }
+}
+
+/// Resets all registered associations of the current thread and restores their values
+/// when dropped, returned by `isolate_threadlocals()`.
+#[must_use = "the values are restored immediately when the guard is not kept"]
+pub struct IsolationGuard {
+    saved: Vec<(&'static AssocDescriptor, Box<dyn Any>)>,
+    // the values must be restored on the thread that saved them
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+/// Saves and resets the values of all registered associations on the current thread.
+/// Dropping the guard restores the saved values, associations that got registered in
+/// the meantime are reset.  Used by the `#[assoc_test]` attribute to isolate tests that
+/// run on reused threads.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Example;
+/// assoc_threadlocal!(Example, u32 = 0);
+///
+/// Example::set_threadlocal(5);
+/// {
+///     let _isolated = registry::isolate_threadlocals();
+///     assert_eq!(Example::get_threadlocal(), 0);
+///     Example::set_threadlocal(6);
+/// }
+/// assert_eq!(Example::get_threadlocal(), 5);
+/// ```
+pub fn isolate_threadlocals() -> IsolationGuard {
+    let saved = associations()
+        .into_iter()
+        .map(|descriptor| (descriptor, descriptor.capture()))
+        .collect();
+    reset_all_threadlocals();
+    IsolationGuard {
+        saved,
+        _not_send: std::marker::PhantomData,
+    }
+}
+
+impl Drop for IsolationGuard {
+    fn drop(&mut self) {
+        for descriptor in associations() {
+            if !self
+                .saved
+                .iter()
+                .any(|(saved, _)| std::ptr::eq(*saved, descriptor))
+            {
+                descriptor.reset();
+            }
+        }
+        for (descriptor, value) in self.saved.drain(..) {
+            descriptor.restore(value);
+        }
+    }
+}
+
+/// Returns the registered associations and their values on the current thread as JSON
+/// array, for crash reports and admin endpoints.
+///
+/// Each entry is an object with the 'implementor', 'tag' and 'target' type names and the
+/// `Debug` representation of the value as string, `null` when it has none.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Exported;
+/// assoc_threadlocal!(Exported, u32 = 42);
+/// Exported::get_threadlocal();
+///
+/// let json = registry::export_current_thread_json();
+/// assert!(json.contains(r#""tag":"()","target":"u32","value":"42"}"#));
+/// ```
+pub fn export_current_thread_json() -> String {
+    let mut json = String::from("[");
+    for (i, descriptor) in associations().into_iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"implementor\":");
+        push_json_string(&mut json, descriptor.implementor_name());
+        json.push_str(",\"tag\":");
+        push_json_string(&mut json, descriptor.tag_name());
+        json.push_str(",\"target\":");
+        push_json_string(&mut json, descriptor.target_name());
+        json.push_str(",\"value\":");
+        match descriptor.debug_value() {
+            Some(value) => push_json_string(&mut json, &value),
+            None => json.push_str("null"),
+        }
+        json.push('}');
+    }
+    json.push(']');
+    json
+}
+
+pub(crate) fn push_json_string(json: &mut String, s: &str) {
+    use std::fmt::Write;
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Formats values with `Debug` when available, used by the `assoc_threadlocal!()` macro
+/// through autoref specialization together with `NoDebug`.
+#[doc(hidden)]
+pub struct DebugProbe<'a, V>(pub &'a V);
+
+#[doc(hidden)]
+pub trait ViaDebug {
+    fn debug_probe(&self) -> Option<String>;
+}
+
+impl<V: fmt::Debug> ViaDebug for DebugProbe<'_, V> {
+    fn debug_probe(&self) -> Option<String> {
+        Some(format!("{:?}", self.0))
+    }
+}
+
+#[doc(hidden)]
+pub trait NoDebug {
+    fn debug_probe(&self) -> Option<String>;
+}
+
+impl<V> NoDebug for &DebugProbe<'_, V> {
+    fn debug_probe(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Registers the association when the registry feature is enabled.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assoc_register {
+    ($TAG:ty, $T:ty, $TARGET:ty, reset = $RESET:expr, restore = $RESTORE:expr) => {
+        $crate::__assoc_register!(
+            $TAG,
+            $T,
+            $TARGET,
+            reset = $RESET,
+            restore = $RESTORE,
+            capture = <$T as $crate::GetAssocThreadLocal<$TARGET, $TAG>>::get_threadlocal_any
+        )
+    };
+    ($TAG:ty, $T:ty, $TARGET:ty, reset = $RESET:expr, restore = $RESTORE:expr, capture = $CAPTURE:expr) => {{
+        static DESCRIPTOR: $crate::registry::AssocDescriptor =
+            $crate::registry::AssocDescriptor::new(
+                std::any::TypeId::of::<$T>,
+                std::any::type_name::<$T>,
+                std::any::TypeId::of::<$TAG>,
+                std::any::type_name::<$TAG>,
+                std::any::type_name::<$TARGET>,
+                || {
+                    #[allow(unused_imports)]
+                    use $crate::registry::{NoDebug as _, ViaDebug as _};
+                    match <$T as $crate::GetAssocThreadLocal<$TARGET, $TAG>>::try_get_threadlocal()
+                    {
+                        Ok(value) => (&$crate::registry::DebugProbe(&value)).debug_probe(),
+                        Err(_) => None,
+                    }
+                },
+                $RESET,
+                $CAPTURE,
+                $RESTORE,
+                $crate::__override_location::<$T, $TARGET, $TAG>,
+            );
+        $crate::registry::register(&DESCRIPTOR);
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct Registered;
+    struct Opaque;
+    #[derive(Clone, Copy)]
+    struct OpaqueValue;
+    crate::assoc_threadlocal!(Registered, u8 = 1);
+    crate::assoc_threadlocal!(Opaque:Registered, OpaqueValue = OpaqueValue);
+
+    #[test]
+    fn describe_and_reset() {
+        <Registered as SetAssocThreadLocal<u8>>::set_threadlocal(5);
+        <Registered as GetAssocThreadLocal<OpaqueValue, Opaque>>::get_threadlocal();
+
+        let descriptors: Vec<_> = associations_of::<Registered>().collect();
+        assert_eq!(descriptors.len(), 2);
+        let u8_desc = descriptors
+            .iter()
+            .find(|d| d.target_name() == "u8")
+            .unwrap();
+        assert_eq!(u8_desc.tag_name(), "()");
+        assert_eq!(u8_desc.debug_value().as_deref(), Some("5"));
+        u8_desc.reset();
+        assert_eq!(
+            <Registered as GetAssocThreadLocal<u8>>::get_threadlocal(),
+            1
+        );
+
+        let opaque = descriptors
+            .iter()
+            .find(|d| d.tag_name().ends_with("Opaque"))
+            .unwrap();
+        assert!(opaque.target_name().ends_with("OpaqueValue"));
+        assert_eq!(opaque.debug_value(), None);
+    }
+
+    struct Warmed;
+    crate::assoc_threadlocal!(Warmed, u64 = const 0);
+
+    #[test]
+    fn const_init_recorded_on_first_set() {
+        let thread = std::thread::spawn(|| {
+            // reading a const association does not initialize anything
+            <Warmed as GetAssocThreadLocal<u64>>::get_threadlocal();
+            assert!(associations_of::<Warmed>().next().is_none());
+            <Warmed as SetAssocThreadLocal<u64>>::set_threadlocal(1);
+            <Warmed as SetAssocThreadLocal<u64>>::set_threadlocal(2);
+            std::thread::current().id()
+        })
+        .join()
+        .unwrap();
+        let descriptor = associations_of::<Warmed>().next().unwrap();
+        let threads = descriptor.initialized_threads();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].thread, thread);
+        assert!(!descriptor.is_initialized_on(std::thread::current().id()));
+    }
+
+    struct Job;
+    struct Special;
+    crate::assoc_threadlocal!(Job, u16 = 100);
+    crate::assoc_threadlocal!(Special:Job, u16 = 200);
+
+    #[test]
+    fn bulk_reset() {
+        <Job as SetAssocThreadLocal<u16>>::set_threadlocal(1);
+        <Job as SetAssocThreadLocal<u16, Special>>::set_threadlocal(2);
+
+        reset_threadlocals_tagged::<Special>();
+        assert_eq!(<Job as GetAssocThreadLocal<u16>>::get_threadlocal(), 1);
+        assert_eq!(
+            <Job as GetAssocThreadLocal<u16, Special>>::get_threadlocal(),
+            200
+        );
+
+        <Job as SetAssocThreadLocal<u16, Special>>::set_threadlocal(2);
+        reset_threadlocals_of::<Job>();
+        assert_eq!(<Job as GetAssocThreadLocal<u16>>::get_threadlocal(), 100);
+        assert_eq!(
+            <Job as GetAssocThreadLocal<u16, Special>>::get_threadlocal(),
+            200
+        );
+
+        <Job as SetAssocThreadLocal<u16>>::set_threadlocal(1);
+        reset_all_threadlocals();
+        assert_eq!(<Job as GetAssocThreadLocal<u16>>::get_threadlocal(), 100);
+    }
+
+    struct Strict;
+    crate::assoc_threadlocal!(Strict, u32 = 0, strict);
+
+    #[test]
+    fn strict_isolated() {
+        <Strict as SetAssocThreadLocal<u32>>::set_threadlocal(3);
+        {
+            let _isolated = isolate_threadlocals();
+            assert!(<Strict as GetAssocThreadLocal<u32>>::try_get_threadlocal().is_err());
+            let descriptor = associations_of::<Strict>().next().unwrap();
+            assert_eq!(descriptor.debug_value(), None);
+            <Strict as SetAssocThreadLocal<u32>>::set_threadlocal(4);
+        }
+        assert_eq!(<Strict as GetAssocThreadLocal<u32>>::get_threadlocal(), 3);
+    }
+
+    #[test]
+    fn json_escaping() {
+        let mut json = String::new();
+        push_json_string(&mut json, "a\"b\\c\nd\u{1}");
+        assert_eq!(json, r#""a\"b\\c\nd\u0001""#);
+    }
+
+    struct Json;
+    crate::assoc_threadlocal!(Json, u8 = 3);
+    crate::assoc_threadlocal!(Json, OpaqueValue = OpaqueValue);
+
+    #[test]
+    fn json_export() {
+        <Json as GetAssocThreadLocal<u8>>::get_threadlocal();
+        <Json as GetAssocThreadLocal<OpaqueValue>>::get_threadlocal();
+        let json = export_current_thread_json();
+        assert!(json.starts_with('[') && json.ends_with(']'));
+        assert!(json.contains(r#"Json","tag":"()","target":"u8","value":"3"}"#));
+        assert!(json.contains(r#"OpaqueValue","value":null}"#));
+    }
+}
@@ -0,0 +1,161 @@
+//! Attaching ambient thread local state to errors (requires the `registry` feature).
+//!
+//! `with_threadlocal_context::<T>()` wraps the error of a `Result` together with a dump of
+//! the values associated to 'T' at the point of failure.  The wrapper follows the
+//! convention of context wrappers in error reporting crates, its `Display` shows the
+//! context and `source()` returns the original error, thus `anyhow` and `eyre` reports show
+//! both.
+
+use crate::registry::associations_of;
+use std::error::Error;
+use std::fmt;
+
+/// An error together with the thread local values of a type captured when it occurred.
+#[derive(Debug)]
+pub struct ThreadLocalReport<E> {
+    error: E,
+    implementor: &'static str,
+    values: Vec<(String, String)>,
+}
+
+impl<E> ThreadLocalReport<E> {
+    /// Captures the current threads values associated to 'T' for 'error'.
+    pub fn capture<T: 'static>(error: E) -> Self {
+        ThreadLocalReport {
+            error,
+            implementor: std::any::type_name::<T>(),
+            values: associations_of::<T>()
+                .map(|descriptor| {
+                    let name = if descriptor.tag_name() == "()" {
+                        descriptor.target_name().to_string()
+                    } else {
+                        format!("{}:{}", descriptor.tag_name(), descriptor.target_name())
+                    };
+                    let value = descriptor
+                        .debug_value()
+                        .unwrap_or_else(|| String::from("?"));
+                    (name, value)
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the captured associations as '(name, value)' pairs, the name is the target
+    /// type prefixed by the tag unless it is `()`.  Values without `Debug` are shown as '?'.
+    pub fn values(&self) -> &[(String, String)] {
+        &self.values
+    }
+
+    /// Returns the original error.
+    pub fn error(&self) -> &E {
+        &self.error
+    }
+
+    /// Returns the original error, dropping the captured values.
+    pub fn into_inner(self) -> E {
+        self.error
+    }
+}
+
+impl<E> fmt::Display for ThreadLocalReport<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread locals of {}:", self.implementor)?;
+        if self.values.is_empty() {
+            return f.write_str(" none");
+        }
+        for (i, (name, value)) in self.values.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{}{} = {}", separator, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: Error + 'static> Error for ThreadLocalReport<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Extends `Result` with attaching thread local values to its error.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Parser;
+/// assoc_threadlocal!(Parser, u32 = 0);
+///
+/// fn parse(line: &str) -> Result<u32, std::num::ParseIntError> {
+///     Parser::set_threadlocal(Parser::get_threadlocal() + 1);
+///     line.parse()
+/// }
+///
+/// let error = parse("x").with_threadlocal_context::<Parser>().unwrap_err();
+/// assert!(error.to_string().ends_with("Parser: u32 = 1"));
+/// ```
+pub trait ErrReportExt<V, E> {
+    /// Wraps the error together with the current threads values associated to 'T'.  The
+    /// values are only captured when there is an error.
+    fn with_threadlocal_context<T: 'static>(self) -> Result<V, ThreadLocalReport<E>>;
+}
+
+impl<V, E> ErrReportExt<V, E> for Result<V, E> {
+    fn with_threadlocal_context<T: 'static>(self) -> Result<V, ThreadLocalReport<E>> {
+        self.map_err(ThreadLocalReport::capture::<T>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrReportExt;
+    use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+    use std::error::Error;
+
+    struct Job;
+    struct Attempt;
+    #[derive(Clone, Copy)]
+    struct Opaque;
+    crate::assoc_threadlocal!(Job, u8 = 1);
+    crate::assoc_threadlocal!(Attempt:Job, u16 = 2);
+    crate::assoc_threadlocal!(Job, Opaque = Opaque);
+
+    #[derive(Debug)]
+    struct Failed;
+    impl std::fmt::Display for Failed {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("failed")
+        }
+    }
+    impl Error for Failed {}
+
+    #[test]
+    fn captured_at_failure() {
+        // associations register on first access
+        <Job as GetAssocThreadLocal<u8>>::get_threadlocal();
+        <Job as GetAssocThreadLocal<Opaque>>::get_threadlocal();
+        <Job as SetAssocThreadLocal<u16, Attempt>>::set_threadlocal(3);
+        let report = Err::<(), _>(Failed)
+            .with_threadlocal_context::<Job>()
+            .unwrap_err();
+        <Job as SetAssocThreadLocal<u16, Attempt>>::set_threadlocal(4);
+
+        let mut values = report.values().to_vec();
+        values.sort();
+        assert_eq!(values.len(), 3);
+        assert!(values[0].0.ends_with("Attempt:u16") && values[0].1 == "3");
+        assert!(values[1].0.ends_with("Opaque") && values[1].1 == "?");
+        assert_eq!(values[2], (String::from("u8"), String::from("1")));
+        assert_eq!(report.source().unwrap().to_string(), "failed");
+    }
+
+    struct Nothing;
+
+    #[test]
+    fn no_associations() {
+        let report = Err::<(), _>(Failed)
+            .with_threadlocal_context::<Nothing>()
+            .unwrap_err();
+        assert!(report.to_string().ends_with("Nothing: none"));
+        assert!(matches!(report.into_inner(), Failed));
+    }
+}
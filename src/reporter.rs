@@ -0,0 +1,237 @@
+//! Periodic snapshots of the associations of all threads (requires the `reporter`
+//! feature).
+//!
+//! Thread local values can only be read by their own thread.  Threads that want to be
+//! reported call `publish()` from time to time, e.g. between jobs, which records the
+//! values of all registered associations.  `start_reporter()` spawns a thread delivering
+//! the latest published values of all threads to a callback in a fixed interval, for
+//! lightweight ops visibility without a metrics stack.
+
+use crate::registry;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle, ThreadId};
+use std::time::{Duration, Instant, SystemTime};
+
+static PUBLISHED: Mutex<Option<HashMap<ThreadId, ThreadSnapshot>>> = Mutex::new(None);
+
+/// The value of one association on one thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueSnapshot {
+    /// The name of the type the value is associated to.
+    pub implementor: &'static str,
+    /// The name of the tag type.
+    pub tag: &'static str,
+    /// The name of the target type.
+    pub target: &'static str,
+    /// The `Debug` representation of the value, `None` when it has none.
+    pub value: Option<String>,
+}
+
+/// The values one thread published last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadSnapshot {
+    /// The id of the thread.
+    pub thread: ThreadId,
+    /// The name of the thread.
+    pub name: Option<String>,
+    /// When the values were published.
+    pub published: Instant,
+    /// The values of all associations registered at that time.
+    pub values: Vec<ValueSnapshot>,
+}
+
+/// The latest published values of all threads, delivered by the reporter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// When the snapshot was taken.
+    pub taken: SystemTime,
+    /// The published values per thread, in no particular order.
+    pub threads: Vec<ThreadSnapshot>,
+}
+
+// withdraws the published values when the thread exits
+struct Published;
+
+impl Drop for Published {
+    fn drop(&mut self) {
+        withdraw();
+    }
+}
+
+std::thread_local!(
+    static PUBLISHED_GUARD: Published = const { Published };
+);
+
+/// Publishes the values of all registered associations on the current thread, replacing
+/// the values it published before.  The values are withdrawn when the thread exits.
+pub fn publish() {
+    let values = registry::associations()
+        .into_iter()
+        .map(|descriptor| ValueSnapshot {
+            implementor: descriptor.implementor_name(),
+            tag: descriptor.tag_name(),
+            target: descriptor.target_name(),
+            value: descriptor.debug_value(),
+        })
+        .collect();
+    let current = thread::current();
+    let snapshot = ThreadSnapshot {
+        thread: current.id(),
+        name: current.name().map(str::to_string),
+        published: Instant::now(),
+        values,
+    };
+    // without the guard the thread is exiting, its values would never be withdrawn
+    if PUBLISHED_GUARD.try_with(|_| ()).is_ok() {
+        PUBLISHED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashMap::new)
+            .insert(current.id(), snapshot);
+    }
+}
+
+/// Withdraws the values published by the current thread.
+pub fn withdraw() {
+    if let Some(published) = PUBLISHED.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        published.remove(&thread::current().id());
+    }
+}
+
+/// Returns the latest published values of all threads.
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        taken: SystemTime::now(),
+        threads: PUBLISHED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|published| published.values().cloned().collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Stops the reporter thread when dropped, returned by `start_reporter()`.
+#[must_use = "the reporter stops immediately when the handle is not kept"]
+#[derive(Debug)]
+pub struct ReporterHandle {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReporterHandle {
+    /// Stops the reporter and waits for its thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // dropping the sender wakes the reporter
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            // a panic in the sink already ended the reporter
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ReporterHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Spawns a thread calling 'sink' with a `snapshot()` every 'interval' until the returned
+/// handle is dropped or stopped.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::sync::mpsc;
+/// use std::time::Duration;
+///
+/// struct Worker;
+/// assoc_threadlocal!(Worker, u32 = 0);
+///
+/// Worker::set_threadlocal(7);
+/// reporter::publish();
+///
+/// let (tx, rx) = mpsc::channel();
+/// let reporter = reporter::start_reporter(Duration::from_millis(1), move |snapshot| {
+///     let _ = tx.send(snapshot);
+/// });
+/// let snapshot = rx.recv().unwrap();
+/// reporter.stop();
+///
+/// let current = std::thread::current().id();
+/// let mine = snapshot.threads.iter().find(|t| t.thread == current).unwrap();
+/// assert!(mine
+///     .values
+///     .iter()
+///     .any(|v| v.target == "u32" && v.value.as_deref() == Some("7")));
+/// ```
+pub fn start_reporter(
+    interval: Duration,
+    mut sink: impl FnMut(Snapshot) + Send + 'static,
+) -> ReporterHandle {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = thread::Builder::new()
+        .name(String::from("assoc_threadlocal reporter"))
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                sink(snapshot());
+            }
+        })
+        .expect("spawning the reporter thread");
+    ReporterHandle {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{publish, snapshot, withdraw};
+    use crate::SetAssocThreadLocal;
+
+    struct Reported;
+    crate::assoc_threadlocal!(Reported, u16 = 0);
+
+    fn published_value(thread: std::thread::ThreadId) -> Option<String> {
+        snapshot()
+            .threads
+            .into_iter()
+            .find(|t| t.thread == thread)?
+            .values
+            .into_iter()
+            .find(|v| v.implementor.ends_with("Reported"))?
+            .value
+    }
+
+    #[test]
+    fn withdrawn_on_exit() {
+        let worker = std::thread::spawn(|| {
+            Reported::set_threadlocal(3);
+            publish();
+            let id = std::thread::current().id();
+            assert_eq!(published_value(id).as_deref(), Some("3"));
+            id
+        });
+        let id = worker.join().unwrap();
+        assert_eq!(published_value(id), None);
+    }
+
+    #[test]
+    fn republish_and_withdraw() {
+        let id = std::thread::current().id();
+        Reported::set_threadlocal(1);
+        publish();
+        Reported::set_threadlocal(2);
+        assert_eq!(published_value(id).as_deref(), Some("1"));
+        publish();
+        assert_eq!(published_value(id).as_deref(), Some("2"));
+        withdraw();
+        assert_eq!(published_value(id), None);
+    }
+}
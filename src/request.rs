@@ -0,0 +1,318 @@
+//! Ambient per-thread request contexts.
+//!
+//! A `RequestContext` carries a request id, an optional deadline and arbitrary baggage.
+//! It is entered on the thread handling the request and readable from everything called
+//! below.  `bind()` carries it across thread boundaries, `inject()` and `extract()` carry
+//! it across process boundaries as header like key/value pairs.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+/// The context of a single request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    id: String,
+    deadline: Option<Instant>,
+    baggage: Vec<(String, String)>,
+}
+
+impl RequestContext {
+    /// Key of the request id for `inject()` and `extract()`.
+    pub const ID_KEY: &'static str = "x-request-id";
+    /// Key of the remaining time in milliseconds for `inject()` and `extract()`.
+    pub const TIMEOUT_KEY: &'static str = "x-request-timeout-ms";
+    /// Prefix of the baggage keys for `inject()` and `extract()`.
+    pub const BAGGAGE_PREFIX: &'static str = "baggage-";
+
+    /// Creates a context with the request id 'id', without deadline and baggage.
+    pub fn new(id: impl Into<String>) -> Self {
+        RequestContext {
+            id: id.into(),
+            deadline: None,
+            baggage: Vec::new(),
+        }
+    }
+
+    /// Returns the context with 'deadline'.
+    #[must_use]
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Returns the context with a deadline 'timeout' from now.
+    #[must_use]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Returns the context with the baggage item 'key' set to 'value'.
+    #[must_use]
+    pub fn with_baggage(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.set_baggage(key, value);
+        self
+    }
+
+    /// Sets the baggage item 'key' to 'value', replacing a previous value.
+    pub fn set_baggage(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let (key, value) = (key.into(), value.into());
+        match self.baggage.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.baggage.push((key, value)),
+        }
+    }
+
+    /// Returns the request id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the deadline.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the time left until the deadline, zero when it passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns whether the deadline passed.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Returns the baggage item 'key'.
+    pub fn baggage(&self, key: &str) -> Option<&str> {
+        self.baggage
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns an iterator over all baggage items in insertion order.
+    pub fn baggage_iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.baggage.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Writes the context as key/value pairs through 'set', the deadline is converted to
+    /// the remaining time.
+    pub fn inject(&self, mut set: impl FnMut(&str, String)) {
+        set(Self::ID_KEY, self.id.clone());
+        if let Some(remaining) = self.remaining() {
+            set(Self::TIMEOUT_KEY, remaining.as_millis().to_string());
+        }
+        for (key, value) in &self.baggage {
+            set(&format!("{}{}", Self::BAGGAGE_PREFIX, key), value.clone());
+        }
+    }
+
+    /// Reads a context from key/value pairs written by `inject()`, keys are compared case
+    /// insensitive.  Returns `None` when there is no request id, malformed timeouts are
+    /// ignored.
+    pub fn extract<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Option<Self> {
+        let mut id = None;
+        let mut deadline = None;
+        let mut baggage = Vec::new();
+        for (key, value) in pairs {
+            let key = key.to_ascii_lowercase();
+            if key == Self::ID_KEY {
+                id = Some(value.to_string());
+            } else if key == Self::TIMEOUT_KEY {
+                deadline = value
+                    .parse()
+                    .ok()
+                    .map(|ms| Instant::now() + Duration::from_millis(ms));
+            } else if let Some(key) = key.strip_prefix(Self::BAGGAGE_PREFIX) {
+                baggage.push((key.to_string(), value.to_string()));
+            }
+        }
+        Some(RequestContext {
+            id: id?,
+            deadline,
+            baggage,
+        })
+    }
+}
+
+/// Associates a per-thread request context to a type.
+/// Use the `assoc_request_context!()` macro for implementing this trait on types.
+pub trait AssocRequestContext: Sized {
+    /// Returns the associated thread local context slot of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_request_context() -> *const RefCell<Option<RequestContext>>;
+
+    /// Makes 'context' the current threads context until the returned guard is dropped.
+    fn enter(context: RequestContext) -> RequestContextGuard<Self> {
+        RequestContextGuard {
+            previous: unsafe { (*Self::the_request_context()).replace(Some(context)) },
+            _marker: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Calls 'f' with the current threads context.  'f' must not enter another context.
+    fn with_current<R>(f: impl FnOnce(Option<&RequestContext>) -> R) -> R {
+        f(unsafe { (*Self::the_request_context()).borrow().as_ref() })
+    }
+
+    /// Returns a clone of the current threads context.
+    fn current() -> Option<RequestContext> {
+        Self::with_current(|context| context.cloned())
+    }
+
+    /// Returns the request id of the current threads context.
+    fn current_id() -> Option<String> {
+        Self::with_current(|context| context.map(|context| context.id.clone()))
+    }
+
+    /// Returns the time left until the deadline of the current threads context.
+    fn current_remaining() -> Option<Duration> {
+        Self::with_current(|context| context?.remaining())
+    }
+
+    /// Wraps 'f' to run with the current threads context entered, for passing work to
+    /// another thread.
+    fn bind<R>(f: impl FnOnce() -> R + Send) -> impl FnOnce() -> R + Send {
+        let context = Self::current();
+        move || match context {
+            Some(context) => {
+                let _guard = Self::enter(context);
+                f()
+            }
+            None => f(),
+        }
+    }
+}
+
+/// Restores the previous request context when dropped.
+#[must_use = "the context is left immediately when the guard is not kept"]
+pub struct RequestContextGuard<T: AssocRequestContext> {
+    previous: Option<RequestContext>,
+    _marker: PhantomData<T>,
+    // guards must be dropped on the thread that created them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: AssocRequestContext> Drop for RequestContextGuard<T> {
+    fn drop(&mut self) {
+        unsafe { *(*T::the_request_context()).borrow_mut() = self.previous.take() }
+    }
+}
+
+/// Associates a per-thread request context to a type.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::time::Duration;
+///
+/// struct Http;
+/// assoc_request_context!(Http);
+///
+/// let _request = Http::enter(
+///     RequestContext::new("req-1")
+///         .with_timeout(Duration::from_secs(5))
+///         .with_baggage("tenant", "acme"),
+/// );
+///
+/// let tenant = std::thread::spawn(Http::bind(|| {
+///     Http::with_current(|context| context.unwrap().baggage("tenant").map(str::to_string))
+/// }))
+/// .join()
+/// .unwrap();
+/// assert_eq!(tenant.as_deref(), Some("acme"));
+///
+/// let mut headers = Vec::new();
+/// Http::current().unwrap().inject(|key, value| headers.push((key.to_string(), value)));
+/// let remote = RequestContext::extract(headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+/// assert_eq!(remote.unwrap().id(), "req-1");
+/// ```
+#[macro_export]
+macro_rules! assoc_request_context {
+    ($T:ty) => {
+        impl $crate::AssocRequestContext for $T {
+            unsafe fn the_request_context(
+            ) -> *const std::cell::RefCell<Option<$crate::RequestContext>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_REQUEST_CONTEXT: (
+                        std::cell::RefCell<Option<$crate::RequestContext>>,
+                        std::marker::PhantomData<$T>,
+                    ) = (std::cell::RefCell::new(None), std::marker::PhantomData);
+                );
+                ASSOCIATED_REQUEST_CONTEXT
+                    .with(|l| &l.0 as *const std::cell::RefCell<Option<$crate::RequestContext>>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestContext;
+    use crate::AssocRequestContext;
+    use std::time::{Duration, Instant};
+
+    struct Api;
+    assoc_request_context!(Api);
+
+    #[test]
+    fn nested() {
+        assert_eq!(Api::current(), None);
+        let _outer = Api::enter(RequestContext::new("outer"));
+        {
+            let _inner = Api::enter(RequestContext::new("inner"));
+            assert_eq!(Api::current_id().as_deref(), Some("inner"));
+        }
+        assert_eq!(Api::current_id().as_deref(), Some("outer"));
+        assert_eq!(Api::current_remaining(), None);
+    }
+
+    #[test]
+    fn baggage_replaced() {
+        let context = RequestContext::new("id")
+            .with_baggage("a", "1")
+            .with_baggage("b", "2")
+            .with_baggage("a", "3");
+        assert_eq!(context.baggage("a"), Some("3"));
+        assert_eq!(
+            context.baggage_iter().collect::<Vec<_>>(),
+            [("a", "3"), ("b", "2")]
+        );
+    }
+
+    #[test]
+    fn deadline() {
+        let context = RequestContext::new("id").with_deadline(Instant::now());
+        assert!(context.is_expired());
+        let context = RequestContext::new("id").with_timeout(Duration::from_secs(60));
+        assert!(!context.is_expired());
+    }
+
+    #[test]
+    fn inject_extract() {
+        let context = RequestContext::new("abc")
+            .with_timeout(Duration::from_secs(60))
+            .with_baggage("user", "bob");
+        let mut pairs = Vec::new();
+        context.inject(|key, value| pairs.push((key.to_ascii_uppercase(), value)));
+        pairs.push((String::from("unrelated"), String::from("x")));
+        let extracted =
+            RequestContext::extract(pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))).unwrap();
+        assert_eq!(extracted.id(), "abc");
+        assert_eq!(extracted.baggage("user"), Some("bob"));
+        assert!(extracted.remaining().unwrap() > Duration::from_secs(50));
+        assert_eq!(RequestContext::extract([("user", "bob")]), None);
+    }
+
+    #[test]
+    fn bind_without_context() {
+        assert_eq!(
+            std::thread::spawn(Api::bind(Api::current)).join().unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Associations of external resources with explicit teardown.
+//!
+//! Thread local destructors run in an unspecified order, too late for resources that must
+//! be released before some other global state goes away.  Associations defined with
+//! `assoc_threadlocal!(T, TARGET = INIT, teardown = DESTRUCTOR)` are released explicitly
+//! by `teardown_threadlocal()`, any later access fails with `AccessError::TornDown`.
+
+use crate::AssocThreadLocal;
+use std::cell::Cell;
+
+/// The lifecycle of a resource association on a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceState {
+    /// Not initialized on this thread yet.
+    Unused,
+    /// Initialized and usable.
+    Live,
+    /// Released by `teardown_threadlocal()`.
+    TornDown,
+}
+
+/// Explicit teardown of a thread local resource, implemented by
+/// `assoc_threadlocal!(T, TARGET = INIT, teardown = DESTRUCTOR)`.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// #[derive(Clone, Copy)]
+/// struct Connection(u32);
+///
+/// fn close(connection: Connection) {
+///     println!("closing {}", connection.0);
+/// }
+///
+/// struct Db;
+/// assoc_threadlocal!(Db, Connection = Connection(1), teardown = close);
+///
+/// assert_eq!(Db::get_threadlocal().0, 1);
+/// assert!(Db::teardown_threadlocal());
+/// assert!(!Db::is_live());
+/// assert_eq!(Db::try_get_threadlocal().err(), Some(AccessError::TornDown));
+/// ```
+pub trait AssocResource<T: Copy, TAG = ()>: AssocThreadLocal<T, TAG> {
+    /// Returns the associated thread local lifecycle state of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_resource_state() -> *const Cell<ResourceState>;
+
+    /// Releases 'value', the destructor given to the macro.
+    fn teardown_value(value: T);
+
+    /// Returns the lifecycle state on the current thread.
+    fn resource_state() -> ResourceState {
+        unsafe { (*Self::the_resource_state()).get() }
+    }
+
+    /// Returns whether the resource was not torn down on the current thread.
+    fn is_live() -> bool {
+        Self::resource_state() != ResourceState::TornDown
+    }
+
+    /// Tears the resource down on the current thread, the destructor runs immediately when
+    /// it was initialized.  Returns whether the destructor ran.
+    fn teardown_threadlocal() -> bool {
+        let state = unsafe { &*Self::the_resource_state() };
+        match state.replace(ResourceState::TornDown) {
+            ResourceState::Live => {
+                // SAFETY: the value is initialized when live
+                let value = unsafe { (*Self::the_threadlocal()).get() };
+                Self::teardown_value(value);
+                true
+            }
+            ResourceState::Unused | ResourceState::TornDown => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AssocResource, ResourceState};
+    use crate::{AccessError, AssocThreadLocal, GetAssocThreadLocal, SetAssocThreadLocal};
+    use std::cell::Cell;
+
+    thread_local!(static CLOSED: Cell<u32> = const { Cell::new(0) });
+
+    #[derive(Clone, Copy)]
+    struct Handle(u32);
+
+    struct Pool;
+    crate::assoc_threadlocal!(
+        Pool,
+        Handle = Handle(7),
+        teardown = |handle: Handle| { CLOSED.with(|c| c.set(handle.0)) }
+    );
+
+    #[test]
+    fn teardown() {
+        assert_eq!(Pool::resource_state(), ResourceState::Unused);
+        Pool::set_threadlocal(Handle(9));
+        assert_eq!(Pool::resource_state(), ResourceState::Live);
+        assert!(Pool::teardown_threadlocal());
+        assert_eq!(CLOSED.with(Cell::get), 9);
+        assert!(!Pool::teardown_threadlocal());
+        assert!(matches!(
+            Pool::try_get_threadlocal(),
+            Err(AccessError::TornDown)
+        ));
+        assert!(std::panic::catch_unwind(|| Pool::set_threadlocal(Handle(1))).is_err());
+        assert!(std::panic::catch_unwind(|| Pool::set_threadlocal_scoped(Handle(1))).is_err());
+    }
+
+    #[test]
+    fn unused_not_initialized() {
+        assert!(!Pool::teardown_threadlocal());
+        assert_eq!(CLOSED.with(Cell::get), 0);
+        assert!(!Pool::is_live());
+        assert!(std::thread::spawn(|| Pool::get_threadlocal().0)
+            .join()
+            .is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+//! Scoped associations of borrowed values.
+//!
+//! A reference with any lifetime can be installed for the duration of a closure and is
+//! accessible from everything called below it on the same thread.  Accessors only hand
+//! out references bound to their own closure, thus the reference can not escape its scope.
+
+use std::cell::Cell;
+use std::ptr::NonNull;
+
+/// Associates a scoped per-thread reference to a T (possibly unsized) to a type.
+/// Use the `assoc_scoped_threadlocal!()` macro for implementing this trait on types.
+pub trait AssocScopedThreadLocal<T: ?Sized + 'static, TAG = ()> {
+    /// Returns the associated thread local reference slot of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_scoped_threadlocal() -> *const Cell<Option<NonNull<T>>>;
+
+    /// Installs 'value' while 'f' runs, the previous value is restored afterwards, even
+    /// when 'f' panics.
+    fn set_scoped_threadlocal<R>(value: &T, f: impl FnOnce() -> R) -> R {
+        struct Restore<'a, T: ?Sized>(&'a Cell<Option<NonNull<T>>>, Option<NonNull<T>>);
+
+        impl<T: ?Sized> Drop for Restore<'_, T> {
+            fn drop(&mut self) {
+                self.0.set(self.1);
+            }
+        }
+
+        let slot = unsafe { &*Self::the_scoped_threadlocal() };
+        let _restore = Restore(slot, slot.replace(Some(NonNull::from(value))));
+        f()
+    }
+
+    /// Calls 'f' with the currently installed reference.
+    ///
+    /// # Panics
+    /// When no reference is installed.
+    fn with_scoped_threadlocal<R>(f: impl FnOnce(&T) -> R) -> R {
+        Self::try_with_scoped_threadlocal(|value| {
+            f(value.expect("no scoped thread local value installed"))
+        })
+    }
+
+    /// Calls 'f' with the currently installed reference or 'None'.
+    fn try_with_scoped_threadlocal<R>(f: impl FnOnce(Option<&T>) -> R) -> R {
+        let value = unsafe { (*Self::the_scoped_threadlocal()).get() };
+        // SAFETY: the pointer is only installed while 'set_scoped_threadlocal()' borrows
+        // the value and the reference given to 'f' can not outlive this call
+        f(value.map(|value| unsafe { value.as_ref() }))
+    }
+
+    /// Returns whether a reference is installed on the current thread.
+    fn is_scoped_threadlocal_set() -> bool {
+        unsafe { (*Self::the_scoped_threadlocal()).get().is_some() }
+    }
+}
+
+/// Associates a scoped per-thread reference to a type.
+///
+///  * 'TAG' is used to discriminate between different associations to the same type
+///  * 'T' is the type you want have a scoped reference associated to
+///  * 'TARGET' is the referenced type, may be unsized
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Frame;
+/// assoc_scoped_threadlocal!(Frame, [u32]);
+///
+/// fn sum() -> u32 {
+///     Frame::with_scoped_threadlocal(|frame| frame.iter().sum())
+/// }
+///
+/// let locals = vec![1, 2, 3];
+/// assert_eq!(Frame::set_scoped_threadlocal(&locals[..], sum), 6);
+/// assert!(!Frame::is_scoped_threadlocal_set());
+/// ```
+#[macro_export]
+macro_rules! assoc_scoped_threadlocal {
+    ($T:ty, $TARGET:ty) => {
+        $crate::assoc_scoped_threadlocal!((): $T, $TARGET);
+    };
+    ($TAG:ty: $T:ty, $TARGET:ty) => {
+        impl $crate::AssocScopedThreadLocal<$TARGET, $TAG> for $T {
+            unsafe fn the_scoped_threadlocal(
+            ) -> *const std::cell::Cell<Option<std::ptr::NonNull<$TARGET>>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_SCOPED: (
+                        std::cell::Cell<Option<std::ptr::NonNull<$TARGET>>>,
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = (
+                        std::cell::Cell::new(None),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_SCOPED
+                    .with(|l| &l.0 as *const std::cell::Cell<Option<std::ptr::NonNull<$TARGET>>>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocScopedThreadLocal;
+
+    trait Visitor {
+        fn visit(&self) -> String;
+    }
+
+    struct Named(String);
+    impl Visitor for Named {
+        fn visit(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    struct Current;
+    struct Outer;
+    assoc_scoped_threadlocal!(Current, dyn Visitor);
+    assoc_scoped_threadlocal!(Outer: Current, dyn Visitor);
+
+    fn visit() -> Option<String> {
+        <Current as AssocScopedThreadLocal<dyn Visitor>>::try_with_scoped_threadlocal(|v| {
+            v.map(|v| v.visit())
+        })
+    }
+
+    fn scoped<R>(visitor: &(dyn Visitor + 'static), f: impl FnOnce() -> R) -> R {
+        <Current as AssocScopedThreadLocal<dyn Visitor>>::set_scoped_threadlocal(visitor, f)
+    }
+
+    #[test]
+    fn nested_scopes() {
+        assert_eq!(visit(), None);
+        let outer = Named(String::from("outer"));
+        scoped(&outer, || {
+            assert_eq!(visit().as_deref(), Some("outer"));
+            let inner = Named(String::from("inner"));
+            scoped(&inner, || {
+                assert_eq!(visit().as_deref(), Some("inner"));
+            });
+            assert_eq!(visit().as_deref(), Some("outer"));
+        });
+        assert_eq!(visit(), None);
+    }
+
+    #[test]
+    fn tagged_independent() {
+        let outer = Named(String::from("tagged"));
+        <Current as AssocScopedThreadLocal<dyn Visitor, Outer>>::set_scoped_threadlocal(
+            &outer,
+            || {
+                assert_eq!(visit(), None);
+                assert!(<Current as AssocScopedThreadLocal<dyn Visitor, Outer>>::is_scoped_threadlocal_set());
+            },
+        );
+    }
+
+    #[test]
+    fn restored_on_panic() {
+        let value = Named(String::from("panicking"));
+        let result = std::panic::catch_unwind(|| {
+            scoped(&value, || panic!("boom"));
+        });
+        assert!(result.is_err());
+        assert_eq!(visit(), None);
+    }
+
+    struct Borrowed;
+    assoc_scoped_threadlocal!(Borrowed, str);
+
+    #[test]
+    fn non_static_borrow() {
+        let owned = String::from("temporary");
+        let len = Borrowed::set_scoped_threadlocal(&owned[..4], || {
+            Borrowed::with_scoped_threadlocal(|s| s.len())
+        });
+        assert_eq!(len, 4);
+        drop(owned);
+        assert!(!Borrowed::is_scoped_threadlocal_set());
+    }
+
+    #[test]
+    #[should_panic(expected = "no scoped thread local value installed")]
+    fn unset_panics() {
+        <Current as AssocScopedThreadLocal<dyn Visitor>>::with_scoped_threadlocal(|v| v.visit());
+    }
+}
@@ -0,0 +1,166 @@
+//! Per-thread replaceable service implementations.
+//!
+//! A service is a trait object associated to a marker type. Code fetches the current
+//! implementation through the marker, tests can swap in a mock for the current thread.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// Associates a per-thread service implementation of type S (usually a `dyn Trait`) to a
+/// type.  Use the `assoc_service!()` macro for implementing this trait on types.
+pub trait AssocService<S: ?Sized + 'static>: Sized {
+    /// Returns the associated thread local service slot of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_service() -> *const RefCell<Rc<S>>;
+
+    /// Calls 'f' with the current threads service implementation.
+    fn with<R>(f: impl FnOnce(&S) -> R) -> R {
+        // cloning the Rc allows 'f' to install another implementation
+        let service = unsafe { (*Self::the_service()).borrow().clone() };
+        f(&service)
+    }
+
+    /// Returns a shared handle to the current threads service implementation.
+    fn get() -> Rc<S> {
+        unsafe { (*Self::the_service()).borrow().clone() }
+    }
+
+    /// Replaces the current threads service implementation, returns the old one.
+    fn install(service: Rc<S>) -> Rc<S> {
+        unsafe { (*Self::the_service()).replace(service) }
+    }
+
+    /// Replaces the current threads service implementation until the guard is dropped.
+    fn install_scoped(service: Rc<S>) -> ServiceGuard<Self, S> {
+        ServiceGuard {
+            previous: Some(Self::install(service)),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Restores the previous service implementation when dropped.
+#[must_use = "the service is restored immediately when the guard is not kept"]
+pub struct ServiceGuard<T: AssocService<S>, S: ?Sized + 'static> {
+    previous: Option<Rc<S>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: AssocService<S>, S: ?Sized + 'static> Drop for ServiceGuard<T, S> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            T::install(previous);
+        }
+    }
+}
+
+/// Associates a per-thread service implementation to a type.
+///
+///  * 'T' is the type you want have a thread local service associated to
+///  * 'S' is the service type, usually `dyn Trait`
+///  * 'INIT' is the default implementation each thread starts with
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::rc::Rc;
+///
+/// trait TimeSource {
+///     fn now(&self) -> u64;
+/// }
+///
+/// struct SystemTime;
+/// impl TimeSource for SystemTime {
+///     fn now(&self) -> u64 {
+///         1234
+///     }
+/// }
+///
+/// struct Mock;
+/// impl TimeSource for Mock {
+///     fn now(&self) -> u64 {
+///         0
+///     }
+/// }
+///
+/// struct Clock;
+/// assoc_service!(Clock: dyn TimeSource = SystemTime);
+///
+/// assert_eq!(Clock::with(|svc| svc.now()), 1234);
+/// {
+///     let _mock = Clock::install_scoped(Rc::new(Mock));
+///     assert_eq!(Clock::with(|svc| svc.now()), 0);
+/// }
+/// assert_eq!(Clock::with(|svc| svc.now()), 1234);
+/// ```
+#[macro_export]
+macro_rules! assoc_service {
+    ($T:ty: $S:ty = $INIT:expr) => {
+        impl $crate::AssocService<$S> for $T {
+            unsafe fn the_service() -> *const std::cell::RefCell<std::rc::Rc<$S>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_SERVICE: (
+                        std::cell::RefCell<std::rc::Rc<$S>>,
+                        std::marker::PhantomData<$T>,
+                    ) = (
+                        std::cell::RefCell::new(std::rc::Rc::new($INIT)),
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_SERVICE.with(|l| &l.0 as *const std::cell::RefCell<std::rc::Rc<$S>>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocService;
+    use std::rc::Rc;
+
+    trait Greeter {
+        fn greet(&self) -> String;
+    }
+
+    struct Plain;
+    impl Greeter for Plain {
+        fn greet(&self) -> String {
+            String::from("hello")
+        }
+    }
+
+    struct Loud(&'static str);
+    impl Greeter for Loud {
+        fn greet(&self) -> String {
+            self.0.to_uppercase()
+        }
+    }
+
+    struct Greeting;
+    assoc_service!(Greeting: dyn Greeter = Plain);
+
+    #[test]
+    fn default_service() {
+        assert_eq!(Greeting::with(|g| g.greet()), "hello");
+    }
+
+    #[test]
+    fn nested_install() {
+        let _outer = Greeting::install_scoped(Rc::new(Loud("outer")));
+        {
+            let _inner = Greeting::install_scoped(Rc::new(Loud("inner")));
+            assert_eq!(Greeting::get().greet(), "INNER");
+        }
+        assert_eq!(Greeting::with(|g| g.greet()), "OUTER");
+    }
+
+    #[test]
+    fn other_threads_unaffected() {
+        let _mock = Greeting::install_scoped(Rc::new(Loud("mock")));
+        std::thread::spawn(|| assert_eq!(Greeting::with(|g| g.greet()), "hello"))
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! Storage of associations in the `single-threaded` build mode.
+//!
+//! Programs that never run more than one thread, like wasm modules, don't need thread
+//! local storage.  With the `single-threaded` feature the macros of this crate declare
+//! their values as plain statics of `Local` instead of `thread_local!()`s.  Accesses are
+//! plain memory accesses without any thread local lookup or owner check.
+//!
+//! The feature is only available on targets without threads, wasm without the `atomics`
+//! target feature.  Elsewhere a second thread could access the statics and checking this
+//! would need thread local storage again.  The values are never dropped, like other
+//! statics.  Side tables used by some association options, e.g. 'history', still live in
+//! thread local storage.
+
+#[cfg(not(any(test, all(target_family = "wasm", not(target_feature = "atomics")))))]
+compile_error!(
+    "the `single-threaded` feature is only available on targets without threads, \
+     wasm without the `atomics` target feature"
+);
+
+use std::cell::OnceCell;
+
+/// A lazily initialized value stored in a plain static, with the subset of the
+/// `LocalKey` interface the macros use.
+pub struct Local<T: 'static> {
+    init: fn() -> T,
+    value: OnceCell<T>,
+}
+
+// the target has no threads, the tests of this crate access it from one thread
+unsafe impl<T> Sync for Local<T> {}
+
+impl<T: 'static> Local<T> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> Self {
+        Local {
+            init,
+            value: OnceCell::new(),
+        }
+    }
+
+    /// Calls 'f' with the value, initializing it on first access.
+    #[inline]
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        f(self.value.get_or_init(self.init))
+    }
+
+    /// Like `with()`, statics are never destroyed thus this never fails.
+    #[inline]
+    pub fn try_with<R>(
+        &'static self,
+        f: impl FnOnce(&T) -> R,
+    ) -> Result<R, std::convert::Infallible> {
+        Ok(self.with(f))
+    }
+}
+
+#[cfg(all(test, feature = "single-threaded"))]
+mod tests {
+    use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct Tool;
+    crate::assoc_threadlocal!(Tool, u32 = 1);
+    crate::assoc_threadlocal!(Tool, u8 = const 2);
+
+    #[test]
+    fn plain_statics() {
+        <Tool as SetAssocThreadLocal<u32>>::set_threadlocal(3);
+        assert_eq!(<Tool as GetAssocThreadLocal<u32>>::get_threadlocal(), 3);
+        assert_eq!(<Tool as GetAssocThreadLocal<u8>>::get_threadlocal(), 2);
+    }
+
+    #[test]
+    fn no_thread_local_storage() {
+        let address = || unsafe { <Tool as GetAssocThreadLocal<u8>>::the_threadlocal() } as usize;
+        // a thread local would be at a different address on every thread
+        let main = address();
+        let other = std::thread::spawn(address).join().unwrap();
+        assert_eq!(main, other);
+    }
+}
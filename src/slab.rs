@@ -0,0 +1,343 @@
+//! Per-thread slabs handing out small `Copy` keys.
+//!
+//! Components that keep thread affine data often pass `Rc`s around only to refer to it
+//! later.  A slab associated to a type stores the items instead and returns a `SlabKey`,
+//! a pair of a slot index and a generation.  Keys of removed items stay invalid when the
+//! slot is reused, they never refer to another item.  A slot whose generation is exhausted
+//! after 2^32 reuses is retired instead of being reused again.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+/// Key of an item in the slab of the type 'T' holding items of type 'V', returned by
+/// `AssocSlab::insert()`.  Keys refer to the slab of the thread that inserted the item
+/// and are not `Send`.
+pub struct SlabKey<T, V> {
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> (T, V)>,
+    // items stay on the thread that inserted them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T, V> SlabKey<T, V> {
+    /// Returns the slot index, smaller indices are reused first.
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+impl<T, V> Clone for SlabKey<T, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, V> Copy for SlabKey<T, V> {}
+
+impl<T, V> PartialEq for SlabKey<T, V> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.index, self.generation) == (other.index, other.generation)
+    }
+}
+
+impl<T, V> Eq for SlabKey<T, V> {}
+
+impl<T, V> Hash for SlabKey<T, V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.index, self.generation).hash(state)
+    }
+}
+
+impl<T, V> fmt::Debug for SlabKey<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SlabKey({}v{})", self.index, self.generation)
+    }
+}
+
+enum Slot<V> {
+    Occupied(V),
+    // the index of the next free slot
+    Vacant(Option<u32>),
+}
+
+struct Entry<V> {
+    generation: u32,
+    slot: Slot<V>,
+}
+
+/// The storage behind `AssocSlab`.
+#[doc(hidden)]
+pub struct Slab<V> {
+    entries: Vec<Entry<V>>,
+    free: Option<u32>,
+    len: usize,
+}
+
+impl<V> Slab<V> {
+    #[doc(hidden)]
+    pub const fn new() -> Self {
+        Slab {
+            entries: Vec::new(),
+            free: None,
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, value: V) -> (u32, u32) {
+        self.len += 1;
+        match self.free {
+            Some(index) => {
+                let entry = &mut self.entries[index as usize];
+                let Slot::Vacant(next) = entry.slot else {
+                    unreachable!("free list points to an occupied slot")
+                };
+                self.free = next;
+                entry.slot = Slot::Occupied(value);
+                (index, entry.generation)
+            }
+            None => {
+                let index = u32::try_from(self.entries.len()).expect("slab exhausted");
+                self.entries.push(Entry {
+                    generation: 0,
+                    slot: Slot::Occupied(value),
+                });
+                (index, 0)
+            }
+        }
+    }
+
+    fn get(&self, index: u32, generation: u32) -> Option<&V> {
+        match self.entries.get(index as usize) {
+            Some(Entry {
+                generation: current,
+                slot: Slot::Occupied(value),
+            }) if *current == generation => Some(value),
+            _ => None,
+        }
+    }
+
+    fn get_mut(&mut self, index: u32, generation: u32) -> Option<&mut V> {
+        match self.entries.get_mut(index as usize) {
+            Some(Entry {
+                generation: current,
+                slot: Slot::Occupied(value),
+            }) if *current == generation => Some(value),
+            _ => None,
+        }
+    }
+
+    fn remove(&mut self, index: u32, generation: u32) -> Option<V> {
+        self.get_mut(index, generation)?;
+        let entry = &mut self.entries[index as usize];
+        // a wrapped generation would revive stale keys, the slot stays vacant for good
+        let next = match entry.generation.checked_add(1) {
+            Some(generation) => {
+                entry.generation = generation;
+                self.free.replace(index)
+            }
+            None => None,
+        };
+        self.len -= 1;
+        match std::mem::replace(&mut entry.slot, Slot::Vacant(next)) {
+            Slot::Occupied(value) => Some(value),
+            Slot::Vacant(_) => unreachable!("checked above"),
+        }
+    }
+}
+
+impl<V> Default for Slab<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A per-thread slab of items of type 'V' associated to a type.
+/// Use the `assoc_slab!()` macro for implementing this trait on types.
+///
+/// # Panics
+/// The closure passed to `with()` may only read the same slab, the one passed to
+/// `with_mut()` must not access it at all, doing so panics.
+pub trait AssocSlab<V: 'static>: Sized + 'static {
+    /// Returns the associated thread local slab of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    #[doc(hidden)]
+    unsafe fn the_slab() -> *const RefCell<Slab<V>>;
+
+    /// Stores 'item' in the current threads slab and returns its key.
+    fn insert(item: V) -> SlabKey<Self, V> {
+        let (index, generation) = unsafe { (*Self::the_slab()).borrow_mut().insert(item) };
+        SlabKey {
+            index,
+            generation,
+            _marker: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Returns a clone of the item of 'key', `None` when it was removed.
+    fn get(key: SlabKey<Self, V>) -> Option<V>
+    where
+        V: Clone,
+    {
+        Self::with(key, V::clone)
+    }
+
+    /// Calls 'f' with the item of 'key', `None` when it was removed.
+    fn with<R>(key: SlabKey<Self, V>, f: impl FnOnce(&V) -> R) -> Option<R> {
+        unsafe {
+            (*Self::the_slab())
+                .borrow()
+                .get(key.index, key.generation)
+                .map(f)
+        }
+    }
+
+    /// Calls 'f' with the item of 'key' mutably, `None` when it was removed.
+    fn with_mut<R>(key: SlabKey<Self, V>, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        unsafe {
+            (*Self::the_slab())
+                .borrow_mut()
+                .get_mut(key.index, key.generation)
+                .map(f)
+        }
+    }
+
+    /// Removes the item of 'key' and returns it, `None` when it was removed already.
+    fn remove(key: SlabKey<Self, V>) -> Option<V> {
+        unsafe {
+            (*Self::the_slab())
+                .borrow_mut()
+                .remove(key.index, key.generation)
+        }
+    }
+
+    /// Returns whether the item of 'key' is still stored.
+    fn contains(key: SlabKey<Self, V>) -> bool {
+        Self::with(key, |_| ()).is_some()
+    }
+
+    /// Returns the number of items in the current threads slab.
+    fn slab_len() -> usize {
+        unsafe { (*Self::the_slab()).borrow().len }
+    }
+}
+
+/// Associates a per-thread slab to a type.
+///
+///  * 'T' is the type you want have a thread local slab associated to
+///  * 'V' is the type of the items
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Widgets;
+/// assoc_slab!(Widgets, String);
+///
+/// // cheap keys instead of shared pointers
+/// struct Button {
+///     label: SlabKey<Widgets, String>,
+/// }
+///
+/// let button = Button { label: Widgets::insert(String::from("OK")) };
+/// Widgets::with_mut(button.label, |label| label.push('!'));
+/// assert_eq!(Widgets::get(button.label).as_deref(), Some("OK!"));
+///
+/// assert_eq!(Widgets::remove(button.label).as_deref(), Some("OK!"));
+/// assert_eq!(Widgets::get(button.label), None);
+/// ```
+#[macro_export]
+macro_rules! assoc_slab {
+    ($T:ty, $V:ty) => {
+        impl $crate::AssocSlab<$V> for $T {
+            unsafe fn the_slab() -> *const std::cell::RefCell<$crate::slab::Slab<$V>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_SLAB: (
+                        std::cell::RefCell<$crate::slab::Slab<$V>>,
+                        std::marker::PhantomData<$T>,
+                    ) = const {
+                        (
+                            std::cell::RefCell::new($crate::slab::Slab::new()),
+                            std::marker::PhantomData,
+                        )
+                    };
+                );
+                ASSOCIATED_SLAB.with(|l| &l.0 as *const std::cell::RefCell<$crate::slab::Slab<$V>>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slab;
+    use crate::AssocSlab;
+
+    struct Nodes;
+    assoc_slab!(Nodes, Vec<u32>);
+    assoc_slab!(Nodes, char);
+
+    #[test]
+    fn reuses_slots_with_new_generation() {
+        let a = Nodes::insert(vec![1]);
+        let b = Nodes::insert(vec![2]);
+        let c = Nodes::insert('c');
+        assert_eq!(<Nodes as AssocSlab<Vec<u32>>>::slab_len(), 2);
+        assert_eq!(<Nodes as AssocSlab<char>>::slab_len(), 1);
+        assert_eq!(c.index(), 0);
+
+        assert_eq!(Nodes::remove(a), Some(vec![1]));
+        assert_eq!(Nodes::remove(a), None);
+        let d = Nodes::insert(vec![3]);
+        assert_eq!(d.index(), a.index());
+        assert_ne!(d, a);
+        assert!(!Nodes::contains(a));
+        assert_eq!(Nodes::with(a, Vec::len), None);
+        assert_eq!(Nodes::get(d), Some(vec![3]));
+        assert_eq!(Nodes::get(b), Some(vec![2]));
+        assert_eq!(Nodes::get(c), Some('c'));
+    }
+
+    #[test]
+    fn retires_exhausted_slots() {
+        let mut slab = Slab::new();
+        let (index, _) = slab.insert('a');
+        slab.entries[index as usize].generation = u32::MAX;
+        assert_eq!(slab.remove(index, u32::MAX), Some('a'));
+        assert_eq!(slab.get(index, u32::MAX), None);
+        assert_eq!(slab.get(index, 0), None);
+
+        let (other, generation) = slab.insert('b');
+        assert_ne!(other, index);
+        assert_eq!(generation, 0);
+        assert_eq!(slab.remove(index, u32::MAX), None);
+        assert_eq!(slab.len, 1);
+    }
+
+    #[test]
+    fn nested_reads() {
+        let parent = Nodes::insert(vec![1, 2]);
+        let child = Nodes::insert(vec![3]);
+        let total = Nodes::with(parent, |parent| {
+            parent.len() + Nodes::with(child, Vec::len).unwrap()
+        });
+        assert_eq!(total, Some(3));
+        assert!(Nodes::with(child, |_| Nodes::contains(parent)).unwrap());
+    }
+
+    #[test]
+    fn per_thread() {
+        let key = Nodes::insert('x');
+        assert_eq!(
+            std::thread::spawn(<Nodes as AssocSlab<char>>::slab_len)
+                .join()
+                .unwrap(),
+            0
+        );
+        assert!(Nodes::contains(key));
+    }
+}
@@ -0,0 +1,152 @@
+//! Per-thread ambient stacks associated to types.
+//!
+//! An ambient stack holds nested state like the file currently processed or the nesting
+//! of sections.  Pushing returns a guard that pops the value again, also when unwinding.
+//! Unlike the context stacks in `context` the values need not be `Clone`.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+
+/// Associates a per-thread stack of V to a type.
+/// Use the `assoc_stack!()` macro for implementing this trait on types.
+pub trait AssocStack<V: 'static, TAG = ()>: Sized {
+    /// Returns the associated thread local stack of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_stack() -> *const RefCell<Vec<V>>;
+
+    /// Pushes 'value', it is popped when the returned guard is dropped.
+    fn push_scoped(value: V) -> StackGuard<Self, V, TAG> {
+        let mut stack = unsafe { (*Self::the_stack()).borrow_mut() };
+        stack.push(value);
+        StackGuard {
+            depth: stack.len() - 1,
+            _marker: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Returns a clone of the innermost value.
+    fn top() -> Option<V>
+    where
+        V: Clone,
+    {
+        unsafe { (*Self::the_stack()).borrow().last().cloned() }
+    }
+
+    /// Calls 'f' with an iterator over the values, innermost first.  'f' must not push to
+    /// or pop from the same stack.
+    fn iter_with<R>(f: impl FnOnce(std::iter::Rev<std::slice::Iter<V>>) -> R) -> R {
+        let stack = unsafe { (*Self::the_stack()).borrow() };
+        f(stack.iter().rev())
+    }
+
+    /// Returns the number of values on the stack.
+    fn stack_depth() -> usize {
+        unsafe { (*Self::the_stack()).borrow().len() }
+    }
+}
+
+/// Pops a value from an ambient stack when dropped.
+#[must_use = "the value is popped immediately when the guard is not kept"]
+pub struct StackGuard<S: AssocStack<V, TAG>, V: 'static, TAG = ()> {
+    depth: usize,
+    _marker: PhantomData<fn() -> (S, TAG)>,
+    // the value must be popped on the thread that pushed it
+    _not_send: PhantomData<*const V>,
+}
+
+impl<S: AssocStack<V, TAG>, V: 'static, TAG> Drop for StackGuard<S, V, TAG> {
+    fn drop(&mut self) {
+        // truncating also pops inner values whose guards were leaked
+        unsafe { (*S::the_stack()).borrow_mut().truncate(self.depth) }
+    }
+}
+
+/// Associates a per-thread ambient stack to a type.
+///
+///  * 'TAG' is used to discriminate between different stacks of the same type
+///  * 'T' is the type you want have a stack associated to
+///  * 'V' is the type of the values on the stack
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Parser;
+/// assoc_stack!(Parser, String);
+///
+/// fn location() -> String {
+///     Parser::iter_with(|files| files.map(String::as_str).collect::<Vec<_>>().join(" <- "))
+/// }
+///
+/// let _main = Parser::push_scoped(String::from("main.conf"));
+/// {
+///     let _include = Parser::push_scoped(String::from("include.conf"));
+///     assert_eq!(location(), "include.conf <- main.conf");
+/// }
+/// assert_eq!(Parser::top().as_deref(), Some("main.conf"));
+/// ```
+#[macro_export]
+macro_rules! assoc_stack {
+    ($T:ty, $V:ty) => {
+        $crate::assoc_stack!((): $T, $V);
+    };
+    ($TAG:ty: $T:ty, $V:ty) => {
+        impl $crate::AssocStack<$V, $TAG> for $T {
+            unsafe fn the_stack() -> *const std::cell::RefCell<Vec<$V>> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_STACK: (
+                        std::cell::RefCell<Vec<$V>>,
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = (
+                        std::cell::RefCell::new(Vec::new()),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_STACK.with(|l| &l.0 as *const std::cell::RefCell<Vec<$V>>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocStack;
+
+    struct Sections;
+    assoc_stack!(Sections, Vec<u8>);
+
+    #[test]
+    fn nested() {
+        assert_eq!(Sections::top(), None);
+        let _a = Sections::push_scoped(vec![1]);
+        {
+            let _b = Sections::push_scoped(vec![2, 3]);
+            let all = Sections::iter_with(|it| it.flatten().copied().collect::<Vec<_>>());
+            assert_eq!(all, [2, 3, 1]);
+        }
+        assert_eq!(Sections::top(), Some(vec![1]));
+        assert_eq!(Sections::stack_depth(), 1);
+    }
+
+    #[test]
+    fn popped_on_unwind() {
+        let result = std::panic::catch_unwind(|| {
+            let _a = Sections::push_scoped(vec![9]);
+            panic!("boom");
+        });
+        assert!(result.is_err());
+        assert_eq!(Sections::stack_depth(), 0);
+    }
+
+    #[test]
+    fn leaked_inner_guard() {
+        let outer = Sections::push_scoped(vec![1]);
+        std::mem::forget(Sections::push_scoped(vec![2]));
+        drop(outer);
+        assert_eq!(Sections::stack_depth(), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! Setting several associations of a type at once.
+//!
+//! Thread setup code often sets a handful of values on the same type.  `threadlocals()`
+//! stages them and `apply()` writes them together, `apply_scoped()` restores the previous
+//! values when the returned guard is dropped.
+
+use crate::AssocThreadLocal;
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+
+type ApplyFn = fn(Box<dyn Any>);
+type ApplyScopedFn = fn(Box<dyn Any>) -> Box<dyn Any>;
+
+fn apply<S, T, TAG>(value: Box<dyn Any>)
+where
+    S: AssocThreadLocal<T, TAG>,
+    T: Copy + 'static,
+{
+    S::set_threadlocal(*value.downcast::<T>().expect("staged value type"));
+}
+
+fn apply_scoped<S, T, TAG>(value: Box<dyn Any>) -> Box<dyn Any>
+where
+    S: AssocThreadLocal<T, TAG> + 'static,
+    T: Copy + 'static,
+    TAG: 'static,
+{
+    Box::new(S::set_threadlocal_scoped(
+        *value.downcast::<T>().expect("staged value type"),
+    ))
+}
+
+/// Starts staging values for the associations of a type, available on every `'static`
+/// type.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Worker;
+/// struct Name;
+/// assoc_threadlocal!(Worker, u32 = 0);
+/// assoc_threadlocal!(Name:Worker, &'static str = "main");
+///
+/// Worker::threadlocals()
+///     .set::<u32>(3)
+///     .set_tagged::<&'static str, Name>("debug")
+///     .apply();
+/// assert_eq!(<Worker as GetAssocThreadLocal<u32>>::get_threadlocal(), 3);
+/// assert_eq!(<Worker as GetAssocThreadLocal<&str, Name>>::get_threadlocal(), "debug");
+/// ```
+pub trait StageThreadLocals: Sized + 'static {
+    /// Returns an empty set of staged values for Self.
+    fn threadlocals() -> Staged<Self> {
+        Staged {
+            values: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S: 'static> StageThreadLocals for S {}
+
+/// Values staged for the associations of 'S', written by `apply()` or `apply_scoped()`.
+#[must_use = "staged values are only written by apply() or apply_scoped()"]
+pub struct Staged<S> {
+    values: Vec<(ApplyFn, ApplyScopedFn, Box<dyn Any>)>,
+    _marker: PhantomData<fn() -> S>,
+}
+
+impl<S: 'static> Staged<S> {
+    /// Stages 'value' for the untagged association of 'T' to 'S'.
+    pub fn set<T>(self, value: T) -> Self
+    where
+        S: AssocThreadLocal<T>,
+        T: Copy + 'static,
+    {
+        self.set_tagged::<T, ()>(value)
+    }
+
+    /// Stages 'value' for the association of 'T' with 'TAG' to 'S'.
+    pub fn set_tagged<T, TAG: 'static>(mut self, value: T) -> Self
+    where
+        S: AssocThreadLocal<T, TAG>,
+        T: Copy + 'static,
+    {
+        self.values.push((
+            apply::<S, T, TAG>,
+            apply_scoped::<S, T, TAG>,
+            Box::new(value),
+        ));
+        self
+    }
+
+    /// Returns the number of staged values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns whether no value is staged.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Writes the staged values in the order they were staged.
+    pub fn apply(self) {
+        for (apply, _, value) in self.values {
+            apply(value);
+        }
+    }
+
+    /// Writes the staged values in the order they were staged, the previous values are
+    /// restored in reverse order when the returned guard is dropped.
+    pub fn apply_scoped(self) -> StagedGuard {
+        StagedGuard {
+            guards: self
+                .values
+                .into_iter()
+                .map(|(_, apply_scoped, value)| apply_scoped(value))
+                .collect(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for Staged<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Staged")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+/// Restores the values overwritten by `Staged::apply_scoped()` when dropped.
+#[must_use = "the previous values are restored immediately when the guard is not kept"]
+pub struct StagedGuard {
+    // the guards of the single values, not 'Send' as these are not
+    guards: Vec<Box<dyn Any>>,
+}
+
+impl Drop for StagedGuard {
+    fn drop(&mut self) {
+        while let Some(guard) = self.guards.pop() {
+            drop(guard);
+        }
+    }
+}
+
+impl fmt::Debug for StagedGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StagedGuard")
+            .field("len", &self.guards.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StageThreadLocals;
+    use crate::{GetAssocThreadLocal, SetAssocThreadLocal};
+
+    struct Setup;
+    struct Retries;
+    crate::assoc_threadlocal!(Setup, u32 = 1);
+    crate::assoc_threadlocal!(Retries:Setup, u32 = 5);
+    crate::assoc_threadlocal!(Setup, bool = false);
+
+    #[test]
+    fn scoped_restores() {
+        {
+            let _setup = Setup::threadlocals()
+                .set::<u32>(2)
+                .set_tagged::<u32, Retries>(0)
+                .set::<bool>(true)
+                .apply_scoped();
+            assert_eq!(<Setup as GetAssocThreadLocal<u32>>::get_threadlocal(), 2);
+            assert_eq!(
+                <Setup as GetAssocThreadLocal<u32, Retries>>::get_threadlocal(),
+                0
+            );
+            assert!(<Setup as GetAssocThreadLocal<bool>>::get_threadlocal());
+        }
+        assert_eq!(<Setup as GetAssocThreadLocal<u32>>::get_threadlocal(), 1);
+        assert_eq!(
+            <Setup as GetAssocThreadLocal<u32, Retries>>::get_threadlocal(),
+            5
+        );
+        assert!(!<Setup as GetAssocThreadLocal<bool>>::get_threadlocal());
+    }
+
+    #[test]
+    fn later_values_win() {
+        Setup::threadlocals().set::<u32>(3).set::<u32>(4).apply();
+        assert_eq!(<Setup as GetAssocThreadLocal<u32>>::get_threadlocal(), 4);
+        <Setup as SetAssocThreadLocal<u32>>::set_threadlocal(7);
+        {
+            let _guard = Setup::threadlocals()
+                .set::<u32>(8)
+                .set::<u32>(9)
+                .apply_scoped();
+            assert_eq!(<Setup as GetAssocThreadLocal<u32>>::get_threadlocal(), 9);
+        }
+        assert_eq!(<Setup as GetAssocThreadLocal<u32>>::get_threadlocal(), 7);
+    }
+}
@@ -0,0 +1,367 @@
+//! Per-thread running statistics.
+//!
+//! `Stats<T>` is a `Copy` target type, any association with it gets `record()` and
+//! `summary()` through the blanket `AssocStats` implementation.  With the `registry`
+//! feature threads publish their summaries to be merged into a process wide one.
+
+use crate::AssocThreadLocal;
+use std::ops::Add;
+
+/// Values that can be recorded in `Stats`.
+pub trait Sample: Copy + PartialOrd + Add<Output = Self> {
+    /// The neutral element of addition.
+    const ZERO: Self;
+
+    /// Adds 'rhs', integers saturate at their bounds instead of overflowing.
+    fn saturating_add(self, rhs: Self) -> Self;
+
+    /// Lossy conversion used for calculating the mean.
+    fn as_f64(self) -> f64;
+}
+
+macro_rules! impl_sample {
+    ($($T:ty: $ZERO:expr),*) => {
+        $(
+            impl Sample for $T {
+                const ZERO: Self = $ZERO;
+
+                fn saturating_add(self, rhs: Self) -> Self {
+                    <$T>::saturating_add(self, rhs)
+                }
+
+                fn as_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_sample!(
+    u8: 0, u16: 0, u32: 0, u64: 0, u128: 0, usize: 0,
+    i8: 0, i16: 0, i32: 0, i64: 0, i128: 0, isize: 0
+);
+
+macro_rules! impl_float_sample {
+    ($($T:ty),*) => {
+        $(
+            impl Sample for $T {
+                const ZERO: Self = 0.0;
+
+                fn saturating_add(self, rhs: Self) -> Self {
+                    self + rhs
+                }
+
+                fn as_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_float_sample!(f32, f64);
+
+/// Count, minimum, maximum and sum of recorded values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats<T: Sample> {
+    count: u64,
+    min: Option<T>,
+    max: Option<T>,
+    sum: T,
+    // the mean stays accurate when the sum saturates
+    mean_sum: f64,
+}
+
+impl<T: Sample> Stats<T> {
+    /// Creates empty statistics, usable as INIT of an association.
+    pub const fn new() -> Self {
+        Stats {
+            count: 0,
+            min: None,
+            max: None,
+            sum: T::ZERO,
+            mean_sum: 0.0,
+        }
+    }
+
+    /// Adds a single value.
+    pub fn record(&mut self, value: T) {
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.mean_sum += value.as_f64();
+        if self.min.is_none_or(|min| value < min) {
+            self.min = Some(value);
+        }
+        if self.max.is_none_or(|max| value > max) {
+            self.max = Some(value);
+        }
+    }
+
+    /// Combines the statistics of 'other' into 'self', e.g. the summaries of several threads.
+    pub fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+        self.mean_sum += other.mean_sum;
+        if let Some(min) = other.min {
+            if self.min.is_none_or(|m| min < m) {
+                self.min = Some(min);
+            }
+        }
+        if let Some(max) = other.max {
+            if self.max.is_none_or(|m| max > m) {
+                self.max = Some(max);
+            }
+        }
+    }
+
+    /// Number of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Smallest recorded value.
+    pub fn min(&self) -> Option<T> {
+        self.min
+    }
+
+    /// Largest recorded value.
+    pub fn max(&self) -> Option<T> {
+        self.max
+    }
+
+    /// Sum of all recorded values, saturated at the bounds of integer types.
+    pub fn sum(&self) -> T {
+        self.sum
+    }
+
+    /// Arithmetic mean of the recorded values.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.mean_sum / self.count as f64)
+    }
+}
+
+impl<T: Sample> Default for Stats<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Helpers for associations with a `Stats` target, implemented for all of these.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct DbLatency;
+/// assoc_threadlocal!(DbLatency, Stats<u32> = Stats::new());
+///
+/// DbLatency::record(10);
+/// DbLatency::record(30);
+/// let summary = DbLatency::summary();
+/// assert_eq!(summary.count(), 2);
+/// assert_eq!(summary.max(), Some(30));
+/// assert_eq!(summary.mean(), Some(20.0));
+/// ```
+pub trait AssocStats<T: Sample, TAG = ()>: AssocThreadLocal<Stats<T>, TAG> {
+    /// Records a value in the current threads statistics.
+    fn record(value: T) {
+        let mut stats = Self::get_threadlocal();
+        stats.record(value);
+        Self::set_threadlocal(stats);
+    }
+
+    /// Returns the current threads statistics.
+    fn summary() -> Stats<T> {
+        Self::get_threadlocal()
+    }
+
+    /// Clears the current threads statistics and returns the old ones.
+    fn take_summary() -> Stats<T> {
+        let stats = Self::get_threadlocal();
+        Self::set_threadlocal(Stats::new());
+        stats
+    }
+
+    /// Publishes the current threads statistics for `collect_summary()`, replacing the
+    /// ones it published before.  Published statistics outlive their thread, workers
+    /// publish before they exit.
+    #[cfg(feature = "registry")]
+    fn publish_summary()
+    where
+        Self: 'static,
+        T: Send + 'static,
+        TAG: 'static,
+    {
+        published::publish::<Self, T, TAG>(Self::get_threadlocal());
+    }
+
+    /// Merges the statistics published by all threads.
+    ///
+    /// ```
+    /// use crate::assoc_threadlocal::*;
+    ///
+    /// struct Jobs;
+    /// assoc_threadlocal!(Jobs, Stats<u64> = Stats::new());
+    ///
+    /// let workers: Vec<_> = (1..=4)
+    ///     .map(|n| {
+    ///         std::thread::spawn(move || {
+    ///             Jobs::record(n * 10);
+    ///             Jobs::publish_summary();
+    ///         })
+    ///     })
+    ///     .collect();
+    /// workers.into_iter().for_each(|worker| worker.join().unwrap());
+    ///
+    /// let total = Jobs::collect_summary();
+    /// assert_eq!(total.count(), 4);
+    /// assert_eq!(total.sum(), 100);
+    /// ```
+    #[cfg(feature = "registry")]
+    fn collect_summary() -> Stats<T>
+    where
+        Self: 'static,
+        T: Send + 'static,
+        TAG: 'static,
+    {
+        published::collect::<Self, T, TAG>()
+    }
+}
+
+impl<S, T: Sample, TAG> AssocStats<T, TAG> for S where S: AssocThreadLocal<Stats<T>, TAG> {}
+
+#[cfg(feature = "registry")]
+mod published {
+    use super::{Sample, Stats};
+    use std::any::{Any, TypeId};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::thread::ThreadId;
+
+    // implementor, target and tag
+    type Association = (TypeId, TypeId, TypeId);
+
+    type Published = HashMap<(Association, ThreadId), Box<dyn Any + Send>>;
+
+    // the latest statistics of every thread, keyed by the association
+    static PUBLISHED: Mutex<Option<Published>> = Mutex::new(None);
+
+    fn key<S: ?Sized + 'static, T: 'static, TAG: 'static>() -> (Association, ThreadId) {
+        (
+            (TypeId::of::<S>(), TypeId::of::<T>(), TypeId::of::<TAG>()),
+            std::thread::current().id(),
+        )
+    }
+
+    pub(super) fn publish<S: ?Sized + 'static, T: Sample + Send + 'static, TAG: 'static>(
+        stats: Stats<T>,
+    ) {
+        PUBLISHED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashMap::new)
+            .insert(key::<S, T, TAG>(), Box::new(stats));
+    }
+
+    pub(super) fn collect<S: ?Sized + 'static, T: Sample + Send + 'static, TAG: 'static>(
+    ) -> Stats<T> {
+        let association = key::<S, T, TAG>().0;
+        let mut total = Stats::new();
+        if let Some(published) = PUBLISHED.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            published
+                .iter()
+                .filter(|((id, _), _)| *id == association)
+                .filter_map(|(_, stats)| stats.downcast_ref::<Stats<T>>())
+                .for_each(|stats| total.merge(stats));
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocStats, Stats};
+
+    struct Latency;
+    crate::assoc_threadlocal!(Latency, Stats<f64> = Stats::new());
+
+    #[test]
+    fn empty() {
+        let stats = Stats::<i32>::new();
+        assert_eq!(stats.count(), 0);
+        assert_eq!(stats.min(), None);
+        assert_eq!(stats.mean(), None);
+    }
+
+    #[test]
+    fn record_and_take() {
+        Latency::record(1.5);
+        Latency::record(-0.5);
+        let summary = Latency::take_summary();
+        assert_eq!(summary.min(), Some(-0.5));
+        assert_eq!(summary.sum(), 1.0);
+        assert_eq!(Latency::summary().count(), 0);
+    }
+
+    #[test]
+    fn merge_threads() {
+        let mut total = Stats::new();
+        for t in 0..3 {
+            let summary = std::thread::spawn(move || {
+                Latency::record(t as f64);
+                Latency::summary()
+            })
+            .join()
+            .unwrap();
+            total.merge(&summary);
+        }
+        assert_eq!(total.count(), 3);
+        assert_eq!(total.min(), Some(0.0));
+        assert_eq!(total.max(), Some(2.0));
+    }
+
+    #[test]
+    fn saturating_sum() {
+        let mut stats = Stats::<u8>::new();
+        stats.record(200);
+        stats.record(100);
+        assert_eq!(stats.sum(), u8::MAX);
+        assert_eq!(stats.mean(), Some(150.0));
+        let mut total = stats;
+        total.merge(&stats);
+        assert_eq!((total.count(), total.sum()), (4, u8::MAX));
+        assert_eq!(total.mean(), Some(150.0));
+
+        let mut signed = Stats::<i8>::new();
+        signed.record(-100);
+        signed.record(-100);
+        assert_eq!(signed.sum(), i8::MIN);
+    }
+
+    #[cfg(feature = "registry")]
+    #[test]
+    fn collect_published() {
+        struct Queue;
+        crate::assoc_threadlocal!(Queue, Stats<u32> = Stats::new());
+
+        assert_eq!(Queue::collect_summary().count(), 0);
+        Queue::record(1);
+        Queue::publish_summary();
+        std::thread::spawn(|| {
+            Queue::record(5);
+            Queue::record(7);
+            Queue::publish_summary();
+        })
+        .join()
+        .unwrap();
+        // publishing again replaces the threads earlier summary
+        Queue::record(3);
+        Queue::publish_summary();
+
+        let total = Queue::collect_summary();
+        assert_eq!(total.count(), 4);
+        assert_eq!((total.min(), total.max()), (Some(1), Some(7)));
+        assert_eq!(total.sum(), 16);
+        assert_eq!(Latency::collect_summary().count(), 0);
+    }
+}
@@ -0,0 +1,200 @@
+//! Associations selected by the variant of a tag enum at runtime.
+//!
+//! Tags of `assoc_threadlocal!()` are types and selected at compile time.  Data driven
+//! code, e.g. picking a log channel or priority from a message, needs to choose at
+//! runtime.  An enum defined with `tag_enum!()` serves as a runtime tag,
+//! `assoc_tag_enum!()` associates one value per variant, stored side by side in one
+//! thread local and selected by index.
+
+use std::cell::Cell;
+
+/// A fieldless enum whose variants select associations at runtime.
+/// Use the `tag_enum!()` macro for defining such enums.
+pub trait TagEnum: Copy + 'static {
+    /// All variants in declaration order.
+    const VARIANTS: &'static [Self];
+
+    /// Returns the position of the variant in `VARIANTS`.
+    fn index(self) -> usize;
+}
+
+/// One value per variant of the tag enum 'E' associated to a type.
+/// Use the `assoc_tag_enum!()` macro for implementing this trait on types.
+pub trait AssocTagEnum<T: Copy, E: TagEnum> {
+    #[doc(hidden)]
+    unsafe fn the_slots() -> *const [Cell<T>];
+
+    /// Returns the initial value, the same for all variants.
+    fn threadlocal_init_for(tag: E) -> T;
+
+    /// Returns the current threads value for the variant 'tag'.
+    #[inline]
+    fn get_threadlocal_for(tag: E) -> T {
+        unsafe { (&(*Self::the_slots()))[tag.index()].get() }
+    }
+
+    /// Sets the current threads value for the variant 'tag'.
+    #[inline]
+    fn set_threadlocal_for(tag: E, value: T) {
+        unsafe { (&(*Self::the_slots()))[tag.index()].set(value) }
+    }
+
+    /// Sets the current threads value for the variant 'tag' and returns the old one.
+    fn replace_threadlocal_for(tag: E, value: T) -> T {
+        unsafe { (&(*Self::the_slots()))[tag.index()].replace(value) }
+    }
+
+    /// Resets the current threads value for the variant 'tag' to INIT.
+    fn reset_threadlocal_for(tag: E) {
+        Self::set_threadlocal_for(tag, Self::threadlocal_init_for(tag))
+    }
+
+    /// Returns the current threads values of all variants in declaration order.
+    fn get_threadlocal_all() -> Vec<(E, T)> {
+        E::VARIANTS
+            .iter()
+            .map(|&tag| (tag, Self::get_threadlocal_for(tag)))
+            .collect()
+    }
+}
+
+/// Defines a fieldless enum usable as runtime tag with `assoc_tag_enum!()`.
+/// The enum derives `Clone`, `Copy`, `PartialEq`, `Eq`, `Hash` and `Debug`.
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// tag_enum! {
+///     /// Where log messages go.
+///     pub enum Channel { Audit, Debug, Trace }
+/// }
+///
+/// assert_eq!(Channel::VARIANTS.len(), 3);
+/// assert_eq!(Channel::Trace.index(), 2);
+/// ```
+#[macro_export]
+macro_rules! tag_enum {
+    ($(#[$ATTR:meta])* $VIS:vis enum $E:ident { $($VARIANT:ident),+ $(,)? }) => {
+        $(#[$ATTR])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        $VIS enum $E {
+            $($VARIANT),+
+        }
+
+        impl $crate::TagEnum for $E {
+            const VARIANTS: &'static [Self] = &[$($E::$VARIANT),+];
+
+            #[inline]
+            fn index(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+/// Associates one thread local value per variant of a tag enum to a type.
+///
+///  * 'E' is the tag enum, defined with `tag_enum!()`
+///  * 'T' is the type you want have the values associated to
+///  * 'TARGET' is the type of the values
+///  * 'INIT' is the initial value of every variant
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// tag_enum! {
+///     pub enum Channel { Audit, Debug, Trace }
+/// }
+///
+/// struct Logger;
+/// assoc_tag_enum!(Channel: Logger, bool = true);
+///
+/// fn enabled(channel: Channel) -> bool {
+///     Logger::get_threadlocal_for(channel)
+/// }
+///
+/// Logger::set_threadlocal_for(Channel::Trace, false);
+/// assert!(enabled(Channel::Debug));
+/// assert!(!enabled(Channel::Trace));
+/// ```
+#[macro_export]
+macro_rules! assoc_tag_enum {
+    ($E:ty: $T:ty, $TARGET:ty = $INIT:expr) => {
+        impl $crate::AssocTagEnum<$TARGET, $E> for $T {
+            unsafe fn the_slots() -> *const [std::cell::Cell<$TARGET>] {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_THREADLOCALS: (
+                        [std::cell::Cell<$TARGET>; <$E as $crate::TagEnum>::VARIANTS.len()],
+                        std::marker::PhantomData<$T>,
+                    ) = (
+                        std::array::from_fn(|index| {
+                            std::cell::Cell::new(
+                                <$T as $crate::AssocTagEnum<$TARGET, $E>>::threadlocal_init_for(
+                                    <$E as $crate::TagEnum>::VARIANTS[index],
+                                ),
+                            )
+                        }),
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_THREADLOCALS.with(|l| &l.0[..] as *const [std::cell::Cell<$TARGET>])
+            }
+
+            fn threadlocal_init_for(_tag: $E) -> $TARGET {
+                $INIT
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AssocTagEnum, TagEnum};
+
+    tag_enum! {
+        enum Priority { Low, Normal, High }
+    }
+
+    struct Queue;
+    assoc_tag_enum!(Priority: Queue, u32 = 10);
+    assoc_tag_enum!(Priority: Queue, char = 'x');
+
+    #[test]
+    fn per_variant() {
+        assert_eq!(
+            Priority::VARIANTS,
+            [Priority::Low, Priority::Normal, Priority::High]
+        );
+        <Queue as AssocTagEnum<u32, _>>::set_threadlocal_for(Priority::High, 1);
+        assert_eq!(
+            <Queue as AssocTagEnum<u32, _>>::replace_threadlocal_for(Priority::Low, 2),
+            10
+        );
+        <Queue as AssocTagEnum<char, _>>::set_threadlocal_for(Priority::Normal, 'n');
+        assert_eq!(
+            <Queue as AssocTagEnum<u32, _>>::get_threadlocal_all(),
+            [
+                (Priority::Low, 2),
+                (Priority::Normal, 10),
+                (Priority::High, 1)
+            ]
+        );
+        assert_eq!(
+            <Queue as AssocTagEnum<char, _>>::get_threadlocal_for(Priority::Normal),
+            'n'
+        );
+        assert_eq!(
+            std::thread::spawn(|| {
+                <Queue as AssocTagEnum<u32, _>>::get_threadlocal_for(Priority::High)
+            })
+            .join()
+            .unwrap(),
+            10
+        );
+        <Queue as AssocTagEnum<u32, _>>::reset_threadlocal_for(Priority::High);
+        assert_eq!(
+            <Queue as AssocTagEnum<u32, _>>::get_threadlocal_for(Priority::High),
+            10
+        );
+    }
+}
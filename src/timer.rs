@@ -0,0 +1,93 @@
+//! Per-thread accumulating stopwatches.
+
+use crate::AssocThreadLocal;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+/// Tag for the thread local accumulated time of a timer.
+pub struct TimerState;
+
+/// A per-thread stopwatch accumulating the time spent in scopes.
+/// Use the `assoc_timer!()` macro for implementing this trait on types.
+pub trait AssocTimer: AssocThreadLocal<Duration, TimerState> + Sized {
+    /// Starts timing, the elapsed time is added to the total when the guard is dropped.
+    fn time_scope() -> TimerGuard<Self> {
+        TimerGuard {
+            start: Instant::now(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the total time accumulated on the current thread.
+    fn elapsed_total() -> Duration {
+        Self::get_threadlocal()
+    }
+
+    /// Resets the current threads total, returns the old value.
+    fn reset_elapsed() -> Duration {
+        let total = Self::get_threadlocal();
+        Self::set_threadlocal(Duration::ZERO);
+        total
+    }
+}
+
+/// Adds the time since its creation to the timer when dropped.
+#[must_use = "the scope is timed until the guard is dropped"]
+pub struct TimerGuard<T: AssocTimer> {
+    start: Instant,
+    // the elapsed time must be added on the thread that started timing
+    _marker: PhantomData<(T, *const ())>,
+}
+
+impl<T: AssocTimer> Drop for TimerGuard<T> {
+    fn drop(&mut self) {
+        T::set_threadlocal(T::get_threadlocal() + self.start.elapsed());
+    }
+}
+
+/// Associates a per-thread stopwatch to a type.
+///
+///  * 'T' is the type you want have a thread local timer associated to
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+/// use std::time::Duration;
+///
+/// struct DbLayer;
+/// assoc_timer!(DbLayer);
+///
+/// {
+///     let _timing = DbLayer::time_scope();
+///     std::thread::sleep(Duration::from_millis(1));
+/// }
+/// assert!(DbLayer::elapsed_total() >= Duration::from_millis(1));
+/// ```
+#[macro_export]
+macro_rules! assoc_timer {
+    ($T:ty) => {
+        $crate::assoc_threadlocal!($crate::TimerState:$T, std::time::Duration = std::time::Duration::ZERO);
+
+        impl $crate::AssocTimer for $T {}
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AssocTimer;
+    use std::time::Duration;
+
+    struct TestLayer;
+    assoc_timer!(TestLayer);
+
+    #[test]
+    fn accumulates() {
+        assert_eq!(TestLayer::elapsed_total(), Duration::ZERO);
+        for _ in 0..2 {
+            let _t = TestLayer::time_scope();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert!(TestLayer::elapsed_total() >= Duration::from_millis(4));
+        assert!(TestLayer::reset_elapsed() >= Duration::from_millis(4));
+        assert_eq!(TestLayer::elapsed_total(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,189 @@
+//! Per-thread heterogeneous maps keyed by type.
+//!
+//! One association can hold an open ended set of per-thread extension data, each value
+//! is stored under its own type.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A map holding at most one value of each type.
+#[derive(Default)]
+pub struct TypedMap {
+    values: HashMap<TypeId, Box<dyn Any>>,
+}
+
+impl TypedMap {
+    /// Creates an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts 'value', returns the previous value of the same type.
+    pub fn insert<V: 'static>(&mut self, value: V) -> Option<V> {
+        self.values
+            .insert(TypeId::of::<V>(), Box::new(value))
+            .map(|old| {
+                *old.downcast::<V>()
+                    .expect("typed map entry of another type")
+            })
+    }
+
+    /// Returns a reference to the value of type 'V'.
+    pub fn get<V: 'static>(&self) -> Option<&V> {
+        self.values.get(&TypeId::of::<V>())?.downcast_ref()
+    }
+
+    /// Returns a mutable reference to the value of type 'V'.
+    pub fn get_mut<V: 'static>(&mut self) -> Option<&mut V> {
+        self.values.get_mut(&TypeId::of::<V>())?.downcast_mut()
+    }
+
+    /// Removes and returns the value of type 'V'.
+    pub fn remove<V: 'static>(&mut self) -> Option<V> {
+        self.values.remove(&TypeId::of::<V>()).map(|old| {
+            *old.downcast::<V>()
+                .expect("typed map entry of another type")
+        })
+    }
+
+    /// Returns whether a value of type 'V' is stored.
+    pub fn contains<V: 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<V>())
+    }
+
+    /// Returns the number of stored values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Removes all values.
+    pub fn clear(&mut self) {
+        self.values.clear()
+    }
+}
+
+impl fmt::Debug for TypedMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TypedMap")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Associates a per-thread `TypedMap` to a type.
+/// Use the `assoc_typed_map!()` macro for implementing this trait on types.
+pub trait AssocTypedMap<TAG = ()> {
+    /// Returns the associated thread local map of the Self type
+    ///
+    /// # Safety
+    /// The returned pointer must be immediately used, not stored/passed somewhere else.
+    unsafe fn the_typed_map() -> *const RefCell<TypedMap>;
+
+    /// Inserts 'value' into the current threads map, returns the previous value of the
+    /// same type.
+    fn insert_local<V: 'static>(value: V) -> Option<V> {
+        unsafe { (*Self::the_typed_map()).borrow_mut().insert(value) }
+    }
+
+    /// Returns a clone of the current threads value of type 'V'.
+    fn get_local<V: Clone + 'static>() -> Option<V> {
+        unsafe { (*Self::the_typed_map()).borrow().get::<V>().cloned() }
+    }
+
+    /// Calls 'f' with the current threads value of type 'V'.  'f' must not access the
+    /// same map.
+    fn with_local<V: 'static, R>(f: impl FnOnce(Option<&mut V>) -> R) -> R {
+        f(unsafe { (*Self::the_typed_map()).borrow_mut().get_mut::<V>() })
+    }
+
+    /// Removes and returns the current threads value of type 'V'.
+    fn remove_local<V: 'static>() -> Option<V> {
+        unsafe { (*Self::the_typed_map()).borrow_mut().remove::<V>() }
+    }
+}
+
+/// Associates a per-thread typed map to a type.
+///
+///  * 'TAG' is used to discriminate between different maps of the same type
+///  * 'T' is the type you want have a map associated to
+///
+/// ```
+/// use crate::assoc_threadlocal::*;
+///
+/// struct Extensions;
+/// assoc_typed_map!(Extensions);
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// struct User(String);
+///
+/// Extensions::insert_local(User(String::from("alice")));
+/// Extensions::insert_local(42u32);
+/// assert_eq!(Extensions::get_local::<User>(), Some(User(String::from("alice"))));
+/// Extensions::with_local::<u32, _>(|n| *n.unwrap() += 1);
+/// assert_eq!(Extensions::remove_local::<u32>(), Some(43));
+/// assert_eq!(Extensions::get_local::<u32>(), None);
+/// ```
+#[macro_export]
+macro_rules! assoc_typed_map {
+    ($T:ty) => {
+        $crate::assoc_typed_map!((): $T);
+    };
+    ($TAG:ty: $T:ty) => {
+        impl $crate::AssocTypedMap<$TAG> for $T {
+            unsafe fn the_typed_map() -> *const std::cell::RefCell<$crate::TypedMap> {
+                $crate::__assoc_thread_local!(
+                    static ASSOCIATED_TYPED_MAP: (
+                        std::cell::RefCell<$crate::TypedMap>,
+                        std::marker::PhantomData<$T>,
+                        std::marker::PhantomData<$TAG>,
+                    ) = (
+                        std::cell::RefCell::new($crate::TypedMap::new()),
+                        std::marker::PhantomData,
+                        std::marker::PhantomData,
+                    );
+                );
+                ASSOCIATED_TYPED_MAP.with(|l| &l.0 as *const std::cell::RefCell<$crate::TypedMap>)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TypedMap;
+    use crate::AssocTypedMap;
+
+    #[test]
+    fn map() {
+        let mut map = TypedMap::new();
+        assert_eq!(map.insert(1u8), None);
+        assert_eq!(map.insert(2u8), Some(1));
+        map.insert("str");
+        assert_eq!(map.len(), 2);
+        *map.get_mut::<u8>().unwrap() += 1;
+        assert_eq!(map.get::<u8>(), Some(&3));
+        assert!(map.contains::<&str>());
+        assert_eq!(map.remove::<u16>(), None);
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    struct Local;
+    assoc_typed_map!(Local);
+
+    #[test]
+    fn per_thread() {
+        Local::insert_local(String::from("main"));
+        std::thread::spawn(|| assert_eq!(Local::get_local::<String>(), None))
+            .join()
+            .unwrap();
+        assert_eq!(Local::get_local::<String>().as_deref(), Some("main"));
+    }
+}
@@ -0,0 +1,37 @@
+#![cfg(feature = "test-utils")]
+
+use assoc_threadlocal::*;
+
+struct Shared;
+assoc_threadlocal!(Shared, u32 = 0);
+
+// both tests run on the same thread when the harness is single threaded
+#[assoc_test]
+fn first() {
+    assert_eq!(Shared::get_threadlocal(), 0);
+    Shared::set_threadlocal(1);
+}
+
+#[assoc_test]
+fn second() {
+    assert_eq!(Shared::get_threadlocal(), 0);
+    Shared::set_threadlocal(2);
+}
+
+#[assoc_test]
+fn returns_result() -> Result<(), String> {
+    Shared::set_threadlocal(3);
+    Ok(())
+}
+
+#[test]
+fn restored_afterwards() {
+    Shared::set_threadlocal(7);
+    isolated();
+    assert_eq!(Shared::get_threadlocal(), 7);
+}
+
+#[assoc_test]
+fn isolated() {
+    Shared::set_threadlocal(8);
+}